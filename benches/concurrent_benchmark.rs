@@ -13,7 +13,7 @@ fn benchmark_read(c: &mut Criterion) {
         group.bench_with_input(BenchmarkId::from_parameter(num_clients), num_clients, |b, &num_clients| {
 
             let clients: Vec<KVClient> = (0..num_clients).map(|_| {
-                rt.block_on(KVClient::new("all_nodes.json")).unwrap()
+                rt.block_on(KVClient::new("cluster.json")).unwrap()
             }).collect();
 
             b.iter(|| {
@@ -45,7 +45,7 @@ fn benchmark_write(c: &mut Criterion) {
         group.bench_with_input(BenchmarkId::from_parameter(num_clients), num_clients, |b, &num_clients| {
 
             let clients: Vec<KVClient> = (0..num_clients).map(|_| {
-                rt.block_on(KVClient::new("all_nodes.json")).unwrap()
+                rt.block_on(KVClient::new("cluster.json")).unwrap()
             }).collect();
 
             b.iter(|| {
@@ -0,0 +1,740 @@
+//! Cluster administration, as done by the `admin` binary.
+//!
+//! [`Admin`] talks to the nodes through their management API only, so it can run anywhere the
+//! nodes can be reached. Shards are identified by the address of their original leader, like in
+//! the hash ring.
+//!
+//! The hash ring is not replicated by Raft. Every change of the ring is published to all nodes
+//! with a bumped `config_id`, clients pick it up through `get_hash_ring`.
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::future::Future;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use openraft::LogId;
+use openraft::RaftMetrics;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::backup::BackupError;
+use crate::backup::Manifest;
+use crate::backup::ShardBackup;
+use crate::backup::MANIFEST;
+use crate::carp::Carp;
+use crate::carp::CarpError;
+use crate::cluster_manager::fetch_hash_ring;
+use crate::cluster_manager::publish_hash_ring_if;
+use crate::cluster_manager::share_quotas;
+use crate::export::ExportError;
+use crate::export::ExportManifest;
+use crate::export::ShardExport;
+use crate::export::EXPORT_MANIFEST;
+use crate::membership::ChangeProgress;
+use crate::membership::LearnerProgress;
+use crate::membership::MembershipStatus;
+use crate::network::error::ClientError;
+use crate::raft_node::RaftNode;
+use crate::Node;
+use crate::NodeId;
+
+/// Errors of administrative operations.
+#[derive(Error, Debug)]
+pub enum AdminError {
+    #[error(transparent)]
+    Client(#[from] ClientError),
+    #[error(transparent)]
+    Ring(#[from] CarpError),
+    #[error("the hash ring could not be fetched from any of {0:?}")]
+    NoRing(Vec<String>),
+    #[error("shard {0} is not part of the hash ring")]
+    UnknownShard(String),
+    #[error("shard {0} has no leader")]
+    NoLeader(String),
+    #[error("node {node} is not a member of shard {shard}")]
+    UnknownNode { shard: String, node: NodeId },
+    #[error("node {0} is the leader, transfer the leadership first")]
+    IsLeader(NodeId),
+    #[error("node {node} stopped catching up, {lag} entries behind the leader")]
+    Stalled { node: NodeId, lag: u64 },
+    #[error("shard {shard} holds {keys} keys, which the change of the ring would not move")]
+    HoldsKeys { shard: String, keys: u64 },
+    #[error(transparent)]
+    Backup(#[from] BackupError),
+    #[error(transparent)]
+    Export(#[from] ExportError),
+}
+
+/// Role of a node in the membership of its shard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    Leader,
+    Follower,
+    Learner,
+    /// The node is in the ring but not in the membership of the shard.
+    NotMember,
+    /// The membership is unknown, as no node of the shard could be reached.
+    Unknown,
+}
+
+/// State of a single node, as reported by itself and by the leader of its shard.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeStatus {
+    pub id: Option<NodeId>,
+    pub api_addr: String,
+    pub role: Role,
+    pub term: Option<u64>,
+    pub last_log_index: Option<u64>,
+    pub last_applied: Option<u64>,
+    pub snapshot: Option<u64>,
+    /// Number of log entries the node is behind the leader. Only known for the nodes of shards
+    /// with a leader.
+    pub lag: Option<u64>,
+    /// Why the node could not be queried.
+    pub error: Option<String>,
+}
+
+/// State of a shard and its nodes.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShardStatus {
+    /// Address of the original leader, which identifies the shard in the ring.
+    pub shard: String,
+    /// Address clients send the requests of the shard to.
+    pub proxy: String,
+    pub relative_load: f32,
+    pub leader: Option<NodeId>,
+    pub term: Option<u64>,
+    pub nodes: Vec<NodeStatus>,
+}
+
+/// Outcome of a snapshot or a log compaction on a single node.
+#[derive(Debug, Clone, Serialize)]
+pub struct SnapshotStatus {
+    pub id: NodeId,
+    pub api_addr: String,
+    /// Id of the last log entry in the snapshot.
+    pub snapshot: Option<LogId<NodeId>>,
+    /// Index up to which the log was purged.
+    pub purged: Option<u64>,
+    pub error: Option<String>,
+}
+
+/// Number of entries a follower with the `matched` log id is behind a leader with the given
+/// last log index.
+pub fn replication_lag(last_log_index: Option<u64>, matched: Option<&LogId<NodeId>>) -> u64 {
+    let last = last_log_index.map_or(0, |index| index + 1);
+    let matched = matched.map_or(0, |log_id| log_id.index + 1);
+    last.saturating_sub(matched)
+}
+
+/// Delay between two steps of a membership change.
+const CHANGE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Number of steps after which a learner whose lag did not shrink is considered stuck.
+const MAX_STALLED_POLLS: usize = 60;
+
+/// Repeat the membership change `step` until it is done.
+///
+/// Fails with [`AdminError::Stalled`] if a learner stops catching up.
+pub(crate) async fn wait_for_change<F, Fut>(
+    mut on_progress: impl FnMut(&LearnerProgress),
+    mut step: F,
+) -> Result<(), AdminError>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<ChangeProgress, ClientError>>,
+{
+    let mut best: BTreeMap<NodeId, u64> = BTreeMap::new();
+    let mut stalled = 0;
+    loop {
+        let learners = match step().await? {
+            ChangeProgress::Done => return Ok(()),
+            ChangeProgress::CatchingUp { learners } => learners,
+        };
+        let mut improved = false;
+        for learner in &learners {
+            on_progress(learner);
+            let lag = best.entry(learner.id).or_insert(u64::MAX);
+            if learner.lag < *lag {
+                *lag = learner.lag;
+                improved = true;
+            }
+        }
+        stalled = if improved { 0 } else { stalled + 1 };
+        if stalled >= MAX_STALLED_POLLS {
+            if let Some(learner) = learners.into_iter().max_by_key(|l| l.lag) {
+                return Err(AdminError::Stalled {
+                    node: learner.id,
+                    lag: learner.lag,
+                });
+            }
+        }
+        tokio::time::sleep(CHANGE_POLL_INTERVAL).await;
+    }
+}
+
+/// Executes administrative operations against a running cluster.
+pub struct Admin {
+    /// Used to create clients for the nodes.
+    transport: RaftNode,
+    /// API addresses of the nodes to fetch the hash ring from.
+    seeds: Vec<String>,
+}
+
+impl Admin {
+    /// Create an admin that talks to the nodes like `transport` does, e.g., over TLS or with a
+    /// token.
+    pub fn new(transport: RaftNode, seeds: Vec<String>) -> Self {
+        Self { transport, seeds }
+    }
+
+    fn node(&self, addr: &str) -> RaftNode {
+        self.transport.with_same_transport(0, addr.to_string())
+    }
+
+    /// Fetch the hash ring from all seeds and return the one with the highest `config_id`.
+    pub async fn ring(&self) -> Result<Carp, AdminError> {
+        fetch_hash_ring(&self.transport, &self.seeds)
+            .await
+            .ok_or_else(|| AdminError::NoRing(self.seeds.clone()))
+    }
+
+    /// Publish `ring`, derived from the ring with `config_id` `read`, to all its nodes and to
+    /// the nodes of `extra`.
+    ///
+    /// Fails with [`ClientError::Conflict`] if the ring was changed since it was read, e.g., by
+    /// the failure detector. The operation can then be repeated on the newer ring.
+    async fn publish(&self, read: u64, ring: &Carp, extra: &[String]) -> Result<(), AdminError> {
+        let mut shards = shards(ring);
+        shards.push(extra.to_vec());
+        publish_hash_ring_if(&self.transport, &shards, ring, read).await?;
+        Ok(())
+    }
+
+    /// Return the state of all shards of the ring.
+    pub async fn status(&self, ring: &Carp) -> Vec<ShardStatus> {
+        let statuses = ring.nodes.iter().map(|node| self.shard_status(ring, &node.addr));
+        futures::future::join_all(statuses).await
+    }
+
+    /// Return the state of a shard and its nodes.
+    ///
+    /// Both the nodes of the shard in the ring and the members of its Raft cluster are listed.
+    pub async fn shard_status(&self, ring: &Carp, shard: &str) -> ShardStatus {
+        let mut addrs = shard_nodes(ring, shard);
+        let mut reports = self.query(&addrs).await;
+
+        // Members that are not in the ring, e.g., if the ring could not be updated.
+        let membership_addrs: Vec<String> = reports
+            .iter()
+            .flatten()
+            .flat_map(|m| m.membership_config.membership().nodes())
+            .map(|(_, node)| node.api_addr.clone())
+            .filter(|addr| !addrs.contains(addr))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        reports.extend(self.query(&membership_addrs).await);
+        addrs.extend(membership_addrs);
+
+        let leader = reports
+            .iter()
+            .flatten()
+            .find(|m| m.current_leader == Some(m.id))
+            .cloned();
+        // Without a leader, the membership as seen by any node.
+        let membership = leader
+            .as_ref()
+            .or_else(|| reports.iter().flatten().next())
+            .map(|m| m.membership_config.membership().clone());
+
+        let nodes = addrs
+            .into_iter()
+            .zip(reports)
+            .map(|(api_addr, report)| {
+                let id = match &report {
+                    Ok(m) => Some(m.id),
+                    Err(_) => membership.as_ref().and_then(|membership| {
+                        membership
+                            .nodes()
+                            .find(|(_, node)| node.api_addr == api_addr)
+                            .map(|(id, _)| *id)
+                    }),
+                };
+                let role = match (id, &membership) {
+                    (_, None) => Role::Unknown,
+                    (Some(id), Some(_)) if leader.as_ref().map(|l| l.id) == Some(id) => {
+                        Role::Leader
+                    }
+                    (Some(id), Some(membership)) if membership.voter_ids().any(|v| v == id) => {
+                        Role::Follower
+                    }
+                    (Some(id), Some(membership)) if membership.get_node(&id).is_some() => {
+                        Role::Learner
+                    }
+                    _ => Role::NotMember,
+                };
+                let lag = match (&leader, id) {
+                    (Some(leader), Some(id)) if leader.id == id => Some(0),
+                    (Some(leader), Some(id)) => leader
+                        .replication
+                        .as_ref()
+                        .and_then(|replication| replication.get(&id))
+                        .map(|matched| replication_lag(leader.last_log_index, matched.as_ref())),
+                    _ => None,
+                };
+                let (term, last_log_index, last_applied, snapshot, error) = match report {
+                    Ok(m) => (
+                        Some(m.current_term),
+                        m.last_log_index,
+                        m.last_applied.map(|log_id| log_id.index),
+                        m.snapshot.map(|log_id| log_id.index),
+                        None,
+                    ),
+                    Err(e) => (None, None, None, None, Some(e.to_string())),
+                };
+                NodeStatus {
+                    id,
+                    api_addr,
+                    role,
+                    term,
+                    last_log_index,
+                    last_applied,
+                    snapshot,
+                    lag,
+                    error,
+                }
+            })
+            .collect();
+
+        ShardStatus {
+            shard: shard.to_string(),
+            proxy: ring.get_proxy(shard).to_string(),
+            relative_load: ring
+                .nodes
+                .iter()
+                .find(|node| node.addr == shard)
+                .map_or(0.0, |node| node.relative_load),
+            leader: leader.as_ref().map(|m| m.id),
+            term: leader.as_ref().map(|m| m.current_term),
+            nodes,
+        }
+    }
+
+    async fn query(
+        &self,
+        addrs: &[String],
+    ) -> Vec<Result<RaftMetrics<NodeId, Node>, ClientError>> {
+        let metrics = addrs.iter().map(|addr| async move { self.node(addr).metrics().await });
+        futures::future::join_all(metrics).await
+    }
+
+    /// Return a client for the leader of `shard`, and the metrics of the leader.
+    async fn leader(
+        &self,
+        ring: &Carp,
+        shard: &str,
+    ) -> Result<(RaftNode, RaftMetrics<NodeId, Node>), AdminError> {
+        if !ring.nodes.iter().any(|node| node.addr == shard) {
+            return Err(AdminError::UnknownShard(shard.to_string()));
+        }
+        let reports = self.query(&shard_nodes(ring, shard)).await;
+        let leader = reports.iter().flatten().find_map(|m| {
+            let id = m.current_leader?;
+            let node = m.membership_config.membership().get_node(&id)?;
+            Some((id, node.api_addr.clone()))
+        });
+        let Some((id, addr)) = leader else {
+            return Err(AdminError::NoLeader(shard.to_string()));
+        };
+        let node = self.transport.with_same_transport(id, addr);
+        let metrics = node.metrics().await?;
+        if metrics.current_leader != Some(id) {
+            return Err(AdminError::NoLeader(shard.to_string()));
+        }
+        Ok((node, metrics))
+    }
+
+    /// Add a node to `shard`: replicate the log to it as learner, then make it a voter.
+    ///
+    /// The node has to be running. `on_progress` is called while it catches up. It is added to
+    /// the followers of the shard in the ring.
+    pub async fn add_node(
+        &self,
+        shard: &str,
+        id: NodeId,
+        api_addr: String,
+        rpc_addr: String,
+        on_progress: impl FnMut(&LearnerProgress),
+    ) -> Result<(), AdminError> {
+        let ring = self.ring().await?;
+        let (leader, _) = self.leader(&ring, shard).await?;
+        let req = (id, api_addr.clone(), rpc_addr);
+        wait_for_change(on_progress, || leader.add_node(req.clone())).await?;
+
+        // The ring may have changed while the node was catching up.
+        let mut ring = self.ring().await?;
+        let read = ring.config_id;
+        if ring.shard_of(&api_addr).is_none() {
+            ring.add_follower(shard, api_addr)?;
+            self.publish(read, &ring, &[]).await?;
+        }
+        Ok(())
+    }
+
+    /// Replace the member `old` of `shard` by a new node, which is added like by [`add_node`].
+    ///
+    /// The new node takes the place of `old` in the ring. If `old` is the leader, the
+    /// leadership moves to another voter first.
+    pub async fn replace_node(
+        &self,
+        shard: &str,
+        old: NodeId,
+        new: (NodeId, String, String),
+        on_progress: impl FnMut(&LearnerProgress),
+    ) -> Result<(), AdminError> {
+        let ring = self.ring().await?;
+        let (leader, metrics) = self.leader(&ring, shard).await?;
+        let old_node = metrics.membership_config.membership().get_node(&old).cloned();
+        let new_addr = new.1.clone();
+        wait_for_change(on_progress, || leader.replace_node(old, new.clone())).await?;
+
+        let mut ring = self.ring().await?;
+        let read = ring.config_id;
+        let mut removed = Vec::new();
+        if let Some(old_node) = old_node {
+            if ring.get_followers(shard).is_some_and(|f| f.contains(&old_node.api_addr)) {
+                ring.remove_follower(shard, &old_node.api_addr)?;
+            }
+            removed.push(old_node.api_addr);
+        }
+        if ring.shard_of(&new_addr).is_none() {
+            ring.add_follower(shard, new_addr)?;
+        }
+        // The proxy may point to the replaced node.
+        let (_, metrics) = self.leader(&ring, shard).await?;
+        if let Some(node) = metrics.membership_config.membership().get_node(&metrics.id) {
+            ring.set_new_proxy(shard, &node.api_addr)?;
+        }
+        ring.config_id += 1;
+        self.publish(read, &ring, &removed).await?;
+        Ok(())
+    }
+
+    /// Return the voters of `shard` and the replication progress of its learners.
+    pub async fn membership(&self, shard: &str) -> Result<MembershipStatus, AdminError> {
+        let ring = self.ring().await?;
+        let (leader, _) = self.leader(&ring, shard).await?;
+        Ok(leader.membership().await?)
+    }
+
+    /// Remove a node from the membership of `shard` and from the ring.
+    ///
+    /// The leader can't be removed, its leadership has to be transferred first. The shard
+    /// refuses to remove a voter if the remaining voters could not form a quorum.
+    pub async fn remove_node(&self, shard: &str, id: NodeId) -> Result<(), AdminError> {
+        let ring = self.ring().await?;
+        let (leader, metrics) = self.leader(&ring, shard).await?;
+        if metrics.id == id {
+            return Err(AdminError::IsLeader(id));
+        }
+        let membership = metrics.membership_config.membership();
+        let Some(node) = membership.get_node(&id).cloned() else {
+            return Err(AdminError::UnknownNode {
+                shard: shard.to_string(),
+                node: id,
+            });
+        };
+
+        leader.remove_node(id).await?;
+
+        let mut ring = self.ring().await?;
+        let read = ring.config_id;
+        if ring.get_followers(shard).is_some_and(|f| f.contains(&node.api_addr)) {
+            ring.remove_follower(shard, &node.api_addr)?;
+        } else if node.api_addr == shard {
+            // The shard keeps its address in the ring, its requests go to the leader.
+            let leader_addr = metrics
+                .membership_config
+                .membership()
+                .get_node(&metrics.id)
+                .map(|node| node.api_addr.clone())
+                .unwrap_or_default();
+            ring.set_new_proxy(shard, &leader_addr)?;
+            ring.config_id += 1;
+        } else {
+            return Ok(());
+        }
+        self.publish(read, &ring, &[node.api_addr]).await?;
+        Ok(())
+    }
+
+    /// Fail with [`AdminError::HoldsKeys`] if one of `shards` holds any keys.
+    ///
+    /// Keys are not moved between shards, so a change of the ring that gives some keys another
+    /// shard is only safe while the shards that lose them are empty.
+    async fn ensure_empty(&self, ring: &Carp, shards: &[String]) -> Result<(), AdminError> {
+        for shard in shards {
+            let (leader, _) = self.leader(ring, shard).await?;
+            let keys = leader.load().await?.key_count;
+            if keys > 0 {
+                return Err(AdminError::HoldsKeys {
+                    shard: shard.clone(),
+                    keys,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Add a running and initialized shard to the ring.
+    ///
+    /// Keys are not moved to the new shard, so the shards of the ring must not hold any keys
+    /// yet. The namespaces are created on the new shard, and their quotas split anew.
+    pub async fn add_shard(
+        &self,
+        addr: String,
+        followers: Vec<String>,
+        relative_load: f32,
+    ) -> Result<(), AdminError> {
+        let old = self.ring().await?;
+        let all: Vec<String> = old.nodes.iter().map(|node| node.addr.clone()).collect();
+        self.ensure_empty(&old, &all).await?;
+        let followers = match old.followers_map.is_empty() && followers.is_empty() {
+            true => None,
+            false => Some(followers),
+        };
+        let mut ring = old.clone();
+        ring.add_node(addr, relative_load, followers)?;
+        self.publish(old.config_id, &ring, &[]).await?;
+        share_quotas(&self.transport, &old, &ring).await?;
+        Ok(())
+    }
+
+    /// Remove a shard from the ring. Its nodes keep running.
+    ///
+    /// Keys are not moved to the remaining shards, so the shard must not hold any keys. Its
+    /// share of the namespace quotas goes to the remaining shards.
+    pub async fn remove_shard(&self, shard: &str) -> Result<(), AdminError> {
+        let old = self.ring().await?;
+        self.ensure_empty(&old, &[shard.to_string()]).await?;
+        let removed = shard_nodes(&old, shard);
+        let mut ring = old.clone();
+        ring.remove_node(shard)?;
+        self.publish(old.config_id, &ring, &removed).await?;
+        share_quotas(&self.transport, &old, &ring).await?;
+        Ok(())
+    }
+
+    /// Set the relative load of a shard. The loads of all shards are normalized to sum up to 1,
+    /// so the load is relative to the loads of the other shards. The namespace quotas are split
+    /// anew.
+    ///
+    /// Keys are not moved between shards, so the shards must not hold any keys yet.
+    pub async fn set_weight(&self, shard: &str, relative_load: f32) -> Result<(), AdminError> {
+        let old = self.ring().await?;
+        let all: Vec<String> = old.nodes.iter().map(|node| node.addr.clone()).collect();
+        self.ensure_empty(&old, &all).await?;
+        let mut ring = old.clone();
+        ring.set_relative_loads(&[(shard.to_string(), relative_load)])?;
+        self.publish(old.config_id, &ring, &[]).await?;
+        share_quotas(&self.transport, &old, &ring).await?;
+        Ok(())
+    }
+
+    /// Transfer the leadership of `shard` to the voter `target`, and route the requests of the
+    /// shard to it.
+    pub async fn transfer_leader(&self, shard: &str, target: NodeId) -> Result<(), AdminError> {
+        let ring = self.ring().await?;
+        let (leader, metrics) = self.leader(&ring, shard).await?;
+        leader.transfer_leader(target).await?;
+
+        let mut ring = self.ring().await?;
+        let read = ring.config_id;
+        if let Some(node) = metrics.membership_config.membership().get_node(&target) {
+            if ring.shard_of(&node.api_addr) == Some(shard) {
+                ring.set_new_proxy(shard, &node.api_addr)?;
+                ring.config_id += 1;
+                self.publish(read, &ring, &[]).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drain the node at `addr` for maintenance, see [`crate::leadership::drain`], and route the
+    /// requests of its shard to the new leader.
+    ///
+    /// Returns the leader of the shard.
+    pub async fn drain(&self, addr: &str) -> Result<NodeId, AdminError> {
+        let leader_id = self.node(addr).drain().await?;
+
+        let mut ring = self.ring().await?;
+        let read = ring.config_id;
+        let Some(shard) = ring.shard_of(addr).map(str::to_string) else {
+            return Ok(leader_id);
+        };
+        if ring.get_proxy(&shard) == addr {
+            let (_, metrics) = self.leader(&ring, &shard).await?;
+            if let Some(node) = metrics.membership_config.membership().get_node(&metrics.id) {
+                ring.set_new_proxy(&shard, &node.api_addr)?;
+                ring.config_id += 1;
+                self.publish(read, &ring, &[]).await?;
+            }
+        }
+        Ok(leader_id)
+    }
+
+    /// Build a snapshot on every member of `shard`, and purge the logs up to the snapshots if
+    /// `compact` is set.
+    pub async fn snapshot(
+        &self,
+        shard: &str,
+        compact: bool,
+    ) -> Result<Vec<SnapshotStatus>, AdminError> {
+        let ring = self.ring().await?;
+        let (_, metrics) = self.leader(&ring, shard).await?;
+        let members: Vec<(NodeId, Node)> = metrics
+            .membership_config
+            .membership()
+            .nodes()
+            .map(|(id, node)| (*id, node.clone()))
+            .collect();
+
+        let snapshots = members.into_iter().map(|(id, node)| async move {
+            let client = self.node(&node.api_addr);
+            let mut status = SnapshotStatus {
+                id,
+                api_addr: node.api_addr,
+                snapshot: None,
+                purged: None,
+                error: None,
+            };
+            match client.snapshot().await {
+                Ok(snapshot) => status.snapshot = snapshot,
+                Err(e) => status.error = Some(e.to_string()),
+            }
+            if let (true, Some(snapshot)) = (compact, status.snapshot) {
+                match client.purge_log(snapshot.index).await {
+                    Ok(()) => status.purged = Some(snapshot.index),
+                    Err(e) => status.error = Some(e.to_string()),
+                }
+            }
+            status
+        });
+        Ok(futures::future::join_all(snapshots).await)
+    }
+
+    /// Back up every shard of the ring to `dir`, from its leader, see [`crate::backup`].
+    ///
+    /// The leaders write the checkpoints themselves, so `dir` is a directory on their host.
+    pub async fn backup(&self, dir: &Path) -> Result<Manifest, AdminError> {
+        if dir.join(MANIFEST).exists() {
+            return Err(BackupError::NotEmpty(dir.to_path_buf()).into());
+        }
+        let ring = self.ring().await?;
+        let mut manifest = Manifest::new();
+        for (i, node) in ring.nodes.iter().enumerate() {
+            let (leader, _) = self.leader(&ring, &node.addr).await?;
+            let path = PathBuf::from(format!("shard-{}", i + 1));
+            let checkpoint = leader.backup(&dir.join(&path)).await?;
+            manifest.shards.push(ShardBackup {
+                shard: node.addr.clone(),
+                path,
+                checkpoint,
+            });
+        }
+        manifest.save(dir)?;
+        Ok(manifest)
+    }
+
+    /// Export the keyspace of every shard of the ring to `dir`, see [`crate::export`].
+    ///
+    /// Unlike a backup, the export is written by the admin, so `dir` is on its host.
+    pub async fn export(&self, dir: &Path) -> Result<ExportManifest, AdminError> {
+        if dir.join(EXPORT_MANIFEST).exists() {
+            return Err(ExportError::NotEmpty(dir.to_path_buf()).into());
+        }
+        let ring = self.ring().await?;
+        std::fs::create_dir_all(dir).map_err(ExportError::from)?;
+        let mut manifest = ExportManifest::new(ring.clone());
+        for (i, node) in ring.nodes.iter().enumerate() {
+            let (leader, _) = self.leader(&ring, &node.addr).await?;
+            let dump = leader.dump().await?;
+            let path = PathBuf::from(format!("shard-{}.json", i + 1));
+            dump.save(&dir.join(&path))?;
+            manifest.shards.push(ShardExport {
+                shard: node.addr.clone(),
+                path,
+                log_id: dump.log_id,
+                keys: dump.state.kvs.len(),
+            });
+        }
+
+        // A change of the placement could have moved keys from a shard that was not exported yet
+        // to one that already was.
+        let after = self.ring().await?;
+        if after.nodes != ring.nodes {
+            let (before, after) = (ring.config_id, after.config_id);
+            return Err(ExportError::RingChanged { before, after }.into());
+        }
+        manifest.save(dir)?;
+        Ok(manifest)
+    }
+}
+
+/// The nodes of every shard of the ring, original leaders first.
+pub fn shards(ring: &Carp) -> Vec<Vec<String>> {
+    ring.nodes
+        .iter()
+        .map(|node| shard_nodes(ring, &node.addr))
+        .collect()
+}
+
+fn shard_nodes(ring: &Carp, shard: &str) -> Vec<String> {
+    std::iter::once(shard.to_string())
+        .chain(ring.get_followers(shard).into_iter().flatten().cloned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use openraft::CommittedLeaderId;
+
+    use super::*;
+
+    fn log_id(index: u64) -> LogId<NodeId> {
+        LogId::new(CommittedLeaderId::new(1, 1), index)
+    }
+
+    #[test]
+    fn test_replication_lag() {
+        assert_eq!(replication_lag(None, None), 0);
+        assert_eq!(replication_lag(Some(0), None), 1);
+        assert_eq!(replication_lag(Some(9), Some(&log_id(9))), 0);
+        assert_eq!(replication_lag(Some(9), Some(&log_id(4))), 5);
+        // The leader may report a follower ahead of its own metrics.
+        assert_eq!(replication_lag(Some(4), Some(&log_id(9))), 0);
+    }
+
+    #[test]
+    fn test_shards() {
+        let ring = Carp::with_followers(
+            vec![
+                ("a1".to_string(), 0.5, vec!["a2".to_string(), "a3".to_string()]),
+                ("b1".to_string(), 0.5, vec![]),
+            ],
+            0,
+        );
+        let mut shards = shards(&ring);
+        shards.sort();
+        assert_eq!(
+            shards,
+            vec![
+                vec!["a1".to_string(), "a2".to_string(), "a3".to_string()],
+                vec!["b1".to_string()],
+            ]
+        );
+    }
+}
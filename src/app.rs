@@ -1,10 +1,24 @@
 use std::collections::BTreeMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::Duration;
 
 use openraft::Config;
+use tokio::sync::watch;
 use tokio::sync::RwLock;
 
+use crate::auth::Authenticator;
 use crate::carp::Carp;
+use crate::load_balancer::LoadStats;
+use crate::metrics::Metrics;
+use crate::namespace::Namespace;
+use crate::network::error::AppError;
+use crate::network::Network;
+use crate::store::StorageMonitor;
+use crate::value::Bytes;
+use crate::value::Value;
+use crate::value::ValueConfig;
 use crate::ExampleRaft;
 use crate::NodeId;
 
@@ -15,7 +29,46 @@ pub struct App {
     pub api_addr: String,
     pub rpc_addr: String,
     pub raft: ExampleRaft,
-    pub key_values: Arc<RwLock<BTreeMap<String, String>>>,
+    /// Connections to the other nodes of the shard, shared with Raft.
+    pub network: Network,
+    pub key_values: Arc<RwLock<BTreeMap<Bytes, Value>>>,
+    pub namespaces: Arc<RwLock<BTreeMap<String, Namespace>>>,
+    /// Total size of the entries of `key_values`, in bytes.
+    pub byte_size: Arc<AtomicU64>,
     pub config: Arc<Config>,
     pub hash_ring: Arc<RwLock<Carp>>,
+    pub load: LoadStats,
+    pub metrics: Metrics,
+    pub storage: StorageMonitor,
+    pub auth: Authenticator,
+    /// Limits and compression of the values written through the application API.
+    pub values: ValueConfig,
+    /// Requests that take longer are answered with a `timeout` error.
+    pub request_timeout: Duration,
+    /// Set while the node is drained, see [`crate::leadership::drain`]. The application API
+    /// answers `unavailable` then.
+    pub draining: AtomicBool,
+    /// Shuts the node down.
+    pub shutdown: watch::Sender<()>,
+}
+
+impl App {
+    /// Check that `key` belongs to the shard of this node, according to its hash ring.
+    ///
+    /// Nodes that are not part of the ring, e.g., before the ring was published, accept all
+    /// keys.
+    pub async fn check_shard(&self, key: &[u8]) -> Result<(), AppError> {
+        let ring = self.hash_ring.read().await;
+        if ring.is_empty() {
+            return Ok(());
+        }
+        let original = ring.get_original(key);
+        match ring.shard_of(&self.api_addr) {
+            Some(own) if own != original => Err(AppError::WrongShard {
+                key: String::from_utf8_lossy(key).into_owned(),
+                shard_addr: ring.get_proxy(original).to_string(),
+            }),
+            _ => Ok(()),
+        }
+    }
 }
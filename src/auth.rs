@@ -0,0 +1,301 @@
+//! Token based authentication and per-prefix authorization for the HTTP API.
+//!
+//! Callers send `Authorization: Bearer <token>`. Every token has a role: admins may use every
+//! endpoint, clients only the application API under `/api`. Which keys a client may read or
+//! write is restricted by the ACLs of its token, so tenants can share a cluster by using
+//! different key prefixes.
+//!
+//! Without any configured token, authentication is disabled and every request is allowed.
+//! The Raft RPC between nodes is not covered, use mutual TLS to protect it.
+use std::fmt;
+
+use axum::extract::Request;
+use axum::extract::State;
+use axum::http::header::AUTHORIZATION;
+use axum::http::HeaderMap;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::network::error::AppError;
+use crate::AppState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// May use the application API on every key and the cluster management API.
+    Admin,
+    /// May use the application API on the keys granted by its ACLs.
+    Client,
+}
+
+/// Grants access to all keys starting with `prefix`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Acl {
+    pub prefix: String,
+    #[serde(default)]
+    pub read: bool,
+    #[serde(default)]
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenConfig {
+    pub token: String,
+    pub role: Role,
+    /// Ignored for admins.
+    #[serde(default)]
+    pub acls: Vec<Acl>,
+}
+
+/// The tokens accepted by a node.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+}
+
+impl AuthConfig {
+    /// The first admin token, used by the cluster manager to talk to the nodes.
+    pub fn admin_token(&self) -> Option<&str> {
+        self.tokens
+            .iter()
+            .find(|t| t.role == Role::Admin)
+            .map(|t| t.token.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Access::Read => write!(f, "read"),
+            Access::Write => write!(f, "write"),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    #[error("missing bearer token")]
+    MissingToken,
+    #[error("invalid token")]
+    InvalidToken,
+    #[error("admin role required")]
+    AdminRequired,
+    #[error("no {access} access to key {key:?}")]
+    Forbidden { key: String, access: Access },
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        AppError::from(self).into_response()
+    }
+}
+
+/// The caller of a request, as identified by its token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Principal {
+    role: Role,
+    acls: Vec<Acl>,
+}
+
+impl Principal {
+    pub fn admin() -> Self {
+        Self {
+            role: Role::Admin,
+            acls: Vec::new(),
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    /// Check that the caller may access `key`. Any ACL whose prefix matches can grant access.
+    pub fn check(&self, key: &[u8], access: Access) -> Result<(), AuthError> {
+        if self.role == Role::Admin {
+            return Ok(());
+        }
+        let allowed = self.acls.iter().any(|acl| {
+            key.starts_with(acl.prefix.as_bytes())
+                && match access {
+                    Access::Read => acl.read,
+                    Access::Write => acl.write,
+                }
+        });
+        if allowed {
+            Ok(())
+        } else {
+            Err(AuthError::Forbidden {
+                key: String::from_utf8_lossy(key).into_owned(),
+                access,
+            })
+        }
+    }
+}
+
+/// Maps the bearer token of a request to its [`Principal`].
+#[derive(Debug, Clone, Default)]
+pub struct Authenticator {
+    tokens: Vec<(String, Principal)>,
+}
+
+impl Authenticator {
+    pub fn new(config: &AuthConfig) -> Self {
+        let tokens = config
+            .tokens
+            .iter()
+            .map(|t| {
+                let principal = Principal {
+                    role: t.role,
+                    acls: t.acls.clone(),
+                };
+                (t.token.clone(), principal)
+            })
+            .collect();
+        Self { tokens }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    pub fn authenticate(&self, headers: &HeaderMap) -> Result<Principal, AuthError> {
+        if !self.is_enabled() {
+            return Ok(Principal::admin());
+        }
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .ok_or(AuthError::MissingToken)?;
+        // Compare against every token so the time taken does not depend on which one matches.
+        let mut found = None;
+        for (candidate, principal) in &self.tokens {
+            if constant_time_eq(candidate.as_bytes(), token.as_bytes()) {
+                found = Some(principal);
+            }
+        }
+        found.cloned().ok_or(AuthError::InvalidToken)
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Middleware for the application API: any valid token is accepted.
+///
+/// The [`Principal`] is added to the request extensions, so handlers can check key access.
+pub async fn client(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let principal = state.auth.authenticate(req.headers())?;
+    req.extensions_mut().insert(principal);
+    Ok(next.run(req).await)
+}
+
+/// Middleware for the cluster management API: only admin tokens are accepted.
+pub async fn admin(
+    State(state): State<AppState>,
+    mut req: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let principal = state.auth.authenticate(req.headers())?;
+    if principal.role() != Role::Admin {
+        return Err(AuthError::AdminRequired);
+    }
+    req.extensions_mut().insert(principal);
+    Ok(next.run(req).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn config() -> AuthConfig {
+        AuthConfig {
+            tokens: vec![
+                TokenConfig {
+                    token: "admin-secret".to_string(),
+                    role: Role::Admin,
+                    acls: Vec::new(),
+                },
+                TokenConfig {
+                    token: "tenant-a".to_string(),
+                    role: Role::Client,
+                    acls: vec![
+                        Acl {
+                            prefix: "a/".to_string(),
+                            read: true,
+                            write: true,
+                        },
+                        Acl {
+                            prefix: "shared/".to_string(),
+                            read: true,
+                            write: false,
+                        },
+                    ],
+                },
+            ],
+        }
+    }
+
+    fn headers(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::from_str(&format!("Bearer {}", token)).unwrap();
+        headers.insert(AUTHORIZATION, value);
+        headers
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let auth = Authenticator::new(&config());
+        assert_eq!(auth.authenticate(&HeaderMap::new()), Err(AuthError::MissingToken));
+        assert_eq!(auth.authenticate(&headers("nope")), Err(AuthError::InvalidToken));
+        assert_eq!(auth.authenticate(&headers("admin-secret")).unwrap().role(), Role::Admin);
+        assert_eq!(auth.authenticate(&headers("tenant-a")).unwrap().role(), Role::Client);
+    }
+
+    #[test]
+    fn test_disabled_allows_everything() {
+        let auth = Authenticator::new(&AuthConfig::default());
+        assert!(!auth.is_enabled());
+        assert_eq!(auth.authenticate(&HeaderMap::new()), Ok(Principal::admin()));
+    }
+
+    #[test]
+    fn test_prefix_acls() {
+        let auth = Authenticator::new(&config());
+        let tenant = auth.authenticate(&headers("tenant-a")).unwrap();
+        assert!(tenant.check(b"a/key", Access::Read).is_ok());
+        assert!(tenant.check(b"a/key", Access::Write).is_ok());
+        assert!(tenant.check(b"shared/key", Access::Read).is_ok());
+        assert_eq!(
+            tenant.check(b"shared/key", Access::Write),
+            Err(AuthError::Forbidden {
+                key: "shared/key".to_string(),
+                access: Access::Write,
+            })
+        );
+        assert!(tenant.check(b"b/key", Access::Read).is_err());
+
+        let admin = auth.authenticate(&headers("admin-secret")).unwrap();
+        assert!(admin.check(b"b/key", Access::Write).is_ok());
+    }
+}
@@ -0,0 +1,349 @@
+//! Online backups of the shards of a cluster, and point-in-time restores from them.
+//!
+//! The backup of a shard is a checkpoint of the storage of its leader, see
+//! [`StorageEngine::checkpoint`]: the last snapshot of the state machine and the log that follows
+//! it, as of a single point in time, taken while the shard keeps serving requests. The backup of
+//! a cluster is a directory with the checkpoint of every shard of the hash ring in
+//! `shard-<n>` and a [`Manifest`] in `manifest.json`. The nodes write their checkpoints
+//! themselves, so the directory has to be on their host, or on storage they share.
+//!
+//! A restore replays the log of a checkpoint on its snapshot, up to a chosen committed entry, and
+//! seeds the data directories of the nodes of a shard with the result: a snapshot whose
+//! membership is made of these nodes, and no log. The nodes then start like a shard that was
+//! initialized before, and elect a leader among themselves.
+//!
+//! [`StorageEngine::checkpoint`]: crate::store::engine::StorageEngine::checkpoint
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use openraft::storage::RaftLogStorage;
+use openraft::storage::RaftStateMachine;
+use openraft::storage::Snapshot;
+use openraft::LogId;
+use openraft::Membership;
+use openraft::RaftLogReader;
+use openraft::RaftSnapshotBuilder;
+use openraft::SnapshotMeta;
+use openraft::StorageError;
+use openraft::StorageIOError;
+use openraft::StoredMembership;
+use openraft::Vote;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::app::App;
+use crate::store::engine::MemEngine;
+use crate::store::engine::RocksEngine;
+use crate::store::new_storage;
+use crate::store::LogStore;
+use crate::store::StateMachineStore;
+use crate::topology::ShardSpec;
+use crate::topology::Topology;
+use crate::topology::TopologyError;
+use crate::Node;
+use crate::NodeId;
+use crate::TypeConfig;
+
+/// File of the [`Manifest`] in the directory of a backup.
+pub const MANIFEST: &str = "manifest.json";
+
+/// Errors of backups and restores.
+#[derive(Error, Debug)]
+pub enum BackupError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Storage(#[from] StorageError<NodeId>),
+    #[error(transparent)]
+    Topology(#[from] TopologyError),
+    #[error("invalid manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+    #[error("{} already holds a backup or a database", .0.display())]
+    NotEmpty(PathBuf),
+    #[error("the backup has no shard {0}")]
+    UnknownShard(String),
+    #[error("shard {0} has no committed entry to restore")]
+    NothingCommitted(String),
+    #[error("shard {shard} can be restored up to an index from {first} to {last}, not {index}")]
+    InvalidIndex {
+        shard: String,
+        index: u64,
+        first: u64,
+        last: u64,
+    },
+    #[error("the log of shard {shard} is missing entries up to {index}")]
+    MissingEntries { shard: String, index: u64 },
+}
+
+/// The checkpoint of the storage of a node, as reported by the node that wrote it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The node the checkpoint was taken on.
+    pub node: NodeId,
+    /// Id of the last log entry in the snapshot of the checkpoint.
+    pub snapshot: Option<LogId<NodeId>>,
+    /// Id of the last committed entry in the checkpoint, up to which it can be restored.
+    pub committed: Option<LogId<NodeId>>,
+}
+
+/// Backup of a shard, as listed in the [`Manifest`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShardBackup {
+    /// Address of the original leader of the shard, which identifies it in the hash ring.
+    pub shard: String,
+    /// Directory of the checkpoint, relative to the backup.
+    pub path: PathBuf,
+    pub checkpoint: Checkpoint,
+}
+
+/// What a backup of a cluster holds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Seconds since the Unix epoch when the backup was started.
+    pub created_at: u64,
+    pub shards: Vec<ShardBackup>,
+}
+
+impl Manifest {
+    /// An empty manifest for a backup started now.
+    pub fn new() -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        Self {
+            created_at,
+            shards: Vec::new(),
+        }
+    }
+
+    /// Read the manifest of the backup in `dir`.
+    pub fn load(dir: &Path) -> Result<Self, BackupError> {
+        let contents = fs::read(dir.join(MANIFEST))?;
+        Ok(serde_json::from_slice(&contents)?)
+    }
+
+    /// Write the manifest to the backup in `dir`.
+    pub fn save(&self, dir: &Path) -> Result<(), BackupError> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(MANIFEST), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// The backup of the shard whose original leader is `shard`.
+    pub fn shard(&self, shard: &str) -> Result<&ShardBackup, BackupError> {
+        self.shards
+            .iter()
+            .find(|backup| backup.shard == shard)
+            .ok_or_else(|| BackupError::UnknownShard(shard.to_string()))
+    }
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A shard seeded from a backup.
+#[derive(Debug, Clone, Serialize)]
+pub struct RestoredShard {
+    /// Name of the shard in the topology.
+    pub name: String,
+    /// Id of the last entry of the backup that was restored.
+    pub log_id: LogId<NodeId>,
+}
+
+/// Write a checkpoint of the storage of the node of `app` to `dir`, which must not exist yet.
+pub(crate) async fn checkpoint(app: &App, dir: &Path) -> Result<Checkpoint, BackupError> {
+    if dir.exists() {
+        return Err(BackupError::NotEmpty(dir.to_path_buf()));
+    }
+    if let Some(parent) = dir.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    app.storage
+        .checkpoint(dir)
+        .map_err(|e| StorageError::from(StorageIOError::write(&e)))?;
+
+    let (mut log_store, sm) = load(dir).await?;
+    Ok(Checkpoint {
+        node: app.id,
+        snapshot: sm.data.last_applied_log_id,
+        committed: log_store.read_committed().await?,
+    })
+}
+
+/// Open the checkpoint in `dir`, copied to memory so that its log can be applied.
+async fn load(dir: &Path) -> Result<(LogStore, StateMachineStore), BackupError> {
+    let engine = RocksEngine::open_read_only(dir)
+        .map_err(|e| StorageError::from(StorageIOError::read(&e)))?;
+    let copy =
+        MemEngine::copy_of(&engine).map_err(|e| StorageError::from(StorageIOError::read(&e)))?;
+    Ok(new_storage(Arc::new(copy)).await)
+}
+
+/// The snapshot of the state machine of the checkpoint of `shard` in `dir`, once its log is
+/// applied up to `index`, or up to its last committed entry.
+///
+/// `index` can't be before the snapshot of the checkpoint, nor after its last committed entry.
+pub async fn replay(
+    dir: &Path,
+    shard: &str,
+    index: Option<u64>,
+) -> Result<Snapshot<TypeConfig>, BackupError> {
+    let (mut log_store, mut sm) = load(dir).await?;
+    let Some(committed) = log_store.read_committed().await? else {
+        return Err(BackupError::NothingCommitted(shard.to_string()));
+    };
+    let first = sm.data.last_applied_log_id.map_or(0, |log_id| log_id.index);
+    let index = index.unwrap_or(committed.index);
+    if index < first || index > committed.index {
+        return Err(BackupError::InvalidIndex {
+            shard: shard.to_string(),
+            index,
+            first,
+            last: committed.index,
+        });
+    }
+
+    let start = sm.data.last_applied_log_id.map_or(0, |log_id| log_id.index + 1);
+    let entries = log_store.try_get_log_entries(start..=index).await?;
+    if entries.len() as u64 != (index + 1).saturating_sub(start) {
+        return Err(BackupError::MissingEntries {
+            shard: shard.to_string(),
+            index,
+        });
+    }
+    sm.apply(entries).await?;
+    Ok(sm.build_snapshot().await?)
+}
+
+/// Seed the data directories of the nodes of `shard` with `snapshot`, as the state of a shard
+/// whose members are these nodes.
+async fn seed(shard: &ShardSpec, snapshot: Snapshot<TypeConfig>) -> Result<(), BackupError> {
+    let log_id = snapshot
+        .meta
+        .last_log_id
+        .expect("a replayed snapshot holds a committed entry");
+    let nodes: BTreeMap<NodeId, Node> = shard
+        .nodes
+        .iter()
+        .map(|node| {
+            let member = Node {
+                api_addr: node.api_addr.clone(),
+                rpc_addr: node.rpc_addr.clone(),
+            };
+            (node.id, member)
+        })
+        .collect();
+    let voters: BTreeSet<NodeId> = nodes.keys().copied().collect();
+    let membership = Membership::new(vec![voters], nodes);
+    let meta = SnapshotMeta {
+        last_log_id: Some(log_id),
+        last_membership: StoredMembership::new(Some(log_id), membership),
+        snapshot_id: format!("restored-{}-{}", log_id.leader_id, log_id.index),
+    };
+    let data = snapshot.snapshot.into_inner();
+
+    for node in &shard.nodes {
+        // Seeded next to the data directory and moved in place, so that an interrupted restore
+        // leaves no half-seeded node behind.
+        let seeding = node.data_dir.with_extension("restoring");
+        if seeding.exists() {
+            fs::remove_dir_all(&seeding)?;
+        }
+        fs::create_dir_all(&seeding)?;
+        {
+            let engine = RocksEngine::open(&seeding)
+                .await
+                .map_err(|e| StorageError::from(StorageIOError::write(&e)))?;
+            let (mut log_store, mut sm) = new_storage(Arc::new(engine)).await;
+            sm.install_snapshot(&meta, Box::new(Cursor::new(data.clone())))
+                .await?;
+            log_store.purge(log_id).await?;
+            log_store.save_committed(Some(log_id)).await?;
+            // The vote of a node can't be older than its last log entry. Voting for itself in
+            // the next term makes the nodes elect a leader in a new term.
+            let vote = Vote::new(log_id.leader_id.get_term() + 1, node.id);
+            log_store.save_vote(&vote).await?;
+        }
+        fs::rename(&seeding, &node.data_dir)?;
+    }
+    Ok(())
+}
+
+/// Check that no node of `shard` holds a database yet.
+fn check_empty(shard: &ShardSpec) -> Result<(), BackupError> {
+    match shard.nodes.iter().find(|node| node.data_dir.join("CURRENT").exists()) {
+        Some(node) => Err(BackupError::NotEmpty(node.data_dir.clone())),
+        None => Ok(()),
+    }
+}
+
+/// Seed the nodes of `target` with the backup in `dir` of the shard whose original leader is
+/// `shard`, up to the log entry `index`, or up to its last committed entry.
+///
+/// The data directories of the nodes must not hold a database. Returns the id of the last
+/// restored entry.
+pub async fn restore_shard(
+    dir: &Path,
+    shard: &str,
+    target: &ShardSpec,
+    index: Option<u64>,
+) -> Result<LogId<NodeId>, BackupError> {
+    let backup = Manifest::load(dir)?.shard(shard)?.clone();
+    check_empty(target)?;
+    let snapshot = replay(&dir.join(&backup.path), shard, index).await?;
+    let log_id = snapshot.meta.last_log_id;
+    seed(target, snapshot).await?;
+    Ok(log_id.expect("a replayed snapshot holds a committed entry"))
+}
+
+/// Seed the nodes of every shard of `topology` with the backup in `dir`.
+///
+/// Every shard is restored from the backup of the shard with the same original leader, so that
+/// the hash ring places the keys like it did, up to the index `until` maps its original leader
+/// to, or up to its last committed entry. No data directory of the topology may hold a
+/// database.
+pub async fn restore(
+    dir: &Path,
+    topology: &Topology,
+    until: &BTreeMap<String, u64>,
+) -> Result<Vec<RestoredShard>, BackupError> {
+    topology.validate()?;
+    let manifest = Manifest::load(dir)?;
+    for shard in until.keys() {
+        manifest.shard(shard)?;
+    }
+    for shard in &topology.shards {
+        check_empty(shard)?;
+    }
+    // Replay every shard before seeding any, so that an invalid index leaves nothing seeded.
+
+    let mut snapshots = Vec::new();
+    for shard in &topology.shards {
+        let original = &shard.first().api_addr;
+        let backup = manifest.shard(original)?;
+        let index = until.get(original).copied();
+        snapshots.push(replay(&dir.join(&backup.path), original, index).await?);
+    }
+
+    let mut restored = Vec::new();
+    for (i, (shard, snapshot)) in topology.shards.iter().zip(snapshots).enumerate() {
+        let log_id = snapshot.meta.last_log_id;
+        seed(shard, snapshot).await?;
+        restored.push(RestoredShard {
+            name: shard.name(i),
+            log_id: log_id.expect("a replayed snapshot holds a committed entry"),
+        });
+    }
+    Ok(restored)
+}
@@ -1,14 +1,708 @@
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::path::Path;
+use std::path::PathBuf;
+
+use clap::Parser;
+use clap::Subcommand;
+use clap::ValueEnum;
+use distrib_kv_store::admin::Admin;
+use distrib_kv_store::admin::Role;
+use distrib_kv_store::admin::ShardStatus;
+use distrib_kv_store::admin::SnapshotStatus;
+use distrib_kv_store::backup;
+use distrib_kv_store::backup::Manifest;
+use distrib_kv_store::backup::RestoredShard;
+use distrib_kv_store::carp::Carp;
+use distrib_kv_store::cluster_manager::ClusterConfig;
 use distrib_kv_store::cluster_manager::ClusterManager;
+use distrib_kv_store::cluster_manager::DeployMode;
+use distrib_kv_store::export;
+use distrib_kv_store::export::ExportManifest;
+use distrib_kv_store::export::ImportSummary;
+use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::membership::LearnerProgress;
+use distrib_kv_store::membership::MembershipStatus;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::supervisor::Supervisor;
+use distrib_kv_store::tls::TlsConfig;
+use distrib_kv_store::topology::ClientConfig;
+use distrib_kv_store::NodeId;
+use serde::Serialize;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::BufReader;
+
+/// Operate a running cluster, or start a local one.
+///
+/// Shards are named by the API address of their original leader, as shown by `ring`.
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Opt {
+    /// Client config written by the cluster manager, or a JSON list of the API addresses of the
+    /// nodes of every shard. The hash ring is fetched from the nodes.
+    #[clap(long, env = "KV_NODES", default_value = "cluster.json")]
+    nodes: PathBuf,
+
+    /// Admin token, for nodes that require authentication.
+    #[clap(long, env = "KV_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// PEM client certificate, for nodes that serve their API over TLS.
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the client certificate.
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM certificates trusted to sign the certificates of the nodes.
+    #[clap(long, requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
+
+    #[clap(long, value_enum, default_value_t = Format::Table)]
+    format: Format,
+
+    /// Starts a local cluster if omitted.
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Start a local cluster as described by a cluster config, and run until Ctrl+C.
+    ///
+    /// With node processes, commands read from stdin control them: `status`, and `kill`, `stop`,
+    /// `start`, `suspend` or `resume` followed by the name of a shard and a node id.
+    Start {
+        #[clap(long, default_value = "Config.toml")]
+        config: String,
+        /// Run every node as a `raft-kv` process, whatever the mode of the config.
+        #[clap(long)]
+        multi_process: bool,
+    },
+    /// Show every node of every shard: role, term, log indexes and replication lag.
+    Status {
+        /// Only show this shard.
+        shard: Option<String>,
+    },
+    /// Show the leader and the largest replication lag of every shard.
+    Leaders,
+    /// Show the hash ring.
+    Ring,
+    /// Add a running node to a shard, first as learner, then as voter.
+    AddNode {
+        shard: String,
+        id: NodeId,
+        api_addr: String,
+        rpc_addr: String,
+    },
+    /// Remove a node from a shard, unless the remaining voters could not form a quorum.
+    RemoveNode { shard: String, id: NodeId },
+    /// Replace a member of a shard by a running node, which is added like by add-node.
+    ReplaceNode {
+        shard: String,
+        old: NodeId,
+        id: NodeId,
+        api_addr: String,
+        rpc_addr: String,
+    },
+    /// Show the voters of a shard and the progress of its learners.
+    Membership { shard: String },
+    /// Add a running and initialized shard to the hash ring.
+    AddShard {
+        /// API address of the leader of the shard.
+        addr: String,
+        /// API addresses of the other nodes of the shard.
+        #[clap(long = "follower")]
+        followers: Vec<String>,
+        #[clap(long)]
+        weight: f32,
+    },
+    /// Remove a shard from the hash ring. Its nodes keep running.
+    RemoveShard { shard: String },
+    /// Set the relative load of a shard in the hash ring.
+    SetWeight { shard: String, weight: f32 },
+    /// Make another voter the leader of a shard.
+    TransferLeader { shard: String, target: NodeId },
+    /// Take a node down for maintenance: move its leadership away, stop serving clients and shut
+    /// it down once its log is applied.
+    Drain {
+        /// API address of the node.
+        addr: String,
+    },
+    /// Build a snapshot on every node of a shard.
+    Snapshot { shard: String },
+    /// Build a snapshot on every node of a shard and purge the logs it contains.
+    Compact { shard: String },
+    /// Back up every shard to a directory on the host of its leader.
+    Backup { dir: PathBuf },
+    /// Seed the data directories of a cluster config from a backup, before starting it.
+    ///
+    /// Every shard is restored from the backup of the shard with the same original leader, up
+    /// to its last committed entry unless `--until` is given for it.
+    Restore {
+        dir: PathBuf,
+        #[clap(long, default_value = "Config.toml")]
+        config: String,
+        /// Restore the shard with this original leader only.
+        #[clap(long)]
+        shard: Option<String>,
+        /// Name of the shard of the config to seed with the backup of `--shard`, if it is not
+        /// the shard with the same original leader, e.g., a new shard.
+        #[clap(long, requires = "shard")]
+        into: Option<String>,
+        /// Restore the shard with the original leader SHARD up to the log index INDEX.
+        #[clap(long, value_name = "SHARD=INDEX", value_parser = parse_until)]
+        until: Vec<(String, u64)>,
+    },
+    /// Export the keys and namespaces of every shard to a local directory, consistently per
+    /// shard.
+    Export { dir: PathBuf },
+    /// Write an export into the cluster, whatever its number of shards. The namespaces of the
+    /// export must not exist in the cluster.
+    Import {
+        dir: PathBuf,
+        /// Maximum number of writes in flight.
+        #[clap(long, default_value_t = 16)]
+        concurrency: usize,
+    },
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let mut cluster = ClusterManager::new("Config.toml").await?;
+    let options = Opt::parse();
+    match &options.command {
+        None => start("Config.toml", false).await,
+        Some(Command::Start {
+            config,
+            multi_process,
+        }) => start(config, *multi_process).await,
+        Some(Command::Restore {
+            dir,
+            config,
+            shard,
+            into,
+            until,
+        }) => {
+            let until = until.iter().cloned().collect();
+            restore(options.format, dir, config, shard.as_deref(), into.as_deref(), &until).await
+        }
+        Some(command) => run(&options, command).await,
+    }
+}
+
+async fn start(config: &str, multi_process: bool) -> Result<(), Box<dyn Error>> {
+    let mut config: ClusterConfig = toml::from_str(&std::fs::read_to_string(config)?)?;
+    if multi_process {
+        config.mode = DeployMode::MultiProcess;
+    }
+    let mut cluster = ClusterManager::start(config).await?;
 
     println!("Application is running. Press Ctrl+C to exit.");
-    tokio::signal::ctrl_c().await.expect("Failed to listen for ctrl_c");
-   
+    match &cluster.supervisor {
+        Some(supervisor) => {
+            print_processes(supervisor);
+            let mut lines = BufReader::new(tokio::io::stdin()).lines();
+            loop {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => break,
+                    line = lines.next_line() => match line? {
+                        Some(line) => control_process(supervisor, &line).await,
+                        // Keep running without a terminal.
+                        None => {
+                            tokio::signal::ctrl_c().await?;
+                            break;
+                        }
+                    },
+                }
+            }
+        }
+        None => tokio::signal::ctrl_c().await.expect("Failed to listen for ctrl_c"),
+    }
+
     cluster.shutdown().await?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+async fn restore(
+    format: Format,
+    dir: &Path,
+    config: &str,
+    shard: Option<&str>,
+    into: Option<&str>,
+    until: &BTreeMap<String, u64>,
+) -> Result<(), Box<dyn Error>> {
+    let config: ClusterConfig = toml::from_str(&std::fs::read_to_string(config)?)?;
+    let Some(shard) = shard else {
+        let restored = ClusterManager::restore(&config, dir, until).await?;
+        print_restored(format, &restored);
+        return Ok(());
+    };
+
+    let topology = config.topology();
+    let target = topology.shards.iter().enumerate().find(|(i, spec)| match into {
+        Some(name) => spec.name(*i) == name,
+        None => spec.first().api_addr == shard,
+    });
+    let Some((i, target)) = target else {
+        return Err(format!("the config has no shard {}", into.unwrap_or(shard)).into());
+    };
+    let log_id = backup::restore_shard(dir, shard, target, until.get(shard).copied()).await?;
+    let restored = RestoredShard {
+        name: target.name(i),
+        log_id,
+    };
+    print_restored(format, &[restored]);
+    Ok(())
+}
+
+/// Parse a `SHARD=INDEX` argument.
+fn parse_until(arg: &str) -> Result<(String, u64), String> {
+    let (shard, index) = arg
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected SHARD=INDEX, got {}", arg))?;
+    let index = index.parse().map_err(|e| format!("invalid index {}: {}", index, e))?;
+    Ok((shard.to_string(), index))
+}
+
+/// Run a command read from stdin on the node processes.
+async fn control_process(supervisor: &Supervisor, line: &str) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let result = match words.as_slice() {
+        [] => Ok(()),
+        ["status"] => {
+            print_processes(supervisor);
+            Ok(())
+        }
+        [op, shard, id] => match id.parse::<NodeId>() {
+            Ok(id) => match *op {
+                "kill" => supervisor.kill(shard, id).await,
+                "stop" => supervisor.stop(shard, id).await,
+                "start" => supervisor.start(shard, id).await,
+                "suspend" => supervisor.suspend(shard, id).await,
+                "resume" => supervisor.resume(shard, id).await,
+                _ => {
+                    eprintln!("unknown command {}", op);
+                    Ok(())
+                }
+            },
+            Err(e) => {
+                eprintln!("invalid node id {}: {}", id, e);
+                Ok(())
+            }
+        },
+        _ => {
+            eprintln!("usage: status | kill|stop|start|suspend|resume <shard> <node id>");
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+    }
+}
+
+fn print_processes(supervisor: &Supervisor) {
+    let rows = supervisor
+        .nodes()
+        .into_iter()
+        .map(|node| {
+            vec![
+                node.shard,
+                node.id.to_string(),
+                node.api_addr,
+                node.status.to_string(),
+                node.restarts.to_string(),
+                node.log_path.display().to_string(),
+            ]
+        })
+        .collect();
+    print_table(&["SHARD", "ID", "ADDR", "STATUS", "RESTARTS", "LOG"], rows);
+}
+
+async fn run(options: &Opt, command: &Command) -> Result<(), Box<dyn Error>> {
+    let admin = admin(options)?;
+    let format = options.format;
+    match command {
+        Command::Start { .. } | Command::Restore { .. } => unreachable!(),
+        Command::Status { shard } => {
+            let ring = admin.ring().await?;
+            let statuses = match shard {
+                Some(shard) => vec![admin.shard_status(&ring, shard).await],
+                None => admin.status(&ring).await,
+            };
+            print_status(format, &statuses);
+        }
+        Command::Leaders => {
+            let ring = admin.ring().await?;
+            print_leaders(format, &admin.status(&ring).await);
+        }
+        Command::Ring => print_ring(format, &admin.ring().await?),
+        Command::AddNode {
+            shard,
+            id,
+            api_addr,
+            rpc_addr,
+        } => {
+            admin
+                .add_node(shard, *id, api_addr.clone(), rpc_addr.clone(), print_progress)
+                .await?;
+            print_done(format, &format!("node {} added to shard {}", id, shard));
+        }
+        Command::RemoveNode { shard, id } => {
+            admin.remove_node(shard, *id).await?;
+            print_done(format, &format!("node {} removed from shard {}", id, shard));
+        }
+        Command::ReplaceNode {
+            shard,
+            old,
+            id,
+            api_addr,
+            rpc_addr,
+        } => {
+            let new = (*id, api_addr.clone(), rpc_addr.clone());
+            admin.replace_node(shard, *old, new, print_progress).await?;
+            print_done(
+                format,
+                &format!("node {} replaced by node {} in shard {}", old, id, shard),
+            );
+        }
+        Command::Membership { shard } => {
+            print_membership(format, &admin.membership(shard).await?);
+        }
+        Command::AddShard {
+            addr,
+            followers,
+            weight,
+        } => {
+            admin
+                .add_shard(addr.clone(), followers.clone(), *weight)
+                .await?;
+            print_done(format, &format!("shard {} added to the ring", addr));
+        }
+        Command::RemoveShard { shard } => {
+            admin.remove_shard(shard).await?;
+            print_done(format, &format!("shard {} removed from the ring", shard));
+        }
+        Command::SetWeight { shard, weight } => {
+            admin.set_weight(shard, *weight).await?;
+            print_done(format, &format!("weight of shard {} set to {}", shard, weight));
+        }
+        Command::TransferLeader { shard, target } => {
+            admin.transfer_leader(shard, *target).await?;
+            print_done(format, &format!("node {} leads shard {}", target, shard));
+        }
+        Command::Drain { addr } => {
+            let leader = admin.drain(addr).await?;
+            print_done(format, &format!("node {} drained, node {} leads", addr, leader));
+        }
+        Command::Snapshot { shard } => {
+            print_snapshots(format, &admin.snapshot(shard, false).await?);
+        }
+        Command::Compact { shard } => {
+            print_snapshots(format, &admin.snapshot(shard, true).await?);
+        }
+        Command::Backup { dir } => print_backup(format, &admin.backup(dir).await?),
+        Command::Export { dir } => print_export(format, &admin.export(dir).await?),
+        Command::Import { dir, concurrency } => {
+            let nodes = options.nodes.to_string_lossy();
+            let client = KVClient::with_transport(&nodes, transport(options)?).await?;
+            print_import(format, &export::import(&client, dir, *concurrency).await?);
+        }
+    }
+    Ok(())
+}
+
+fn admin(options: &Opt) -> Result<Admin, Box<dyn Error>> {
+    let shards = ClientConfig::load(&options.nodes)?.api_addrs();
+    Ok(Admin::new(transport(options)?, shards.into_iter().flatten().collect()))
+}
+
+/// A client for any node, with the credentials of the options.
+fn transport(options: &Opt) -> Result<RaftNode, Box<dyn Error>> {
+    let mut transport = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => {
+            let mut tls = TlsConfig::new(cert.clone(), key.clone());
+            tls.ca_path = options.tls_ca.clone();
+            RaftNode::with_tls(0, String::new(), &tls)?
+        }
+        _ => RaftNode::new(0, String::new()),
+    };
+    if let Some(token) = &options.token {
+        transport = transport.with_token(token.clone());
+    }
+    Ok(transport)
+}
+
+// --- Output
+
+fn print_json<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).unwrap());
+}
+
+/// Print `rows` as columns aligned to the widest cell.
+fn print_table(header: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+    let line = |cells: Vec<String>| {
+        let cells: Vec<String> = cells
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    };
+    line(header.iter().map(|h| h.to_string()).collect());
+    for row in rows {
+        line(row);
+    }
+}
+
+fn or_dash<T: ToString>(value: Option<T>) -> String {
+    value.map_or_else(|| "-".to_string(), |v| v.to_string())
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::Leader => "leader",
+        Role::Follower => "follower",
+        Role::Learner => "learner",
+        Role::NotMember => "not member",
+        Role::Unknown => "unknown",
+    }
+}
+
+/// Report the progress of a learner on stderr, so it does not mix with the result.
+fn print_progress(learner: &LearnerProgress) {
+    eprintln!(
+        "node {} catching up: {} entries behind (matched {})",
+        learner.id,
+        learner.lag,
+        or_dash(learner.matched)
+    );
+}
+
+fn print_membership(format: Format, status: &MembershipStatus) {
+    if format == Format::Json {
+        return print_json(status);
+    }
+    let mut rows: Vec<Vec<String>> = status
+        .voters
+        .iter()
+        .map(|id| {
+            let role = if *id == status.leader { "leader" } else { "follower" };
+            vec![id.to_string(), role.to_string(), "-".to_string(), "-".to_string()]
+        })
+        .collect();
+    rows.extend(status.learners.iter().map(|learner| {
+        vec![
+            learner.id.to_string(),
+            "learner".to_string(),
+            or_dash(learner.matched),
+            learner.lag.to_string(),
+        ]
+    }));
+    print_table(&["ID", "ROLE", "MATCHED", "LAG"], rows);
+    if status.joint {
+        println!("a membership change is in progress");
+    }
+}
+
+fn print_done(format: Format, message: &str) {
+    match format {
+        Format::Table => println!("{}", message),
+        Format::Json => print_json(&serde_json::json!({ "ok": true, "message": message })),
+    }
+}
+
+fn print_status(format: Format, statuses: &[ShardStatus]) {
+    if format == Format::Json {
+        return print_json(&statuses);
+    }
+    let rows = statuses
+        .iter()
+        .flat_map(|shard| {
+            shard.nodes.iter().map(move |node| {
+                vec![
+                    shard.shard.clone(),
+                    or_dash(node.id),
+                    node.api_addr.clone(),
+                    role_name(node.role).to_string(),
+                    or_dash(node.term),
+                    or_dash(node.last_log_index),
+                    or_dash(node.last_applied),
+                    or_dash(node.snapshot),
+                    or_dash(node.lag),
+                    node.error.clone().unwrap_or_default(),
+                ]
+            })
+        })
+        .collect();
+    print_table(
+        &[
+            "SHARD", "ID", "ADDR", "ROLE", "TERM", "LAST LOG", "APPLIED", "SNAPSHOT", "LAG",
+            "ERROR",
+        ],
+        rows,
+    );
+}
+
+fn print_leaders(format: Format, statuses: &[ShardStatus]) {
+    #[derive(Serialize)]
+    struct Leader<'a> {
+        shard: &'a str,
+        leader: Option<NodeId>,
+        leader_addr: Option<&'a str>,
+        term: Option<u64>,
+        max_lag: Option<u64>,
+    }
+
+    let leaders: Vec<Leader> = statuses
+        .iter()
+        .map(|shard| Leader {
+            shard: &shard.shard,
+            leader: shard.leader,
+            leader_addr: shard
+                .nodes
+                .iter()
+                .find(|node| node.id.is_some() && node.id == shard.leader)
+                .map(|node| node.api_addr.as_str()),
+            term: shard.term,
+            max_lag: shard.nodes.iter().filter_map(|node| node.lag).max(),
+        })
+        .collect();
+    if format == Format::Json {
+        return print_json(&leaders);
+    }
+    let rows = leaders
+        .iter()
+        .map(|l| {
+            vec![
+                l.shard.to_string(),
+                or_dash(l.leader),
+                or_dash(l.leader_addr),
+                or_dash(l.term),
+                or_dash(l.max_lag),
+            ]
+        })
+        .collect();
+    print_table(&["SHARD", "LEADER", "ADDR", "TERM", "MAX LAG"], rows);
+}
+
+fn print_ring(format: Format, ring: &Carp) {
+    if format == Format::Json {
+        return print_json(ring);
+    }
+    println!("config id: {}", ring.config_id);
+    let rows = ring
+        .nodes
+        .iter()
+        .map(|node| {
+            vec![
+                node.addr.clone(),
+                format!("{:.3}", node.relative_load),
+                ring.get_proxy(&node.addr).to_string(),
+                ring.get_followers(&node.addr)
+                    .map(|followers| followers.join(","))
+                    .unwrap_or_default(),
+            ]
+        })
+        .collect();
+    print_table(&["SHARD", "LOAD", "PROXY", "FOLLOWERS"], rows);
+}
+
+fn print_snapshots(format: Format, snapshots: &[SnapshotStatus]) {
+    if format == Format::Json {
+        return print_json(&snapshots);
+    }
+    let rows = snapshots
+        .iter()
+        .map(|s| {
+            vec![
+                s.id.to_string(),
+                s.api_addr.clone(),
+                or_dash(s.snapshot.map(|log_id| log_id.index)),
+                or_dash(s.purged),
+                s.error.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    print_table(&["ID", "ADDR", "SNAPSHOT", "PURGED", "ERROR"], rows);
+}
+
+fn print_backup(format: Format, manifest: &Manifest) {
+    if format == Format::Json {
+        return print_json(manifest);
+    }
+    let rows = manifest
+        .shards
+        .iter()
+        .map(|shard| {
+            vec![
+                shard.shard.clone(),
+                shard.path.display().to_string(),
+                shard.checkpoint.node.to_string(),
+                or_dash(shard.checkpoint.snapshot.map(|log_id| log_id.index)),
+                or_dash(shard.checkpoint.committed.map(|log_id| log_id.index)),
+            ]
+        })
+        .collect();
+    print_table(&["SHARD", "PATH", "NODE", "SNAPSHOT", "COMMITTED"], rows);
+}
+
+fn print_restored(format: Format, restored: &[RestoredShard]) {
+    if format == Format::Json {
+        return print_json(&restored);
+    }
+    let rows = restored
+        .iter()
+        .map(|shard| {
+            vec![
+                shard.name.clone(),
+                shard.log_id.index.to_string(),
+                shard.log_id.leader_id.to_string(),
+            ]
+        })
+        .collect();
+    print_table(&["SHARD", "INDEX", "LEADER ID"], rows);
+}
+
+fn print_export(format: Format, manifest: &ExportManifest) {
+    if format == Format::Json {
+        return print_json(manifest);
+    }
+    println!("config id: {}", manifest.ring.config_id);
+    let rows = manifest
+        .shards
+        .iter()
+        .map(|shard| {
+            vec![
+                shard.shard.clone(),
+                shard.path.display().to_string(),
+                or_dash(shard.log_id.map(|log_id| log_id.index)),
+                shard.keys.to_string(),
+            ]
+        })
+        .collect();
+    print_table(&["SHARD", "PATH", "INDEX", "KEYS"], rows);
+}
+
+fn print_import(format: Format, summary: &ImportSummary) {
+    if format == Format::Json {
+        return print_json(summary);
+    }
+    println!(
+        "{} keys and {} namespaces imported, {} stale keys skipped",
+        summary.keys, summary.namespaces, summary.skipped
+    );
+}
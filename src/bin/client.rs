@@ -1,24 +1,285 @@
-use distrib_kv_store::kvclient::KVClient;
 use std::error::Error;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::Parser;
+use clap::Subcommand;
+use distrib_kv_store::bulk;
+use distrib_kv_store::bulk::Format;
+use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::telemetry;
+use distrib_kv_store::telemetry::TraceLayer;
+use distrib_kv_store::tls::TlsConfig;
+use tokio::io::AsyncBufReadExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Read and write the keys of the store.
+///
+/// Starts an interactive shell if no command is given.
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Opt {
+    /// Client config written by the cluster manager, or a JSON list of the API addresses of the
+    /// nodes of every shard. The hash ring is fetched from the nodes.
+    #[clap(long, env = "KV_NODES", default_value = "cluster.json")]
+    nodes: String,
+
+    /// Token, for nodes that require authentication.
+    #[clap(long, env = "KV_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+
+    /// PEM client certificate, for nodes that serve their API over TLS.
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the client certificate.
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// PEM certificates trusted to sign the certificates of the nodes.
+    #[clap(long, requires = "tls_cert")]
+    tls_ca: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print the value of a key, as known by the node serving its shard.
+    Get { key: String },
+    /// Print the value of a key, as confirmed by a quorum of its shard.
+    ConsistentGet { key: String },
+    /// Set a key.
+    Put { key: String, value: String },
+    /// Delete a key.
+    Delete { key: String },
+    /// Print the keys with a prefix and their values, in key order.
+    Scan {
+        #[clap(default_value = "")]
+        prefix: String,
+        /// Only print keys after this one.
+        #[clap(long)]
+        start_after: Option<String>,
+        #[clap(long, default_value_t = 100)]
+        limit: usize,
+    },
+    /// Poll a key and print its value whenever it changes, until Ctrl+C.
+    Watch {
+        key: String,
+        #[clap(long, default_value_t = 500)]
+        interval_ms: u64,
+        /// Poll with consistent reads.
+        #[clap(long)]
+        consistent: bool,
+    },
+    /// Write the records of a file, or of stdin.
+    Import {
+        file: Option<PathBuf>,
+        /// `jsonl` or `csv`.
+        #[clap(long, default_value = "jsonl")]
+        format: Format,
+        /// Maximum number of writes in flight.
+        #[clap(long, default_value_t = 16)]
+        concurrency: usize,
+    },
+    /// Write the keys with a prefix and their values to a file, or to stdout.
+    Export {
+        file: Option<PathBuf>,
+        /// `jsonl` or `csv`.
+        #[clap(long, default_value = "jsonl")]
+        format: Format,
+        #[clap(long, default_value = "")]
+        prefix: String,
+    },
+    /// Start an interactive shell that runs the other commands.
+    Repl,
+}
+
+/// A line of the interactive shell.
+#[derive(Parser, Debug)]
+#[clap(no_binary_name = true, disable_version_flag = true)]
+struct Line {
+    #[clap(subcommand)]
+    command: Command,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    let client = KVClient::new("all_nodes.json").await?;
+    let options = Opt::parse();
 
-    client.write("key", "value").await?;
-    client.write("hi", "test").await?;
-    client.write("hello", "testing").await?;
+    // Requests are traced across the nodes. Spans are written to `KV_TRACE_FILE` if it is set.
+    let tracer = match std::env::var("KV_TRACE_FILE") {
+        Ok(path) => TraceLayer::with_file("client", path)?,
+        Err(_) => TraceLayer::new("client"),
+    };
+    tracing_subscriber::registry()
+        .with(tracer.with_filter(telemetry::filter()))
+        .init();
 
-    match client.read("hello").await {
-        Ok(response) => println!("Value retrieved: {}", response),
-        Err(e) => println!("Error reading value: {}", e),
+    let mut transport = match (&options.tls_cert, &options.tls_key) {
+        (Some(cert), Some(key)) => {
+            let mut tls = TlsConfig::new(cert.clone(), key.clone());
+            tls.ca_path = options.tls_ca.clone();
+            RaftNode::with_tls(0, String::new(), &tls)?
+        }
+        _ => RaftNode::new(0, String::new()),
+    };
+    if let Some(token) = &options.token {
+        transport = transport.with_token(token.clone());
     }
+    let client = KVClient::with_transport(&options.nodes, transport).await?;
 
-    match client.consistent_read("hello").await {
-        Ok(response) => println!("Value retrieved: {}", response),
-        Err(e) => println!("Error reading value: {}", e),
+    match options.command.unwrap_or(Command::Repl) {
+        Command::Repl => repl(&client).await,
+        command => run(&client, command).await,
     }
+}
 
-
+async fn run(client: &KVClient, command: Command) -> Result<(), Box<dyn Error>> {
+    match command {
+        Command::Get { key } => println!("{}", client.read(&key).await?),
+        Command::ConsistentGet { key } => println!("{}", client.consistent_read(&key).await?),
+        Command::Put { key, value } => client.write(&key, &value).await?,
+        Command::Delete { key } => client.delete(&key).await?,
+        Command::Scan {
+            prefix,
+            start_after,
+            limit,
+        } => {
+            let start_after = start_after.as_deref().map(str::as_bytes);
+            for (key, value) in client.scan(&prefix, start_after, limit).await? {
+                println!("{}\t{}", key, value);
+            }
+        }
+        Command::Watch {
+            key,
+            interval_ms,
+            consistent,
+        } => {
+            let mut last = None;
+            let mut interval = tokio::time::interval(Duration::from_millis(interval_ms));
+            loop {
+                interval.tick().await;
+                let value = match consistent {
+                    true => client.consistent_read(&key).await,
+                    false => client.read(&key).await,
+                };
+                let value = match value {
+                    Ok(value) => Ok(Some(value)),
+                    Err(ClientError::NotFound(_)) => Ok(None),
+                    Err(e) => Err(e),
+                };
+                match value {
+                    Ok(value) if last.as_ref() != Some(&value) => {
+                        match &value {
+                            Some(value) => println!("{}", value),
+                            None => println!("(not found)"),
+                        }
+                        last = Some(value);
+                    }
+                    Ok(_) => {}
+                    Err(e) => eprintln!("error: {}", e),
+                }
+            }
+        }
+        Command::Import {
+            file,
+            format,
+            concurrency,
+        } => {
+            let input = match file {
+                Some(path) => std::fs::read_to_string(path)?,
+                None => std::io::read_to_string(std::io::stdin())?,
+            };
+            let records = bulk::parse(&input, format)?;
+            let count = bulk::import(client, records, concurrency).await?;
+            eprintln!("imported {} keys", count);
+        }
+        Command::Export {
+            file,
+            format,
+            prefix,
+        } => {
+            let mut out: Box<dyn Write> = match file {
+                Some(path) => Box::new(std::io::BufWriter::new(std::fs::File::create(path)?)),
+                None => Box::new(std::io::stdout().lock()),
+            };
+            let count = bulk::export(client, &prefix, format, &mut out).await?;
+            out.flush()?;
+            eprintln!("exported {} keys", count);
+        }
+        Command::Repl => return Err("already in the shell".into()),
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Run the commands read from stdin, one per line, until `exit` or the end of the input.
+async fn repl(client: &KVClient) -> Result<(), Box<dyn Error>> {
+    let mut lines = tokio::io::BufReader::new(tokio::io::stdin()).lines();
+    loop {
+        print!("> ");
+        std::io::stdout().flush()?;
+        let Some(line) = lines.next_line().await? else {
+            println!();
+            return Ok(());
+        };
+        let words = match split_words(&line) {
+            Ok(words) => words,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                continue;
+            }
+        };
+        match words.first().map(String::as_str) {
+            None => continue,
+            Some("exit" | "quit") => return Ok(()),
+            Some(_) => {}
+        }
+        match Line::try_parse_from(words) {
+            Ok(Line { command }) => {
+                if let Err(e) = run(client, command).await {
+                    eprintln!("error: {}", e);
+                }
+            }
+            // Also prints the help, if requested.
+            Err(e) => {
+                let _ = e.print();
+            }
+        }
+    }
+}
+
+/// Split a line of the shell into words. Words are separated by whitespace, unless quoted
+/// with `"` or `'`. A `\` escapes the next character outside of `'`.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('"') | None, '\\') => {
+                let escaped = chars.next().ok_or("trailing backslash")?;
+                word.get_or_insert_with(String::new).push(escaped);
+            }
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err("unterminated quote".to_string());
+    }
+    words.extend(word);
+    Ok(words)
+}
@@ -1,6 +1,23 @@
+use std::path::PathBuf;
+
 use clap::Parser;
-use distrib_kv_store::start_example_raft_node;
+use clap::ValueEnum;
+use distrib_kv_store::auth::AuthConfig;
+use distrib_kv_store::start_raft_node;
+use distrib_kv_store::store::engine::StorageBackend;
+use distrib_kv_store::telemetry;
+use distrib_kv_store::telemetry::TraceLayer;
+use distrib_kv_store::tls::TlsConfig;
+use distrib_kv_store::value::ValueConfig;
+use distrib_kv_store::value::DEFAULT_COMPRESSION_THRESHOLD;
+use distrib_kv_store::value::DEFAULT_MAX_VALUE_SIZE;
+use distrib_kv_store::NodeConfig;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::EnvFilter;
+use tracing_subscriber::Layer;
+use tokio::signal::unix::signal;
+use tokio::signal::unix::SignalKind;
 use tokio::sync::watch;
 
 #[derive(Parser, Clone, Debug)]
@@ -14,30 +31,124 @@ pub struct Opt {
 
     #[clap(long)]
     pub rpc_addr: String,
+
+    /// Address the HTTP API binds to, if not `--http-addr`, e.g., `0.0.0.0:<port>`.
+    #[clap(long)]
+    pub listen_http_addr: Option<String>,
+
+    /// Address the Raft RPC binds to, if not `--rpc-addr`.
+    #[clap(long)]
+    pub listen_rpc_addr: Option<String>,
+
+    /// Directory of the node's storage. Defaults to `<rpc-addr>-db`.
+    #[clap(long)]
+    pub data_dir: Option<PathBuf>,
+
+    /// Storage engine. `memory` loses the node's state when it stops, for tests and benchmarks.
+    #[clap(long, value_enum, default_value_t = Storage::Rocksdb)]
+    pub storage: Storage,
+
+    /// PEM certificate chain. Enables TLS for the HTTP API and the Raft RPC.
+    #[clap(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM private key of the certificate.
+    #[clap(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM certificates trusted to sign the certificates of nodes and clients.
+    #[clap(long, requires = "tls_cert")]
+    pub tls_ca: Option<PathBuf>,
+
+    /// Require clients and other nodes to present a certificate (mutual TLS).
+    #[clap(long, requires = "tls_cert")]
+    pub require_client_auth: bool,
+
+    /// TOML file with the tokens accepted by the HTTP API. Authentication is disabled without it.
+    #[clap(long)]
+    pub auth_config: Option<PathBuf>,
+
+    /// Writes of larger values are rejected, in bytes.
+    #[clap(long, default_value_t = DEFAULT_MAX_VALUE_SIZE)]
+    pub max_value_size: usize,
+
+    /// Values of at least this many bytes are stored compressed with zstd.
+    #[clap(long, default_value_t = DEFAULT_COMPRESSION_THRESHOLD)]
+    pub compression_threshold: usize,
+
+    /// Store all values uncompressed.
+    #[clap(long, conflicts_with = "compression_threshold")]
+    pub no_compression: bool,
+
+    /// Append the spans of traced requests to this file, as OpenTelemetry JSON.
+    #[clap(long)]
+    pub trace_file: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Storage {
+    Rocksdb,
+    Memory,
 }
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
-    // Setup the logger
-    tracing_subscriber::fmt()
-        .with_target(true)
-        .with_thread_ids(true)
-        .with_level(true)
-        .with_ansi(false)
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
-
     // Parse the parameters passed by arguments.
     let options = Opt::parse();
 
-    let (_shutdown_tx, shutdown_rx) = watch::channel(());
+    // Setup the logger, and the tracer that propagates trace contexts to other nodes.
+    let tracer = match &options.trace_file {
+        Some(path) => TraceLayer::with_file(format!("node-{}", options.id), path)?,
+        None => TraceLayer::new(format!("node-{}", options.id)),
+    };
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_target(true)
+                .with_thread_ids(true)
+                .with_level(true)
+                .with_ansi(false)
+                .with_filter(EnvFilter::from_default_env()),
+        )
+        .with(tracer.with_filter(telemetry::filter()))
+        .init();
+
+    // Shut down cleanly on Ctrl+C or `SIGTERM`, e.g., when stopped by the cluster manager.
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        let _ = shutdown_tx.send(());
+    });
+
+    let data_dir = options
+        .data_dir
+        .unwrap_or_else(|| PathBuf::from(format!("{}-db", options.rpc_addr)));
+    let mut config = NodeConfig::new(options.id, data_dir, options.http_addr, options.rpc_addr);
+    config.storage = match options.storage {
+        Storage::Rocksdb => StorageBackend::RocksDb,
+        Storage::Memory => StorageBackend::Memory,
+    };
+    config.http_listen_addr = options.listen_http_addr;
+    config.rpc_listen_addr = options.listen_rpc_addr;
+    if let (Some(cert), Some(key)) = (options.tls_cert, options.tls_key) {
+        let mut tls = TlsConfig::new(cert, key);
+        tls.ca_path = options.tls_ca;
+        tls.require_client_auth = options.require_client_auth;
+        config.tls = Some(tls);
+    }
+    if let Some(path) = options.auth_config {
+        let contents = std::fs::read_to_string(path)?;
+        config.auth = toml::from_str::<AuthConfig>(&contents)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    }
+    config.values = ValueConfig {
+        max_size: options.max_value_size,
+        compression_threshold: (!options.no_compression).then_some(options.compression_threshold),
+    };
 
-    start_example_raft_node(
-        options.id,
-        format!("{}-db", options.rpc_addr),
-        options.http_addr,
-        options.rpc_addr,
-        shutdown_rx.clone()
-    )
-    .await
+    start_raft_node(config, shutdown_rx).await
 }
@@ -0,0 +1,328 @@
+//! Bulk import and export of key-value pairs through [`KVClient`].
+//!
+//! Pairs are read and written as JSON lines, one `{"key": ..., "value": ...}` object per line,
+//! or as CSV with a `key,value` header. CSV fields are quoted as in RFC 4180 when needed.
+//!
+//! Keys and values that aren't UTF-8 are written as `{"base64": ...}` in JSON lines, see
+//! [`crate::value`]. CSV only holds text, so exporting them as CSV fails.
+use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+
+use futures::StreamExt;
+use futures::TryStreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::kvclient::KVClient;
+use crate::network::error::ClientError;
+use crate::value::Bytes;
+
+/// Number of keys fetched per scan during an export.
+const EXPORT_BATCH: usize = 1_000;
+
+/// A key-value pair.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    pub key: Bytes,
+    pub value: Bytes,
+}
+
+/// Encoding of imported and exported records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    JsonLines,
+    Csv,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jsonl" | "json" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format {:?}, expected jsonl or csv", s)),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Format::JsonLines => write!(f, "jsonl"),
+            Format::Csv => write!(f, "csv"),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum BulkError {
+    #[error("line {line}: {msg}")]
+    Parse { line: usize, msg: String },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Client(#[from] ClientError),
+}
+
+/// Parse the records of `input`.
+pub fn parse(input: &str, format: Format) -> Result<Vec<Record>, BulkError> {
+    match format {
+        Format::JsonLines => input
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| {
+                serde_json::from_str(line).map_err(|e| BulkError::Parse {
+                    line: i + 1,
+                    msg: e.to_string(),
+                })
+            })
+            .collect(),
+        Format::Csv => {
+            let mut records = Vec::new();
+            for (line, mut fields) in parse_csv(input)? {
+                if fields.len() != 2 {
+                    return Err(BulkError::Parse {
+                        line,
+                        msg: format!("expected 2 fields, found {}", fields.len()),
+                    });
+                }
+                let value = fields.pop().unwrap();
+                let key = fields.pop().unwrap();
+                // The header is optional.
+                if line == 1 && key == "key" && value == "value" {
+                    continue;
+                }
+                records.push(Record {
+                    key: key.into(),
+                    value: value.into(),
+                });
+            }
+            Ok(records)
+        }
+    }
+}
+
+/// Split CSV into rows of fields, each with the line it starts on.
+fn parse_csv(input: &str) -> Result<Vec<(usize, Vec<String>)>, BulkError> {
+    let mut rows = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut line = 1;
+    let mut row_line = 1;
+    let mut chars = input.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted => {
+                if chars.peek() == Some(&'"') {
+                    chars.next();
+                    field.push('"');
+                } else {
+                    quoted = false;
+                }
+            }
+            '"' if field.is_empty() => quoted = true,
+            '\n' if quoted => {
+                line += 1;
+                field.push(c);
+            }
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                fields.push(std::mem::take(&mut field));
+                if fields.len() > 1 || !fields[0].is_empty() {
+                    rows.push((row_line, std::mem::take(&mut fields)));
+                }
+                fields.clear();
+                line += 1;
+                row_line = line;
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(BulkError::Parse {
+            line: row_line,
+            msg: "unterminated quoted field".to_string(),
+        });
+    }
+    if !fields.is_empty() || !field.is_empty() {
+        fields.push(field);
+        rows.push((row_line, fields));
+    }
+    Ok(rows)
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Write the header of `format`, if it has one.
+pub fn write_header(out: &mut impl Write, format: Format) -> std::io::Result<()> {
+    match format {
+        Format::JsonLines => Ok(()),
+        Format::Csv => writeln!(out, "key,value"),
+    }
+}
+
+/// Write a single record.
+pub fn write_record(
+    out: &mut impl Write,
+    format: Format,
+    record: &Record,
+) -> std::io::Result<()> {
+    match format {
+        Format::JsonLines => {
+            serde_json::to_writer(&mut *out, record)?;
+            writeln!(out)
+        }
+        Format::Csv => {
+            let (Some(key), Some(value)) = (record.key.as_str(), record.value.as_str()) else {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("the record of {:?} is not UTF-8, which CSV can't hold", record.key),
+                ));
+            };
+            writeln!(out, "{},{}", csv_field(key), csv_field(value))
+        }
+    }
+}
+
+/// Write all records to the cluster, with at most `concurrency` writes in flight.
+///
+/// Stops at the first failed write. Returns the number of records written.
+pub async fn import(
+    client: &KVClient,
+    records: Vec<Record>,
+    concurrency: usize,
+) -> Result<usize, BulkError> {
+    let count = records.len();
+    futures::stream::iter(records)
+        .map(|record| async move { client.write(&record.key, &record.value).await })
+        .buffer_unordered(concurrency.max(1))
+        .try_collect::<Vec<()>>()
+        .await?;
+    Ok(count)
+}
+
+/// Write all keys with `prefix` and their values to `out`, in key order.
+///
+/// Returns the number of records written.
+pub async fn export(
+    client: &KVClient,
+    prefix: &str,
+    format: Format,
+    out: &mut impl Write,
+) -> Result<usize, BulkError> {
+    write_header(out, format)?;
+    let mut count = 0;
+    let mut last: Option<Bytes> = None;
+    loop {
+        let entries = client.scan(prefix, last.as_deref(), EXPORT_BATCH).await?;
+        for (key, value) in &entries {
+            write_record(
+                out,
+                format,
+                &Record {
+                    key: key.clone(),
+                    value: value.clone(),
+                },
+            )?;
+        }
+        count += entries.len();
+        match entries.last() {
+            Some((key, _)) => last = Some(key.clone()),
+            None => break,
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(key: &str, value: &str) -> Record {
+        Record {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+
+    fn roundtrip(records: &[Record], format: Format) -> Vec<Record> {
+        let mut out = Vec::new();
+        write_header(&mut out, format).unwrap();
+        for record in records {
+            write_record(&mut out, format, record).unwrap();
+        }
+        parse(std::str::from_utf8(&out).unwrap(), format).unwrap()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let records = vec![
+            record("plain", "value"),
+            record("comma", "a,b"),
+            record("quote", "say \"hi\""),
+            record("newline", "line 1\nline 2"),
+            record("empty", ""),
+        ];
+        assert_eq!(roundtrip(&records, Format::JsonLines), records);
+        assert_eq!(roundtrip(&records, Format::Csv), records);
+    }
+
+    #[test]
+    fn test_binary() {
+        let binary = Record {
+            key: "binary".into(),
+            value: vec![0xff, 0x00].into(),
+        };
+        let records = vec![binary.clone()];
+        assert_eq!(roundtrip(&records, Format::JsonLines), records);
+        let err = write_record(&mut Vec::new(), Format::Csv, &binary).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_parse_csv() {
+        let input = "a,1\r\nb,\"2,\"\"3\"\"\"\n\nc,\"x\ny\"\n";
+        assert_eq!(
+            parse(input, Format::Csv).unwrap(),
+            vec![record("a", "1"), record("b", "2,\"3\""), record("c", "x\ny")]
+        );
+
+        // Without a trailing newline.
+        assert_eq!(parse("a,1", Format::Csv).unwrap(), vec![record("a", "1")]);
+
+        assert!(matches!(
+            parse("a,1\nb,2,3\n", Format::Csv),
+            Err(BulkError::Parse { line: 2, .. })
+        ));
+        assert!(matches!(
+            parse("a,\"1\n", Format::Csv),
+            Err(BulkError::Parse { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn test_parse_json_lines() {
+        let input = "{\"key\":\"a\",\"value\":\"1\"}\n\n{\"key\":\"b\",\"value\":\"2\"}\n";
+        assert_eq!(
+            parse(input, Format::JsonLines).unwrap(),
+            vec![record("a", "1"), record("b", "2")]
+        );
+        assert!(matches!(
+            parse("{\"key\":\"a\"}\n", Format::JsonLines),
+            Err(BulkError::Parse { line: 1, .. })
+        ));
+    }
+}
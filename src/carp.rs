@@ -2,14 +2,31 @@
 //!
 //! Follows implementation details outlined in this RFC:
 //! https://datatracker.ietf.org/doc/html/draft-vinod-carp-v1-03#section-3.1
+use std::collections::HashMap;
+
 use serde::Deserialize;
 use serde::Deserializer;
 use serde::Serialize;
+use thiserror::Error;
 
 const CARP_PRIME: u32 = 0x62531965;
 
+/// Errors returned when modifying a hash ring.
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum CarpError {
+    #[error("node {0} is already part of the ring")]
+    DuplicateNode(String),
+    #[error("node {0} is not part of the ring")]
+    NodeNotFound(String),
+    #[error("node {0} must be added with followers, as the other nodes of the ring have followers")]
+    FollowersRequired(String),
+    #[error("node {0} can't be added with followers, as the other nodes of the ring have none")]
+    FollowersNotAllowed(String),
+}
+
 /// A node in the hash ring.
-/// TODO: Currently ignores the fact that a node is a Raft cluster.
+///
+/// Every node is a Raft cluster and is identified by the address of its original leader.
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RingNode {
     pub addr: String,
@@ -47,6 +64,14 @@ pub struct Carp {
     pub list_ttl: u32,
     #[serde(deserialize_with = "deserialize_nodes")]
     pub nodes: Vec<RingNode>,
+    /// Maps the original leader of a cluster to the addresses of its followers.
+    /// Empty if the ring was created without followers.
+    #[serde(default)]
+    pub followers_map: HashMap<String, Vec<String>>,
+    /// Maps the original leader of a cluster to the node that currently serves it.
+    /// A missing entry means the original leader serves the cluster itself.
+    #[serde(default)]
+    pub proxy_map: HashMap<String, String>,
 }
 
 impl Carp {
@@ -61,28 +86,200 @@ impl Carp {
             version: 1.0,
             config_id,
             list_ttl: 10 * 60, // 10 minutes
+            followers_map: HashMap::new(),
+            proxy_map: HashMap::new(),
         };
         rebalance(&mut ring.nodes);
         ring
     }
 
+    /// Creates a new hash ring from a vector of leader addresses, relative loads and the
+    /// addresses of the followers of each leader.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use distrib_kv_store::carp::Carp;
+    ///
+    /// let ring = Carp::with_followers(
+    ///     vec![("node-1".to_string(), 1.0, vec!["node-2".to_string()])],
+    ///     0,
+    /// );
+    ///
+    /// assert_eq!(ring.get_followers("node-1"), Some(&vec!["node-2".to_string()]));
+    /// ```
+    pub fn with_followers(nodes: Vec<(String, f32, Vec<String>)>, config_id: u32) -> Self {
+        let mut followers_map = HashMap::new();
+        let nodes = nodes
+            .into_iter()
+            .map(|(addr, relative_load, followers)| {
+                followers_map.insert(addr.clone(), followers);
+                (addr, relative_load)
+            })
+            .collect();
+        let mut ring = Self::new(nodes, config_id);
+        ring.followers_map = followers_map;
+        ring
+    }
+
     /// Adds a new node to the hash ring.
     /// Recalculates relative loads and load factors.
-    pub fn add_node(&mut self, addr: String, relative_load: f32) {
-        let node = RingNode::new(addr, relative_load);
-        self.nodes.push(node);
+    ///
+    /// Either every node of the ring has followers or none has, so `followers` must be
+    /// provided exactly when the other nodes have followers.
+    pub fn add_node(
+        &mut self,
+        addr: String,
+        relative_load: f32,
+        followers: Option<Vec<String>>,
+    ) -> Result<(), CarpError> {
+        if self.nodes.iter().any(|node| node.addr == addr) {
+            return Err(CarpError::DuplicateNode(addr));
+        }
+        let has_followers = !self.followers_map.is_empty();
+        match followers {
+            Some(_) if !has_followers && !self.nodes.is_empty() => {
+                return Err(CarpError::FollowersNotAllowed(addr));
+            }
+            None if has_followers => return Err(CarpError::FollowersRequired(addr)),
+            Some(followers) => {
+                self.followers_map.insert(addr.clone(), followers);
+            }
+            None => {}
+        }
+        self.nodes.push(RingNode::new(addr, relative_load));
         rebalance(&mut self.nodes);
         self.config_id += 1;
+        Ok(())
     }
 
     /// Removes a node from the hash ring.
     /// Recalculates relative loads and load factors.
-    pub fn remove_node(&mut self, addr: &str) {
+    pub fn remove_node(&mut self, addr: &str) -> Result<(), CarpError> {
+        if !self.nodes.iter().any(|node| node.addr == addr) {
+            return Err(CarpError::NodeNotFound(addr.to_string()));
+        }
         self.nodes.retain(|node| node.addr != addr);
+        self.followers_map.remove(addr);
+        self.proxy_map.remove(addr);
         if !self.nodes.is_empty() {
             rebalance(&mut self.nodes);
         }
         self.config_id += 1;
+        Ok(())
+    }
+
+    /// Adds a follower to the cluster of the given original leader.
+    pub fn add_follower(
+        &mut self,
+        original_leader_addr: &str,
+        addr: String,
+    ) -> Result<(), CarpError> {
+        if !self.nodes.iter().any(|node| node.addr == original_leader_addr) {
+            return Err(CarpError::NodeNotFound(original_leader_addr.to_string()));
+        }
+        if self.shard_of(&addr).is_some() {
+            return Err(CarpError::DuplicateNode(addr));
+        }
+        self.followers_map
+            .entry(original_leader_addr.to_string())
+            .or_default()
+            .push(addr);
+        self.config_id += 1;
+        Ok(())
+    }
+
+    /// Removes a follower from the cluster of the given original leader.
+    ///
+    /// A proxy pointing to the follower is removed as well.
+    pub fn remove_follower(
+        &mut self,
+        original_leader_addr: &str,
+        addr: &str,
+    ) -> Result<(), CarpError> {
+        let Some(followers) = self.followers_map.get_mut(original_leader_addr) else {
+            return Err(CarpError::NodeNotFound(addr.to_string()));
+        };
+        if !followers.iter().any(|f| f == addr) {
+            return Err(CarpError::NodeNotFound(addr.to_string()));
+        }
+        followers.retain(|f| f != addr);
+        if self.proxy_map.get(original_leader_addr).map(String::as_str) == Some(addr) {
+            self.proxy_map.remove(original_leader_addr);
+        }
+        self.config_id += 1;
+        Ok(())
+    }
+
+    /// Sets the relative loads of the given nodes.
+    /// Recalculates relative loads and load factors.
+    ///
+    /// Nodes that are not listed keep their current relative load.
+    pub fn set_relative_loads(&mut self, loads: &[(String, f32)]) -> Result<(), CarpError> {
+        if let Some((addr, _)) = loads
+            .iter()
+            .find(|(addr, _)| !self.nodes.iter().any(|node| node.addr == *addr))
+        {
+            return Err(CarpError::NodeNotFound(addr.clone()));
+        }
+        for node in self.nodes.iter_mut() {
+            if let Some((_, relative_load)) = loads.iter().find(|(addr, _)| *addr == node.addr) {
+                node.relative_load = *relative_load;
+            }
+        }
+        rebalance(&mut self.nodes);
+        self.config_id += 1;
+        Ok(())
+    }
+
+    /// Sets a new proxy for a leader that is down. Subsequent calls to [`Carp::get`] return the
+    /// proxy instead of the original leader.
+    ///
+    /// Setting the proxy back to the original leader removes the proxy.
+    pub fn set_new_proxy(
+        &mut self,
+        original_leader_addr: &str,
+        new_leader_addr: &str,
+    ) -> Result<(), CarpError> {
+        if !self.nodes.iter().any(|node| node.addr == original_leader_addr) {
+            return Err(CarpError::NodeNotFound(original_leader_addr.to_string()));
+        }
+        if original_leader_addr == new_leader_addr {
+            self.proxy_map.remove(original_leader_addr);
+        } else {
+            self.proxy_map.insert(
+                original_leader_addr.to_string(),
+                new_leader_addr.to_string(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Returns the followers of a given original leader.
+    pub fn get_followers(&self, original_leader_addr: &str) -> Option<&Vec<String>> {
+        self.followers_map.get(original_leader_addr)
+    }
+
+    /// Returns the original leader of the cluster `addr` belongs to, as leader or follower.
+    pub fn shard_of(&self, addr: &str) -> Option<&str> {
+        self.nodes
+            .iter()
+            .map(|node| node.addr.as_str())
+            .find(|original| {
+                *original == addr
+                    || self
+                        .get_followers(original)
+                        .map(|followers| followers.iter().any(|f| f == addr))
+                        .unwrap_or(false)
+            })
+    }
+
+    /// Returns the node that currently serves the cluster of the given original leader.
+    pub fn get_proxy<'a>(&'a self, original_leader_addr: &'a str) -> &'a str {
+        self.proxy_map
+            .get(original_leader_addr)
+            .map(String::as_str)
+            .unwrap_or(original_leader_addr)
     }
 
     /// Returns `true` if the ring is empty.
@@ -115,7 +312,8 @@ impl Carp {
         self.nodes.len()
     }
 
-    /// Returns the node responsible for the given URL.
+    /// Returns the node that currently serves the cluster responsible for the given URL.
+    /// This is the original leader of the cluster, unless a proxy has been set for it.
     ///
     /// # Panics
     ///
@@ -131,10 +329,20 @@ impl Carp {
     /// assert_eq!(ring.get("foo"), "node-1");
     /// ```
     pub fn get(&self, url: &str) -> &str {
+        self.get_proxy(self.get_original(url))
+    }
+
+    /// Returns the original leader of the cluster responsible for the given URL, ignoring
+    /// proxies.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the ring is empty.
+    pub fn get_original(&self, url: impl AsRef<[u8]>) -> &str {
         if self.is_empty() {
             panic!("Hash ring is empty");
         }
-        let url_hash = url_hash(url);
+        let url_hash = url_hash(url.as_ref());
         let mut best_score = f32::MIN;
         let mut best_node = &self.nodes[0];
         for node in self.nodes.iter() {
@@ -165,9 +373,9 @@ fn membership_hash(addr: &str) -> u32 {
 }
 
 /// Calculates the hash for a given URL.
-fn url_hash(url: &str) -> u32 {
+fn url_hash(url: &[u8]) -> u32 {
     let mut hash: u32 = 0;
-    for c in url.bytes() {
+    for &c in url {
         let rotated = hash.rotate_left(19).wrapping_add(c as u32);
         hash = hash.wrapping_add(rotated)
     }
@@ -243,6 +451,21 @@ mod tests {
         }};
     }
 
+    #[test]
+    fn test_shard_of() {
+        let ring = Carp::with_followers(
+            vec![
+                ("a1".to_string(), 0.5, vec!["a2".to_string(), "a3".to_string()]),
+                ("b1".to_string(), 0.5, vec!["b2".to_string()]),
+            ],
+            0,
+        );
+        assert_eq!(ring.shard_of("a1"), Some("a1"));
+        assert_eq!(ring.shard_of("a3"), Some("a1"));
+        assert_eq!(ring.shard_of("b2"), Some("b1"));
+        assert_eq!(ring.shard_of("c1"), None);
+    }
+
     #[test]
     fn test_size_empty() {
         let ring = Carp::new(vec![], 0);
@@ -274,7 +497,7 @@ mod tests {
     #[test]
     fn test_add_node() {
         let mut ring = Carp::new(vec![("0".to_string(), 0.5), ("1".to_string(), 0.5)], 0);
-        ring.add_node("2".to_string(), 0.25);
+        ring.add_node("2".to_string(), 0.25, None).unwrap();
         assert_eq!(ring.len(), 3);
         // Check that rebalance works correctly.
         assert_eq!(ring.nodes[0].addr, "2");
@@ -291,7 +514,7 @@ mod tests {
     #[test]
     fn test_remove_node() {
         let mut ring = Carp::new(vec![("0".to_string(), 0.5), ("1".to_string(), 0.5)], 0);
-        ring.remove_node("0");
+        ring.remove_node("0").unwrap();
         assert_eq!(ring.len(), 1);
         assert_eq!(ring.nodes[0].addr, "1");
         assert_approx_eq!(ring.nodes[0].relative_load, 1.0);
@@ -348,4 +571,128 @@ mod tests {
         assert_approx_eq!(ring.nodes[0].load_factor, deserialized.nodes[0].load_factor);
         assert_approx_eq!(ring.nodes[1].load_factor, deserialized.nodes[1].load_factor);
     }
+
+    #[test]
+    fn test_set_relative_loads() {
+        let mut ring = Carp::new(vec![("0".to_string(), 0.5), ("1".to_string(), 0.5)], 0);
+        ring.set_relative_loads(&[("0".to_string(), 1.5)]).unwrap();
+        assert_eq!(ring.config_id, 1);
+        assert_eq!(ring.nodes[0].addr, "1");
+        assert_approx_eq!(ring.nodes[0].relative_load, 0.25);
+        assert_approx_eq!(ring.nodes[1].relative_load, 0.75);
+        assert!(ring.set_relative_loads(&[("2".to_string(), 1.0)]).is_err());
+    }
+
+    #[test]
+    fn test_remove_missing_node() {
+        let mut ring = Carp::new(vec![("0".to_string(), 1.0)], 0);
+        assert_eq!(
+            ring.remove_node("1"),
+            Err(CarpError::NodeNotFound("1".to_string()))
+        );
+        assert_eq!(ring.config_id, 0);
+    }
+
+    #[test]
+    fn test_deserializing_without_followers() {
+        let json = r#"{"version":1.0,"config_id":3,"list_ttl":600,"nodes":[{"addr":"0","relative_load":1.0}]}"#;
+        let ring: Carp = serde_json::from_str(json).unwrap();
+        assert_eq!(ring.config_id, 3);
+        assert!(ring.followers_map.is_empty());
+        assert_eq!(ring.get("foo"), "0");
+    }
+
+    #[test]
+    fn test_with_followers() {
+        let ring = Carp::with_followers(
+            vec![
+                ("0".to_string(), 0.8, vec!["2".to_string(), "3".to_string()]),
+                ("1".to_string(), 0.2, vec!["4".to_string(), "5".to_string()]),
+            ],
+            0,
+        );
+        assert_eq!(ring.get_followers("0").unwrap(), &vec!["2", "3"]);
+        assert_eq!(ring.get_followers("1").unwrap(), &vec!["4", "5"]);
+
+        let serialized = serde_json::to_string(&ring).unwrap();
+        let deserialized: Carp = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(ring.followers_map, deserialized.followers_map);
+    }
+
+    #[test]
+    fn test_add_node_followers_consistency() {
+        let mut ring = Carp::new(vec![("0".to_string(), 1.0)], 0);
+        assert_eq!(
+            ring.add_node("1".to_string(), 1.0, Some(vec!["2".to_string()])),
+            Err(CarpError::FollowersNotAllowed("1".to_string()))
+        );
+
+        let mut ring = Carp::with_followers(vec![("0".to_string(), 1.0, vec!["1".to_string()])], 0);
+        assert_eq!(
+            ring.add_node("2".to_string(), 1.0, None),
+            Err(CarpError::FollowersRequired("2".to_string()))
+        );
+        assert_eq!(
+            ring.add_node("0".to_string(), 1.0, Some(vec![])),
+            Err(CarpError::DuplicateNode("0".to_string()))
+        );
+        ring.add_node("2".to_string(), 1.0, Some(vec!["3".to_string()]))
+            .unwrap();
+        assert_eq!(ring.len(), 2);
+        assert_eq!(ring.config_id, 1);
+
+        ring.remove_node("2").unwrap();
+        assert!(ring.get_followers("2").is_none());
+    }
+
+    #[test]
+    fn test_proxy_leader() {
+        let mut ring = Carp::with_followers(
+            vec![
+                ("0".to_string(), 0.8, vec!["2".to_string(), "3".to_string()]),
+                ("1".to_string(), 0.2, vec!["4".to_string(), "5".to_string()]),
+            ],
+            0,
+        );
+        let target: String = rand::thread_rng()
+            .sample_iter::<char, _>(rand::distributions::Standard)
+            .take(50)
+            .collect();
+
+        let original = ring.get(&target).to_string();
+        let follower = ring.get_followers(&original).unwrap()[0].clone();
+        ring.set_new_proxy(&original, &follower).unwrap();
+        assert_eq!(ring.get(&target), follower);
+        assert_eq!(ring.get_original(&target), original);
+
+        ring.set_new_proxy(&original, &original).unwrap();
+        assert_eq!(ring.get(&target), original);
+        assert!(ring.set_new_proxy("6", "7").is_err());
+    }
+
+    #[test]
+    fn test_add_remove_follower() {
+        let mut ring = Carp::with_followers(vec![("0".to_string(), 1.0, vec!["1".to_string()])], 0);
+        ring.add_follower("0", "2".to_string()).unwrap();
+        assert_eq!(ring.shard_of("2"), Some("0"));
+        assert_eq!(ring.config_id, 1);
+        assert_eq!(
+            ring.add_follower("0", "1".to_string()),
+            Err(CarpError::DuplicateNode("1".to_string()))
+        );
+        assert_eq!(
+            ring.add_follower("3", "4".to_string()),
+            Err(CarpError::NodeNotFound("3".to_string()))
+        );
+
+        ring.set_new_proxy("0", "1").unwrap();
+        ring.remove_follower("0", "1").unwrap();
+        assert_eq!(ring.get_followers("0"), Some(&vec!["2".to_string()]));
+        assert_eq!(ring.get_proxy("0"), "0");
+        assert_eq!(ring.config_id, 2);
+        assert_eq!(
+            ring.remove_follower("0", "1"),
+            Err(CarpError::NodeNotFound("1".to_string()))
+        );
+    }
 }
@@ -1,134 +1,537 @@
+//! Starts the nodes of a cluster described by a [`Topology`] and keeps its hash ring up to date.
+//!
+//! Data directories are kept across restarts. On start, every shard is brought in line with the
+//! topology: a shard whose nodes stored no vote or membership yet is initialized on its first
+//! node. The nodes of a shard that was initialized before rejoin it with their stored state, and
+//! its membership is reconciled by adding the missing nodes and removing the ones that are no
+//! longer listed. The hash ring of the topology is then published to all nodes, and the
+//! client config is written for clients to find the cluster.
+//!
+//! The nodes run as tokio tasks of the manager, or, in [`DeployMode::MultiProcess`], as
+//! `raft-kv` processes supervised by a [`Supervisor`], so that they fail independently.
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::time::Duration;
+use std::time::Instant;
 
-use crate::raft_node::RaftNode;
-use crate::start_example_raft_node;
+use openraft::RaftMetrics;
+use serde::Deserialize;
+use tokio::sync::watch;
+use tokio::sync::watch::Sender;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+
+use crate::admin::shards;
+use crate::admin::wait_for_change;
+use crate::admin::Admin;
+use crate::admin::AdminError;
+use crate::auth::AuthConfig;
+use crate::backup;
+use crate::backup::BackupError;
+use crate::backup::Manifest;
+use crate::backup::RestoredShard;
 use crate::carp::Carp;
-use std::collections::HashMap;
+use crate::export;
+use crate::export::ExportManifest;
+use crate::export::ImportSummary;
+use crate::failure_detector::FailureDetector;
+use crate::failure_detector::FailureDetectorConfig;
+use crate::kvclient::KVClient;
+use crate::load_balancer::LoadBalancerConfig;
+use crate::load_balancer::LoadController;
+use crate::membership::LearnerProgress;
+use crate::namespace::Quota;
+use crate::network::error::ClientError;
+use crate::raft_node::RaftNode;
+use crate::start_raft_node;
+use crate::store::read_raft_state;
+use crate::supervisor::ProcessConfig;
+use crate::supervisor::Supervisor;
+use crate::topology::ShardSpec;
+use crate::topology::Topology;
+use crate::value::ValueConfig;
+use crate::Node;
+use crate::NodeConfig;
+use crate::NodeId;
 
-use std::fs;
-use std::error::Error;
+/// Time the nodes get to start answering requests.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
 
-use tokio::sync::watch::Sender;
-use tokio::task::JoinHandle;
-use tokio::sync::watch;
+/// Time a shard gets to elect a leader.
+const LEADER_TIMEOUT: Duration = Duration::from_secs(10);
 
-use toml;
-use serde::Deserialize;
-use serde_json;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 pub struct ClusterManager {
     shutdown_channels: Vec<Sender<()>>,
     pub handles: Vec<JoinHandle<()>>,
+    /// The hash ring published to all nodes. Kept up to date by the failure detector.
+    pub hash_ring: Arc<RwLock<Carp>>,
+    /// The node processes, in [`DeployMode::MultiProcess`].
+    pub supervisor: Option<Supervisor>,
+    /// Used to talk to the nodes.
+    transport: RaftNode,
+    /// Where the client config was written.
+    client_config: PathBuf,
+}
+
+/// How the nodes of a cluster are run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployMode {
+    /// Every node is a tokio task of the manager.
+    #[default]
+    InProcess,
+    /// Every node is a `raft-kv` process, see [`Supervisor`].
+    MultiProcess,
+}
+
+/// Config of a cluster, as read from `Config.toml`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClusterConfig {
+    /// The shards of the cluster, see [`Topology`].
+    pub shards: Vec<ShardSpec>,
+    /// File the client config is written to.
+    #[serde(default = "default_client_config")]
+    pub client_config: PathBuf,
+    #[serde(default)]
+    pub mode: DeployMode,
+    /// How the node processes are run, in [`DeployMode::MultiProcess`].
+    #[serde(default)]
+    pub processes: ProcessConfig,
+    #[serde(default)]
+    pub failure_detector: FailureDetectorConfig,
+    #[serde(default)]
+    pub load_balancer: LoadBalancerConfig,
+    /// Tokens accepted by all nodes. The first admin token is used by the manager itself.
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// Maximum size and compression of the values, on all nodes.
+    #[serde(default)]
+    pub values: ValueConfig,
 }
 
-#[derive(Deserialize)]
-struct Config {
-    num_clusters: usize,
-    nodes_per_cluster: usize,
+fn default_client_config() -> PathBuf {
+    PathBuf::from("cluster.json")
+}
+
+impl ClusterConfig {
+    /// A config for `topology`, with the defaults of the other settings.
+    pub fn new(topology: Topology) -> Self {
+        Self {
+            shards: topology.shards,
+            client_config: default_client_config(),
+            mode: DeployMode::default(),
+            processes: ProcessConfig::default(),
+            failure_detector: FailureDetectorConfig::default(),
+            load_balancer: LoadBalancerConfig::default(),
+            auth: AuthConfig::default(),
+            values: ValueConfig::default(),
+        }
+    }
+
+    pub fn topology(&self) -> Topology {
+        Topology {
+            shards: self.shards.clone(),
+        }
+    }
 }
 
 impl ClusterManager {
+    /// Start the cluster described by the config at `cluster_config_path`.
     pub async fn new(cluster_config_path: &str) -> Result<Self, Box<dyn Error>> {
-
         let config_contents = fs::read_to_string(cluster_config_path)?;
-        let config: Config = toml::from_str(&config_contents)?;
+        let config: ClusterConfig = toml::from_str(&config_contents)?;
+        Self::start(config).await
+    }
+
+    /// Start the nodes of the topology, bring the shards in line with it and publish its hash
+    /// ring.
+    pub async fn start(config: ClusterConfig) -> Result<Self, Box<dyn Error>> {
+        let topology = config.topology();
+        topology.validate()?;
+        println!("Number of shards: {}", topology.shards.len());
 
-        let num_clusters = config.num_clusters;
-        let nodes_per_cluster = config.nodes_per_cluster;
+        // Used to talk to the nodes.
+        let mut transport = RaftNode::new(0, String::new());
+        if let Some(token) = config.auth.admin_token() {
+            transport = transport.with_token(token);
+        }
 
-        println!("Number of clusters: {}", num_clusters);
-        println!("Nodes per cluster: {}", nodes_per_cluster);
+        // Shards that were initialized before rejoin with their stored membership.
+        let mut initialized = Vec::new();
+        for shard in &topology.shards {
+            let mut any = false;
+            for node in &shard.nodes {
+                any |= read_raft_state(&node.data_dir)?.is_initialized();
+            }
+            initialized.push(any);
+        }
 
         let mut handles = Vec::new();
         let mut shutdown_channels = Vec::new();
 
-        fn get_addr(node_id: u64, cluster_id: u64) -> String {
-            format!("127.0.0.1:{}", 31000 + cluster_id * 10 + node_id)
-        }
-        
-        fn get_rpc_addr(node_id: u64, cluster_id: u64) -> String {
-            format!("127.0.0.1:{}", 32000 + cluster_id * 10 + node_id)
-        }
+        // Start the nodes
+        let supervisor = match config.mode {
+            DeployMode::InProcess => {
+                for node in topology.shards.iter().flat_map(|shard| &shard.nodes) {
+                    fs::create_dir_all(&node.data_dir)?;
+                    let mut node_config = NodeConfig::new(
+                        node.id,
+                        node.data_dir.clone(),
+                        node.api_addr.clone(),
+                        node.rpc_addr.clone(),
+                    );
+                    node_config.http_listen_addr = node.listen_api_addr.clone();
+                    node_config.rpc_listen_addr = node.listen_rpc_addr.clone();
+                    node_config.auth = config.auth.clone();
+                    node_config.values = config.values;
 
-        let mut all_nodes = Vec::new();
-        let mut node_map = HashMap::new();
+                    let (shutdown_tx, shutdown_rx) = watch::channel(());
+                    shutdown_channels.push(shutdown_tx);
+                    handles.push(tokio::spawn(async move {
+                        let _ = start_raft_node(node_config, shutdown_rx).await;
+                    }));
+                }
+                None
+            }
+            DeployMode::MultiProcess => {
+                let supervisor =
+                    Supervisor::new(&config.processes, &topology, &config.auth, config.values)?;
+                println!(
+                    "=== node processes started, logs in {}",
+                    config.processes.log_dir.display()
+                );
+                Some(supervisor)
+            }
+        };
 
-        // Start clusters
-        for cluster_id in 1..=num_clusters as u64 {
-            let mut cluster_nodes = Vec::new();
-            let temp_dirs: Vec<_> = (1..=nodes_per_cluster)
-                .map(|_| tempfile::TempDir::new().unwrap())
-                .collect();
+        let all_nodes = topology.api_addrs();
+        for addr in all_nodes.iter().flatten() {
+            wait_until_up(&transport, addr).await?;
+        }
 
+        // Initialize or reconcile every shard, and point the ring at their leaders.
+        let mut leaders = Vec::new();
+        for (i, shard) in topology.shards.iter().enumerate() {
+            let leader = reconcile_shard(&transport, &shard.name(i), shard, initialized[i]).await?;
+            leaders.push(leader);
+        }
 
-            for node_id in 1..=nodes_per_cluster as u64 {
-                let temp_dir = temp_dirs[node_id as usize - 1].path().to_path_buf();
+        // Nodes that were running before keep the ring with the highest `config_id`.
+        let rings = futures::future::join_all(all_nodes.iter().flatten().map(|addr| {
+            let node = transport.with_same_transport(0, addr.clone());
+            async move { node.get_hash_ring().await }
+        }))
+        .await;
+        let config_id = rings.into_iter().flatten().map(|ring| ring.config_id).max();
+        let mut carp_ring = topology.hash_ring(config_id.map_or(0, |id| id + 1));
+        for (shard, leader) in topology.shards.iter().zip(&leaders) {
+            let first = &shard.first().api_addr;
+            if first != leader {
+                carp_ring.set_new_proxy(first, leader)?;
+            }
+        }
+        publish_hash_ring(&transport, &all_nodes, &carp_ring).await;
 
-                let addr = get_addr(node_id, cluster_id);            
-                let rpc_addr = get_rpc_addr(node_id, cluster_id);
-                let addr_clone = addr.clone();
+        topology.client_config().save(&config.client_config)?;
+        println!("=== client config written to {}", config.client_config.display());
 
-                let (shutdown_tx, shutdown_rx) = watch::channel(());
-                shutdown_channels.push(shutdown_tx);
+        // Watch the shard leaders and keep the ring up to date
+        let hash_ring = Arc::new(RwLock::new(carp_ring));
+        let detector =
+            FailureDetector::new(config.failure_detector, hash_ring.clone(), transport.clone());
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        shutdown_channels.push(shutdown_tx);
+        handles.push(tokio::spawn(detector.run(shutdown_rx)));
 
-                let handle = tokio::spawn(async move {
-                    let _ = start_example_raft_node(node_id, &temp_dir, addr_clone, rpc_addr, shutdown_rx).await;
-                });
-                handles.push(handle);
-                cluster_nodes.push(addr);
-            }
-            all_nodes.push(cluster_nodes);
-        }
-
-        // Wait for servers to start up.
-        tokio::time::sleep(Duration::from_millis(1_000)).await;
-
-        // Create a CARP ring with the initial leaders
-        let initial_load = 1.0 / num_clusters as f32;
-        let carp_ring = Carp::new(
-            all_nodes.iter().map(|cluster| {
-                let leader_addr = &cluster[0];
-                (leader_addr.clone(), initial_load)
-            }).collect(),
-            0,
-            // None
-        );
-
-        // Initialize each cluster
-        for (cluster_id, nodes) in all_nodes.iter().enumerate() {
-            let leader = RaftNode::new(1, nodes[0].clone());
-            println!("=== init cluster {} with leader at {}", cluster_id + 1, nodes[0]);
-            leader.init().await?;
-            for (node_id, node) in nodes.iter().enumerate().skip(1) {
-                println!("=== add node {} to cluster {}", node_id + 1, cluster_id + 1);
-                leader.add_learner((node_id as u64 + 1, node.clone(), get_rpc_addr(node_id as u64 + 1, cluster_id as u64 + 1))).await?;
-            }
-            println!("=== change-membership for cluster {}", cluster_id + 1);
-            leader.change_membership(&nodes.iter().enumerate().map(|(id, _)| id as u64 + 1).collect()).await?;
-            let _ = leader.update_hash_ring(carp_ring.clone()).await;
-            node_map.insert(nodes[0].clone(), leader);
+        // Even out the load of the shards by adjusting their weights in the ring
+        if config.load_balancer.enabled {
+            let controller =
+                LoadController::new(config.load_balancer, hash_ring.clone(), transport.clone());
+            let (shutdown_tx, shutdown_rx) = watch::channel(());
+            shutdown_channels.push(shutdown_tx);
+            handles.push(tokio::spawn(controller.run(shutdown_rx)));
         }
 
-        let serialized_all_nodes = serde_json::to_string(&all_nodes)?;
-        fs::write("all_nodes.json", serialized_all_nodes)?;
-    
         Ok(ClusterManager {
-            shutdown_channels: shutdown_channels,
-            handles: handles,
+            shutdown_channels,
+            handles,
+            hash_ring,
+            supervisor,
+            transport,
+            client_config: config.client_config.clone(),
         })
     }
 
-    pub async fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
+    /// Back up every shard to `dir`, from its leader, see [`crate::backup`].
+    pub async fn backup(&self, dir: &Path) -> Result<Manifest, AdminError> {
+        let seeds = self.hash_ring.read().await.nodes.iter().map(|node| node.addr.clone());
+        Admin::new(self.transport.clone(), seeds.collect())
+            .backup(dir)
+            .await
+    }
+
+    /// Export the keyspace of every shard to `dir`, see [`crate::export`].
+    pub async fn export(&self, dir: &Path) -> Result<ExportManifest, AdminError> {
+        let seeds = self.hash_ring.read().await.nodes.iter().map(|node| node.addr.clone());
+        Admin::new(self.transport.clone(), seeds.collect())
+            .export(dir)
+            .await
+    }
+
+    /// Import the export in `dir` into the cluster, with at most `concurrency` writes in flight,
+    /// see [`export::import`].
+    pub async fn import(
+        &self,
+        dir: &Path,
+        concurrency: usize,
+    ) -> Result<ImportSummary, Box<dyn Error>> {
+        let path = self.client_config.to_string_lossy();
+        let client = KVClient::with_transport(&path, self.transport.clone()).await?;
+        Ok(export::import(&client, dir, concurrency).await?)
+    }
+
+    /// Seed the data directories of the nodes of `config` with the backup in `dir`, before the
+    /// cluster is started on them, see [`backup::restore`].
+    ///
+    /// Every shard is restored up to the index `until` maps its original leader to, or up to
+    /// its last committed entry.
+    pub async fn restore(
+        config: &ClusterConfig,
+        dir: &Path,
+        until: &BTreeMap<String, u64>,
+    ) -> Result<Vec<RestoredShard>, BackupError> {
+        backup::restore(dir, &config.topology(), until).await
+    }
 
+    pub async fn shutdown(&mut self) -> Result<(), Box<dyn Error>> {
         // Signal shutdown to all nodes
         for shutdown_tx in &self.shutdown_channels {
-            shutdown_tx.send(()).unwrap();
+            let _ = shutdown_tx.send(());
         }
-        
+
         // Wait for all nodes to shutdown
         let handles = std::mem::take(&mut self.handles);
         let _ = futures::future::join_all(handles).await;
+        if let Some(supervisor) = self.supervisor.take() {
+            supervisor.shutdown().await;
+        }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Wait until the node at `addr` answers requests.
+pub(crate) async fn wait_until_up(transport: &RaftNode, addr: &str) -> Result<(), Box<dyn Error>> {
+    let node = transport.with_same_transport(0, addr.to_string());
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        match node.metrics().await {
+            Ok(_) => return Ok(()),
+            Err(e) if Instant::now() >= deadline => {
+                return Err(format!("node {} did not start: {}", addr, e).into());
+            }
+            Err(_) => tokio::time::sleep(POLL_INTERVAL).await,
+        }
+    }
+}
+
+/// Initialize `shard` unless it is `initialized`, i.e., one of its nodes stored a vote or a
+/// membership. Then add the nodes of the topology that are missing from its membership and
+/// remove the others.
+///
+/// Returns the API address of the leader of the shard.
+pub(crate) async fn reconcile_shard(
+    transport: &RaftNode,
+    name: &str,
+    shard: &ShardSpec,
+    initialized: bool,
+) -> Result<String, Box<dyn Error>> {
+    let first = shard.first();
+    if initialized {
+        println!("=== shard {} rejoined its stored membership, reconciling it", name);
+    } else {
+        println!("=== init shard {} on node {} at {}", name, first.id, first.api_addr);
+        transport
+            .with_same_transport(first.id, first.api_addr.clone())
+            .init()
+            .await?;
+    }
+
+    let (leader, metrics) = wait_for_leader(transport, name, shard).await?;
+    let membership = metrics.membership_config.membership().clone();
+    let voters: BTreeSet<NodeId> = membership.voter_ids().collect();
+    for node in &shard.nodes {
+        let wanted = Node {
+            api_addr: node.api_addr.clone(),
+            rpc_addr: node.rpc_addr.clone(),
+        };
+        match membership.get_node(&node.id) {
+            // The addresses of a member can't be changed, it has to be removed and added again.
+            Some(member) if *member != wanted => println!(
+                "=== warning: node {} of shard {} is a member as {}, not as in the topology",
+                node.id, name, member
+            ),
+            _ => {}
+        }
+        if voters.contains(&node.id) {
+            continue;
+        }
+        println!("=== add node {} to shard {}", node.id, name);
+        let req = (node.id, node.api_addr.clone(), node.rpc_addr.clone());
+        let on_progress = |learner: &LearnerProgress| {
+            println!("=== node {} is {} entries behind", learner.id, learner.lag)
+        };
+        wait_for_change(on_progress, || leader.add_node(req.clone())).await?;
+    }
+
+    let listed: BTreeSet<NodeId> = shard.nodes.iter().map(|node| node.id).collect();
+    for (id, _) in membership.nodes().filter(|(id, _)| !listed.contains(id)) {
+        println!("=== remove node {} from shard {}", id, name);
+        leader.remove_node(*id).await?;
+    }
+
+    // The leader may have changed while removing nodes.
+    let (_, metrics) = wait_for_leader(transport, name, shard).await?;
+    let leader_addr = metrics
+        .membership_config
+        .membership()
+        .get_node(&metrics.id)
+        .map(|node| node.api_addr.clone())
+        .unwrap_or_else(|| first.api_addr.clone());
+    Ok(leader_addr)
+}
+
+/// Wait until a node of `shard` is the leader. Returns a client for it and its metrics.
+async fn wait_for_leader(
+    transport: &RaftNode,
+    name: &str,
+    shard: &ShardSpec,
+) -> Result<(RaftNode, RaftMetrics<NodeId, Node>), Box<dyn Error>> {
+    let deadline = Instant::now() + LEADER_TIMEOUT;
+    loop {
+        for node in &shard.nodes {
+            let client = transport.with_same_transport(node.id, node.api_addr.clone());
+            if let Ok(metrics) = client.metrics().await {
+                if metrics.current_leader == Some(metrics.id) {
+                    return Ok((client, metrics));
+                }
+            }
+        }
+        if Instant::now() >= deadline {
+            return Err(format!("shard {} has no leader", name).into());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Send the hash ring to every node of every shard.
+///
+/// The ring is not replicated by Raft, so every node keeps its own copy. Nodes that can't be
+/// reached are skipped, they will receive the next published ring.
+pub(crate) async fn publish_hash_ring(transport: &RaftNode, shards: &[Vec<String>], ring: &Carp) {
+    let updates = shards.iter().flatten().map(|addr| async move {
+        let node = transport.with_same_transport(0, addr.clone());
+        if let Err(e) = node.update_hash_ring(ring.clone()).await {
+            tracing::debug!("failed to publish hash ring to {}: {}", addr, e);
+        }
+    });
+    futures::future::join_all(updates).await;
+}
+
+/// Like [`publish_hash_ring`], for a ring derived from the ring with `config_id` `expected`.
+///
+/// Nodes that already have a newer ring keep it, so that concurrent changes don't revert each
+/// other. Fails with the [`ClientError::Conflict`] of such a node: the change has to be made
+/// again on top of the newer ring.
+pub(crate) async fn publish_hash_ring_if(
+    transport: &RaftNode,
+    shards: &[Vec<String>],
+    ring: &Carp,
+    expected: u64,
+) -> Result<(), ClientError> {
+    let updates = shards.iter().flatten().map(|addr| async move {
+        let node = transport.with_same_transport(0, addr.clone());
+        match node.update_hash_ring_if(ring.clone(), expected).await {
+            Err(e @ ClientError::Conflict(_)) => Err(e),
+            Err(e) => {
+                tracing::debug!("failed to publish hash ring to {}: {}", addr, e);
+                Ok(())
+            }
+            Ok(()) => Ok(()),
+        }
+    });
+    futures::future::join_all(updates).await.into_iter().collect()
+}
+
+/// Split the quota of every namespace over the shards of `to` by their weights, see
+/// [`Quota::share`]. `to` is derived from `from`, whose shards hold the current shares.
+///
+/// Shards of `to` that don't have a namespace yet, e.g., new ones, are given it.
+pub(crate) async fn share_quotas(
+    transport: &RaftNode,
+    from: &Carp,
+    to: &Carp,
+) -> Result<(), ClientError> {
+    let client = |ring: &Carp, shard: &str| {
+        transport.with_same_transport(0, ring.get_proxy(shard).to_string())
+    };
+    let mut shares = BTreeMap::<String, Vec<Quota>>::new();
+    for node in &from.nodes {
+        for (name, namespace) in client(from, &node.addr).namespaces().await? {
+            shares.entry(name).or_default().push(namespace.quota);
+        }
+    }
+    let quotas: BTreeMap<String, Quota> = shares
+        .into_iter()
+        .map(|(name, shares)| (name, Quota::sum(shares)))
+        .collect();
+
+    let weights: Vec<f32> = to.nodes.iter().map(|node| node.relative_load).collect();
+    for (index, node) in to.nodes.iter().enumerate() {
+        let shard = client(to, &node.addr);
+        let namespaces = shard.namespaces().await?;
+        for (name, quota) in &quotas {
+            let share = quota.share(&weights, index);
+            match namespaces.get(name) {
+                Some(namespace) if namespace.quota == share => {}
+                Some(_) => shard.set_namespace_quota(name, share).await?,
+                None => shard.create_namespace(name, share).await?,
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fetch the hash ring from each of `addrs` and return the newest one. Nodes that can't be
+/// reached or that have no ring yet are skipped.
+pub(crate) async fn fetch_hash_ring(transport: &RaftNode, addrs: &[String]) -> Option<Carp> {
+    let rings = futures::future::join_all(addrs.iter().map(|addr| async move {
+        transport.with_same_transport(0, addr.clone()).get_hash_ring().await
+    }))
+    .await;
+    rings
+        .into_iter()
+        .flatten()
+        .filter(|ring| !ring.is_empty())
+        .max_by_key(|ring| ring.config_id)
+}
+
+/// Fetch the newest ring from the nodes of the ring in `hash_ring`, store it there and return it.
+/// The stored ring is kept if no node has a newer one.
+pub(crate) async fn refresh_hash_ring(transport: &RaftNode, hash_ring: &RwLock<Carp>) -> Carp {
+    let known = hash_ring.read().await.clone();
+    let addrs: Vec<String> = shards(&known).into_iter().flatten().collect();
+    let ring = match fetch_hash_ring(transport, &addrs).await {
+        Some(ring) if ring.config_id > known.config_id => ring,
+        _ => known,
+    };
+    *hash_ring.write().await = ring.clone();
+    ring
+}
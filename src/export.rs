@@ -0,0 +1,218 @@
+//! Consistent exports of the whole keyspace of a cluster, and imports into any cluster.
+//!
+//! The export of a shard is a snapshot of its state machine built by its leader right after a
+//! linearizable read, so it holds every write acknowledged before the export of the shard
+//! started. The export of a cluster is a directory with the [`ShardDump`] of every shard of the
+//! hash ring in `shard-<n>.json` and an [`ExportManifest`] in `export.json`, which records the
+//! ring, and so its `config_id`, the dumps were taken with. The export fails if the placement of
+//! the ring changed while it ran, since keys could then be missing from every dump.
+//!
+//! Unlike a restore from a backup, see [`crate::backup`], an import does not need the target
+//! cluster to have the shards of the exported one: keys are written through a [`KVClient`], so
+//! they are routed by the ring of the target cluster, whatever its number of shards.
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use openraft::error::Fatal;
+use openraft::LogId;
+use openraft::StorageIOError;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::app::App;
+use crate::bulk;
+use crate::bulk::BulkError;
+use crate::bulk::Record;
+use crate::carp::Carp;
+use crate::kvclient::KVClient;
+use crate::namespace::Quota;
+use crate::network::error::AppError;
+use crate::network::error::ClientError;
+use crate::store::StateMachineSnapshot;
+use crate::value::ValueError;
+use crate::NodeId;
+
+/// File of the [`ExportManifest`] in the directory of an export.
+pub const EXPORT_MANIFEST: &str = "export.json";
+
+/// Errors of exports and imports.
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("invalid export: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Client(#[from] ClientError),
+    #[error(transparent)]
+    Bulk(#[from] BulkError),
+    #[error("invalid export: {0}")]
+    Value(#[from] ValueError),
+    #[error("{} already holds an export", .0.display())]
+    NotEmpty(PathBuf),
+    #[error("the hash ring changed from config {before} to config {after} during the export")]
+    RingChanged { before: u32, after: u32 },
+}
+
+/// The state machine of a shard, as exported by its leader.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShardDump {
+    /// Id of the last log entry in the dump.
+    pub log_id: Option<LogId<NodeId>>,
+    pub state: StateMachineSnapshot,
+}
+
+impl ShardDump {
+    /// Read the dump in `path`.
+    pub fn load(path: &Path) -> Result<Self, ExportError> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Write the dump to `path`.
+    pub fn save(&self, path: &Path) -> Result<(), ExportError> {
+        fs::write(path, serde_json::to_vec(self)?)?;
+        Ok(())
+    }
+}
+
+/// Export of a shard, as listed in the [`ExportManifest`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShardExport {
+    /// Address of the original leader of the shard, which identifies it in the hash ring.
+    pub shard: String,
+    /// File of the [`ShardDump`], relative to the export.
+    pub path: PathBuf,
+    /// Id of the last log entry in the dump.
+    pub log_id: Option<LogId<NodeId>>,
+    /// Number of keys in the dump.
+    pub keys: usize,
+}
+
+/// What an export of a cluster holds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportManifest {
+    /// Seconds since the Unix epoch when the export was started.
+    pub created_at: u64,
+    /// The hash ring the shards were exported with.
+    pub ring: Carp,
+    pub shards: Vec<ShardExport>,
+}
+
+impl ExportManifest {
+    /// An empty manifest for an export of the shards of `ring` started now.
+    pub fn new(ring: Carp) -> Self {
+        let created_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        Self {
+            created_at,
+            ring,
+            shards: Vec::new(),
+        }
+    }
+
+    /// Read the manifest of the export in `dir`.
+    pub fn load(dir: &Path) -> Result<Self, ExportError> {
+        Ok(serde_json::from_slice(&fs::read(dir.join(EXPORT_MANIFEST))?)?)
+    }
+
+    /// Write the manifest to the export in `dir`.
+    pub fn save(&self, dir: &Path) -> Result<(), ExportError> {
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(EXPORT_MANIFEST), serde_json::to_vec_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+/// What an import wrote.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ImportSummary {
+    /// Number of keys written.
+    pub keys: usize,
+    /// Number of namespaces created.
+    pub namespaces: usize,
+    /// Number of keys left out because the ring of the export placed them on another shard than
+    /// the one that held them, see [`import`].
+    pub skipped: usize,
+}
+
+/// Dump the state machine of the node of `app`, which must be the leader of its shard, with
+/// every write committed before the call.
+pub(crate) async fn dump(app: &App) -> Result<ShardDump, AppError> {
+    let read_log_id = app.raft.ensure_linearizable().await?;
+    app.raft.trigger().snapshot().await?;
+    app.raft
+        .wait(Some(app.request_timeout))
+        .metrics(|m| m.snapshot >= read_log_id, "snapshot built")
+        .await?;
+
+    let snapshot = app.storage.snapshot().map_err(|e| read_snapshot_error(&e))?;
+    let Some(snapshot) = snapshot else {
+        return Ok(ShardDump::default());
+    };
+    let state =
+        StateMachineSnapshot::decode(&snapshot.data).map_err(|e| read_snapshot_error(&e))?;
+    Ok(ShardDump {
+        log_id: snapshot.meta.last_log_id,
+        state,
+    })
+}
+
+/// The snapshot of the node can't be read, which Raft can't recover from either.
+fn read_snapshot_error(e: &(impl std::error::Error + 'static)) -> AppError {
+    Fatal::StorageError(StorageIOError::read_snapshot(None, e).into()).into()
+}
+
+/// Write the export in `dir` through `client`, with at most `concurrency` writes in flight.
+///
+/// Namespaces are created without limits before the keys are written and get their exported
+/// quotas afterwards, as the keys of a namespace may be spread differently over the shards of
+/// the target cluster. They must not exist in the target cluster yet.
+///
+/// A shard can keep keys that the ring moved to another shard, until they are cleaned up. Only
+/// the keys the ring of the export places on the shard that held them are imported, so that an
+/// outdated copy never overwrites the current one.
+pub async fn import(
+    client: &KVClient,
+    dir: &Path,
+    concurrency: usize,
+) -> Result<ImportSummary, ExportError> {
+    let manifest = ExportManifest::load(dir)?;
+    let mut summary = ImportSummary::default();
+    let mut records = Vec::new();
+    let mut shares = BTreeMap::<String, Vec<Quota>>::new();
+    for shard in &manifest.shards {
+        let dump = ShardDump::load(&dir.join(&shard.path))?;
+        for (key, value) in dump.state.kvs {
+            if manifest.ring.get_original(&key) == shard.shard {
+                let value = value.decode()?;
+                records.push(Record { key, value });
+            } else {
+                summary.skipped += 1;
+            }
+        }
+        for (name, namespace) in dump.state.namespaces {
+            shares.entry(name).or_default().push(namespace.quota);
+        }
+    }
+
+    // Every shard held its share of the quota of the namespace.
+    let quotas: BTreeMap<String, Quota> = shares
+        .into_iter()
+        .map(|(name, shares)| (name, Quota::sum(shares)))
+        .collect();
+    for name in quotas.keys() {
+        client.create_namespace(name, Quota::default()).await?;
+    }
+    summary.keys = bulk::import(client, records, concurrency).await?;
+    for (name, quota) in &quotas {
+        client.set_namespace_quota(name, *quota).await?;
+    }
+    summary.namespaces = quotas.len();
+    Ok(summary)
+}
@@ -0,0 +1,278 @@
+//! Failure detector for the shard leaders of a managed deployment.
+//!
+//! The detector periodically probes every node of every shard through `/cluster/metrics`.
+//! When the leader of a shard changes, or the node currently serving a shard stops answering,
+//! the proxy of the shard in the hash ring is updated and the new ring is published to all
+//! nodes with a bumped `config_id`. Clients pick it up through `get_hash_ring`.
+//!
+//! The ring is also changed by [`crate::admin::Admin`]. Every round starts from the newest ring
+//! of the nodes, so that shards and nodes added since are probed, and the new ring is only
+//! accepted by nodes that did not get another change in between.
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde::Deserialize;
+use tokio::sync::watch;
+use tokio::sync::RwLock;
+
+use crate::admin::shards;
+use crate::carp::Carp;
+use crate::cluster_manager::publish_hash_ring_if;
+use crate::cluster_manager::refresh_hash_ring;
+use crate::raft_node::RaftNode;
+
+/// Thresholds used to decide whether a node is down.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct FailureDetectorConfig {
+    /// Time between two rounds of probes, in milliseconds.
+    pub probe_interval_ms: u64,
+    /// Time after which an unanswered probe counts as failed, in milliseconds.
+    pub probe_timeout_ms: u64,
+    /// Number of consecutive failed probes after which a node is considered down.
+    pub failure_threshold: u32,
+}
+
+impl Default for FailureDetectorConfig {
+    fn default() -> Self {
+        Self {
+            probe_interval_ms: 500,
+            probe_timeout_ms: 300,
+            failure_threshold: 3,
+        }
+    }
+}
+
+/// The state of a single node, as observed by the last probes.
+#[derive(Debug, Clone, Default)]
+struct NodeHealth {
+    consecutive_failures: u32,
+    /// Term and API address of the leader reported by the node in its last successful probe.
+    reported_leader: Option<(u64, String)>,
+}
+
+pub struct FailureDetector {
+    config: FailureDetectorConfig,
+    /// The newest ring the detector has seen.
+    hash_ring: Arc<RwLock<Carp>>,
+    health: HashMap<String, NodeHealth>,
+    /// Used to talk to the nodes.
+    transport: RaftNode,
+}
+
+impl FailureDetector {
+    pub fn new(
+        config: FailureDetectorConfig,
+        hash_ring: Arc<RwLock<Carp>>,
+        transport: RaftNode,
+    ) -> Self {
+        Self {
+            config,
+            hash_ring,
+            health: HashMap::new(),
+            transport,
+        }
+    }
+
+    /// Probe all shards until `shutdown_signal` fires.
+    pub async fn run(mut self, mut shutdown_signal: watch::Receiver<()>) {
+        let mut interval =
+            tokio::time::interval(Duration::from_millis(self.config.probe_interval_ms));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.probe_all().await,
+                _ = shutdown_signal.changed() => return,
+            }
+        }
+    }
+
+    /// Run a single round of probes and publish the ring if a proxy changed.
+    pub async fn probe_all(&mut self) {
+        let mut ring = refresh_hash_ring(&self.transport, &self.hash_ring).await;
+        let shards = shards(&ring);
+        let nodes: Vec<String> = shards.iter().flatten().cloned().collect();
+        // Forget the nodes that left the ring.
+        self.health.retain(|addr, _| nodes.contains(addr));
+        let probes = nodes.iter().map(|addr| self.probe(addr));
+        let results = futures::future::join_all(probes).await;
+
+        for (addr, reported_leader) in nodes.into_iter().zip(results) {
+            let health = self.health.entry(addr).or_default();
+            match reported_leader {
+                Some(leader) => {
+                    health.consecutive_failures = 0;
+                    health.reported_leader = leader;
+                }
+                None => {
+                    health.consecutive_failures += 1;
+                    health.reported_leader = None;
+                }
+            }
+        }
+
+        let read = ring.config_id;
+        let mut changed = false;
+        for shard in shards.iter() {
+            let original = &shard[0];
+            let proxy = ring.get_proxy(original).to_string();
+            if let Some(new_proxy) =
+                next_proxy(shard, &proxy, &self.health, self.config.failure_threshold)
+            {
+                tracing::info!(
+                    "shard {} is now served by {} instead of {}",
+                    original,
+                    new_proxy,
+                    proxy
+                );
+                if let Err(e) = ring.set_new_proxy(original, &new_proxy) {
+                    tracing::warn!("failed to update proxy of shard {}: {}", original, e);
+                    continue;
+                }
+                changed = true;
+            }
+        }
+        if !changed {
+            return;
+        }
+        ring.config_id += 1;
+
+        match publish_hash_ring_if(&self.transport, &shards, &ring, read).await {
+            Ok(()) => *self.hash_ring.write().await = ring,
+            // The next round starts from the newer ring.
+            Err(e) => tracing::info!("not publishing the proxies of the shards: {}", e),
+        }
+    }
+
+    /// Probe a node. Returns `None` if the node did not answer, otherwise the term and the API
+    /// address of the leader it knows about.
+    async fn probe(&self, addr: &str) -> Option<Option<(u64, String)>> {
+        let node = self.transport.with_same_transport(0, addr.to_string());
+        let timeout = Duration::from_millis(self.config.probe_timeout_ms);
+        let metrics = tokio::time::timeout(timeout, node.metrics()).await.ok()?.ok()?;
+        let leader = metrics.current_leader.and_then(|leader_id| {
+            metrics
+                .membership_config
+                .nodes()
+                .find(|(id, _)| **id == leader_id)
+                .map(|(_, node)| (metrics.current_term, node.api_addr.clone()))
+        });
+        Some(leader)
+    }
+}
+
+/// Decide which node should serve a shard, given the health of its nodes.
+///
+/// The leader reported in the highest term wins, if a majority of the nodes that answered the
+/// last probe agree on it: a deposed leader cut off from the others still reports itself, in
+/// an older term. Otherwise, if the current proxy is down, the first healthy node of the shard
+/// takes over until a leader is known again. Returns `None` if the proxy should not change.
+fn next_proxy(
+    shard: &[String],
+    proxy: &str,
+    health: &HashMap<String, NodeHealth>,
+    failure_threshold: u32,
+) -> Option<String> {
+    let is_up = |addr: &str| {
+        health
+            .get(addr)
+            .map(|h| h.consecutive_failures < failure_threshold)
+            .unwrap_or(true)
+    };
+
+    let answered: Vec<&NodeHealth> = shard
+        .iter()
+        .filter_map(|addr| health.get(addr))
+        .filter(|h| h.consecutive_failures == 0)
+        .collect();
+    let latest = answered
+        .iter()
+        .filter_map(|h| h.reported_leader.as_ref())
+        .max_by_key(|(term, _)| *term);
+    let reported_leader = latest.and_then(|latest| {
+        let votes = answered
+            .iter()
+            .filter(|h| h.reported_leader.as_ref() == Some(latest))
+            .count();
+        (votes * 2 > answered.len()).then(|| latest.1.clone())
+    });
+
+    match reported_leader {
+        Some(leader) if is_up(leader.as_str()) => (leader != proxy).then_some(leader),
+        _ if !is_up(proxy) => shard
+            .iter()
+            .find(|addr| addr.as_str() != proxy && is_up(addr.as_str()))
+            .cloned(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shard() -> Vec<String> {
+        vec!["a".to_string(), "b".to_string(), "c".to_string()]
+    }
+
+    /// Address of a node, its consecutive failures and the term and leader it reports.
+    type Probed<'a> = (&'a str, u32, Option<(u64, &'a str)>);
+
+    fn health(entries: &[Probed]) -> HashMap<String, NodeHealth> {
+        entries
+            .iter()
+            .map(|(addr, failures, leader)| {
+                (
+                    addr.to_string(),
+                    NodeHealth {
+                        consecutive_failures: *failures,
+                        reported_leader: leader.map(|(term, addr)| (term, addr.to_string())),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_healthy_leader_keeps_proxy() {
+        let leader = Some((1, "a"));
+        let h = health(&[("a", 0, leader), ("b", 0, leader), ("c", 0, leader)]);
+        assert_eq!(next_proxy(&shard(), "a", &h, 3), None);
+    }
+
+    #[test]
+    fn test_leader_change_updates_proxy() {
+        let h = health(&[("a", 1, None), ("b", 0, Some((2, "c"))), ("c", 0, Some((2, "c")))]);
+        assert_eq!(next_proxy(&shard(), "a", &h, 3), Some("c".to_string()));
+    }
+
+    #[test]
+    fn test_deposed_leader_is_ignored() {
+        // The old leader is cut off from the others, but still answers the probes.
+        let h = health(&[
+            ("a", 0, Some((1, "a"))),
+            ("b", 0, Some((2, "c"))),
+            ("c", 0, Some((2, "c"))),
+        ]);
+        assert_eq!(next_proxy(&shard(), "a", &h, 3), Some("c".to_string()));
+        assert_eq!(next_proxy(&shard(), "c", &h, 3), None);
+    }
+
+    #[test]
+    fn test_leader_without_majority_is_ignored() {
+        let h = health(&[("a", 0, Some((1, "a"))), ("b", 0, None), ("c", 0, Some((2, "c")))]);
+        assert_eq!(next_proxy(&shard(), "a", &h, 3), None);
+    }
+
+    #[test]
+    fn test_dead_proxy_without_leader_falls_back_to_follower() {
+        let h = health(&[("a", 3, None), ("b", 0, None), ("c", 0, None)]);
+        assert_eq!(next_proxy(&shard(), "a", &h, 3), Some("b".to_string()));
+    }
+
+    #[test]
+    fn test_proxy_below_threshold_is_kept() {
+        let h = health(&[("a", 2, None), ("b", 0, None), ("c", 0, None)]);
+        assert_eq!(next_proxy(&shard(), "a", &h, 3), None);
+    }
+}
@@ -1,79 +1,411 @@
 use crate::raft_node::RaftNode;
 use crate::store::Request;
 use crate::carp::Carp;
+use crate::namespace::NamespaceError;
+use crate::namespace::Quota;
+use crate::namespace::Usage;
+use crate::tls::TlsConfig;
+use crate::topology::ClientConfig;
+use crate::network::api::ScanRequest;
+use crate::network::error::ClientError;
+use crate::value::Bytes;
 use std::collections::HashMap;
 use std::error::Error;
+use std::future::Future;
 use tokio::sync::Mutex;
-use rand::prelude::IteratorRandom;
+use tokio::sync::RwLock;
 
 pub struct KVClient {
-    carp_ring: Carp,
+    carp_ring: RwLock<Carp>,
     node_map: Mutex<HashMap<String, RaftNode>>,
+    /// Used to create clients for nodes that are not in `node_map` yet.
+    transport: RaftNode,
 }
 
 impl KVClient {
     pub async fn new(nodes_config_path: &str) -> Result<Self, Box<dyn Error>> {
-        let (carp_ring, node_map) = Self::setup(nodes_config_path).await;
+        Self::with_transport(nodes_config_path, RaftNode::new(0, String::new())).await
+    }
+
+    /// Create a client for nodes that serve their API over TLS.
+    pub async fn with_tls(nodes_config_path: &str, tls: &TlsConfig) -> Result<Self, Box<dyn Error>> {
+        Self::with_transport(nodes_config_path, RaftNode::with_tls(0, String::new(), tls)?).await
+    }
+
+    /// Create a client that talks to the nodes like `transport` does, e.g., over TLS or with a
+    /// token from [`RaftNode::with_token`].
+    pub async fn with_transport(nodes_config_path: &str, transport: RaftNode) -> Result<Self, Box<dyn Error>> {
+        let (carp_ring, node_map) = Self::setup(nodes_config_path, &transport).await?;
         Ok(KVClient {
-            carp_ring,
+            carp_ring: RwLock::new(carp_ring),
             node_map: Mutex::new(node_map),
+            transport,
+        })
+    }
+
+    #[tracing::instrument(
+        name = "kv_client.write",
+        skip_all,
+        fields(otel.kind = "client", key = %String::from_utf8_lossy(key.as_ref()))
+    )]
+    pub async fn write(
+        &self,
+        key: impl AsRef<[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<(), ClientError> {
+        let req = Request::Set {
+            key: key.as_ref().into(),
+            value: Bytes::from(value.as_ref()).into(),
+        };
+        self.send_with_failover(key.as_ref(), |node| {
+            let req = req.clone();
+            async move { node.write(&req).await }
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Read the value of `key` from the node serving its shard, which may be stale. Returns
+    /// [`ClientError::NotFound`] if the key does not exist.
+    #[tracing::instrument(
+        name = "kv_client.read",
+        skip_all,
+        fields(otel.kind = "client", key = %String::from_utf8_lossy(key.as_ref()))
+    )]
+    pub async fn read(&self, key: impl AsRef<[u8]>) -> Result<Bytes, ClientError> {
+        let key = key.as_ref();
+        self.send_with_failover(key, |node| async move { node.read(key).await })
+            .await
+    }
+
+    /// Read the value of `key` from the leader of its shard. Returns [`ClientError::NotFound`]
+    /// if the key does not exist.
+    #[tracing::instrument(
+        name = "kv_client.consistent_read",
+        skip_all,
+        fields(otel.kind = "client", key = %String::from_utf8_lossy(key.as_ref()))
+    )]
+    pub async fn consistent_read(&self, key: impl AsRef<[u8]>) -> Result<Bytes, ClientError> {
+        let key = key.as_ref();
+        self.send_with_failover(key, |node| async move { node.consistent_read(key).await })
+            .await
+    }
+
+    #[tracing::instrument(
+        name = "kv_client.delete",
+        skip_all,
+        fields(otel.kind = "client", key = %String::from_utf8_lossy(key.as_ref()))
+    )]
+    pub async fn delete(&self, key: impl AsRef<[u8]>) -> Result<(), ClientError> {
+        let req = Request::Delete {
+            key: key.as_ref().into(),
+        };
+        self.send_with_failover(key.as_ref(), |node| {
+            let req = req.clone();
+            async move { node.write(&req).await }
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Set `key` to `value` if its value is `expected`, `None` meaning that it does not exist.
+    /// Returns whether the value was swapped.
+    ///
+    /// Unlike the other writes, the request is not sent again once it may have reached a node:
+    /// it may have been applied, and would fail when repeated. Such errors are returned as
+    /// [`ClientError::UnknownOutcome`], read the key to find out.
+    #[tracing::instrument(
+        name = "kv_client.compare_and_swap",
+        skip_all,
+        fields(otel.kind = "client", key = %String::from_utf8_lossy(key.as_ref()))
+    )]
+    pub async fn compare_and_swap(
+        &self,
+        key: impl AsRef<[u8]>,
+        expected: Option<&[u8]>,
+        value: impl AsRef<[u8]>,
+    ) -> Result<bool, ClientError> {
+        let req = Request::CompareAndSwap {
+            key: key.as_ref().into(),
+            expected: expected.map(Bytes::from),
+            value: Bytes::from(value.as_ref()).into(),
+        };
+        let res = self
+            .send_with_failover(key.as_ref(), |node| {
+                let req = req.clone();
+                async move {
+                    node.write(&req).await.map_err(|e| match e {
+                        ClientError::Network(e) => ClientError::UnknownOutcome(e),
+                        e => e,
+                    })
+                }
+            })
+            .await?;
+        // The node answers with the value as written.
+        let current = res
+            .data
+            .value
+            .map(|value| value.decode())
+            .transpose()
+            .map_err(|e| ClientError::Internal(e.to_string()))?;
+        Ok(current.as_deref() == expected)
+    }
+
+    /// Up to `limit` keys with `prefix` that follow `start_after`, in order, with their values.
+    ///
+    /// Every shard returns its first `limit` keys, of which the first `limit` are kept. Pass the
+    /// last returned key as `start_after` to continue the scan. Values may be stale, like
+    /// [`KVClient::read`] returns them.
+    pub async fn scan(
+        &self,
+        prefix: impl AsRef<[u8]>,
+        start_after: Option<&[u8]>,
+        limit: usize,
+    ) -> Result<Vec<(Bytes, Bytes)>, ClientError> {
+        let req = ScanRequest {
+            prefix: prefix.as_ref().into(),
+            start_after: start_after.map(Bytes::from),
+            limit,
+        };
+        let mut entries: Vec<(Bytes, Bytes)> = self
+            .send_to_all_shards(|node| {
+                let req = req.clone();
+                async move { node.scan(&req).await }
+            })
+            .await?
+            .into_iter()
+            .flatten()
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries.dedup_by(|a, b| a.0 == b.0);
+        entries.truncate(limit);
+        Ok(entries)
+    }
+
+    /// Create a namespace on every shard.
+    ///
+    /// Keys of a namespace are spread over all shards. `quota` applies to the whole cluster, every
+    /// shard enforces its share of it by its weight in the ring, see [`Quota::share`].
+    pub async fn create_namespace(&self, name: &str, quota: Quota) -> Result<(), ClientError> {
+        self.send_to_each_shard(|index, weights, node| {
+            let (name, quota) = (name.to_string(), quota.share(weights, index));
+            async move { node.create_namespace(&name, quota).await }
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Delete a namespace and all its keys on every shard.
+    pub async fn delete_namespace(&self, name: &str) -> Result<(), ClientError> {
+        self.send_to_all_shards(|node| {
+            let name = name.to_string();
+            async move { node.delete_namespace(&name).await }
         })
+        .await?;
+        Ok(())
     }
 
-    pub async fn write(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
-        let node_map = self.node_map.lock().await;
-        let responsible_node_addr = self.carp_ring.get(key);
-        if let Some(responsible_node) = node_map.get(responsible_node_addr) {
-            responsible_node.write(&Request::Set {
-                key: key.to_string(),
-                value: value.to_string(),
-            }).await?;
-            Ok(())
-        } else {
-            Err(Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "RaftNode not found")))
+    /// Replace the quota of a namespace, like [`Self::create_namespace`] sets it.
+    pub async fn set_namespace_quota(&self, name: &str, quota: Quota) -> Result<(), ClientError> {
+        self.send_to_each_shard(|index, weights, node| {
+            let (name, quota) = (name.to_string(), quota.share(weights, index));
+            async move { node.set_namespace_quota(&name, quota).await }
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Usage of a namespace, summed over all shards.
+    pub async fn namespace_usage(&self, name: &str) -> Result<Usage, ClientError> {
+        let namespaces = self
+            .send_to_all_shards(|node| async move { node.namespaces().await })
+            .await?;
+        let mut usage = Usage::default();
+        for namespace in namespaces {
+            let namespace = namespace.get(name).ok_or_else(|| {
+                ClientError::NotFound(NamespaceError::NotFound(name.to_string()).to_string())
+            })?;
+            usage.key_count += namespace.usage.key_count;
+            usage.byte_size += namespace.usage.byte_size;
+        }
+        Ok(usage)
+    }
+
+    /// Fetch the hash ring from the nodes and keep it if it is newer than the local one.
+    ///
+    /// The ring is published to the nodes whenever it changes, e.g., when a shard leader fails.
+    pub async fn refresh_hash_ring(&self) -> Result<(), ClientError> {
+        let nodes: Vec<RaftNode> = self.node_map.lock().await.values().cloned().collect();
+        let mut last_err = None;
+        for node in nodes {
+            match node.get_hash_ring().await {
+                Ok(ring) => {
+                    let mut local = self.carp_ring.write().await;
+                    if ring.config_id > local.config_id {
+                        *local = ring;
+                    }
+                    return Ok(());
+                }
+                Err(e) => last_err = Some(e),
+            }
         }
+        Err(last_err.unwrap_or_else(|| ClientError::Unreachable("RaftNode not found".to_string())))
     }
 
-    pub async fn read(&self, key: &str) -> Result<String, Box<dyn Error>> {
-        let node_map = self.node_map.lock().await;
-        let responsible_node_addr = self.carp_ring.get(key);
-        if let Some(responsible_node) = node_map.get(responsible_node_addr) {
-            let response = responsible_node.read(&key.to_string()).await?;
-            Ok(response)
-        } else {
-            Err(Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "RaftNode not found")))
+    /// Send a request to the cluster responsible for `key`.
+    ///
+    /// If the node answers that the key belongs to another shard, the local hash ring is
+    /// outdated. It is refreshed and the request is sent once more.
+    async fn send_with_failover<T, F, Fut>(&self, key: &[u8], send: F) -> Result<T, ClientError>
+    where
+        F: Fn(RaftNode) -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        let original = self.carp_ring.read().await.get_original(key).to_string();
+        match self.send_to_shard(original, &send).await {
+            Err(ClientError::WrongShard { .. }) => {
+                self.refresh_hash_ring().await?;
+                let original = self.carp_ring.read().await.get_original(key).to_string();
+                self.send_to_shard(original, &send).await
+            }
+            res => res,
         }
     }
 
-    pub async fn consistent_read(&self, key: &str) -> Result<String, Box<dyn Error>> {
-        let node_map = self.node_map.lock().await;
-        let responsible_node_addr = self.carp_ring.get(key);
-        if let Some(responsible_node) = node_map.get(responsible_node_addr) {
-            let response = responsible_node.consistent_read(&key.to_string()).await?;
-            Ok(response)
-        } else {
-            Err(Box::new(std::io::Error::new(std::io::ErrorKind::NotFound, "RaftNode not found")))
+    /// Send a request to every cluster of the ring, one after the other.
+    async fn send_to_all_shards<T, F, Fut>(&self, send: F) -> Result<Vec<T>, ClientError>
+    where
+        F: Fn(RaftNode) -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        self.send_to_each_shard(|_, _, node| send(node)).await
+    }
+
+    /// Like [`Self::send_to_all_shards`], with the index of the cluster in the ring and the
+    /// relative loads of all clusters passed to `send`.
+    async fn send_to_each_shard<T, F, Fut>(&self, send: F) -> Result<Vec<T>, ClientError>
+    where
+        F: Fn(usize, &[f32], RaftNode) -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        let (shards, weights): (Vec<String>, Vec<f32>) = {
+            let ring = self.carp_ring.read().await;
+            ring.nodes.iter().map(|node| (node.addr.clone(), node.relative_load)).unzip()
+        };
+        let mut results = Vec::with_capacity(shards.len());
+        for (index, original) in shards.iter().enumerate() {
+            let send = |node: RaftNode| send(index, &weights, node);
+            results.push(self.send_to_shard(original.clone(), send).await?);
         }
+        Ok(results)
     }
 
-    async fn setup(nodes_config_path: &str) -> (Carp, HashMap<String, RaftNode>) {
-        let data = std::fs::read_to_string(nodes_config_path).unwrap();
-        let all_nodes: Vec<Vec<String>> = serde_json::from_str(&data).unwrap();
-    
+    /// Send a request to the cluster whose original leader is `original`.
+    ///
+    /// If the node currently serving the cluster can't be reached, the followers of the cluster
+    /// are tried in order. The first follower that answers becomes the proxy of the cluster in
+    /// the local hash ring, so subsequent requests go to it directly.
+    async fn send_to_shard<T, F, Fut>(&self, original: String, send: F) -> Result<T, ClientError>
+    where
+        F: Fn(RaftNode) -> Fut,
+        Fut: Future<Output = Result<T, ClientError>>,
+    {
+        let mut candidates = {
+            let ring = self.carp_ring.read().await;
+            let proxy = ring.get_proxy(&original).to_string();
+            let mut candidates = vec![proxy.clone()];
+            candidates.extend(
+                std::iter::once(&original)
+                    .chain(ring.get_followers(&original).into_iter().flatten())
+                    .filter(|addr| **addr != proxy)
+                    .cloned(),
+            );
+            candidates
+        };
+
+        let mut last_err = None;
+        for (i, addr) in candidates.drain(..).enumerate() {
+            let node = {
+                let mut node_map = self.node_map.lock().await;
+                node_map
+                    .entry(addr.clone())
+                    .or_insert_with(|| self.transport.with_same_transport(1, addr.clone()))
+                    .clone()
+            };
+            match send(node).await {
+                Ok(res) => {
+                    if i > 0 {
+                        let mut ring = self.carp_ring.write().await;
+                        ring.set_new_proxy(&original, &addr)
+                            .map_err(|e| ClientError::Internal(e.to_string()))?;
+                    }
+                    return Ok(res);
+                }
+                Err(e) if e.is_node_failure() => last_err = Some(e),
+                Err(e) => return Err(e),
+            }
+        }
+
+        // Every known node of the shard is unreachable. The ring may be outdated, so fetch the
+        // latest one for the next request.
+        let _ = self.refresh_hash_ring().await;
+
+        Err(last_err.unwrap_or_else(|| ClientError::Unreachable("RaftNode not found".to_string())))
+    }
+
+    /// Create a client for every node of the client config, and fetch the hash ring from all of
+    /// them. The ring with the highest `config_id` is used, it only fails if no node has one.
+    async fn setup(
+        nodes_config_path: &str,
+        transport: &RaftNode,
+    ) -> Result<(Carp, HashMap<String, RaftNode>), ClientError> {
+        let all_nodes = ClientConfig::load(nodes_config_path)
+            .map_err(|e| ClientError::BadRequest(e.to_string()))?
+            .api_addrs();
+
         let mut node_map = HashMap::new();
-        for (_, nodes) in all_nodes.iter().enumerate() {
-            let leader = RaftNode::new(1, nodes[0].clone());
-            node_map.insert(nodes[0].clone(), leader);
+        for nodes in all_nodes.iter() {
+            for node in nodes {
+                node_map.insert(node.clone(), transport.with_same_transport(1, node.clone()));
+            }
         }
-    
-        let carp_ring: Carp;
-        let mut rng = rand::thread_rng();
-        if let Some((_, random_node)) = node_map.iter().choose(&mut rng) {
-            carp_ring = random_node.get_hash_ring().await.unwrap();
-        } else {
-            panic!("No nodes available in the node_map to get the Carp ring.");
+
+        let rings = futures::future::join_all(node_map.values().map(|node| node.get_hash_ring()));
+        let mut newest: Option<Carp> = None;
+        let mut last_err = None;
+        for ring in rings.await {
+            match ring {
+                // The node was not given a ring yet.
+                Ok(ring) if ring.is_empty() => {}
+                Ok(ring) if newest.as_ref().is_some_and(|n| n.config_id >= ring.config_id) => {}
+                Ok(ring) => newest = Some(ring),
+                Err(e) => last_err = Some(e),
+            }
         }
-    
-        (carp_ring, node_map)
+        let Some(carp_ring) = newest else {
+            let path = nodes_config_path;
+            return Err(last_err.unwrap_or_else(|| {
+                ClientError::Unavailable(format!("no node of {} has a hash ring", path))
+            }));
+        };
+
+        Ok((carp_ring, node_map))
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_setup_fails_without_nodes() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("cluster.json");
+        let path = path.to_str().unwrap();
+        assert!(KVClient::new(path).await.is_err());
+
+        // No node can be reached.
+        std::fs::write(path, r#"[["127.0.0.1:1"]]"#).unwrap();
+        assert!(KVClient::new(path).await.is_err());
+    }
+}
@@ -0,0 +1,249 @@
+//! Leadership transfer.
+//!
+//! openraft 0.9 can't hand over the leadership by itself, so the leader arranges an election
+//! that only the target can win:
+//!
+//! 1. It waits until the target has replicated its whole log.
+//! 2. It stops sending heartbeats and starting elections itself.
+//! 3. It tells the other voters to not start an election for a while.
+//! 4. Once the followers stopped accepting it as leader, it asks the target to start an election.
+//!
+//! Heartbeats and elections are enabled again on every node once the transfer completed or
+//! failed.
+//!
+//! [`drain`] builds on it to take a node down for maintenance without an election timeout.
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use openraft::error::CheckIsLeaderError;
+use openraft::error::ForwardToLeader;
+use openraft::RaftMetrics;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::app::App;
+use crate::network::error::AppError;
+use crate::network::rpc::METHOD_TRANSFER_LEADER;
+use crate::ExampleRaft;
+use crate::Node;
+use crate::NodeId;
+
+/// Timeout of the requests the leader sends to the other voters.
+const RPC_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Sent by the leader to the voters of its shard, see [`handle_transfer_leader`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransferLeaderRequest {
+    /// The node that should become the leader.
+    pub target: NodeId,
+    /// Time during which the other voters don't start an election.
+    pub pause_ms: u64,
+}
+
+/// Transfer the leadership of the shard from this node to the voter `target`.
+///
+/// Fails with `not_leader` if this node is not the leader, and with `timeout` if `target` did
+/// not catch up or win the election in time.
+pub async fn transfer_leader(app: &App, target: NodeId) -> Result<(), AppError> {
+    let metrics = leader_metrics(app)?;
+    let membership = metrics.membership_config.membership().clone();
+    if target == app.id {
+        return Ok(());
+    }
+
+    let voters: Vec<(NodeId, Node)> = membership
+        .voter_ids()
+        .filter(|id| *id != app.id)
+        .filter_map(|id| Some((id, membership.get_node(&id)?.clone())))
+        .collect();
+    if !voters.iter().any(|(id, _)| *id == target) {
+        return Err(AppError::BadRequest(format!(
+            "node {} is not a voter of this shard",
+            target
+        )));
+    }
+
+    let timeout = Duration::from_millis(app.config.election_timeout_max * 10);
+    // Only a node with the whole log can win the election.
+    app.raft
+        .wait(Some(timeout))
+        .metrics(
+            |m| {
+                let matched = m
+                    .replication
+                    .as_ref()
+                    .and_then(|replication| replication.get(&target))
+                    .and_then(|log_id| log_id.as_ref())
+                    .map(|log_id| log_id.index);
+                matched >= m.last_log_index
+            },
+            "target replicated the log",
+        )
+        .await?;
+
+    let _paused = Paused::new(&app.raft);
+    hand_over(app, target, &voters, timeout).await
+}
+
+/// Stops the heartbeats and elections of a node while it lives, so that they are enabled again
+/// even if the transfer is cancelled, e.g., because the request was dropped.
+struct Paused<'a>(&'a ExampleRaft);
+
+impl<'a> Paused<'a> {
+    fn new(raft: &'a ExampleRaft) -> Self {
+        raft.runtime_config().heartbeat(false);
+        raft.runtime_config().elect(false);
+        Self(raft)
+    }
+}
+
+impl Drop for Paused<'_> {
+    fn drop(&mut self) {
+        self.0.runtime_config().heartbeat(true);
+        self.0.runtime_config().elect(true);
+    }
+}
+
+/// The metrics of this node, if it is the leader. Otherwise a `not_leader` error that names the
+/// current leader, if known.
+pub(crate) fn leader_metrics(app: &App) -> Result<RaftMetrics<NodeId, Node>, AppError> {
+    let metrics = app.raft.metrics().borrow().clone();
+    match metrics.current_leader {
+        Some(id) if id == app.id => Ok(metrics),
+        leader => Err(forward_to(&metrics, leader)),
+    }
+}
+
+/// A `not_leader` error that names `leader`, as known to the membership in `metrics`.
+pub(crate) fn forward_to(metrics: &RaftMetrics<NodeId, Node>, leader: Option<NodeId>) -> AppError {
+    let membership = metrics.membership_config.membership();
+    let forward = match leader.and_then(|id| Some((id, membership.get_node(&id)?.clone()))) {
+        Some((id, node)) => ForwardToLeader::new(id, node),
+        None => ForwardToLeader::empty(),
+    };
+    CheckIsLeaderError::ForwardToLeader(forward).into()
+}
+
+async fn hand_over(
+    app: &App,
+    target: NodeId,
+    voters: &[(NodeId, Node)],
+    timeout: Duration,
+) -> Result<(), AppError> {
+    let req = TransferLeaderRequest {
+        target,
+        pause_ms: timeout.as_millis() as u64,
+    };
+
+    // The other voters must not compete with the target. A voter that can't be reached won't
+    // either.
+    let pauses = voters.iter().filter(|(id, _)| *id != target).map(|(id, node)| {
+        let req = &req;
+        async move {
+            let res: Result<(), _> = app
+                .network
+                .call(&node.rpc_addr, METHOD_TRANSFER_LEADER, req, RPC_TIMEOUT)
+                .await;
+            if let Err(e) = res {
+                tracing::warn!("failed to pause elections on node {}: {}", id, e);
+            }
+        }
+    });
+    futures::future::join_all(pauses).await;
+
+    // Followers reject votes while they consider the leader alive.
+    tokio::time::sleep(Duration::from_millis(app.config.election_timeout_max)).await;
+
+    let (_, target_node) = voters.iter().find(|(id, _)| *id == target).unwrap();
+    app.network
+        .call::<_, ()>(&target_node.rpc_addr, METHOD_TRANSFER_LEADER, &req, RPC_TIMEOUT)
+        .await
+        .map_err(|e| AppError::Unavailable(format!("failed to reach node {}: {}", target, e)))?;
+
+    app.raft
+        .wait(Some(timeout))
+        .metrics(|m| m.current_leader == Some(target), "target became leader")
+        .await?;
+    Ok(())
+}
+
+/// Handle a [`TransferLeaderRequest`] of the leader.
+///
+/// The target starts an election right away, the other voters don't start one for `pause_ms`.
+pub async fn handle_transfer_leader(app: &App, req: TransferLeaderRequest) -> Result<(), String> {
+    if req.target == app.id {
+        tracing::info!("starting an election to take over the leadership");
+        return app.raft.trigger().elect().await.map_err(|e| e.to_string());
+    }
+
+    app.raft.runtime_config().elect(false);
+    let raft = app.raft.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(req.pause_ms)).await;
+        raft.runtime_config().elect(true);
+    });
+    Ok(())
+}
+
+/// Prepare this node for maintenance and shut it down.
+///
+/// 1. If this node is the leader, the leadership moves to the most up-to-date voter.
+/// 2. The application API answers `unavailable`, so clients fail over to other nodes.
+/// 3. Once this node applied every entry of its log, i.e., nothing is left that only this node
+///    has, the node is shut down through its shutdown channel.
+///
+/// The node stays a member of its shard. Returns the leader of the shard.
+pub async fn drain(app: &App) -> Result<NodeId, AppError> {
+    let metrics = app.raft.metrics().borrow().clone();
+    if metrics.current_leader == Some(app.id) {
+        let target = metrics
+            .membership_config
+            .membership()
+            .voter_ids()
+            .filter(|id| *id != app.id)
+            .max_by_key(|id| {
+                metrics
+                    .replication
+                    .as_ref()
+                    .and_then(|replication| replication.get(id))
+                    .and_then(|log_id| log_id.as_ref())
+                    .map(|log_id| log_id.index)
+            });
+        let Some(target) = target else {
+            return Err(AppError::BadRequest(
+                "the only voter of a shard can't be drained".to_string(),
+            ));
+        };
+        tracing::info!("draining: transferring the leadership to node {}", target);
+        transfer_leader(app, target).await?;
+    }
+
+    tracing::info!("draining: rejecting client requests");
+    app.draining.store(true, Ordering::SeqCst);
+
+    let timeout = Duration::from_millis(app.config.election_timeout_max * 10);
+    let res = app
+        .raft
+        .wait(Some(timeout))
+        .metrics(
+            |m| {
+                let applied = m.last_applied.map(|log_id| log_id.index);
+                m.current_leader.is_some()
+                    && m.current_leader != Some(app.id)
+                    && applied >= m.last_log_index
+            },
+            "log applied",
+        )
+        .await;
+    let metrics = match res {
+        Ok(metrics) => metrics,
+        Err(e) => {
+            app.draining.store(false, Ordering::SeqCst);
+            return Err(e.into());
+        }
+    };
+
+    tracing::info!("draining: shutting down");
+    let _ = app.shutdown.send(());
+    Ok(metrics.current_leader.unwrap_or_default())
+}
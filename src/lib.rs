@@ -4,8 +4,20 @@
 use std::fmt::Display;
 use std::io::Cursor;
 use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
+use axum::extract::DefaultBodyLimit;
+use axum::extract::Request as HttpRequest;
+use axum::extract::State;
+use axum::middleware;
+use axum::middleware::Next;
+use axum::response::Response as HttpResponse;
+use axum::routing::get;
+use axum_server::tls_rustls::RustlsConfig;
 use openraft::Config;
 use tokio::net::TcpListener;
 use tokio::sync::RwLock;
@@ -13,21 +25,50 @@ use tokio::sync::watch;
 use tokio::task;
 
 use crate::app::App;
+use crate::auth::AuthConfig;
+use crate::auth::Authenticator;
 use crate::carp::Carp;
+use crate::metrics::Metrics;
 use crate::network::api;
+use crate::network::error::AppError;
 use crate::network::management;
+use crate::network::rpc::MAX_FRAME_LEN;
+use crate::network::sim::SimNetwork;
 use crate::network::Network;
-use crate::store::new_storage;
+use crate::store::engine::StorageBackend;
+use crate::store::open_storage;
 use crate::store::Request;
 use crate::store::Response;
+use crate::telemetry::Traced;
+use crate::tls::TlsConfig;
+use crate::tls::TlsContext;
+use crate::value::ValueConfig;
 
+pub mod admin;
 pub mod app;
+pub mod auth;
+pub mod backup;
+pub mod bulk;
 pub mod carp;
+pub mod export;
 pub mod raft_node;
 pub mod network;
 pub mod store;
 pub mod kvclient;
+pub mod leadership;
+pub mod linearizability;
+pub mod membership;
 pub mod cluster_manager;
+pub mod failure_detector;
+pub mod load_balancer;
+pub mod metrics;
+pub mod namespace;
+pub mod simulation;
+pub mod supervisor;
+pub mod telemetry;
+pub mod tls;
+pub mod topology;
+pub mod value;
 
 pub type NodeId = u64;
 
@@ -51,7 +92,7 @@ pub type SnapshotData = Cursor<Vec<u8>>;
 
 openraft::declare_raft_types!(
     pub TypeConfig:
-        D = Request,
+        D = Traced<Request>,
         R = Response,
         Node = Node,
 );
@@ -80,6 +121,53 @@ pub type ExampleRaft = openraft::Raft<TypeConfig>;
 
 type AppState = Arc<App>;
 
+/// Settings of a single Raft node.
+#[derive(Debug, Clone)]
+pub struct NodeConfig {
+    pub id: NodeId,
+    /// Directory of the node's storage.
+    pub dir: PathBuf,
+    /// Storage engine of the node. With [`StorageBackend::Memory`], `dir` is not used.
+    pub storage: StorageBackend,
+    /// Address of the HTTP API, as reached by clients and other nodes.
+    pub http_addr: String,
+    /// Address of the Raft RPC, as reached by other nodes.
+    pub rpc_addr: String,
+    /// Address the HTTP API binds to, if not `http_addr`, e.g., `0.0.0.0:<port>`.
+    pub http_listen_addr: Option<String>,
+    /// Address the Raft RPC binds to, if not `rpc_addr`.
+    pub rpc_listen_addr: Option<String>,
+    /// Serve the HTTP API and the Raft RPC over TLS, and dial other nodes over TLS.
+    pub tls: Option<TlsConfig>,
+    /// Tokens accepted by the HTTP API. Authentication is disabled if there are none.
+    pub auth: AuthConfig,
+    /// Time after which an HTTP request is answered with a `timeout` error.
+    pub request_timeout: Duration,
+    /// Maximum size and compression of the values written through the HTTP API.
+    pub values: ValueConfig,
+    /// Send the Raft RPCs through this simulated network instead of TCP, see [`SimNetwork`].
+    pub simulated_network: Option<SimNetwork>,
+}
+
+impl NodeConfig {
+    pub fn new(id: NodeId, dir: impl Into<PathBuf>, http_addr: String, rpc_addr: String) -> Self {
+        Self {
+            id,
+            dir: dir.into(),
+            storage: StorageBackend::default(),
+            http_addr,
+            rpc_addr,
+            http_listen_addr: None,
+            rpc_listen_addr: None,
+            tls: None,
+            auth: AuthConfig::default(),
+            request_timeout: Duration::from_secs(10),
+            values: ValueConfig::default(),
+            simulated_network: None,
+        }
+    }
+}
+
 pub async fn start_example_raft_node<P>(
     node_id: NodeId,
     dir: P,
@@ -90,58 +178,144 @@ pub async fn start_example_raft_node<P>(
 where
     P: AsRef<Path>,
 {
+    let config = NodeConfig::new(node_id, dir.as_ref(), http_addr, rpc_addr);
+    start_raft_node(config, shutdown_signal).await
+}
+
+pub async fn start_raft_node(
+    node_config: NodeConfig,
+    shutdown_signal: watch::Receiver<()>,
+) -> std::io::Result<()> {
+    let NodeConfig {
+        id: node_id,
+        dir,
+        storage,
+        http_addr,
+        rpc_addr,
+        http_listen_addr,
+        rpc_listen_addr,
+        tls,
+        auth,
+        request_timeout,
+        values,
+        simulated_network,
+    } = node_config;
+    let http_listen_addr = http_listen_addr.unwrap_or_else(|| http_addr.clone());
+    let rpc_listen_addr = rpc_listen_addr.unwrap_or_else(|| rpc_addr.clone());
+
+    let tls = match tls {
+        Some(tls) => Some(
+            TlsContext::load(tls)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+        ),
+        None => None,
+    };
+
     // Create a configuration for the raft instance.
     let config = Config {
         heartbeat_interval: 250,
         election_timeout_min: 299,
+        max_payload_entries: max_payload_entries(&values),
         ..Default::default()
     };
 
     let config = Arc::new(config.validate().unwrap());
 
-    let (log_store, state_machine_store) = new_storage(&dir).await;
+    let (log_store, state_machine_store) = open_storage(storage, &dir).await;
+    match log_store.raft_state() {
+        Ok(state) if state.is_initialized() => tracing::info!(
+            "rejoining the cluster with vote {:?}, last log id {:?} and membership {:?}",
+            state.vote,
+            state.last_log_id,
+            state.membership
+        ),
+        Ok(_) => tracing::info!("starting a node that is not part of a cluster yet"),
+        Err(e) => tracing::warn!("failed to read the stored raft state: {}", e),
+    }
 
+    let storage = log_store.monitor();
     let kvs = state_machine_store.data.kvs.clone();
+    let namespaces = state_machine_store.data.namespaces.clone();
+    let byte_size = state_machine_store.data.byte_size.clone();
 
     // Create the network layer that will connect and communicate the raft instances and
     // will be used in conjunction with the store created above.
-    let network = Network {};
+    let network = Network::new(node_id, tls.clone());
 
     // Create a local raft instance.
-    let raft = openraft::Raft::new(
-        node_id,
-        config.clone(),
-        network,
-        log_store,
-        state_machine_store,
-    )
-    .await
-    .unwrap();
+    let raft = match &simulated_network {
+        Some(sim) => {
+            let raft = openraft::Raft::new(
+                node_id,
+                config.clone(),
+                sim.node(node_id, rpc_addr.clone()),
+                log_store,
+                state_machine_store,
+            )
+            .await
+            .unwrap();
+            sim.register(rpc_addr.clone(), raft.clone());
+            raft
+        }
+        None => openraft::Raft::new(
+            node_id,
+            config.clone(),
+            network.clone(),
+            log_store,
+            state_machine_store,
+        )
+        .await
+        .unwrap(),
+    };
+
+    // The node shuts down on the signal of the caller, or when it was drained.
+    let (shutdown, shutdown_signal) = {
+        let (shutdown, internal_signal) = watch::channel(());
+        let mut shutdown_signal = shutdown_signal;
+        let forward = shutdown.clone();
+        task::spawn(async move {
+            let _ = shutdown_signal.changed().await;
+            let _ = forward.send(());
+        });
+        (shutdown, internal_signal)
+    };
 
     // Create a consistent hashing ring that can be retrieved by the clients for client based
     // routing.
     let hash_ring = Arc::new(RwLock::new(Carp::new(vec![(http_addr.clone(), 1.0)], 0)));
 
+    // Stopped once the servers are, so that the storage is released.
+    let raft_handle = raft.clone();
     let app_state = Arc::new(App {
         id: node_id,
         api_addr: http_addr.clone(),
         rpc_addr: rpc_addr.clone(),
         raft,
+        network,
         key_values: kvs,
+        namespaces,
+        byte_size,
         config,
         hash_ring,
+        load: Default::default(),
+        metrics: Metrics::new(),
+        storage,
+        auth: Authenticator::new(&auth),
+        values,
+        request_timeout,
+        draining: AtomicBool::new(false),
+        shutdown,
     });
 
-    let echo_service = Arc::new(network::raft::Raft::new(app_state.clone()));
+    let raft_service = Arc::new(network::raft::Raft::new(app_state.clone()));
 
-    let server = toy_rpc::Server::builder().register(echo_service).build();
-
-    let rpc_listener = TcpListener::bind(rpc_addr).await.unwrap();
+    let rpc_listener = TcpListener::bind(rpc_listen_addr).await.unwrap();
     let _ = task::spawn({
         let mut shutdown_signal_clone = shutdown_signal.clone();
+        let tls = tls.clone();
         async move {
             tokio::select! {
-                _ = server.accept_websocket(rpc_listener) => {},
+                _ = raft_service.serve(rpc_listener, tls) => {},
                 _ = shutdown_signal_clone.changed() => {},
             }
         }
@@ -149,21 +323,129 @@ where
 
     // Create an application that will store all the instances created above, this will
     // be later used on the axum handlers.
-    let app_listener = TcpListener::bind(http_addr).await.unwrap();
     let app = axum::Router::new()
-        .nest("/api", api::rest())
-        .nest("/cluster", management::rest())
+        .nest(
+            "/api",
+            api::rest()
+                .route_layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    auth::client,
+                ))
+                .route_layer(middleware::from_fn_with_state(
+                    app_state.clone(),
+                    reject_while_draining,
+                ))
+                .layer(DefaultBodyLimit::max(values.body_limit())),
+        )
+        .nest(
+            "/cluster",
+            management::rest().route_layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                auth::admin,
+            )),
+        )
+        .route(
+            "/metrics",
+            get(metrics::serve).route_layer(middleware::from_fn_with_state(
+                app_state.clone(),
+                auth::client,
+            )),
+        )
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            timeout,
+        ))
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            telemetry::http,
+        ))
+        // Outermost, so requests that time out are counted as well.
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            metrics::track,
+        ))
         .with_state(app_state);
 
-    axum::serve(app_listener, app)
-        .with_graceful_shutdown({
-            let mut shutdown_signal_clone = shutdown_signal.clone();
-            async move {
-                shutdown_signal_clone.changed().await.ok();
-            }
-        })
+    let Some(tls) = tls else {
+        let app_listener = TcpListener::bind(http_listen_addr).await.unwrap();
+        axum::serve(app_listener, app)
+            .with_graceful_shutdown({
+                let mut shutdown_signal_clone = shutdown_signal.clone();
+                async move {
+                    shutdown_signal_clone.changed().await.ok();
+                }
+            })
+            .await
+            .unwrap();
+        shutdown_raft(&raft_handle, simulated_network.as_ref(), &rpc_addr).await;
+        return Ok(());
+    };
+
+    // Serve the HTTP API over TLS and pick up renewed certificates.
+    let http_tls = RustlsConfig::from_config(tls.server_config());
+    task::spawn(tls.watch(Some(http_tls.clone()), shutdown_signal.clone()));
+
+    let handle = axum_server::Handle::new();
+    task::spawn({
+        let mut shutdown_signal_clone = shutdown_signal.clone();
+        let handle = handle.clone();
+        async move {
+            shutdown_signal_clone.changed().await.ok();
+            handle.graceful_shutdown(None);
+        }
+    });
+
+    let app_listener = std::net::TcpListener::bind(http_listen_addr)?;
+    let res = axum_server::from_tcp_rustls(app_listener, http_tls)
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await;
+    shutdown_raft(&raft_handle, simulated_network.as_ref(), &rpc_addr).await;
+    res
+}
+
+/// Number of log entries sent per `AppendEntries` RPC, lowered for large values so that the
+/// entries still fit well into an RPC frame.
+fn max_payload_entries(values: &ValueConfig) -> u64 {
+    let max = Config::default().max_payload_entries;
+    ((MAX_FRAME_LEN / 2 / values.max_size.max(1)) as u64).clamp(1, max)
+}
+
+/// Shut down `raft`, after disconnecting it from its simulated network, if any.
+async fn shutdown_raft(raft: &ExampleRaft, simulated_network: Option<&SimNetwork>, rpc_addr: &str) {
+    if let Some(sim) = simulated_network {
+        sim.unregister(rpc_addr);
+    }
+    if let Err(e) = raft.shutdown().await {
+        tracing::warn!("failed to shut down raft: {}", e);
+    }
+}
+
+/// Answer requests that take longer than the configured timeout with a `timeout` error.
+///
+/// The request is dropped, but a write that was already proposed to Raft may still be applied.
+async fn timeout(
+    State(state): State<AppState>,
+    req: HttpRequest,
+    next: Next,
+) -> Result<HttpResponse, AppError> {
+    tokio::time::timeout(state.request_timeout, next.run(req))
         .await
-        .unwrap();
+        .map_err(|_| AppError::Timeout(state.request_timeout))
+}
 
-    Ok(())
+/// Answer requests of the application API with `unavailable` while the node is drained, so
+/// clients fail over to the other nodes of the shard.
+async fn reject_while_draining(
+    State(state): State<AppState>,
+    req: HttpRequest,
+    next: Next,
+) -> Result<HttpResponse, AppError> {
+    if state.draining.load(Ordering::SeqCst) {
+        return Err(AppError::Unavailable(format!(
+            "node {} is draining",
+            state.id
+        )));
+    }
+    Ok(next.run(req).await)
 }
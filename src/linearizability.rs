@@ -0,0 +1,431 @@
+//! Linearizability checker for histories of register operations, for fault-injection tests.
+//!
+//! Clients record every operation they send with [`Recorder::invoke`], and its outcome with
+//! [`Invocation::complete`]. [`History::check`] then looks, for every key, for an order of the
+//! operations that respects their real-time order, i.e., an operation that completed before
+//! another one was invoked comes first, and the semantics of a register with compare-and-swap:
+//! a read returns the last value written, and a compare-and-swap swaps exactly when the register
+//! holds the expected value. Keys are independent registers, so they are checked one at a time.
+//!
+//! An operation whose request failed or timed out may or may not have taken effect, possibly
+//! long after the client gave up on it. It is recorded as [`Outcome::Unknown`], and the checker
+//! may place it anywhere after its invocation, or nowhere. Failed reads have no effect and are
+//! left out.
+//!
+//! The search is the algorithm of Wing and Gong, with the memoization of Lowe: it skips the
+//! sets of linearized operations that it already explored with the same register value.
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Operation {
+    Read,
+    Write {
+        value: String,
+    },
+    /// Set the register to `value` if it holds `expected`, `None` meaning that it is unset.
+    Cas {
+        expected: Option<String>,
+        value: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Outcome {
+    /// A read returned the value of the register, `None` if it is unset.
+    Value(Option<String>),
+    /// A write, or a compare-and-swap that swapped.
+    Ok,
+    /// A compare-and-swap found another value than the expected one, and had no effect.
+    Mismatch,
+    /// The request failed or timed out: it may or may not have taken effect.
+    Unknown,
+}
+
+/// An operation of a history. Times are in microseconds since the start of the recording.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The client that sent the operation. A client sends one operation at a time.
+    pub process: usize,
+    pub key: String,
+    pub op: Operation,
+    pub outcome: Outcome,
+    pub invoke: u64,
+    /// When the client got the outcome. An [`Outcome::Unknown`] operation may take effect later.
+    pub complete: u64,
+}
+
+/// Records the operations of concurrent clients, see the module documentation.
+#[derive(Clone)]
+pub struct Recorder {
+    start: Instant,
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl Default for Recorder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            events: Arc::default(),
+        }
+    }
+
+    /// Record that `process` sends `op` on `key`. The operation is added to the history once it
+    /// completes.
+    pub fn invoke(&self, process: usize, key: &str, op: Operation) -> Invocation {
+        Invocation {
+            recorder: self.clone(),
+            process,
+            key: key.to_string(),
+            op,
+            invoke: self.now(),
+        }
+    }
+
+    /// The operations completed so far, in the order of their invocation.
+    pub fn history(&self) -> History {
+        let mut events = self.events.lock().unwrap().clone();
+        events.sort_by_key(|event| event.invoke);
+        History { events }
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+}
+
+/// An operation that was sent, see [`Recorder::invoke`].
+pub struct Invocation {
+    recorder: Recorder,
+    process: usize,
+    key: String,
+    op: Operation,
+    invoke: u64,
+}
+
+impl Invocation {
+    pub fn complete(self, outcome: Outcome) {
+        let event = Event {
+            process: self.process,
+            key: self.key,
+            op: self.op,
+            outcome,
+            invoke: self.invoke,
+            complete: self.recorder.now(),
+        };
+        self.recorder.events.lock().unwrap().push(event);
+    }
+}
+
+/// The operations on a key can't be ordered consistently with their outcomes.
+#[derive(Debug, thiserror::Error)]
+#[error("the operations on key {key:?} are not linearizable")]
+pub struct Violation {
+    pub key: String,
+    /// The operations on the key, in the order of their invocation.
+    pub events: Vec<Event>,
+    /// The longest order of operations the search found before it got stuck, as indexes into
+    /// `events`.
+    pub longest: Vec<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct History {
+    pub events: Vec<Event>,
+}
+
+impl History {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Ok(serde_json::from_slice(&fs::read(path)?)?)
+    }
+
+    /// Write the history as JSON, e.g., to replay the check of a failing history.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_vec_pretty(self)?)
+    }
+
+    /// Check that the operations on every key are linearizable, see the module documentation.
+    pub fn check(&self) -> Result<(), Violation> {
+        let mut keys: BTreeMap<&str, Vec<&Event>> = BTreeMap::new();
+        for event in &self.events {
+            if event.op == Operation::Read && event.outcome == Outcome::Unknown {
+                continue;
+            }
+            keys.entry(&event.key).or_default().push(event);
+        }
+        for (key, mut events) in keys {
+            events.sort_by_key(|event| event.invoke);
+            let mut search = Search::new(&events);
+            if !search.run() {
+                let longest = search.longest;
+                return Err(Violation {
+                    key: key.to_string(),
+                    events: events.into_iter().cloned().collect(),
+                    longest,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The search for a linearization of the operations on one key, sorted by invocation.
+struct Search<'a> {
+    events: &'a [&'a Event],
+    /// When an operation must have taken effect, `u64::MAX` if its outcome is unknown.
+    deadlines: Vec<u64>,
+    /// Sets of linearized operations, with the resulting register value, already explored.
+    explored: HashSet<(Vec<u64>, Option<String>)>,
+    path: Vec<usize>,
+    longest: Vec<usize>,
+}
+
+impl<'a> Search<'a> {
+    fn new(events: &'a [&'a Event]) -> Self {
+        Self {
+            events,
+            deadlines: events
+                .iter()
+                .map(|event| match event.outcome {
+                    Outcome::Unknown => u64::MAX,
+                    _ => event.complete,
+                })
+                .collect(),
+            explored: HashSet::new(),
+            path: Vec::new(),
+            longest: Vec::new(),
+        }
+    }
+
+    fn run(&mut self) -> bool {
+        let mut linearized = vec![0; self.events.len().div_ceil(64)];
+        self.search(&mut linearized, &None)
+    }
+
+    fn search(&mut self, linearized: &mut [u64], state: &Option<String>) -> bool {
+        if self.path.len() == self.events.len() {
+            return true;
+        }
+        if self.path.len() > self.longest.len() {
+            self.longest = self.path.clone();
+        }
+        let is_linearized = |linearized: &[u64], i: usize| linearized[i / 64] & 1 << (i % 64) != 0;
+
+        // The next operation must have been invoked before every pending operation completed.
+        let horizon = (0..self.events.len())
+            .filter(|&i| !is_linearized(linearized, i))
+            .map(|i| self.deadlines[i])
+            .min()
+            .unwrap_or(u64::MAX);
+        for i in 0..self.events.len() {
+            let event = self.events[i];
+            if event.invoke > horizon {
+                break;
+            }
+            if is_linearized(linearized, i) {
+                continue;
+            }
+            let Some(next) = step(state, &event.op, &event.outcome) else {
+                continue;
+            };
+            linearized[i / 64] |= 1 << (i % 64);
+            if self.explored.insert((linearized.to_vec(), next.clone())) {
+                self.path.push(i);
+                if self.search(linearized, &next) {
+                    return true;
+                }
+                self.path.pop();
+            }
+            linearized[i / 64] &= !(1 << (i % 64));
+        }
+        false
+    }
+}
+
+/// The value of a register holding `state` after `op`, or `None` if `op` can't have `outcome`.
+fn step(state: &Option<String>, op: &Operation, outcome: &Outcome) -> Option<Option<String>> {
+    match (op, outcome) {
+        (Operation::Read, Outcome::Value(value)) => (value == state).then(|| state.clone()),
+        (Operation::Write { value }, Outcome::Ok | Outcome::Unknown) => Some(Some(value.clone())),
+        (Operation::Cas { expected, value }, Outcome::Ok) => {
+            (expected == state).then(|| Some(value.clone()))
+        }
+        (Operation::Cas { expected, .. }, Outcome::Mismatch) => {
+            (expected != state).then(|| state.clone())
+        }
+        (Operation::Cas { expected, value }, Outcome::Unknown) if expected == state => {
+            Some(Some(value.clone()))
+        }
+        (Operation::Cas { .. }, Outcome::Unknown) => Some(state.clone()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(process: usize, op: Operation, outcome: Outcome, invoke: u64, complete: u64) -> Event {
+        Event {
+            process,
+            key: "key".to_string(),
+            op,
+            outcome,
+            invoke,
+            complete,
+        }
+    }
+
+    fn read(value: Option<&str>) -> (Operation, Outcome) {
+        (Operation::Read, Outcome::Value(value.map(str::to_string)))
+    }
+
+    fn write(value: &str) -> Operation {
+        Operation::Write {
+            value: value.to_string(),
+        }
+    }
+
+    fn cas(expected: Option<&str>, value: &str) -> Operation {
+        Operation::Cas {
+            expected: expected.map(str::to_string),
+            value: value.to_string(),
+        }
+    }
+
+    fn check(events: Vec<Event>) -> Result<(), Violation> {
+        History { events }.check()
+    }
+
+    #[test]
+    fn test_sequential() {
+        let (r, unset) = read(None);
+        let (_, a) = read(Some("a"));
+        let (_, b) = read(Some("b"));
+        let history = vec![
+            event(0, r.clone(), unset, 0, 1),
+            event(0, write("a"), Outcome::Ok, 2, 3),
+            event(0, r.clone(), a.clone(), 4, 5),
+            event(0, cas(Some("b"), "c"), Outcome::Mismatch, 6, 7),
+            event(0, cas(Some("a"), "b"), Outcome::Ok, 8, 9),
+            event(0, r.clone(), b, 10, 11),
+        ];
+        check(history).unwrap();
+
+        // The read after the compare-and-swap still sees the old value.
+        let history = vec![
+            event(0, write("a"), Outcome::Ok, 0, 1),
+            event(0, cas(Some("a"), "b"), Outcome::Ok, 2, 3),
+            event(1, r, a, 4, 5),
+        ];
+        let violation = check(history).unwrap_err();
+        assert_eq!(violation.key, "key");
+        assert_eq!(violation.longest, vec![0, 1]);
+    }
+
+    #[test]
+    fn test_concurrent() {
+        let (r, a) = read(Some("a"));
+        let (_, b) = read(Some("b"));
+        // Both reads overlap the write of "b": the first may see it, and the second not, as long
+        // as they overlap each other too.
+        let history = vec![
+            event(0, write("a"), Outcome::Ok, 0, 1),
+            event(1, write("b"), Outcome::Ok, 2, 10),
+            event(2, r.clone(), b.clone(), 3, 6),
+            event(3, r.clone(), a.clone(), 4, 7),
+        ];
+        check(history).unwrap();
+
+        // Once a read saw "b", a later read can't see "a" anymore.
+        let history = vec![
+            event(0, write("a"), Outcome::Ok, 0, 1),
+            event(1, write("b"), Outcome::Ok, 2, 10),
+            event(2, r.clone(), b, 3, 4),
+            event(3, r, a, 5, 6),
+        ];
+        assert!(check(history).is_err());
+
+        // Two compare-and-swaps from the same value can't both swap.
+        let history = vec![
+            event(0, write("a"), Outcome::Ok, 0, 1),
+            event(1, cas(Some("a"), "b"), Outcome::Ok, 2, 5),
+            event(2, cas(Some("a"), "c"), Outcome::Ok, 3, 6),
+        ];
+        assert!(check(history).is_err());
+    }
+
+    #[test]
+    fn test_unknown() {
+        let (r, a) = read(Some("a"));
+        let (_, unset) = read(None);
+        // A failed write may take effect long after the client gave up on it, or never.
+        let history = vec![
+            event(0, write("a"), Outcome::Unknown, 0, 1),
+            event(1, r.clone(), unset.clone(), 2, 3),
+            event(1, r.clone(), a.clone(), 4, 5),
+        ];
+        check(history).unwrap();
+        let history = vec![
+            event(0, write("a"), Outcome::Unknown, 0, 1),
+            event(1, r.clone(), unset, 2, 3),
+        ];
+        check(history).unwrap();
+
+        // But not before it was sent.
+        let history = vec![
+            event(1, r.clone(), a.clone(), 0, 1),
+            event(0, write("a"), Outcome::Unknown, 2, 3),
+        ];
+        assert!(check(history).is_err());
+
+        // Failed reads are left out.
+        let history = vec![event(0, Operation::Read, Outcome::Unknown, 0, 1)];
+        check(history).unwrap();
+
+        // A failed compare-and-swap only swaps from the expected value.
+        let history = vec![
+            event(0, write("b"), Outcome::Ok, 0, 1),
+            event(0, cas(Some("c"), "a"), Outcome::Unknown, 2, 3),
+            event(1, r, a, 4, 5),
+        ];
+        assert!(check(history).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("histories").join("history.json");
+        let recorder = Recorder::new();
+        recorder.invoke(0, "key", write("a")).complete(Outcome::Ok);
+        recorder.invoke(1, "key", cas(None, "b")).complete(Outcome::Mismatch);
+        recorder.invoke(0, "key", Operation::Read).complete(Outcome::Unknown);
+
+        let history = recorder.history();
+        assert_eq!(history.events.len(), 3);
+        history.save(&path).unwrap();
+        let loaded = History::load(&path).unwrap();
+        assert_eq!(loaded.events, history.events);
+        loaded.check().unwrap();
+    }
+}
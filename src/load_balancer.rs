@@ -0,0 +1,558 @@
+//! Load-aware reweighting of the hash ring.
+//!
+//! Every node counts the requests it serves and reports them, together with the size of its
+//! state machine, through `/cluster/load`. The [`LoadController`] runs in the admin process,
+//! turns these reports into a request rate per shard and adjusts the `relative_load` of the
+//! shards so that the load evens out. A single key that takes a large share of the requests of a
+//! shard (a hot key) can't be moved by reweighting, so its excess load is ignored.
+//!
+//! The keys that change shard with the new weights are migrated, see [`migrate`]. A step that
+//! would move more than [`LoadBalancerConfig::max_moved_keys`] keys is skipped. While keys are
+//! moved, reads of them on the new shard may return a value that was just overwritten on the old
+//! one. The controller is disabled by default.
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::watch;
+use tokio::sync::RwLock;
+
+use crate::admin::shards;
+use crate::carp::Carp;
+use crate::cluster_manager::publish_hash_ring_if;
+use crate::cluster_manager::refresh_hash_ring;
+use crate::cluster_manager::share_quotas;
+use crate::network::error::ClientError;
+use crate::raft_node::RaftNode;
+use crate::store::Request;
+use crate::value::Bytes;
+use crate::value::Value;
+use crate::value::ValueError;
+
+/// Maximum number of distinct keys tracked per window.
+const MAX_TRACKED_KEYS: usize = 1024;
+
+/// Number of hot keys included in a load report.
+const NUM_HOT_KEYS: usize = 5;
+
+/// Load of a single node, as reported by `/cluster/load`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ShardLoad {
+    /// Number of client requests served since the node started.
+    pub request_count: u64,
+    /// Number of keys in the state machine.
+    pub key_count: u64,
+    /// Total size of the keys and values in the state machine, in bytes.
+    pub byte_size: u64,
+    /// The most requested keys in the current window, with their request counts. Only the
+    /// [`LoadController`] starts new windows, see [`RaftNode::take_load`].
+    pub hot_keys: Vec<(Bytes, u64)>,
+}
+
+/// Request of `/cluster/moved-keys`, sent to a shard before the ring changes from `from` to
+/// `to`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MovedKeysRequest {
+    pub from: Carp,
+    pub to: Carp,
+    /// Original leader of the shard, which identifies it in both rings.
+    pub shard: String,
+    /// Maximum number of keys returned.
+    pub limit: Option<usize>,
+}
+
+/// Answer of `/cluster/moved-keys`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MovedKeys {
+    /// Keys the shard serves under `from` but not under `to`, with their values as stored.
+    pub keys: Vec<(Bytes, Value)>,
+    /// Keys the shard holds without serving them under `from`, but serves under `to`. They were
+    /// left behind by an interrupted migration and would reappear.
+    pub leftovers: Vec<Bytes>,
+}
+
+/// Errors of a key migration.
+#[derive(Error, Debug)]
+pub enum MigrationError {
+    #[error(transparent)]
+    Client(#[from] ClientError),
+    #[error(transparent)]
+    Value(#[from] ValueError),
+    #[error("more than {0} keys would change shard")]
+    TooManyKeys(u64),
+}
+
+/// Request counters of a node.
+#[derive(Debug, Default)]
+pub struct LoadStats {
+    requests: AtomicU64,
+    /// Requests per key in the current window.
+    key_hits: Mutex<HashMap<Bytes, u64>>,
+}
+
+impl LoadStats {
+    /// Record a client request for `key`.
+    pub fn record(&self, key: &[u8]) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let mut key_hits = self.key_hits.lock().unwrap();
+        if let Some(hits) = key_hits.get_mut(key) {
+            *hits += 1;
+        } else if key_hits.len() < MAX_TRACKED_KEYS {
+            key_hits.insert(key.into(), 1);
+        }
+    }
+
+    /// Build a load report without the size of the state machine. The window of the hot keys
+    /// starts over if `new_window` is set.
+    pub fn report(&self, new_window: bool) -> ShardLoad {
+        let mut hot_keys: Vec<(Bytes, u64)> = {
+            let mut key_hits = self.key_hits.lock().unwrap();
+            match new_window {
+                true => std::mem::take(&mut *key_hits).into_iter().collect(),
+                false => key_hits.iter().map(|(k, hits)| (k.clone(), *hits)).collect(),
+            }
+        };
+        hot_keys.sort_by(|a, b| b.1.cmp(&a.1));
+        hot_keys.truncate(NUM_HOT_KEYS);
+
+        ShardLoad {
+            request_count: self.requests.load(Ordering::Relaxed),
+            hot_keys,
+            ..Default::default()
+        }
+    }
+}
+
+/// Settings of the [`LoadController`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoadBalancerConfig {
+    /// Whether the controller runs at all.
+    pub enabled: bool,
+    /// Time between two reweight steps, in milliseconds.
+    pub interval_ms: u64,
+    /// Lower bound of a shard's relative load, as a multiple of an even share.
+    pub min_weight_factor: f32,
+    /// Upper bound of a shard's relative load, as a multiple of an even share.
+    pub max_weight_factor: f32,
+    /// How strongly a step corrects an imbalance, between 0 and 1.
+    pub gain: f32,
+    /// Changes of the relative loads smaller than this are not published.
+    pub dead_band: f32,
+    /// Maximum number of keys a single step may move between shards.
+    pub max_moved_keys: u64,
+    /// Share of a shard's requests above which a single key is considered hot.
+    pub hot_key_fraction: f64,
+}
+
+impl Default for LoadBalancerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_ms: 10_000,
+            min_weight_factor: 0.5,
+            max_weight_factor: 2.0,
+            gain: 0.5,
+            dead_band: 0.01,
+            max_moved_keys: 10_000,
+            hot_key_fraction: 0.2,
+        }
+    }
+}
+
+/// Load of a shard during the last interval, aggregated over its nodes.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct ShardSample {
+    request_rate: f64,
+    key_count: u64,
+    byte_size: u64,
+}
+
+pub struct LoadController {
+    config: LoadBalancerConfig,
+    /// The last known ring, the newest one is fetched from its nodes before every step.
+    hash_ring: Arc<RwLock<Carp>>,
+    /// Request count of every shard at the previous step.
+    last_counts: HashMap<String, (u64, Instant)>,
+    /// Used to talk to the nodes.
+    transport: RaftNode,
+}
+
+impl LoadController {
+    pub fn new(
+        config: LoadBalancerConfig,
+        hash_ring: Arc<RwLock<Carp>>,
+        transport: RaftNode,
+    ) -> Self {
+        Self {
+            config,
+            hash_ring,
+            last_counts: HashMap::new(),
+            transport,
+        }
+    }
+
+    /// Reweight the ring periodically until `shutdown_signal` fires.
+    pub async fn run(mut self, mut shutdown_signal: watch::Receiver<()>) {
+        let mut interval = tokio::time::interval(Duration::from_millis(self.config.interval_ms));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.step().await,
+                _ = shutdown_signal.changed() => return,
+            }
+        }
+    }
+
+    /// Collect the load of all shards and, if needed, move the keys for new weights and publish
+    /// them.
+    pub async fn step(&mut self) {
+        let ring = refresh_hash_ring(&self.transport, &self.hash_ring).await;
+        let shards = shards(&ring);
+        let mut samples = Vec::with_capacity(shards.len());
+        for shard in shards.iter() {
+            let transport = &self.transport;
+            let reports = futures::future::join_all(shard.iter().map(|addr| async move {
+                transport.with_same_transport(0, addr.clone()).take_load().await.ok()
+            }))
+            .await;
+            let reports: Vec<ShardLoad> = reports.into_iter().flatten().collect();
+            if reports.is_empty() {
+                tracing::debug!("no load report for shard {}", shard[0]);
+                return;
+            }
+            samples.push((shard[0].clone(), self.sample(&shard[0], &reports)));
+        }
+        let samples: Vec<(String, ShardSample)> = samples
+            .into_iter()
+            .filter_map(|(addr, sample)| Some((addr, sample?)))
+            .collect();
+        if samples.len() != shards.len() {
+            // The first step only records the request counts.
+            return;
+        }
+
+        let current: Vec<(String, f32)> = ring
+            .nodes
+            .iter()
+            .map(|node| (node.addr.clone(), node.relative_load))
+            .collect();
+        let samples: Vec<ShardSample> = samples.into_iter().map(|(_, s)| s).collect();
+        let Some(weights) = compute_weights(&current, &samples, &self.config) else {
+            return;
+        };
+        let mut next = ring.clone();
+        if let Err(e) = next.set_relative_loads(&weights) {
+            tracing::warn!("failed to reweight hash ring: {}", e);
+            return;
+        }
+        tracing::info!("reweighting hash ring: {:?} -> {:?}", current, weights);
+        match migrate(&self.transport, &ring, &next, self.config.max_moved_keys).await {
+            Ok(moved) => {
+                tracing::info!("moved {} keys to their new shards", moved);
+                *self.hash_ring.write().await = next;
+            }
+            Err(e) => tracing::warn!("not reweighting hash ring: {}", e),
+        }
+    }
+
+    /// Turn the reports of the nodes of a shard into a sample. Returns `None` if there is no
+    /// previous request count to compute a rate from.
+    fn sample(&mut self, addr: &str, reports: &[ShardLoad]) -> Option<ShardSample> {
+        let now = Instant::now();
+        let request_count: u64 = reports.iter().map(|r| r.request_count).sum();
+        let previous = self.last_counts.insert(addr.to_string(), (request_count, now));
+        let (last_count, last_time) = previous?;
+
+        let elapsed = now.duration_since(last_time).as_secs_f64().max(f64::EPSILON);
+        let requests = request_count.saturating_sub(last_count) as f64;
+
+        // Requests to a single hot key can't be moved to another shard by reweighting.
+        let mut hot_keys: HashMap<&Bytes, u64> = HashMap::new();
+        for (key, hits) in reports.iter().flat_map(|r| r.hot_keys.iter()) {
+            *hot_keys.entry(key).or_default() += hits;
+        }
+        let threshold = requests * self.config.hot_key_fraction;
+        let mut excess = 0.0;
+        for (key, hits) in hot_keys {
+            let hits = hits as f64;
+            if hits > threshold {
+                tracing::warn!("hot key {:?} on shard {}: {} requests", key, addr, hits);
+                excess += hits - threshold;
+            }
+        }
+
+        Some(ShardSample {
+            request_rate: (requests - excess).max(0.0) / elapsed,
+            // Replicas hold the same data, so take the most up to date one.
+            key_count: reports.iter().map(|r| r.key_count).max().unwrap_or(0),
+            byte_size: reports.iter().map(|r| r.byte_size).max().unwrap_or(0),
+        })
+    }
+}
+
+/// Move the keys that change shard from `from` to `to` and publish `to`, which has the shards of
+/// `from` with other weights. Returns the number of moved keys.
+///
+/// The namespace quotas are split by the new weights and the keys are copied to their new shards
+/// first. `to` is only published if no more than `max_keys` keys move and the ring did not change
+/// since `from`, otherwise the quotas are split back. The old shards stop serving the keys once
+/// they have `to`, so the writes they accepted in between are then copied over, unless the key
+/// was written on its new shard since. Finally, the keys are deleted from the old shards.
+async fn migrate(
+    transport: &RaftNode,
+    from: &Carp,
+    to: &Carp,
+    max_keys: u64,
+) -> Result<u64, MigrationError> {
+    let client = |addr: &str| transport.with_same_transport(0, addr.to_string());
+    let new_shard = |key: &Bytes| client(to.get_proxy(to.get_original(key)));
+    let request = |shard: &str, limit: Option<u64>| MovedKeysRequest {
+        from: from.clone(),
+        to: to.clone(),
+        shard: shard.to_string(),
+        limit: limit.map(|limit| usize::try_from(limit).unwrap_or(usize::MAX)),
+    };
+
+    let mut copied = Vec::with_capacity(from.nodes.len());
+    let mut total = 0;
+    for shard in from.nodes.iter().map(|node| node.addr.as_str()) {
+        let source = client(from.get_proxy(shard));
+        let limit = max_keys.saturating_sub(total).saturating_add(1);
+        let moved = source.moved_keys(&request(shard, Some(limit))).await?;
+        total += moved.keys.len() as u64;
+        if total > max_keys {
+            return Err(MigrationError::TooManyKeys(max_keys));
+        }
+        for key in moved.leftovers {
+            source.migrate(&Request::Delete { key }).await?;
+        }
+        copied.push((shard, moved.keys));
+    }
+
+    // The new shards need their new quota shares to take the keys.
+    share_quotas(transport, from, to).await?;
+    let published = async {
+        for (key, value) in copied.iter().flat_map(|(_, keys)| keys) {
+            let set = Request::Set {
+                key: key.clone(),
+                value: value.clone(),
+            };
+            new_shard(key).migrate(&set).await?;
+        }
+        publish_hash_ring_if(transport, &shards(to), to, from.config_id).await
+    };
+    if let Err(e) = published.await {
+        if let Err(e) = share_quotas(transport, to, from).await {
+            tracing::warn!("failed to split the namespace quotas back: {}", e);
+        }
+        return Err(e.into());
+    }
+
+    for (shard, keys) in copied {
+        let source = client(from.get_proxy(shard));
+        let mut copies: HashMap<Bytes, Value> = keys.into_iter().collect();
+        let moved = source.moved_keys(&request(shard, None)).await?;
+        for (key, value) in &moved.keys {
+            let expected = match copies.remove(key) {
+                Some(copy) if copy == *value => continue,
+                Some(copy) => Some(copy.decode()?),
+                None => None,
+            };
+            // Keeps a value written on the new shard since the copy.
+            let cas = Request::CompareAndSwap {
+                key: key.clone(),
+                expected,
+                value: value.clone(),
+            };
+            new_shard(key).migrate(&cas).await?;
+        }
+        // Deleted from the old shard since the copy.
+        for (key, copy) in copies {
+            let target = new_shard(&key);
+            let current = match target.consistent_read(&key).await {
+                Ok(value) => value,
+                Err(ClientError::NotFound(_)) => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if current == copy.decode()? {
+                target.migrate(&Request::Delete { key }).await?;
+            }
+        }
+        for (key, _) in moved.keys {
+            source.migrate(&Request::Delete { key }).await?;
+        }
+    }
+    Ok(total)
+}
+
+/// Compute new relative loads from the current ones and the load of every shard.
+///
+/// Shards with more than an even share of the load lose weight and the others gain weight. The
+/// request rate is used if there were any requests, otherwise the data size. Returns `None` if
+/// the weights should not change.
+fn compute_weights(
+    current: &[(String, f32)],
+    samples: &[ShardSample],
+    config: &LoadBalancerConfig,
+) -> Option<Vec<(String, f32)>> {
+    let n = current.len();
+    if n < 2 {
+        return None;
+    }
+
+    let total_rate: f64 = samples.iter().map(|s| s.request_rate).sum();
+    let loads: Vec<f64> = if total_rate > 0.0 {
+        samples.iter().map(|s| s.request_rate).collect()
+    } else {
+        samples.iter().map(|s| s.byte_size as f64).collect()
+    };
+    let total_load: f64 = loads.iter().sum();
+    if total_load <= 0.0 {
+        return None;
+    }
+
+    let even_share = 1.0 / n as f32;
+    let min = even_share * config.min_weight_factor;
+    let max = even_share * config.max_weight_factor;
+
+    let mut weights: Vec<f32> = current
+        .iter()
+        .zip(loads.iter())
+        .map(|((_, weight), load)| {
+            // Avoid dividing by zero for idle shards; they get the largest correction.
+            let share = (*load / total_load).max(1e-6) as f32;
+            weight * (even_share / share).powf(config.gain)
+        })
+        .collect();
+    clamp_and_normalize(&mut weights, min, max);
+
+    // Under CARP, the share of keys that moves is the weight lost by the shrinking shards.
+    let moved_fraction: f32 = current
+        .iter()
+        .zip(weights.iter())
+        .map(|((_, old), new)| (old - new).max(0.0))
+        .sum();
+    let total_keys: u64 = samples.iter().map(|s| s.key_count).sum();
+    let moved_keys = moved_fraction as f64 * total_keys as f64;
+    if moved_keys > config.max_moved_keys as f64 {
+        let scale = (config.max_moved_keys as f64 / moved_keys) as f32;
+        for ((_, old), new) in current.iter().zip(weights.iter_mut()) {
+            *new = old + (*new - old) * scale;
+        }
+    }
+
+    let max_change = current
+        .iter()
+        .zip(weights.iter())
+        .map(|((_, old), new)| (old - new).abs())
+        .fold(0.0, f32::max);
+    if max_change < config.dead_band {
+        return None;
+    }
+
+    Some(
+        current
+            .iter()
+            .zip(weights)
+            .map(|((addr, _), weight)| (addr.clone(), weight))
+            .collect(),
+    )
+}
+
+/// Scale the weights to sum up to 1 while keeping each of them within `[min, max]`.
+fn clamp_and_normalize(weights: &mut [f32], min: f32, max: f32) {
+    for _ in 0..10 {
+        let total: f32 = weights.iter().sum();
+        for w in weights.iter_mut() {
+            *w = (*w / total).clamp(min, max);
+        }
+    }
+    let total: f32 = weights.iter().sum();
+    for w in weights.iter_mut() {
+        *w /= total;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn even(n: usize) -> Vec<(String, f32)> {
+        (0..n).map(|i| (i.to_string(), 1.0 / n as f32)).collect()
+    }
+
+    fn sample(request_rate: f64, key_count: u64) -> ShardSample {
+        ShardSample {
+            request_rate,
+            key_count,
+            byte_size: key_count * 10,
+        }
+    }
+
+    #[test]
+    fn test_balanced_load_keeps_weights() {
+        let samples = vec![sample(100.0, 10), sample(100.0, 10)];
+        assert_eq!(
+            compute_weights(&even(2), &samples, &LoadBalancerConfig::default()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_overloaded_shard_loses_weight() {
+        let samples = vec![sample(300.0, 10), sample(100.0, 10)];
+        let weights = compute_weights(&even(2), &samples, &LoadBalancerConfig::default()).unwrap();
+        assert!(weights[0].1 < 0.5);
+        assert!(weights[1].1 > 0.5);
+        assert!((weights[0].1 + weights[1].1 - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_weights_stay_within_bounds() {
+        let samples = vec![sample(10_000.0, 10), sample(1.0, 10), sample(1.0, 10)];
+        let config = LoadBalancerConfig {
+            gain: 1.0,
+            ..Default::default()
+        };
+        let weights = compute_weights(&even(3), &samples, &config).unwrap();
+        for (_, w) in weights.iter() {
+            assert!(*w >= 1.0 / 6.0 - 1e-5);
+            assert!(*w <= 2.0 / 3.0 + 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_moved_keys_are_capped() {
+        let samples = vec![sample(300.0, 50_000), sample(100.0, 50_000)];
+        let config = LoadBalancerConfig {
+            max_moved_keys: 1_000,
+            ..Default::default()
+        };
+        let weights = compute_weights(&even(2), &samples, &config).unwrap();
+        let moved = (0.5 - weights[0].1) * 100_000.0;
+        assert!(moved <= 1_000.0 + 1.0, "moved {} keys", moved);
+    }
+
+    #[test]
+    fn test_hot_keys_are_reported() {
+        let stats = LoadStats::default();
+        for _ in 0..3 {
+            stats.record(b"hot");
+        }
+        stats.record(b"cold");
+        let report = stats.report(false);
+        assert_eq!(report.request_count, 4);
+        assert_eq!(report.hot_keys[0], ("hot".into(), 3));
+        // Only a new window resets the hot keys.
+        assert_eq!(stats.report(true), report);
+        assert!(stats.report(false).hot_keys.is_empty());
+        assert_eq!(stats.report(false).request_count, 4);
+    }
+}
@@ -0,0 +1,355 @@
+//! Safe membership changes.
+//!
+//! `change-membership` replaces the voters of a shard with any set and `add-learner` blocks
+//! until the learner caught up. The operations here check that a change is safe before doing
+//! it, and make progress one step per call instead of blocking:
+//!
+//! - A node is only added if its RPC address can be reached.
+//! - A learner only becomes a voter once it is close to the leader's log. Until then the calls
+//!   return [`ChangeProgress::CatchingUp`] and have to be repeated.
+//! - A voter is only removed if enough of the remaining voters are up to date, and answered the
+//!   leader within the election timeout, to form a quorum.
+//! - The leader is never removed directly: the leadership first moves to a remaining voter, and
+//!   the request is forwarded to it.
+//!
+//! The state of a change lives in the membership of the shard, so an interrupted change resumes
+//! when the same call is made again. A joint configuration left behind by a leader that crashed
+//! in the middle of a change is completed by the next call.
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use openraft::ChangeMembers;
+use openraft::RaftMetrics;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::admin::replication_lag;
+use crate::app::App;
+use crate::leadership;
+use crate::network::error::AppError;
+use crate::Node;
+use crate::NodeId;
+
+/// A learner this many entries behind the leader, or closer, is considered caught up.
+pub const MAX_LEARNER_LAG: u64 = 100;
+
+/// Timeout of the connection attempt to a node being added.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where a membership change stands.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ChangeProgress {
+    /// The membership is the requested one.
+    Done,
+    /// Waiting for learners to catch up with the leader. Repeat the request to continue.
+    CatchingUp { learners: Vec<LearnerProgress> },
+}
+
+/// Replication progress of a node, as seen by the leader.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LearnerProgress {
+    pub id: NodeId,
+    /// Index of the last entry the node is known to have.
+    pub matched: Option<u64>,
+    /// Number of entries the node is behind the leader.
+    pub lag: u64,
+}
+
+/// Membership of a shard, as seen by its leader.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MembershipStatus {
+    pub leader: NodeId,
+    pub voters: BTreeSet<NodeId>,
+    /// Progress of the nodes that are not voters.
+    pub learners: Vec<LearnerProgress>,
+    /// Whether a change is in the middle of a joint configuration.
+    pub joint: bool,
+}
+
+/// The membership of the shard. Has to be called on the leader.
+pub fn status(app: &App) -> Result<MembershipStatus, AppError> {
+    let metrics = leadership::leader_metrics(app)?;
+    let membership = metrics.membership_config.membership();
+    let voters: BTreeSet<NodeId> = membership.voter_ids().collect();
+    let learners = membership
+        .nodes()
+        .map(|(id, _)| *id)
+        .filter(|id| !voters.contains(id))
+        .map(|id| progress(&metrics, id))
+        .collect();
+    Ok(MembershipStatus {
+        leader: app.id,
+        voters,
+        learners,
+        joint: membership.get_joint_config().len() > 1,
+    })
+}
+
+/// Add `id` to the voters of the shard. Has to be called on the leader.
+///
+/// The node is added as learner first and promoted once it caught up.
+pub async fn add_node(app: &App, id: NodeId, node: Node) -> Result<ChangeProgress, AppError> {
+    let metrics = resume(app).await?;
+    let membership = metrics.membership_config.membership();
+    let mut voters: BTreeSet<NodeId> = membership.voter_ids().collect();
+    if voters.contains(&id) {
+        return Ok(ChangeProgress::Done);
+    }
+    if let Some(progress) = catch_up(app, &metrics, id, node).await? {
+        return Ok(progress);
+    }
+
+    voters.insert(id);
+    tracing::info!("promoting node {} to voter", id);
+    app.raft.change_membership(voters, false).await?;
+    Ok(ChangeProgress::Done)
+}
+
+/// Replace the member `old` of the shard by `new`. Has to be called on the leader.
+///
+/// `new` is added as learner first and swapped in for `old` in a single change once it caught
+/// up, so the number of voters never drops.
+pub async fn replace_node(
+    app: &App,
+    old: NodeId,
+    new: NodeId,
+    node: Node,
+) -> Result<ChangeProgress, AppError> {
+    if old == new {
+        return Err(AppError::BadRequest("a node can't replace itself".to_string()));
+    }
+    let metrics = resume(app).await?;
+    let membership = metrics.membership_config.membership();
+    let mut voters: BTreeSet<NodeId> = membership.voter_ids().collect();
+    if membership.get_node(&old).is_none() {
+        if voters.contains(&new) {
+            return Ok(ChangeProgress::Done);
+        }
+        return Err(AppError::BadRequest(format!(
+            "node {} is not a member of this shard",
+            old
+        )));
+    }
+    if !voters.contains(&old) {
+        return Err(AppError::BadRequest(format!(
+            "node {} is a learner, remove it instead",
+            old
+        )));
+    }
+
+    if !voters.contains(&new) {
+        if let Some(progress) = catch_up(app, &metrics, new, node).await? {
+            return Ok(progress);
+        }
+    }
+
+    voters.remove(&old);
+    voters.insert(new);
+    check_quorum(app, &metrics, &voters, old)?;
+    if old == app.id {
+        return Err(step_down(app, &metrics, &voters).await?);
+    }
+
+    tracing::info!("replacing node {} by node {}", old, new);
+    app.raft.change_membership(voters, false).await?;
+    Ok(ChangeProgress::Done)
+}
+
+/// Remove `id` from the shard. Has to be called on the leader.
+///
+/// Fails with `conflict` if the remaining voters could not form a quorum.
+pub async fn remove_node(app: &App, id: NodeId) -> Result<ChangeProgress, AppError> {
+    let metrics = resume(app).await?;
+    let membership = metrics.membership_config.membership();
+    let mut voters: BTreeSet<NodeId> = membership.voter_ids().collect();
+    if membership.get_node(&id).is_none() {
+        return Ok(ChangeProgress::Done);
+    }
+    if !voters.contains(&id) {
+        tracing::info!("removing learner {}", id);
+        app.raft
+            .change_membership(ChangeMembers::RemoveNodes(BTreeSet::from([id])), false)
+            .await?;
+        return Ok(ChangeProgress::Done);
+    }
+
+    voters.remove(&id);
+    if voters.is_empty() {
+        return Err(AppError::BadRequest(
+            "the only voter of a shard can't be removed".to_string(),
+        ));
+    }
+    check_quorum(app, &metrics, &voters, id)?;
+    if id == app.id {
+        return Err(step_down(app, &metrics, &voters).await?);
+    }
+
+    tracing::info!("removing voter {}", id);
+    app.raft.change_membership(voters, false).await?;
+    Ok(ChangeProgress::Done)
+}
+
+/// The metrics of the leader, once a change interrupted in a joint configuration is completed.
+async fn resume(app: &App) -> Result<RaftMetrics<NodeId, Node>, AppError> {
+    let metrics = leadership::leader_metrics(app)?;
+    let configs = metrics.membership_config.membership().get_joint_config().clone();
+    if configs.len() <= 1 {
+        return Ok(metrics);
+    }
+
+    let goal = configs.last().cloned().unwrap_or_default();
+    tracing::info!("resuming the interrupted change to voters {:?}", goal);
+    app.raft.change_membership(goal, false).await?;
+    leadership::leader_metrics(app)
+}
+
+/// Make sure `id` is a learner and report its progress while it is not caught up.
+///
+/// Returns `None` once `id` is close enough to the leader's log to be promoted.
+async fn catch_up(
+    app: &App,
+    metrics: &RaftMetrics<NodeId, Node>,
+    id: NodeId,
+    node: Node,
+) -> Result<Option<ChangeProgress>, AppError> {
+    if metrics.membership_config.membership().get_node(&id).is_none() {
+        app.network
+            .probe(&node.rpc_addr, PROBE_TIMEOUT)
+            .await
+            .map_err(|e| {
+                AppError::BadRequest(format!(
+                    "node {} can't be reached at {}: {}",
+                    id, node.rpc_addr, e
+                ))
+            })?;
+        tracing::info!("adding node {} as learner", id);
+        app.raft.add_learner(id, node, false).await?;
+    }
+
+    let metrics = app.raft.metrics().borrow().clone();
+    let progress = progress(&metrics, id);
+    if progress.lag > MAX_LEARNER_LAG {
+        return Ok(Some(ChangeProgress::CatchingUp {
+            learners: vec![progress],
+        }));
+    }
+    Ok(None)
+}
+
+/// Fail with `conflict` unless a majority of `voters` is up to date and answered the leader
+/// recently.
+///
+/// A voter that stopped answering keeps its replication progress, so being up to date alone
+/// says nothing about whether it can still vote.
+fn check_quorum(
+    app: &App,
+    metrics: &RaftMetrics<NodeId, Node>,
+    voters: &BTreeSet<NodeId>,
+    removed: NodeId,
+) -> Result<(), AppError> {
+    let healthy = voters
+        .iter()
+        .filter(|id| {
+            **id == app.id
+                || (progress(metrics, **id).lag <= MAX_LEARNER_LAG
+                    && answered_recently(app, metrics, **id))
+        })
+        .count();
+    if !is_quorum(healthy, voters.len()) {
+        return Err(AppError::Conflict(format!(
+            "removing node {} leaves {} of {} voters up to date and reachable, a quorum needs {}",
+            removed,
+            healthy,
+            voters.len(),
+            voters.len() / 2 + 1
+        )));
+    }
+    Ok(())
+}
+
+/// Whether `id` answered a Raft RPC of this node, such as a heartbeat, within the election
+/// timeout. The timeout is counted from the last heartbeat sent, which may be up to a heartbeat
+/// interval old.
+fn answered_recently(app: &App, metrics: &RaftMetrics<NodeId, Node>, id: NodeId) -> bool {
+    let Some(node) = metrics.membership_config.membership().get_node(&id) else {
+        return false;
+    };
+    let timeout = app.config.election_timeout_max + app.config.heartbeat_interval;
+    let timeout = Duration::from_millis(timeout);
+    app.network
+        .last_reply(&node.rpc_addr)
+        .is_some_and(|at| at.elapsed() <= timeout)
+}
+
+/// Move the leadership to the most up-to-date of `voters`, before this node is removed.
+///
+/// Returns the `not_leader` error that sends the caller to the new leader.
+async fn step_down(
+    app: &App,
+    metrics: &RaftMetrics<NodeId, Node>,
+    voters: &BTreeSet<NodeId>,
+) -> Result<AppError, AppError> {
+    let target = voters
+        .iter()
+        .copied()
+        .max_by_key(|id| progress(metrics, *id).matched)
+        .unwrap_or_default();
+    tracing::info!("transferring the leadership to node {} before leaving", target);
+    leadership::transfer_leader(app, target).await?;
+    Ok(leadership::forward_to(metrics, Some(target)))
+}
+
+/// Replication progress of `id` according to the leader's `metrics`.
+fn progress(metrics: &RaftMetrics<NodeId, Node>, id: NodeId) -> LearnerProgress {
+    let matched = metrics
+        .replication
+        .as_ref()
+        .and_then(|replication| replication.get(&id))
+        .and_then(|log_id| log_id.as_ref());
+    LearnerProgress {
+        id,
+        matched: matched.map(|log_id| log_id.index),
+        lag: replication_lag(metrics.last_log_index, matched),
+    }
+}
+
+/// Whether `healthy` of `voters` nodes form a quorum.
+fn is_quorum(healthy: usize, voters: usize) -> bool {
+    healthy > voters / 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_quorum() {
+        assert!(is_quorum(1, 1));
+        assert!(!is_quorum(1, 2));
+        assert!(is_quorum(2, 2));
+        assert!(is_quorum(2, 3));
+        assert!(!is_quorum(2, 4));
+        assert!(is_quorum(3, 5));
+    }
+
+    #[test]
+    fn test_change_progress_json() {
+        let progress = ChangeProgress::CatchingUp {
+            learners: vec![LearnerProgress {
+                id: 4,
+                matched: Some(3),
+                lag: 7,
+            }],
+        };
+        let json = serde_json::to_string(&progress).unwrap();
+        assert_eq!(
+            json,
+            r#"{"state":"catching_up","learners":[{"id":4,"matched":3,"lag":7}]}"#
+        );
+        assert_eq!(
+            serde_json::to_string(&ChangeProgress::Done).unwrap(),
+            r#"{"state":"done"}"#
+        );
+    }
+}
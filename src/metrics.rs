@@ -0,0 +1,327 @@
+//! Prometheus metrics of a node, served at `/metrics` in the Prometheus text format.
+//!
+//! Request counts and latencies are recorded by the [`track`] middleware. Everything else,
+//! i.e., the Raft state, the storage statistics and the hash ring, is read when the endpoint
+//! is scraped.
+//!
+//! Every node has its own registry, because the cluster manager runs many nodes in a single
+//! process.
+use std::time::Instant;
+
+use axum::extract::MatchedPath;
+use axum::extract::Request;
+use axum::extract::State;
+use axum::http::header::CONTENT_TYPE;
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::IntoResponse;
+use axum::response::Response;
+use prometheus::Encoder;
+use prometheus::GaugeVec;
+use prometheus::HistogramOpts;
+use prometheus::HistogramVec;
+use prometheus::IntCounterVec;
+use prometheus::IntGauge;
+use prometheus::IntGaugeVec;
+use prometheus::Opts;
+use prometheus::Registry;
+use prometheus::TextEncoder;
+
+use crate::admin::replication_lag;
+use crate::store::COLUMN_FAMILIES;
+use crate::AppState;
+
+/// RocksDB properties exported for every column family.
+const ROCKSDB_PROPERTIES: [&str; 6] = [
+    "rocksdb.estimate-num-keys",
+    "rocksdb.estimate-live-data-size",
+    "rocksdb.total-sst-files-size",
+    "rocksdb.cur-size-all-mem-tables",
+    "rocksdb.estimate-pending-compaction-bytes",
+    "rocksdb.num-running-compactions",
+];
+
+/// Upper bounds of the request latency buckets, in seconds. From 0.5ms to about 16s.
+fn latency_buckets() -> Vec<f64> {
+    prometheus::exponential_buckets(0.0005, 2.0, 16).unwrap()
+}
+
+/// The metrics of a node.
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    current_term: IntGauge,
+    current_leader: IntGauge,
+    is_leader: IntGauge,
+    last_log_index: IntGauge,
+    committed_index: IntGauge,
+    last_applied_index: IntGauge,
+    snapshot_index: IntGauge,
+    replication_lag: IntGaugeVec,
+    log_entries: IntGauge,
+    log_size: IntGauge,
+    snapshot_size: IntGauge,
+    rocksdb: GaugeVec,
+    key_count: IntGauge,
+    hash_ring_config_id: IntGauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests = IntCounterVec::new(
+            Opts::new("kv_http_requests_total", "HTTP requests by route, method and status."),
+            &["route", "method", "status"],
+        )
+        .unwrap();
+        let request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "kv_http_request_duration_seconds",
+                "Latency of HTTP requests by route.",
+            )
+            .buckets(latency_buckets()),
+            &["route"],
+        )
+        .unwrap();
+        let gauge = |name: &str, help: &str| IntGauge::new(name, help).unwrap();
+        let replication_lag = IntGaugeVec::new(
+            Opts::new(
+                "kv_raft_replication_lag_entries",
+                "Log entries a follower is behind the leader. Only reported by the leader.",
+            ),
+            &["follower"],
+        )
+        .unwrap();
+        let rocksdb = GaugeVec::new(
+            Opts::new("kv_rocksdb_property", "RocksDB properties by column family."),
+            &["cf", "property"],
+        )
+        .unwrap();
+
+        let metrics = Self {
+            registry,
+            requests,
+            request_duration,
+            current_term: gauge("kv_raft_current_term", "Current Raft term."),
+            current_leader: gauge(
+                "kv_raft_current_leader",
+                "Id of the current leader, 0 if there is none.",
+            ),
+            is_leader: gauge("kv_raft_is_leader", "1 if this node is the leader."),
+            last_log_index: gauge("kv_raft_last_log_index", "Index of the last log entry."),
+            committed_index: gauge(
+                "kv_raft_committed_index",
+                "Index of the last committed log entry.",
+            ),
+            last_applied_index: gauge(
+                "kv_raft_last_applied_index",
+                "Index of the last log entry applied to the state machine.",
+            ),
+            snapshot_index: gauge(
+                "kv_raft_snapshot_index",
+                "Index of the last log entry in the current snapshot.",
+            ),
+            replication_lag,
+            log_entries: gauge("kv_raft_log_entries", "Log entries that are not purged yet."),
+            log_size: gauge("kv_raft_log_size_bytes", "Size of the Raft log in RocksDB."),
+            snapshot_size: gauge("kv_raft_snapshot_size_bytes", "Size of the current snapshot."),
+            rocksdb,
+            key_count: gauge("kv_keys", "Keys in the state machine."),
+            hash_ring_config_id: gauge(
+                "kv_hash_ring_config_id",
+                "Config id of the hash ring known to this node.",
+            ),
+        };
+        metrics.register_all();
+        metrics
+    }
+
+    fn register_all(&self) {
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(self.requests.clone()),
+            Box::new(self.request_duration.clone()),
+            Box::new(self.current_term.clone()),
+            Box::new(self.current_leader.clone()),
+            Box::new(self.is_leader.clone()),
+            Box::new(self.last_log_index.clone()),
+            Box::new(self.committed_index.clone()),
+            Box::new(self.last_applied_index.clone()),
+            Box::new(self.snapshot_index.clone()),
+            Box::new(self.replication_lag.clone()),
+            Box::new(self.log_entries.clone()),
+            Box::new(self.log_size.clone()),
+            Box::new(self.snapshot_size.clone()),
+            Box::new(self.rocksdb.clone()),
+            Box::new(self.key_count.clone()),
+            Box::new(self.hash_ring_config_id.clone()),
+        ];
+        for collector in collectors {
+            // Names are unique and static, so registering can't fail.
+            self.registry.register(collector).unwrap();
+        }
+    }
+
+    /// Record a finished HTTP request.
+    pub fn observe_request(&self, route: &str, method: &str, status: u16, seconds: f64) {
+        self.requests
+            .with_label_values(&[route, method, &status.to_string()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[route])
+            .observe(seconds);
+    }
+
+    /// Read the current state of the node into the gauges.
+    async fn update(&self, state: &AppState) {
+        let raft = state.raft.metrics().borrow().clone();
+        let last_log_index = raft.last_log_index.unwrap_or(0);
+
+        self.current_term.set(raft.current_term as i64);
+        self.current_leader.set(raft.current_leader.unwrap_or(0) as i64);
+        self.is_leader.set((raft.current_leader == Some(state.id)) as i64);
+        self.last_log_index.set(last_log_index as i64);
+        self.last_applied_index
+            .set(raft.last_applied.map(|id| id.index).unwrap_or(0) as i64);
+        self.snapshot_index
+            .set(raft.snapshot.map(|id| id.index).unwrap_or(0) as i64);
+        self.log_entries.set(log_entries(
+            raft.last_log_index,
+            raft.purged.map(|id| id.index),
+        ) as i64);
+
+        self.replication_lag.reset();
+        if let Some(replication) = &raft.replication {
+            for (follower, matched) in replication {
+                if *follower == state.id {
+                    continue;
+                }
+                self.replication_lag
+                    .with_label_values(&[&follower.to_string()])
+                    .set(replication_lag(raft.last_log_index, matched.as_ref()) as i64);
+            }
+        }
+
+        let storage = &state.storage;
+        self.committed_index
+            .set(storage.committed_index().unwrap_or(0) as i64);
+        self.snapshot_size.set(storage.snapshot_size() as i64);
+        self.log_size.set(
+            ["rocksdb.total-sst-files-size", "rocksdb.cur-size-all-mem-tables"]
+                .iter()
+                .filter_map(|property| storage.property("logs", property))
+                .sum::<u64>() as i64,
+        );
+        for cf in COLUMN_FAMILIES {
+            for property in ROCKSDB_PROPERTIES {
+                if let Some(value) = storage.property(cf, property) {
+                    self.rocksdb
+                        .with_label_values(&[cf, property])
+                        .set(value as f64);
+                }
+            }
+        }
+
+        self.key_count.set(state.key_values.read().await.len() as i64);
+        self.hash_ring_config_id
+            .set(state.hash_ring.read().await.config_id as i64);
+    }
+
+    fn encode(&self) -> prometheus::Result<(String, Vec<u8>)> {
+        let encoder = TextEncoder::new();
+        let mut buf = Vec::new();
+        encoder.encode(&self.registry.gather(), &mut buf)?;
+        Ok((encoder.format_type().to_string(), buf))
+    }
+}
+
+/// Number of entries in a log that ends at `last_log_index` and was purged up to `purged`.
+fn log_entries(last_log_index: Option<u64>, purged: Option<u64>) -> u64 {
+    match (last_log_index, purged) {
+        (Some(last), Some(purged)) => last.saturating_sub(purged),
+        // Log indexes start at 0.
+        (Some(last), None) => last + 1,
+        (None, _) => 0,
+    }
+}
+
+/// Serve the metrics in the Prometheus text format.
+pub async fn serve(State(state): State<AppState>) -> Response {
+    state.metrics.update(&state).await;
+    match state.metrics.encode() {
+        Ok((content_type, body)) => ([(CONTENT_TYPE, content_type)], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
+
+/// Middleware that counts requests and measures their latency.
+///
+/// Requests are labelled with their route rather than their path, so the number of series
+/// stays bounded. Requests that match no route are labelled `unmatched`.
+pub async fn track(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let method = req.method().to_string();
+
+    let start = Instant::now();
+    let response = next.run(req).await;
+    state.metrics.observe_request(
+        &route,
+        &method,
+        response.status().as_u16(),
+        start.elapsed().as_secs_f64(),
+    );
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_entries() {
+        assert_eq!(log_entries(None, None), 0);
+        assert_eq!(log_entries(Some(0), None), 1);
+        assert_eq!(log_entries(Some(10), None), 11);
+        assert_eq!(log_entries(Some(10), Some(4)), 6);
+        assert_eq!(log_entries(Some(4), Some(4)), 0);
+    }
+
+    #[test]
+    fn test_observe_request() {
+        let metrics = Metrics::new();
+        metrics.observe_request("/api/write", "POST", 200, 0.002);
+        metrics.observe_request("/api/write", "POST", 200, 0.004);
+        metrics.observe_request("/api/read", "POST", 421, 0.001);
+
+        let (content_type, body) = metrics.encode().unwrap();
+        let text = String::from_utf8(body).unwrap();
+        assert!(content_type.starts_with("text/plain"));
+        assert!(text.contains(
+            r#"kv_http_requests_total{method="POST",route="/api/write",status="200"} 2"#
+        ));
+        assert!(text.contains(
+            r#"kv_http_requests_total{method="POST",route="/api/read",status="421"} 1"#
+        ));
+        assert!(text.contains(r#"kv_http_request_duration_seconds_count{route="/api/write"} 2"#));
+    }
+
+    #[test]
+    fn test_registries_are_separate() {
+        let a = Metrics::new();
+        let b = Metrics::new();
+        a.observe_request("/api/write", "POST", 200, 0.001);
+        let text = String::from_utf8(b.encode().unwrap().1).unwrap();
+        assert!(!text.contains("kv_http_requests_total{"));
+    }
+}
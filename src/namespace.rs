@@ -0,0 +1,327 @@
+//! Namespaces let several tenants share a cluster.
+//!
+//! A namespace owns all keys of the form `<namespace>/<key>`. Each namespace has a [`Quota`]
+//! that limits its number of keys, its total size and the size of single values. Quotas are
+//! checked when a write is applied to the state machine, so every node decides the same way.
+//! Keys outside of any namespace are not limited.
+//!
+//! The keys of a namespace are spread over all shards, and the limits on their number and total
+//! size are cluster-wide: every shard enforces its share of them, in proportion to its weight in
+//! the hash ring, see [`Quota::share`]. The shares are recomputed whenever the weights change.
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::value::Bytes;
+use crate::value::Value;
+
+/// Separates the namespace from the rest of a key.
+pub const SEPARATOR: char = '/';
+
+/// Limits of a namespace. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quota {
+    #[serde(default)]
+    pub max_keys: Option<u64>,
+    /// Limit of the summed size of all keys and values, in bytes, as written.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_value_size: Option<u64>,
+}
+
+impl Quota {
+    /// The share of shard `index` of this cluster-wide quota, for shards with the relative loads
+    /// `weights` in the hash ring.
+    ///
+    /// The limits on the number and the total size of the keys are split in proportion to the
+    /// weights, as CARP places the keys, so that the shares add up to them. The limit of single
+    /// values applies to every shard as is. A shard rejects writes once it reaches its share,
+    /// even if other shards did not.
+    pub fn share(&self, weights: &[f32], index: usize) -> Quota {
+        let weights: Vec<f64> = match weights.iter().any(|w| *w > 0.0) {
+            true => weights.iter().map(|w| f64::from(w.max(0.0))).collect(),
+            false => vec![1.0; weights.len()],
+        };
+        let total: f64 = weights.iter().sum();
+        // The part of `max` that goes to the shards before `end`. A share is the difference of
+        // two of them, so the shares add up to `max` whatever the rounding.
+        let before = |max: u64, end: usize| match end >= weights.len() {
+            true => max,
+            false => (max as f64 * weights[..end].iter().sum::<f64>() / total).round() as u64,
+        };
+        let split = |max: u64| before(max, index + 1).saturating_sub(before(max, index));
+        Quota {
+            max_keys: self.max_keys.map(split),
+            max_bytes: self.max_bytes.map(split),
+            max_value_size: self.max_value_size,
+        }
+    }
+
+    /// The cluster-wide quota whose shares are `shares`, see [`Quota::share`].
+    pub fn sum(shares: impl IntoIterator<Item = Quota>) -> Quota {
+        // A share without a limit makes the whole quota unlimited.
+        let add = |a: Option<u64>, b: Option<u64>| Some(a? + b?);
+        let max = |a: Option<u64>, b: Option<u64>| Some(a?.max(b?));
+        shares
+            .into_iter()
+            .reduce(|sum, share| Quota {
+                max_keys: add(sum.max_keys, share.max_keys),
+                max_bytes: add(sum.max_bytes, share.max_bytes),
+                max_value_size: max(sum.max_value_size, share.max_value_size),
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// What a namespace currently stores.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub key_count: u64,
+    /// Summed size of all keys and values, in bytes.
+    pub byte_size: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Namespace {
+    pub quota: Quota,
+    pub usage: Usage,
+}
+
+/// The limit of a [`Quota`] that a write would exceed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Limit {
+    Keys,
+    Bytes,
+    ValueSize,
+}
+
+#[derive(Error, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NamespaceError {
+    #[error("namespace {0:?} does not exist")]
+    NotFound(String),
+    #[error("namespace {0:?} already exists")]
+    AlreadyExists(String),
+    #[error("invalid namespace name {0:?}")]
+    InvalidName(String),
+    #[error("write exceeds the {limit:?} quota of namespace {namespace:?}")]
+    QuotaExceeded { namespace: String, limit: Limit },
+}
+
+/// Returns the namespace part of `key`, if it has one. Namespace names are UTF-8, so keys whose
+/// first part isn't are outside of any namespace.
+pub fn namespace_of(key: &[u8]) -> Option<&str> {
+    let end = key.iter().position(|&b| b == SEPARATOR as u8)?;
+    std::str::from_utf8(&key[..end]).ok()
+}
+
+/// Prefix shared by all keys of `namespace`.
+pub fn key_prefix(namespace: &str) -> String {
+    format!("{}{}", namespace, SEPARATOR)
+}
+
+pub fn validate_name(name: &str) -> Result<(), NamespaceError> {
+    if name.is_empty() || name.contains(SEPARATOR) {
+        return Err(NamespaceError::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// Size of an entry as counted against [`Quota::max_bytes`], with the size of the value as
+/// written, see [`Value::size`].
+pub fn entry_size(key: &[u8], value_size: u64) -> u64 {
+    key.len() as u64 + value_size
+}
+
+/// Usage of `namespace` computed from the stored keys.
+pub fn usage_of(namespace: &str, kvs: &BTreeMap<Bytes, Value>) -> Usage {
+    let prefix = Bytes::from(key_prefix(namespace));
+    kvs.range(prefix.clone()..)
+        .take_while(|(k, _)| k.starts_with(&prefix))
+        .fold(Usage::default(), |usage, (k, v)| Usage {
+            key_count: usage.key_count + 1,
+            byte_size: usage.byte_size + entry_size(k, v.size()),
+        })
+}
+
+impl Namespace {
+    /// Usage after setting `key` to a value of `value_size` bytes, or the limit the write would
+    /// exceed.
+    ///
+    /// `old_size` is the size of the value `key` currently has.
+    pub fn usage_after_set(
+        &self,
+        key: &[u8],
+        value_size: u64,
+        old_size: Option<u64>,
+    ) -> Result<Usage, Limit> {
+        if let Some(max) = self.quota.max_value_size {
+            if value_size > max {
+                return Err(Limit::ValueSize);
+            }
+        }
+
+        let mut usage = self.usage;
+        match old_size {
+            Some(old) => {
+                usage.byte_size -= entry_size(key, old);
+            }
+            None => usage.key_count += 1,
+        }
+        usage.byte_size += entry_size(key, value_size);
+
+        if self.quota.max_keys.map(|max| usage.key_count > max).unwrap_or(false) {
+            return Err(Limit::Keys);
+        }
+        // Writes that don't grow the namespace are allowed even when it is over its quota,
+        // e.g., after the quota was lowered.
+        let grows = usage.byte_size > self.usage.byte_size;
+        if grows && self.quota.max_bytes.map(|max| usage.byte_size > max).unwrap_or(false) {
+            return Err(Limit::Bytes);
+        }
+        Ok(usage)
+    }
+
+    /// Usage after deleting `key`, whose value has `old_size` bytes.
+    pub fn usage_after_delete(&self, key: &[u8], old_size: u64) -> Usage {
+        Usage {
+            key_count: self.usage.key_count.saturating_sub(1),
+            byte_size: self
+                .usage
+                .byte_size
+                .saturating_sub(entry_size(key, old_size)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn namespace(quota: Quota) -> Namespace {
+        Namespace {
+            quota,
+            usage: Usage::default(),
+        }
+    }
+
+    #[test]
+    fn test_namespace_of() {
+        assert_eq!(namespace_of(b"team/key"), Some("team"));
+        assert_eq!(namespace_of(b"team/a/b"), Some("team"));
+        assert_eq!(namespace_of(b"key"), None);
+        assert_eq!(namespace_of(b"\xff/key"), None);
+    }
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("team").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("a/b").is_err());
+    }
+
+    #[test]
+    fn test_quota_shares() {
+        let quota = Quota {
+            max_keys: Some(10),
+            max_bytes: Some(2),
+            max_value_size: Some(5),
+        };
+        let even = [1.0 / 3.0; 3];
+        let shares: Vec<Quota> = (0..3).map(|i| quota.share(&even, i)).collect();
+        assert_eq!(
+            shares.iter().map(|q| q.max_keys).collect::<Vec<_>>(),
+            [Some(3), Some(4), Some(3)]
+        );
+        assert_eq!(
+            shares.iter().map(|q| q.max_bytes).collect::<Vec<_>>(),
+            [Some(1), Some(0), Some(1)]
+        );
+        assert!(shares.iter().all(|q| q.max_value_size == Some(5)));
+        assert_eq!(Quota::sum(shares), quota);
+        assert_eq!(Quota::default().share(&even, 1), Quota::default());
+        assert_eq!(Quota::sum([Quota::default(), quota]), Quota::default());
+
+        // The limits are split by weight.
+        let weights = [0.2, 0.5, 0.3];
+        let shares: Vec<Quota> = (0..3).map(|i| quota.share(&weights, i)).collect();
+        assert_eq!(
+            shares.iter().map(|q| q.max_keys).collect::<Vec<_>>(),
+            [Some(2), Some(5), Some(3)]
+        );
+        assert_eq!(Quota::sum(shares), quota);
+    }
+
+    #[test]
+    fn test_usage_of() {
+        let kvs = BTreeMap::from([
+            ("a/x".into(), "1".into()),
+            ("a/y".into(), "22".into()),
+            ("ab/z".into(), "3".into()),
+            ("b".into(), "4".into()),
+        ]);
+        assert_eq!(
+            usage_of("a", &kvs),
+            Usage {
+                key_count: 2,
+                byte_size: 3 + 1 + 3 + 2,
+            }
+        );
+        assert_eq!(usage_of("c", &kvs), Usage::default());
+    }
+
+    #[test]
+    fn test_key_quota() {
+        let mut ns = namespace(Quota {
+            max_keys: Some(1),
+            ..Default::default()
+        });
+        ns.usage = ns.usage_after_set(b"a/x", 1, None).unwrap();
+        assert_eq!(ns.usage_after_set(b"a/y", 1, None), Err(Limit::Keys));
+        // Overwriting an existing key doesn't add a key.
+        assert!(ns.usage_after_set(b"a/x", 1, Some(1)).is_ok());
+    }
+
+    #[test]
+    fn test_byte_quota() {
+        let mut ns = namespace(Quota {
+            max_bytes: Some(10),
+            ..Default::default()
+        });
+        ns.usage = ns.usage_after_set(b"a/x", 5, None).unwrap();
+        assert_eq!(ns.usage.byte_size, 8);
+        assert_eq!(ns.usage_after_set(b"a/y", 1, None), Err(Limit::Bytes));
+        assert_eq!(ns.usage_after_set(b"a/x", 7, Some(5)).unwrap().byte_size, 10);
+
+        // Shrinking is allowed even above the quota.
+        ns.quota.max_bytes = Some(5);
+        assert_eq!(ns.usage_after_set(b"a/x", 1, Some(5)).unwrap().byte_size, 4);
+    }
+
+    #[test]
+    fn test_value_size_quota() {
+        let ns = namespace(Quota {
+            max_value_size: Some(3),
+            ..Default::default()
+        });
+        assert!(ns.usage_after_set(b"a/x", 3, None).is_ok());
+        assert_eq!(ns.usage_after_set(b"a/x", 4, None), Err(Limit::ValueSize));
+    }
+
+    #[test]
+    fn test_usage_after_delete() {
+        let mut ns = namespace(Quota::default());
+        ns.usage = ns.usage_after_set(b"a/x", 5, None).unwrap();
+        ns.usage = ns.usage_after_set(b"a/y", 1, None).unwrap();
+        assert_eq!(
+            ns.usage_after_delete(b"a/x", 5),
+            Usage {
+                key_count: 1,
+                byte_size: 4,
+            }
+        );
+    }
+}
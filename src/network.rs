@@ -1,8 +1,11 @@
 pub mod api;
-mod error;
+pub mod error;
+mod json;
 pub mod management;
 pub mod raft;
 mod raft_network_impl;
+pub mod rpc;
+pub mod sim;
 
 pub use raft_network_impl::Network;
 pub use raft_network_impl::NetworkConnection;
@@ -1,21 +1,44 @@
-use axum::extract::Json;
+use std::ops::Bound;
+
 use axum::extract::State;
+use axum::Extension;
 use axum::http::StatusCode;
 use axum::routing::post;
 use axum::routing::get;
 use axum::Router;
-use openraft::error::CheckIsLeaderError;
-use openraft::error::Infallible;
 use openraft::raft::ClientWriteResponse;
+use serde::Deserialize;
+use serde::Serialize;
 
+use crate::auth::Access;
+use crate::auth::AuthError;
+use crate::auth::Principal;
+use crate::auth::Role;
 use crate::carp::Carp;
 use crate::network::error::AppError;
+use crate::network::json::Json;
 use crate::store;
+use crate::telemetry::Traced;
+use crate::value::Bytes;
 use crate::AppState;
-use crate::Node;
-use crate::NodeId;
 use crate::TypeConfig;
 
+/// Maximum number of entries returned by a single `/scan`.
+pub const MAX_SCAN_LIMIT: usize = 10_000;
+
+/// Parameters of `/scan`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScanRequest {
+    /// Only keys with this prefix are returned.
+    #[serde(default)]
+    pub prefix: Bytes,
+    /// Only keys after this one are returned, to continue a previous scan.
+    #[serde(default)]
+    pub start_after: Option<Bytes>,
+    /// Maximum number of entries returned, at most [`MAX_SCAN_LIMIT`].
+    pub limit: usize,
+}
+
 /// Creates a new `axum::Router` instance with the configured routes for Application API.
 ///
 /// The returned `Router` instance will have the following routes set up:
@@ -23,56 +46,118 @@ use crate::TypeConfig;
 /// - `/write` (HTTP POST)
 /// - `/read` (HTTP POST)
 /// - `/consistent_read` (HTTP POST)
+/// - `/scan` (HTTP POST)
+/// - `/get_hash_ring` (HTTP GET)
+///
+/// The routes expect the [`Principal`] of the caller in the request extensions, see
+/// [`crate::auth::client`].
 pub fn rest() -> Router<AppState> {
     Router::new()
         .route("/write", post(write))
         .route("/read", post(read))
         .route("/consistent_read", post(consistent_read))
+        .route("/scan", post(scan))
         .route("/get_hash_ring", get(get_hash_ring))
 }
 
 /**
  * Application API
  *
- *  - `POST - /write` saves a value in a key and sync the nodes.
- *  - `POST - /read` attempt to find a value from a given key.
+ *  - `POST - /write` saves a value in a key and sync the nodes. Values over the configured
+ *    threshold are compressed before they are proposed, values over the maximum size rejected.
+ *  - `POST - /read` attempt to find a value from a given key, `not_found` if there is none.
  *  - `POST - /consistent_read` attempt to find a value from a given key ensuring that the value is linearizable.
+ *  - `POST - /scan` list the keys of this shard with a given prefix, in order.
  *  - `POST - /get_hash_ring` to get the routing table for all nodes.
  */
 async fn write(
     State(state): State<AppState>,
-    Json(payload): Json<store::Request>,
+    Extension(principal): Extension<Principal>,
+    Json(mut payload): Json<store::Request>,
 ) -> Result<(StatusCode, Json<ClientWriteResponse<TypeConfig>>), AppError> {
-    let res = state.raft.client_write(payload).await?;
+    match &mut payload {
+        store::Request::Set { key, value } | store::Request::CompareAndSwap { key, value, .. } => {
+            principal.check(key, Access::Write)?;
+            state.check_shard(key).await?;
+            state.load.record(key);
+            *value = state.values.prepare(std::mem::take(value))?;
+        }
+        store::Request::Delete { key } => {
+            principal.check(key, Access::Write)?;
+            state.check_shard(key).await?;
+            state.load.record(key);
+        }
+        // Namespaces are managed through the cluster management API.
+        _ if principal.role() != Role::Admin => return Err(AuthError::AdminRequired.into()),
+        _ => {}
+    }
+    let mut res = state.raft.client_write(Traced::new(payload)).await?;
+    if let Some(e) = res.data.error {
+        return Err(e.into());
+    }
+    if let Some(value) = &mut res.data.value {
+        *value = value.decode()?.into();
+    }
     Ok((StatusCode::CREATED, Json(res)))
 }
 
 async fn read(
     State(state): State<AppState>,
-    Json(key): Json<String>,
-) -> Result<(StatusCode, Json<String>), AppError> {
+    Extension(principal): Extension<Principal>,
+    Json(key): Json<Bytes>,
+) -> Result<(StatusCode, Json<Bytes>), AppError> {
+    principal.check(&key, Access::Read)?;
+    state.check_shard(&key).await?;
+    state.load.record(&key);
     let kvs = state.key_values.read().await;
-    let value = kvs.get(&key);
-
-    let res: Result<String, Infallible> = Ok(value.cloned().unwrap_or_default());
+    let value = kvs.get(&key).ok_or_else(|| AppError::KeyNotFound(key.clone()))?;
 
-    Ok((StatusCode::OK, Json(res?)))
+    Ok((StatusCode::OK, Json(value.decode()?)))
 }
 
 async fn consistent_read(
     State(state): State<AppState>,
-    Json(key): Json<String>,
-) -> Result<(StatusCode, Json<String>), AppError> {
+    Extension(principal): Extension<Principal>,
+    Json(key): Json<Bytes>,
+) -> Result<(StatusCode, Json<Bytes>), AppError> {
+    principal.check(&key, Access::Read)?;
+    state.check_shard(&key).await?;
+    state.load.record(&key);
     let _ = state.raft.ensure_linearizable().await?;
 
     let kvs = state.key_values.read().await;
 
-    let value = kvs.get(&key);
+    let value = kvs.get(&key).ok_or_else(|| AppError::KeyNotFound(key.clone()))?;
 
-    let res: Result<String, CheckIsLeaderError<NodeId, Node>> =
-        Ok(value.cloned().unwrap_or_default());
+    Ok((StatusCode::OK, Json(value.decode()?)))
+}
 
-    Ok((StatusCode::OK, Json(res?)))
+/// Keys of this shard with the prefix of the request, in order, with their values.
+///
+/// Like `read`, the values may be stale.
+async fn scan(
+    State(state): State<AppState>,
+    Extension(principal): Extension<Principal>,
+    Json(req): Json<ScanRequest>,
+) -> Result<(StatusCode, Json<Vec<(Bytes, Bytes)>>), AppError> {
+    principal.check(&req.prefix, Access::Read)?;
+    let start = match req.start_after {
+        Some(key) if key >= req.prefix => Bound::Excluded(key),
+        _ => Bound::Included(req.prefix.clone()),
+    };
+
+    let ring = state.hash_ring.read().await;
+    let own = ring.shard_of(&state.api_addr);
+    let kvs = state.key_values.read().await;
+    let entries: Vec<(Bytes, Bytes)> = kvs
+        .range((start, Bound::Unbounded))
+        .take_while(|(key, _)| key.starts_with(&req.prefix))
+        // Keys left over from a change of the ring are served by their new shard.
+        .filter(|(key, _)| own.map_or(true, |own| ring.get_original(key) == own))
+        .take(req.limit.min(MAX_SCAN_LIMIT))
+        .map(|(key, value)| Ok((key.clone(), value.decode()?)))
+        .collect::<Result<_, AppError>>()?;
+    Ok((StatusCode::OK, Json(entries)))
 }
 
 async fn get_hash_ring(
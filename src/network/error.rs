@@ -1,19 +1,130 @@
-use crate::Node;
-use crate::NodeId;
+//! Errors of the HTTP API.
+//!
+//! Every failed request is answered with a JSON envelope:
+//!
+//! ```json
+//! {
+//!   "error": {
+//!     "code": "not_leader",
+//!     "message": "has to forward request to: Some(1), ...",
+//!     "leader_id": 1,
+//!     "leader_addr": "127.0.0.1:31001"
+//!   }
+//! }
+//! ```
+//!
+//! | code             | status | meaning                                                        |
+//! |------------------|--------|----------------------------------------------------------------|
+//! | `not_leader`     | 421    | The node is not the leader. `leader_*` name it, if known.      |
+//! | `wrong_shard`    | 421    | The key belongs to the shard served by `shard_addr`.           |
+//! | `bad_request`    | 400    | The request could not be parsed or is invalid.                 |
+//! | `unauthorized`   | 401    | The token is missing or unknown.                               |
+//! | `forbidden`      | 403    | The token does not grant access.                               |
+//! | `not_found`      | 404    | The addressed object, e.g., a namespace, does not exist.       |
+//! | `conflict`       | 409    | The request conflicts with the current state.                  |
+//! | `too_large`      | 413    | The value or the request is larger than the node accepts.      |
+//! | `quota_exceeded` | 507    | The write exceeds the quota of its namespace.                  |
+//! | `unavailable`    | 503    | The node can't serve requests right now, e.g., no quorum.      |
+//! | `timeout`        | 504    | The request did not complete in time.                          |
+//! | `internal`       | 500    | Anything else.                                                 |
+//!
+//! Clients decode the envelope into a [`ClientError`].
+use std::time::Duration;
+
 use axum::http::StatusCode;
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum::Json;
 use openraft::error::CheckIsLeaderError;
 use openraft::error::ClientWriteError;
+use openraft::error::Fatal;
+use openraft::error::ForwardToLeader;
 use openraft::error::InitializeError;
 use openraft::error::RaftError;
+use openraft::metrics::WaitError;
+use serde::Deserialize;
 use serde::Serialize;
-use serde::Serializer;
 use thiserror::Error;
 
+use crate::auth::AuthError;
+use crate::backup::BackupError;
+use crate::namespace::NamespaceError;
+use crate::value::Bytes;
+use crate::value::ValueError;
+use crate::Node;
+use crate::NodeId;
+
+/// Machine-readable error codes of the HTTP API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NotLeader,
+    WrongShard,
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    TooLarge,
+    QuotaExceeded,
+    Unavailable,
+    Timeout,
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn status(self) -> StatusCode {
+        match self {
+            ErrorCode::NotLeader | ErrorCode::WrongShard => StatusCode::MISDIRECTED_REQUEST,
+            ErrorCode::BadRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::Unauthorized => StatusCode::UNAUTHORIZED,
+            ErrorCode::Forbidden => StatusCode::FORBIDDEN,
+            ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::Conflict => StatusCode::CONFLICT,
+            ErrorCode::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            ErrorCode::QuotaExceeded => StatusCode::INSUFFICIENT_STORAGE,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ErrorCode::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// The error of a failed request, as sent in the `error` field of the envelope.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorBody {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leader_id: Option<NodeId>,
+    /// API address of the leader.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leader_addr: Option<String>,
+    /// API address of the node serving the shard of the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shard_addr: Option<String>,
+}
+
+impl ErrorBody {
+    pub fn new(code: ErrorCode, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+            leader_id: None,
+            leader_addr: None,
+            shard_addr: None,
+        }
+    }
+}
+
+/// The body of every error response.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: ErrorBody,
+}
+
 /// Error type for the application.
-/// Used to convert `openraft::error::RaftError` into `axum::response::Response`.
+/// Used to convert errors of the handlers into an error response.
 #[derive(Error, Debug)]
 pub enum AppError {
     #[error("{0}")]
@@ -26,28 +137,299 @@ pub enum AppError {
     RaftInitializeError(#[from] RaftError<NodeId, InitializeError<NodeId, Node>>),
     #[error("{0}")]
     Infallible(#[from] openraft::error::Infallible),
+    #[error("{0}")]
+    Auth(#[from] AuthError),
+    #[error("{0}")]
+    Namespace(#[from] NamespaceError),
+    #[error("{0}")]
+    Value(#[from] ValueError),
+    /// The body of the request exceeds the limit of the node.
+    #[error("{0}")]
+    PayloadTooLarge(String),
+    #[error("key {0:?} not found")]
+    KeyNotFound(Bytes),
+    #[error("key {key:?} belongs to the shard served by {shard_addr}")]
+    WrongShard { key: String, shard_addr: String },
+    #[error("{0}")]
+    BadRequest(String),
+    #[error("request did not complete within {0:?}")]
+    Timeout(Duration),
+    #[error("{0}")]
+    Fatal(#[from] Fatal<NodeId>),
+    #[error("{0}")]
+    Unavailable(String),
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Backup(#[from] BackupError),
+}
+
+impl AppError {
+    pub fn body(&self) -> ErrorBody {
+        match self {
+            AppError::RaftClientWriteError(err) => raft_error_body(err, |e| match e {
+                ClientWriteError::ForwardToLeader(e) => not_leader(e),
+                ClientWriteError::ChangeMembershipError(e) => {
+                    ErrorBody::new(ErrorCode::Conflict, e)
+                }
+            }),
+            AppError::RaftCheckIsLeaderError(err) => {
+                raft_error_body(err, check_is_leader_error_body)
+            }
+            AppError::CheckIsLeaderError(err) => check_is_leader_error_body(err),
+            AppError::RaftInitializeError(err) => raft_error_body(err, |e| match e {
+                InitializeError::NotAllowed(e) => ErrorBody::new(ErrorCode::Conflict, e),
+                InitializeError::NotInMembers(e) => ErrorBody::new(ErrorCode::BadRequest, e),
+            }),
+            AppError::Infallible(err) => ErrorBody::new(ErrorCode::Internal, err),
+            AppError::Auth(err) => {
+                let code = match err {
+                    AuthError::MissingToken | AuthError::InvalidToken => ErrorCode::Unauthorized,
+                    AuthError::AdminRequired | AuthError::Forbidden { .. } => ErrorCode::Forbidden,
+                };
+                ErrorBody::new(code, err)
+            }
+            AppError::Namespace(err) => {
+                let code = match err {
+                    NamespaceError::NotFound(_) => ErrorCode::NotFound,
+                    NamespaceError::AlreadyExists(_) => ErrorCode::Conflict,
+                    NamespaceError::InvalidName(_) => ErrorCode::BadRequest,
+                    NamespaceError::QuotaExceeded { .. } => ErrorCode::QuotaExceeded,
+                };
+                ErrorBody::new(code, err)
+            }
+            AppError::Value(err) => {
+                let code = match err {
+                    ValueError::TooLarge { .. } => ErrorCode::TooLarge,
+                    ValueError::Compressed => ErrorCode::BadRequest,
+                    ValueError::Corrupt(_) => ErrorCode::Internal,
+                };
+                ErrorBody::new(code, err)
+            }
+            AppError::PayloadTooLarge(msg) => ErrorBody::new(ErrorCode::TooLarge, msg),
+            AppError::KeyNotFound(_) => ErrorBody::new(ErrorCode::NotFound, self),
+            AppError::WrongShard { shard_addr, .. } => ErrorBody {
+                shard_addr: Some(shard_addr.clone()),
+                ..ErrorBody::new(ErrorCode::WrongShard, self)
+            },
+            AppError::BadRequest(msg) => ErrorBody::new(ErrorCode::BadRequest, msg),
+            AppError::Timeout(_) => ErrorBody::new(ErrorCode::Timeout, self),
+            AppError::Fatal(e @ Fatal::Stopped) => ErrorBody::new(ErrorCode::Unavailable, e),
+            AppError::Fatal(e) => ErrorBody::new(ErrorCode::Internal, e),
+            AppError::Unavailable(msg) => ErrorBody::new(ErrorCode::Unavailable, msg),
+            AppError::Conflict(msg) => ErrorBody::new(ErrorCode::Conflict, msg),
+            AppError::Backup(err) => {
+                let code = match err {
+                    BackupError::NotEmpty(_) => ErrorCode::Conflict,
+                    _ => ErrorCode::Internal,
+                };
+                ErrorBody::new(code, err)
+            }
+        }
+    }
+}
+
+impl From<WaitError> for AppError {
+    fn from(err: WaitError) -> Self {
+        match err {
+            WaitError::Timeout(timeout, _) => AppError::Timeout(timeout),
+            WaitError::ShuttingDown => AppError::Unavailable(err.to_string()),
+        }
+    }
+}
+
+fn raft_error_body<E>(err: &RaftError<NodeId, E>, api_error: impl Fn(&E) -> ErrorBody) -> ErrorBody
+where
+    E: std::error::Error,
+{
+    match err {
+        RaftError::APIError(e) => api_error(e),
+        RaftError::Fatal(e @ Fatal::Stopped) => ErrorBody::new(ErrorCode::Unavailable, e),
+        RaftError::Fatal(e) => ErrorBody::new(ErrorCode::Internal, e),
+    }
+}
+
+fn check_is_leader_error_body(err: &CheckIsLeaderError<NodeId, Node>) -> ErrorBody {
+    match err {
+        CheckIsLeaderError::ForwardToLeader(e) => not_leader(e),
+        CheckIsLeaderError::QuorumNotEnough(e) => ErrorBody::new(ErrorCode::Unavailable, e),
+    }
+}
+
+fn not_leader(err: &ForwardToLeader<NodeId, Node>) -> ErrorBody {
+    ErrorBody {
+        leader_id: err.leader_id,
+        leader_addr: err.leader_node.as_ref().map(|node| node.api_addr.clone()),
+        ..ErrorBody::new(ErrorCode::NotLeader, err)
+    }
 }
 
 // Tell axum how to convert `AppError` into a response.
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let body = Json(self);
-        (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        let error = self.body();
+        (error.code.status(), Json(ErrorResponse { error })).into_response()
     }
 }
 
-// Custom serialization for `AppError` to avoid leaking internal details to client.
-impl Serialize for AppError {
-    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
-    where
-        S: Serializer,
-    {
-        match self {
-            AppError::RaftClientWriteError(err) => err.serialize(serializer),
-            AppError::RaftCheckIsLeaderError(err) => err.serialize(serializer),
-            AppError::CheckIsLeaderError(err) => err.serialize(serializer),
-            AppError::RaftInitializeError(err) => err.serialize(serializer),
-            AppError::Infallible(err) => err.serialize(serializer),
+/// Error returned by [`crate::raft_node::RaftNode`] and [`crate::kvclient::KVClient`].
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ClientError {
+    #[error("not the leader, the leader is {leader_id:?} at {leader_addr:?}")]
+    NotLeader {
+        leader_id: Option<NodeId>,
+        leader_addr: Option<String>,
+    },
+    #[error("the key belongs to the shard served by {shard_addr}")]
+    WrongShard { shard_addr: String },
+    #[error("bad request: {0}")]
+    BadRequest(String),
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
+    #[error("not found: {0}")]
+    NotFound(String),
+    #[error("conflict: {0}")]
+    Conflict(String),
+    /// The value, or the whole request, is larger than the node accepts.
+    #[error("too large: {0}")]
+    TooLarge(String),
+    #[error("quota exceeded: {0}")]
+    QuotaExceeded(String),
+    #[error("unavailable: {0}")]
+    Unavailable(String),
+    #[error("timeout: {0}")]
+    Timeout(String),
+    #[error("internal error: {0}")]
+    Internal(String),
+    /// The node could not be connected to.
+    #[error("unreachable: {0}")]
+    Unreachable(String),
+    /// The connection broke or the response could not be decoded.
+    #[error("network error: {0}")]
+    Network(String),
+    /// The connection broke after a request that is not safe to repeat was sent, so it may or
+    /// may not have been applied.
+    #[error("unknown outcome: {0}")]
+    UnknownOutcome(String),
+}
+
+impl ClientError {
+    /// Returns `true` if another node of the shard may be able to serve the request.
+    pub fn is_node_failure(&self) -> bool {
+        matches!(
+            self,
+            ClientError::Unreachable(_) | ClientError::Network(_) | ClientError::Unavailable(_)
+        )
+    }
+}
+
+impl From<ErrorBody> for ClientError {
+    fn from(body: ErrorBody) -> Self {
+        let msg = body.message;
+        match body.code {
+            ErrorCode::NotLeader => ClientError::NotLeader {
+                leader_id: body.leader_id,
+                leader_addr: body.leader_addr,
+            },
+            ErrorCode::WrongShard => match body.shard_addr {
+                Some(shard_addr) => ClientError::WrongShard { shard_addr },
+                None => ClientError::Internal(msg),
+            },
+            ErrorCode::BadRequest => ClientError::BadRequest(msg),
+            ErrorCode::Unauthorized => ClientError::Unauthorized(msg),
+            ErrorCode::Forbidden => ClientError::Forbidden(msg),
+            ErrorCode::NotFound => ClientError::NotFound(msg),
+            ErrorCode::Conflict => ClientError::Conflict(msg),
+            ErrorCode::TooLarge => ClientError::TooLarge(msg),
+            ErrorCode::QuotaExceeded => ClientError::QuotaExceeded(msg),
+            ErrorCode::Unavailable => ClientError::Unavailable(msg),
+            ErrorCode::Timeout => ClientError::Timeout(msg),
+            ErrorCode::Internal => ClientError::Internal(msg),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::namespace::Limit;
+
+    #[test]
+    fn test_envelope_roundtrip() {
+        let err = AppError::Namespace(NamespaceError::QuotaExceeded {
+            namespace: "team".to_string(),
+            limit: Limit::Keys,
+        });
+        let body = err.body();
+        assert_eq!(body.code.status(), StatusCode::INSUFFICIENT_STORAGE);
+
+        let json = serde_json::to_value(ErrorResponse { error: body }).unwrap();
+        assert_eq!(json["error"]["code"], "quota_exceeded");
+        assert!(json["error"].get("leader_id").is_none());
+
+        let decoded: ErrorResponse = serde_json::from_value(json).unwrap();
+        assert!(matches!(
+            ClientError::from(decoded.error),
+            ClientError::QuotaExceeded(_)
+        ));
+    }
+
+    #[test]
+    fn test_not_leader() {
+        let err = AppError::CheckIsLeaderError(CheckIsLeaderError::ForwardToLeader(
+            ForwardToLeader::new(
+                1,
+                Node {
+                    rpc_addr: "127.0.0.1:32001".to_string(),
+                    api_addr: "127.0.0.1:31001".to_string(),
+                },
+            ),
+        ));
+        let body = err.body();
+        assert_eq!(body.code.status(), StatusCode::MISDIRECTED_REQUEST);
+        assert_eq!(
+            ClientError::from(body),
+            ClientError::NotLeader {
+                leader_id: Some(1),
+                leader_addr: Some("127.0.0.1:31001".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_too_large() {
+        let err = AppError::Value(ValueError::TooLarge { size: 10, max: 8 });
+        let body = err.body();
+        assert_eq!(body.code.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(
+            ClientError::from(body),
+            ClientError::TooLarge("the value has 10 bytes, more than the maximum of 8 bytes".into())
+        );
+    }
+
+    #[test]
+    fn test_key_not_found() {
+        let body = AppError::KeyNotFound("foo".into()).body();
+        assert_eq!(body.code.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            ClientError::from(body),
+            ClientError::NotFound("key \"foo\" not found".into())
+        );
+    }
+
+    #[test]
+    fn test_wrong_shard() {
+        let err = AppError::WrongShard {
+            key: "foo".to_string(),
+            shard_addr: "127.0.0.1:31011".to_string(),
+        };
+        assert_eq!(
+            ClientError::from(err.body()),
+            ClientError::WrongShard {
+                shard_addr: "127.0.0.1:31011".to_string(),
+            }
+        );
+    }
+}
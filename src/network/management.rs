@@ -1,17 +1,37 @@
 use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
 
-use axum::extract::Json;
+use axum::extract::Query;
 use axum::extract::State;
 use axum::http::StatusCode;
 use axum::routing::get;
 use axum::routing::post;
 use axum::Router;
 use openraft::raft::ClientWriteResponse;
+use openraft::LogId;
 use openraft::RaftMetrics;
+use serde::Deserialize;
 
+use crate::backup;
+use crate::backup::Checkpoint;
 use crate::carp::Carp;
+use crate::export;
+use crate::export::ShardDump;
+use crate::leadership;
+use crate::membership;
+use crate::membership::ChangeProgress;
+use crate::membership::MembershipStatus;
+use crate::load_balancer::MovedKeys;
+use crate::load_balancer::MovedKeysRequest;
+use crate::load_balancer::ShardLoad;
+use crate::namespace::Namespace;
+use crate::namespace::Quota;
 use crate::network::error::AppError;
+use crate::network::json::Json;
+use crate::store::Request;
+use crate::telemetry::Traced;
 use crate::AppState;
 use crate::Node;
 use crate::NodeId;
@@ -20,24 +40,61 @@ use crate::TypeConfig;
 // --- Cluster management
 
 /// Creates a new `axum::Router` instance with the configured routes for Cluster Management API.
+///
+/// All routes are restricted to admins by [`crate::auth::admin`].
 pub fn rest() -> Router<AppState> {
     Router::new()
         .route("/update-hash-ring", post(update_hash_ring))
         .route("/add-learner", post(add_learner))
         .route("/change-membership", post(change_membership))
         .route("/init", post(init))
+        .route("/add-node", post(add_node))
+        .route("/replace-node", post(replace_node))
+        .route("/remove-node", post(remove_node))
+        .route("/membership", get(membership_status))
+        .route("/transfer-leader", post(transfer_leader))
+        .route("/drain", post(drain))
+        .route("/snapshot", post(snapshot))
+        .route("/purge-log", post(purge_log))
+        .route("/backup", post(backup))
+        .route("/dump", post(dump))
         .route("/metrics", get(metrics))
+        .route("/load", get(load))
+        .route("/moved-keys", post(moved_keys))
+        .route("/migrate", post(migrate))
+        .route("/create-namespace", post(create_namespace))
+        .route("/delete-namespace", post(delete_namespace))
+        .route("/set-namespace-quota", post(set_namespace_quota))
+        .route("/namespaces", get(namespaces))
 }
 
 // --- Consistent Hashing API
 
+/// Query of `/cluster/update-hash-ring`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct UpdateHashRingQuery {
+    /// `config_id` of the ring the new one was derived from. The update fails with `conflict`
+    /// if this node already has a newer ring, which the new one would revert.
+    expected_config_id: Option<u64>,
+}
+
 /// Update the consistent hashing ring stored on the server.
 /// Only configs with higher config_id numbers are accepted.
 async fn update_hash_ring(
     State(state): State<AppState>,
+    Query(query): Query<UpdateHashRingQuery>,
     Json(payload): Json<Carp>,
 ) -> Result<(StatusCode, Json<()>), AppError> {
     let mut ring_lock = state.hash_ring.write().await;
+    if let Some(expected) = query.expected_config_id {
+        if ring_lock.config_id > expected {
+            return Err(AppError::Conflict(format!(
+                "the hash ring changed from config {} to {}",
+                expected, ring_lock.config_id
+            )));
+        }
+    }
     if payload.config_id >= ring_lock.config_id {
         *ring_lock = payload;
     }
@@ -70,6 +127,49 @@ async fn change_membership(
     Ok((StatusCode::OK, Json(res)))
 }
 
+/// Add a node as voter, once it can be reached and caught up. Has to be sent to the leader.
+///
+/// Returns [`ChangeProgress::CatchingUp`] while the node is a learner that is behind, the
+/// request has to be repeated until it returns [`ChangeProgress::Done`].
+async fn add_node(
+    State(state): State<AppState>,
+    Json(payload): Json<(NodeId, String, String)>,
+) -> Result<(StatusCode, Json<ChangeProgress>), AppError> {
+    let (node_id, api_addr, rpc_addr) = payload;
+    let node = Node { rpc_addr, api_addr };
+    let res = membership::add_node(&state, node_id, node).await?;
+    Ok((StatusCode::OK, Json(res)))
+}
+
+/// Replace the member `old` by the node `new`, see [`add_node`]. Has to be sent to the leader.
+async fn replace_node(
+    State(state): State<AppState>,
+    Json(payload): Json<(NodeId, (NodeId, String, String))>,
+) -> Result<(StatusCode, Json<ChangeProgress>), AppError> {
+    let (old, (new, api_addr, rpc_addr)) = payload;
+    let node = Node { rpc_addr, api_addr };
+    let res = membership::replace_node(&state, old, new, node).await?;
+    Ok((StatusCode::OK, Json(res)))
+}
+
+/// Remove a member, unless the remaining voters could not form a quorum. Has to be sent to
+/// the leader.
+async fn remove_node(
+    State(state): State<AppState>,
+    Json(node_id): Json<NodeId>,
+) -> Result<(StatusCode, Json<ChangeProgress>), AppError> {
+    let res = membership::remove_node(&state, node_id).await?;
+    Ok((StatusCode::OK, Json(res)))
+}
+
+/// Get the voters of the cluster and the replication progress of its learners. Has to be sent
+/// to the leader.
+async fn membership_status(
+    State(state): State<AppState>,
+) -> Result<(StatusCode, Json<MembershipStatus>), AppError> {
+    Ok((StatusCode::OK, Json(membership::status(&state)?)))
+}
+
 /// Initialize a single-node cluster.
 async fn init(State(state): State<AppState>) -> Result<(StatusCode, Json<()>), AppError> {
     let mut nodes = BTreeMap::new();
@@ -83,6 +183,68 @@ async fn init(State(state): State<AppState>) -> Result<(StatusCode, Json<()>), A
     Ok((StatusCode::OK, Json(())))
 }
 
+/// Transfer the leadership to the given voter. Has to be sent to the leader.
+async fn transfer_leader(
+    State(state): State<AppState>,
+    Json(target): Json<NodeId>,
+) -> Result<(StatusCode, Json<()>), AppError> {
+    leadership::transfer_leader(&state, target).await?;
+    Ok((StatusCode::OK, Json(())))
+}
+
+/// Take this node down for maintenance: move its leadership away, stop serving clients and shut
+/// down once nothing is left that only this node has.
+///
+/// Returns the leader of the shard.
+async fn drain(State(state): State<AppState>) -> Result<(StatusCode, Json<NodeId>), AppError> {
+    let leader = leadership::drain(&state).await?;
+    Ok((StatusCode::OK, Json(leader)))
+}
+
+/// Build a snapshot of the state machine of this node.
+///
+/// Returns the id of the last log entry in the snapshot.
+async fn snapshot(
+    State(state): State<AppState>,
+) -> Result<(StatusCode, Json<Option<LogId<NodeId>>>), AppError> {
+    let applied = state.raft.metrics().borrow().last_applied;
+    state.raft.trigger().snapshot().await?;
+    let metrics = state
+        .raft
+        .wait(Some(state.request_timeout))
+        .metrics(|m| m.snapshot >= applied, "snapshot built")
+        .await?;
+    Ok((StatusCode::OK, Json(metrics.snapshot)))
+}
+
+/// Purge the log of this node up to and including the given index.
+///
+/// Entries that are not part of a snapshot yet are kept.
+async fn purge_log(
+    State(state): State<AppState>,
+    Json(upto): Json<u64>,
+) -> Result<(StatusCode, Json<()>), AppError> {
+    state.raft.trigger().purge_log(upto).await?;
+    Ok((StatusCode::OK, Json(())))
+}
+
+/// Write a checkpoint of the storage of this node to the given directory on its host, which
+/// must not exist yet. See [`crate::backup`].
+async fn backup(
+    State(state): State<AppState>,
+    Json(dir): Json<PathBuf>,
+) -> Result<(StatusCode, Json<Checkpoint>), AppError> {
+    let checkpoint = backup::checkpoint(&state, &dir).await?;
+    Ok((StatusCode::OK, Json(checkpoint)))
+}
+
+/// Dump the state machine of this node, which must be the leader of its shard, with every write
+/// committed before the request. See [`crate::export`].
+async fn dump(State(state): State<AppState>) -> Result<(StatusCode, Json<ShardDump>), AppError> {
+    let dump = export::dump(&state).await?;
+    Ok((StatusCode::OK, Json(dump)))
+}
+
 /// Get the latest metrics of the cluster
 async fn metrics(
     State(state): State<AppState>,
@@ -90,3 +252,107 @@ async fn metrics(
     let metrics = state.raft.metrics().borrow().clone();
     Ok((StatusCode::OK, Json(metrics)))
 }
+
+/// Query of `/cluster/load`.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct LoadQuery {
+    /// Start a new window for the hot keys.
+    new_window: bool,
+}
+
+/// Get the load served by this node and the size of its state machine.
+async fn load(
+    State(state): State<AppState>,
+    Query(query): Query<LoadQuery>,
+) -> Result<(StatusCode, Json<ShardLoad>), AppError> {
+    let key_count = state.key_values.read().await.len() as u64;
+    let load = ShardLoad {
+        key_count,
+        byte_size: state.byte_size.load(Ordering::Relaxed),
+        ..state.load.report(query.new_window)
+    };
+    Ok((StatusCode::OK, Json(load)))
+}
+
+/// List the keys this shard serves under `from` and another shard serves under `to`, and the
+/// leftover keys it holds that it only serves under `to`. Has to be sent to the leader.
+async fn moved_keys(
+    State(state): State<AppState>,
+    Json(req): Json<MovedKeysRequest>,
+) -> Result<(StatusCode, Json<MovedKeys>), AppError> {
+    if req.from.is_empty() || req.to.is_empty() {
+        return Err(AppError::BadRequest("the hash ring is empty".to_string()));
+    }
+    // Include every write committed before the request.
+    let _ = state.raft.ensure_linearizable().await?;
+
+    let kvs = state.key_values.read().await;
+    let mut moved = MovedKeys::default();
+    for (key, value) in kvs.iter() {
+        let serves = req.from.get_original(key) == req.shard;
+        let will_serve = req.to.get_original(key) == req.shard;
+        if serves && !will_serve {
+            if req.limit.is_some_and(|limit| moved.keys.len() >= limit) {
+                continue;
+            }
+            moved.keys.push((key.clone(), value.clone()));
+        } else if !serves && will_serve {
+            moved.leftovers.push(key.clone());
+        }
+    }
+    Ok((StatusCode::OK, Json(moved)))
+}
+
+/// Apply a write whether this shard serves its key or not, to move keys between shards.
+async fn migrate(
+    State(state): State<AppState>,
+    Json(req): Json<Request>,
+) -> Result<(StatusCode, Json<()>), AppError> {
+    write_request(&state, req).await
+}
+
+// --- Namespace API
+
+/// Create a namespace with a quota.
+async fn create_namespace(
+    State(state): State<AppState>,
+    Json((name, quota)): Json<(String, Quota)>,
+) -> Result<(StatusCode, Json<()>), AppError> {
+    write_request(&state, Request::CreateNamespace { name, quota }).await
+}
+
+/// Delete a namespace and all its keys.
+async fn delete_namespace(
+    State(state): State<AppState>,
+    Json(name): Json<String>,
+) -> Result<(StatusCode, Json<()>), AppError> {
+    write_request(&state, Request::DeleteNamespace { name }).await
+}
+
+/// Replace the quota of a namespace.
+async fn set_namespace_quota(
+    State(state): State<AppState>,
+    Json((name, quota)): Json<(String, Quota)>,
+) -> Result<(StatusCode, Json<()>), AppError> {
+    write_request(&state, Request::SetNamespaceQuota { name, quota }).await
+}
+
+/// Get all namespaces with their quota and usage on this node.
+async fn namespaces(
+    State(state): State<AppState>,
+) -> Result<(StatusCode, Json<BTreeMap<String, Namespace>>), AppError> {
+    let namespaces = state.namespaces.read().await;
+    Ok((StatusCode::OK, Json(namespaces.clone())))
+}
+
+async fn write_request(
+    state: &AppState,
+    req: Request,
+) -> Result<(StatusCode, Json<()>), AppError> {
+    let res = state.raft.client_write(Traced::new(req)).await?;
+    if let Some(e) = res.data.error {
+        return Err(e.into());
+    }
+    Ok((StatusCode::OK, Json(())))
+}
@@ -1,14 +1,24 @@
 use std::sync::Arc;
 
 use openraft::raft::AppendEntriesRequest;
-use openraft::raft::AppendEntriesResponse;
 use openraft::raft::InstallSnapshotRequest;
-use openraft::raft::InstallSnapshotResponse;
 use openraft::raft::VoteRequest;
-use openraft::raft::VoteResponse;
-use toy_rpc::macros::export_impl;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tracing::Instrument;
+use tracing::Span;
 
+use super::rpc;
 use crate::app::App;
+use crate::leadership;
+use crate::leadership::TransferLeaderRequest;
+use crate::telemetry::Traced;
+use crate::tls::TlsContext;
+use crate::NodeId;
 use crate::TypeConfig;
 
 /// Raft protocol service.
@@ -16,43 +26,121 @@ pub struct Raft {
     app: Arc<App>,
 }
 
-#[export_impl]
 impl Raft {
     pub fn new(app: Arc<App>) -> Self {
         Self { app }
     }
 
-    #[export_method]
-    pub async fn vote(&self, vote: VoteRequest<u64>) -> Result<VoteResponse<u64>, toy_rpc::Error> {
-        self.app
-            .raft
-            .vote(vote)
-            .await
-            .map_err(|e| toy_rpc::Error::Internal(Box::new(e)))
+    /// Accept connections from other Raft nodes and serve their requests.
+    ///
+    /// If `tls` is given, connections have to complete a TLS handshake first.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener, tls: Option<Arc<TlsContext>>) {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    tracing::warn!("failed to accept rpc connection: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = stream.set_nodelay(true) {
+                tracing::debug!("failed to set TCP_NODELAY: {}", e);
+            }
+            let service = self.clone();
+            match &tls {
+                None => {
+                    tokio::spawn(service.serve_connection(stream));
+                }
+                Some(tls) => {
+                    let acceptor = tls.acceptor();
+                    tokio::spawn(async move {
+                        match acceptor.accept(stream).await {
+                            Ok(stream) => service.serve_connection(stream).await,
+                            Err(e) => tracing::warn!("rpc TLS handshake failed: {}", e),
+                        }
+                    });
+                }
+            }
+        }
     }
 
-    #[export_method]
-    pub async fn append(
-        &self,
-        req: AppendEntriesRequest<TypeConfig>,
-    ) -> Result<AppendEntriesResponse<u64>, toy_rpc::Error> {
-        tracing::debug!("handle append");
-        self.app
-            .raft
-            .append_entries(req)
-            .await
-            .map_err(|e| toy_rpc::Error::Internal(Box::new(e)))
+    /// Serve the requests of a single connection.
+    ///
+    /// Every request is handled in its own task, so pipelined requests don't wait for each other.
+    async fn serve_connection<S>(self: Arc<Self>, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, writer) = tokio::io::split(stream);
+        let (responses, frames) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            if let Err(e) = rpc::write_frames(writer, frames).await {
+                tracing::debug!("rpc connection write error: {}", e);
+            }
+        });
+
+        loop {
+            let (id, method, payload) = match rpc::read_frame(&mut reader).await {
+                Ok(frame) => frame,
+                Err(e) => {
+                    tracing::debug!("rpc connection closed: {}", e);
+                    return;
+                }
+            };
+            let service = self.clone();
+            let responses = responses.clone();
+            tokio::spawn(async move {
+                let (status, body) = match service.handle(method, &payload).await {
+                    Ok(body) => (rpc::STATUS_OK, body),
+                    Err(msg) => (rpc::STATUS_ERROR, msg.into_bytes()),
+                };
+                let _ = responses.send((id, status, body));
+            });
+        }
     }
 
-    #[export_method]
-    pub async fn snapshot(
-        &self,
-        req: InstallSnapshotRequest<TypeConfig>,
-    ) -> Result<InstallSnapshotResponse<u64>, toy_rpc::Error> {
-        self.app
-            .raft
-            .install_snapshot(req)
-            .await
-            .map_err(|e| toy_rpc::Error::Internal(Box::new(e)))
+    /// Dispatch a request to the Raft instance.
+    ///
+    /// Returns the encoded result of the Raft call, or a message if the request is invalid.
+    async fn handle(&self, method: u8, payload: &[u8]) -> Result<Vec<u8>, String> {
+        match method {
+            rpc::METHOD_VOTE => {
+                let req: VoteRequest<NodeId> = decode(payload)?;
+                encode(&self.app.raft.vote(req).await)
+            }
+            rpc::METHOD_APPEND => {
+                tracing::debug!("handle append");
+                let req: Traced<AppendEntriesRequest<TypeConfig>> = decode(payload)?;
+                let span = match req.traceparent {
+                    Some(traceparent) => tracing::info_span!(
+                        "raft.handle_append_entries",
+                        otel.kind = "server",
+                        traceparent = %traceparent,
+                        node_id = self.app.id,
+                        entries = req.inner.entries.len(),
+                    ),
+                    None => Span::none(),
+                };
+                encode(&self.app.raft.append_entries(req.inner).instrument(span).await)
+            }
+            rpc::METHOD_SNAPSHOT => {
+                let req: InstallSnapshotRequest<TypeConfig> = decode(payload)?;
+                encode(&self.app.raft.install_snapshot(req).await)
+            }
+            rpc::METHOD_TRANSFER_LEADER => {
+                let req: TransferLeaderRequest = decode(payload)?;
+                leadership::handle_transfer_leader(&self.app, req).await?;
+                encode(&())
+            }
+            _ => Err(format!("unknown rpc method {}", method)),
+        }
     }
 }
+
+fn decode<T: DeserializeOwned>(payload: &[u8]) -> Result<T, String> {
+    rpc::decode(payload).map_err(|e| format!("failed to decode request: {}", e))
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, String> {
+    rpc::encode(value).map_err(|e| format!("failed to encode response: {}", e))
+}
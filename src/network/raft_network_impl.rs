@@ -1,12 +1,19 @@
-use std::any::Any;
-use std::fmt::Display;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
 use openraft::error::InstallSnapshotError;
 use openraft::error::NetworkError;
 use openraft::error::RPCError;
 use openraft::error::RaftError;
 use openraft::error::RemoteError;
+use openraft::error::Timeout;
+use openraft::error::Unreachable;
+use openraft::network::Backoff;
 use openraft::network::RPCOption;
+use openraft::network::RPCTypes;
 use openraft::network::RaftNetwork;
 use openraft::network::RaftNetworkFactory;
 use openraft::raft::AppendEntriesRequest;
@@ -16,85 +23,342 @@ use openraft::raft::InstallSnapshotResponse;
 use openraft::raft::VoteRequest;
 use openraft::raft::VoteResponse;
 use openraft::AnyError;
+use openraft::EntryPayload;
 use serde::de::DeserializeOwned;
-use toy_rpc::pubsub::AckModeNone;
-use toy_rpc::Client;
+use serde::Serialize;
+use tracing::Instrument;
 
-use super::raft::RaftClientStub;
+use super::rpc;
+use super::rpc::RpcClient;
+use crate::telemetry::Traced;
+use crate::tls::TlsContext;
 use crate::Node;
 use crate::NodeId;
 use crate::TypeConfig;
 
-pub struct Network {}
+/// Delay before the first reconnect attempt to a peer that could not be reached.
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(100);
+
+/// Upper bound of the delay between two reconnect attempts.
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+/// Number of consecutive timeouts after which a peer is considered unreachable.
+const MAX_CONSECUTIVE_TIMEOUTS: u32 = 2;
+
+/// Dialing a peer that does not complete the connection within this time, or within half the
+/// time of the RPC, counts as a failed connection attempt.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Returns the delay before the next reconnect attempt after `failures` failed attempts.
+fn backoff_delay(failures: u32) -> Duration {
+    let exp = failures.saturating_sub(1).min(16);
+    RECONNECT_BACKOFF_MIN
+        .saturating_mul(1 << exp)
+        .min(RECONNECT_BACKOFF_MAX)
+}
+
+/// Connection state of a single peer.
+#[derive(Default)]
+struct Peer {
+    client: Option<Arc<RpcClient>>,
+    /// Number of failed connection attempts since the last successful one.
+    failures: u32,
+    /// No connection attempt is made before this instant.
+    retry_at: Option<Instant>,
+    /// When the peer last answered a Raft RPC.
+    last_reply: Option<Instant>,
+}
+
+impl Peer {
+    /// Drop the connection after it failed with `e`, and back off before dialing again.
+    fn fail(&mut self, addr: &str, e: &AnyError) -> Unreachable {
+        self.client = None;
+        self.failures += 1;
+        let delay = backoff_delay(self.failures);
+        self.retry_at = Some(Instant::now() + delay);
+        // Only the first failure is worth a warning, the rest would flood the logs.
+        if self.failures == 1 {
+            tracing::warn!("failed to connect to {}: {}", addr, e);
+        } else {
+            tracing::debug!(
+                "failed to connect to {} ({} attempts), retrying in {:?}: {}",
+                addr,
+                self.failures,
+                delay,
+                e
+            );
+        }
+        Unreachable::new(e)
+    }
+}
+
+/// Connections to the other Raft nodes, shared by all [`NetworkConnection`]s of a node.
+///
+/// There is at most one connection per peer. All RPCs to a peer are multiplexed over it.
+/// A peer that can't be reached is not dialed again before an exponentially growing delay.
+#[derive(Default)]
+pub struct ConnectionPool {
+    peers: Mutex<HashMap<String, Peer>>,
+    tls: Option<Arc<TlsContext>>,
+}
+
+impl ConnectionPool {
+    pub fn new(tls: Option<Arc<TlsContext>>) -> Self {
+        Self {
+            peers: Default::default(),
+            tls,
+        }
+    }
+
+    /// Returns an open connection to `addr`, dialing a new one if needed. Dialing fails after
+    /// `connect_timeout`.
+    ///
+    /// Returns [`Unreachable`] without dialing while the peer is backing off.
+    async fn get(
+        &self,
+        addr: &str,
+        connect_timeout: Duration,
+    ) -> Result<Arc<RpcClient>, Unreachable> {
+        {
+            let mut peers = self.peers.lock().unwrap();
+            let peer = peers.entry(addr.to_string()).or_default();
+            if let Some(client) = &peer.client {
+                if !client.is_closed() {
+                    return Ok(client.clone());
+                }
+                tracing::debug!("dropping broken connection to {}", addr);
+                peer.client = None;
+            }
+            if let Some(retry_at) = peer.retry_at {
+                if Instant::now() < retry_at {
+                    return Err(Unreachable::new(&AnyError::error(format!(
+                        "{} is unreachable after {} failed connection attempts",
+                        addr, peer.failures
+                    ))));
+                }
+            }
+        }
+
+        let connect = RpcClient::connect(addr, self.tls.as_deref());
+        let res = match tokio::time::timeout(connect_timeout, connect).await {
+            Ok(res) => res.map_err(|e| AnyError::new(&e)),
+            Err(_) => Err(AnyError::error(format!(
+                "connecting to {} timed out after {:?}",
+                addr, connect_timeout
+            ))),
+        };
+
+        let mut peers = self.peers.lock().unwrap();
+        let peer = peers.entry(addr.to_string()).or_default();
+        match res {
+            Ok(client) => {
+                tracing::debug!("connected to {}", addr);
+                let client = Arc::new(client);
+                peer.client = Some(client.clone());
+                peer.failures = 0;
+                peer.retry_at = None;
+                Ok(client)
+            }
+            Err(e) => Err(peer.fail(addr, &e)),
+        }
+    }
+
+    /// Drop the connection to `addr`, which failed with `e`, and back off before dialing it
+    /// again, like after a failed connection attempt.
+    fn mark_unreachable(&self, addr: &str, e: &AnyError) -> Unreachable {
+        let mut peers = self.peers.lock().unwrap();
+        peers.entry(addr.to_string()).or_default().fail(addr, e)
+    }
+
+    /// Record that `addr` answered a Raft RPC.
+    fn record_reply(&self, addr: &str) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.entry(addr.to_string()).or_default().last_reply = Some(Instant::now());
+    }
+
+    /// When `addr` last answered a Raft RPC, if it ever did.
+    fn last_reply(&self, addr: &str) -> Option<Instant> {
+        let peers = self.peers.lock().unwrap();
+        peers.get(addr).and_then(|peer| peer.last_reply)
+    }
+
+    /// Drop `client` from the pool, so that the next call dials a new connection.
+    ///
+    /// Does nothing if `client` has already been replaced by a newer connection.
+    fn invalidate(&self, addr: &str, client: &Arc<RpcClient>) {
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(peer) = peers.get_mut(addr) {
+            if peer.client.as_ref().is_some_and(|c| Arc::ptr_eq(c, client)) {
+                peer.client = None;
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Network {
+    /// The id of the node that owns this network.
+    id: NodeId,
+    pool: Arc<ConnectionPool>,
+}
+
+impl Network {
+    /// Create the network of node `id`. Peers are dialed over TLS if `tls` is given.
+    pub fn new(id: NodeId, tls: Option<Arc<TlsContext>>) -> Self {
+        Self {
+            id,
+            pool: Arc::new(ConnectionPool::new(tls)),
+        }
+    }
+
+    /// Check that the node at `addr` accepts connections.
+    pub async fn probe(&self, addr: &str, timeout: Duration) -> Result<(), AnyError> {
+        match self.pool.get(addr, timeout).await {
+            Ok(_) => Ok(()),
+            Err(e) => Err(AnyError::new(&e)),
+        }
+    }
+
+    /// When the node at `addr` last answered a Raft RPC of this node, such as a heartbeat.
+    pub fn last_reply(&self, addr: &str) -> Option<Instant> {
+        self.pool.last_reply(addr)
+    }
+
+    /// Call `method` on the node at `addr` outside of the Raft protocol, over the connection
+    /// Raft uses, and decode its result.
+    pub async fn call<Req, Resp>(
+        &self,
+        addr: &str,
+        method: u8,
+        req: &Req,
+        timeout: Duration,
+    ) -> Result<Resp, AnyError>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let payload = rpc::encode(req).map_err(|e| AnyError::new(&e))?;
+        let call = async {
+            let client = self.pool.get(addr, timeout).await.map_err(|e| AnyError::new(&e))?;
+            client.call(method, payload).await.map_err(|e| {
+                self.pool.invalidate(addr, &client);
+                AnyError::new(&e)
+            })
+        };
+        let Ok(res) = tokio::time::timeout(timeout, call).await else {
+            return Err(AnyError::error(format!(
+                "{} did not answer within {:?}",
+                addr, timeout
+            )));
+        };
+        let (status, body) = res?;
+        if status != rpc::STATUS_OK {
+            return Err(AnyError::error(String::from_utf8_lossy(&body)));
+        }
+        rpc::decode(&body).map_err(|e| AnyError::new(&e))
+    }
+}
 
-// NOTE: This could be implemented also on `Arc<ExampleNetwork>`, but since it's empty, implemented
-// directly.
 impl RaftNetworkFactory<TypeConfig> for Network {
     type Network = NetworkConnection;
 
     #[tracing::instrument(level = "debug", skip_all)]
     async fn new_client(&mut self, target: NodeId, node: &Node) -> Self::Network {
-        let addr = format!("ws://{}", node.rpc_addr);
-
-        let client = Client::dial_websocket(&addr).await.ok();
-        tracing::debug!("new_client: is_none: {}", client.is_none());
-
         NetworkConnection {
-            addr,
-            client,
+            addr: node.rpc_addr.clone(),
+            pool: self.pool.clone(),
+            id: self.id,
             target,
+            consecutive_timeouts: 0,
         }
     }
 }
 
 pub struct NetworkConnection {
     addr: String,
-    client: Option<Client<AckModeNone>>,
+    pool: Arc<ConnectionPool>,
+    id: NodeId,
     target: NodeId,
+    /// A connection to a partitioned peer may stay open but never answer. After too many
+    /// consecutive timeouts, the peer is treated as unreachable.
+    consecutive_timeouts: u32,
 }
+
 impl NetworkConnection {
-    async fn c<E: std::error::Error + DeserializeOwned>(
+    /// Call `method` on the target and decode its result.
+    ///
+    /// The whole call is bounded by the hard TTL of `option`, and dialing by a part of it, see
+    /// [`CONNECT_TIMEOUT`].
+    async fn call<Req, Resp, E>(
         &mut self,
-    ) -> Result<&Client<AckModeNone>, RPCError<NodeId, Node, E>> {
-        if self.client.is_none() {
-            self.client = Client::dial_websocket(&self.addr).await.ok();
+        method: u8,
+        action: RPCTypes,
+        req: &Req,
+        option: RPCOption,
+    ) -> Result<Resp, RPCError<NodeId, Node, RaftError<NodeId, E>>>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        E: std::error::Error + DeserializeOwned,
+    {
+        let ttl = option.hard_ttl();
+        let deadline = tokio::time::Instant::now() + ttl;
+        let client = self.pool.get(&self.addr, CONNECT_TIMEOUT.min(ttl / 2)).await;
+        let client = client.map_err(RPCError::Unreachable)?;
+
+        match tokio::time::timeout_at(deadline, self.call_inner(&client, method, req)).await {
+            Ok(res) => {
+                self.consecutive_timeouts = 0;
+                res
+            }
+            Err(_) => {
+                self.consecutive_timeouts += 1;
+                if self.consecutive_timeouts >= MAX_CONSECUTIVE_TIMEOUTS {
+                    let e = AnyError::error(format!(
+                        "{} timed out {} times in a row",
+                        self.addr, self.consecutive_timeouts
+                    ));
+                    self.consecutive_timeouts = 0;
+                    return Err(RPCError::Unreachable(self.pool.mark_unreachable(&self.addr, &e)));
+                }
+                Err(RPCError::Timeout(Timeout {
+                    action,
+                    id: self.id,
+                    target: self.target,
+                    timeout: ttl,
+                }))
+            }
         }
-        self.client
-            .as_ref()
-            .ok_or_else(|| RPCError::Network(NetworkError::from(AnyError::default())))
     }
-}
 
-#[derive(Debug)]
-struct ErrWrap(Box<dyn std::error::Error>);
+    async fn call_inner<Req, Resp, E>(
+        &self,
+        client: &Arc<RpcClient>,
+        method: u8,
+        req: &Req,
+    ) -> Result<Resp, RPCError<NodeId, Node, RaftError<NodeId, E>>>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+        E: std::error::Error + DeserializeOwned,
+    {
+        let payload = rpc::encode(req).map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
 
-impl Display for ErrWrap {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.0.fmt(f)
-    }
-}
-
-impl std::error::Error for ErrWrap {}
+        let (status, body) = client.call(method, payload).await.map_err(|e| {
+            // The connection broke, the peer is likely down.
+            self.pool.invalidate(&self.addr, client);
+            RPCError::Unreachable(Unreachable::new(&e))
+        })?;
 
-fn to_error<E: std::error::Error + 'static + Clone>(
-    e: toy_rpc::Error,
-    target: NodeId,
-) -> RPCError<NodeId, Node, E> {
-    match e {
-        toy_rpc::Error::IoError(e) => RPCError::Network(NetworkError::new(&e)),
-        toy_rpc::Error::ParseError(e) => RPCError::Network(NetworkError::new(&ErrWrap(e))),
-        toy_rpc::Error::Internal(e) => {
-            let any: &dyn Any = &e;
-            let error: &E = any.downcast_ref().unwrap();
-            RPCError::RemoteError(RemoteError::new(target, error.clone()))
+        if status != rpc::STATUS_OK {
+            let msg = String::from_utf8_lossy(&body);
+            return Err(RPCError::Network(NetworkError::new(&AnyError::error(msg))));
         }
-        e @ (toy_rpc::Error::InvalidArgument
-        | toy_rpc::Error::ServiceNotFound
-        | toy_rpc::Error::MethodNotFound
-        | toy_rpc::Error::ExecutionError(_)
-        | toy_rpc::Error::Canceled(_)
-        | toy_rpc::Error::Timeout(_)
-        | toy_rpc::Error::MaxRetriesReached(_)) => RPCError::Network(NetworkError::new(&e)),
+        self.pool.record_reply(&self.addr);
+
+        let res: Result<Resp, RaftError<NodeId, E>> =
+            rpc::decode(&body).map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
+        res.map_err(|e| RPCError::RemoteError(RemoteError::new(self.target, e)))
     }
 }
 
@@ -125,49 +389,147 @@ impl RaftNetwork<TypeConfig> for NetworkConnection {
     async fn append_entries(
         &mut self,
         req: AppendEntriesRequest<TypeConfig>,
-        _option: RPCOption,
+        option: RPCOption,
     ) -> Result<AppendEntriesResponse<NodeId>, RPCError<NodeId, Node, RaftError<NodeId>>> {
         tracing::debug!(req = debug(&req), "append_entries");
-
-        let c = self.c().await?;
-        tracing::debug!("got connection");
-
-        let raft = c.raft();
-        tracing::debug!("got raft");
-
-        raft.append(req).await.map_err(|e| to_error(e, self.target))
+        // Replicating a traced entry continues its trace. A batch of entries from several traces
+        // is attributed to the first one.
+        let traceparent = req.entries.iter().find_map(|entry| match &entry.payload {
+            EntryPayload::Normal(request) => request.traceparent,
+            _ => None,
+        });
+        let Some(traceparent) = traceparent else {
+            return self
+                .call(rpc::METHOD_APPEND, RPCTypes::AppendEntries, &req, option)
+                .await;
+        };
+        let span = tracing::info_span!(
+            "raft.append_entries",
+            otel.kind = "client",
+            traceparent = %traceparent,
+            node_id = self.id,
+            target = self.target,
+            entries = req.entries.len(),
+        );
+        let req = Traced::in_span(req, &span);
+        self.call(rpc::METHOD_APPEND, RPCTypes::AppendEntries, &req, option)
+            .instrument(span)
+            .await
     }
 
     #[tracing::instrument(level = "debug", skip_all, err(Debug))]
     async fn install_snapshot(
         &mut self,
         req: InstallSnapshotRequest<TypeConfig>,
-        _option: RPCOption,
+        option: RPCOption,
     ) -> Result<
         InstallSnapshotResponse<NodeId>,
         RPCError<NodeId, Node, RaftError<NodeId, InstallSnapshotError>>,
     > {
         tracing::debug!(req = debug(&req), "install_snapshot");
-        self.c()
-            .await?
-            .raft()
-            .snapshot(req)
+        self.call(rpc::METHOD_SNAPSHOT, RPCTypes::InstallSnapshot, &req, option)
             .await
-            .map_err(|e| to_error(e, self.target))
     }
 
     #[tracing::instrument(level = "debug", skip_all, err(Debug))]
     async fn vote(
         &mut self,
         req: VoteRequest<NodeId>,
-        _option: RPCOption,
+        option: RPCOption,
     ) -> Result<VoteResponse<NodeId>, RPCError<NodeId, Node, RaftError<NodeId>>> {
         tracing::debug!(req = debug(&req), "vote");
-        self.c()
-            .await?
-            .raft()
-            .vote(req)
+        self.call(rpc::METHOD_VOTE, RPCTypes::Vote, &req, option)
             .await
-            .map_err(|e| to_error(e, self.target))
+    }
+
+    /// Back off exponentially from a peer that returned [`Unreachable`].
+    fn backoff(&self) -> Backoff {
+        Backoff::new((1..).map(backoff_delay))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openraft::Vote;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(backoff_delay(1), RECONNECT_BACKOFF_MIN);
+        assert_eq!(backoff_delay(2), RECONNECT_BACKOFF_MIN * 2);
+        assert_eq!(backoff_delay(3), RECONNECT_BACKOFF_MIN * 4);
+        assert_eq!(backoff_delay(100), RECONNECT_BACKOFF_MAX);
+    }
+
+    #[tokio::test]
+    async fn test_dead_peer_is_not_redialed_while_backing_off() {
+        // Find a port nobody listens on.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+
+        let pool = ConnectionPool::default();
+        assert!(pool.get(&addr, CONNECT_TIMEOUT).await.is_err());
+        assert!(pool.get(&addr, CONNECT_TIMEOUT).await.is_err());
+
+        let peers = pool.peers.lock().unwrap();
+        let peer = &peers[&addr];
+        assert_eq!(peer.failures, 1);
+        assert!(peer.retry_at.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_broken_connection_is_dropped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let pool = ConnectionPool::default();
+        let accept = tokio::spawn(async move { listener.accept().await.unwrap() });
+        let first = pool.get(&addr, CONNECT_TIMEOUT).await.unwrap();
+        // Close the server side of the connection.
+        drop(accept.await.unwrap());
+        assert!(first.call(rpc::METHOD_VOTE, vec![]).await.is_err());
+        assert!(first.is_closed());
+
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        let accept = tokio::spawn(async move { listener.accept().await.unwrap() });
+        let second = pool.get(&addr, CONNECT_TIMEOUT).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        drop(accept);
+    }
+
+    async fn vote(
+        conn: &mut NetworkConnection,
+    ) -> Result<VoteResponse<NodeId>, RPCError<NodeId, Node, RaftError<NodeId>>> {
+        let req = VoteRequest::new(Vote::new(1, 1), None);
+        conn.vote(req, RPCOption::new(Duration::from_millis(50))).await
+    }
+
+    #[tokio::test]
+    async fn test_silent_peer_becomes_unreachable() {
+        // The peer accepts the connection but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let accept = tokio::spawn(async move {
+            let _conn = listener.accept().await.unwrap();
+            std::future::pending::<()>().await
+        });
+
+        let mut network = Network::new(1, None);
+        let node = Node {
+            rpc_addr: addr.clone(),
+            api_addr: String::new(),
+        };
+        let mut conn = network.new_client(2, &node).await;
+        for _ in 1..MAX_CONSECUTIVE_TIMEOUTS {
+            assert!(matches!(vote(&mut conn).await, Err(RPCError::Timeout(_))));
+        }
+        assert!(matches!(vote(&mut conn).await, Err(RPCError::Unreachable(_))));
+        // The peer is not dialed again while backing off.
+        assert!(matches!(vote(&mut conn).await, Err(RPCError::Unreachable(_))));
+        assert_eq!(network.pool.peers.lock().unwrap()[&addr].failures, 1);
+        assert_eq!(network.last_reply(&addr), None);
+        accept.abort();
     }
 }
@@ -0,0 +1,325 @@
+//! Binary RPC transport used between Raft nodes.
+//!
+//! Every message is a frame on a plain TCP connection:
+//!
+//! ```text
+//! | len: u32 | request id: u64 | tag: u8 | payload: [u8; len - 9] |
+//! ```
+//!
+//! All integers are big endian. For a request the tag is the method to call, for a response it
+//! is a status. The request id matches responses to requests, so any number of requests can be
+//! in flight on a single connection and responses may arrive out of order.
+//!
+//! Payloads are encoded with MessagePack using named fields. Unlike bincode, it is
+//! self-describing and therefore supports the `#[serde(flatten)]` attributes used by openraft.
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufWriter;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+use crate::tls::TlsContext;
+
+/// Request tag of a `Vote` RPC.
+pub const METHOD_VOTE: u8 = 1;
+/// Request tag of an `AppendEntries` RPC.
+pub const METHOD_APPEND: u8 = 2;
+/// Request tag of an `InstallSnapshot` RPC.
+pub const METHOD_SNAPSHOT: u8 = 3;
+/// Request tag of a leadership transfer, see [`crate::leadership`].
+pub const METHOD_TRANSFER_LEADER: u8 = 4;
+
+/// Response tag of a handled request. The payload is the encoded result of the handler.
+pub const STATUS_OK: u8 = 0;
+/// Response tag of a request that could not be handled. The payload is a UTF-8 message.
+pub const STATUS_ERROR: u8 = 1;
+
+/// Upper bound of a frame, to avoid allocating arbitrary amounts of memory for a corrupt length.
+pub const MAX_FRAME_LEN: usize = 256 * 1024 * 1024;
+
+/// Length of the request id and the tag.
+const HEADER_LEN: usize = 9;
+
+/// A decoded frame: request id, tag and payload.
+pub type Frame = (u64, u8, Vec<u8>);
+
+pub fn encode<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, rmp_serde::encode::Error> {
+    rmp_serde::to_vec_named(value)
+}
+
+pub fn decode<T: DeserializeOwned>(buf: &[u8]) -> Result<T, rmp_serde::decode::Error> {
+    rmp_serde::from_slice(buf)
+}
+
+pub async fn write_frame<W>(w: &mut W, id: u64, tag: u8, payload: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let len = HEADER_LEN + payload.len();
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame of {} bytes exceeds the limit of {}", len, MAX_FRAME_LEN),
+        ));
+    }
+    w.write_u32(len as u32).await?;
+    w.write_u64(id).await?;
+    w.write_u8(tag).await?;
+    w.write_all(payload).await
+}
+
+pub async fn read_frame<R>(r: &mut R) -> io::Result<Frame>
+where
+    R: AsyncRead + Unpin,
+{
+    let len = r.read_u32().await? as usize;
+    if !(HEADER_LEN..=MAX_FRAME_LEN).contains(&len) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid frame length {}", len),
+        ));
+    }
+    let id = r.read_u64().await?;
+    let tag = r.read_u8().await?;
+    let mut payload = vec![0; len - HEADER_LEN];
+    r.read_exact(&mut payload).await?;
+    Ok((id, tag, payload))
+}
+
+/// Write the frames received on `frames` until the channel or the connection is closed.
+///
+/// Frames are buffered and only flushed once no more frames are queued, so that pipelined
+/// messages are sent in as few packets as possible.
+pub async fn write_frames<W>(w: W, mut frames: mpsc::UnboundedReceiver<Frame>) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut w = BufWriter::new(w);
+    while let Some((id, tag, payload)) = frames.recv().await {
+        write_frame(&mut w, id, tag, &payload).await?;
+        while let Ok((id, tag, payload)) = frames.try_recv() {
+            write_frame(&mut w, id, tag, &payload).await?;
+        }
+        w.flush().await?;
+    }
+    Ok(())
+}
+
+type Pending = Arc<Mutex<HashMap<u64, oneshot::Sender<(u8, Vec<u8>)>>>>;
+
+/// A connection to an RPC server that supports many concurrent requests.
+///
+/// The connection is closed when the client is dropped.
+pub struct RpcClient {
+    next_id: AtomicU64,
+    outgoing: mpsc::UnboundedSender<Frame>,
+    pending: Pending,
+    closed: Arc<AtomicBool>,
+    writer: JoinHandle<()>,
+    reader: JoinHandle<()>,
+}
+
+impl RpcClient {
+    /// Connect to the server at `addr`, over TLS if `tls` is given.
+    pub async fn connect(addr: &str, tls: Option<&TlsContext>) -> io::Result<Self> {
+        match tls {
+            Some(tls) => Ok(Self::new(tls.connect(addr).await?)),
+            None => {
+                let stream = TcpStream::connect(addr).await?;
+                stream.set_nodelay(true)?;
+                Ok(Self::new(stream))
+            }
+        }
+    }
+
+    /// Start a client on an established connection.
+    pub fn new<S>(stream: S) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (mut reader, writer) = tokio::io::split(stream);
+
+        let (outgoing, frames) = mpsc::unbounded_channel();
+        let pending: Pending = Default::default();
+        let closed = Arc::new(AtomicBool::new(false));
+
+        let writer = tokio::spawn({
+            let pending = pending.clone();
+            let closed = closed.clone();
+            async move {
+                if let Err(e) = write_frames(writer, frames).await {
+                    tracing::debug!("rpc connection write error: {}", e);
+                }
+                // The requests that were not sent won't get a response.
+                closed.store(true, Ordering::SeqCst);
+                pending.lock().unwrap().clear();
+            }
+        });
+
+        let reader = tokio::spawn({
+            let pending = pending.clone();
+            let closed = closed.clone();
+            async move {
+                loop {
+                    match read_frame(&mut reader).await {
+                        Ok((id, tag, payload)) => {
+                            let tx = pending.lock().unwrap().remove(&id);
+                            if let Some(tx) = tx {
+                                let _ = tx.send((tag, payload));
+                            }
+                        }
+                        Err(e) => {
+                            tracing::debug!("rpc connection read error: {}", e);
+                            break;
+                        }
+                    }
+                }
+                // Fail all requests still waiting for a response.
+                closed.store(true, Ordering::SeqCst);
+                pending.lock().unwrap().clear();
+            }
+        });
+
+        Self {
+            next_id: AtomicU64::new(0),
+            outgoing,
+            pending,
+            closed,
+            writer,
+            reader,
+        }
+    }
+
+    /// Returns `true` if the connection is broken and a new one has to be dialed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Send a request and wait for its response.
+    ///
+    /// Returns the status tag and the payload of the response.
+    pub async fn call(&self, method: u8, payload: Vec<u8>) -> io::Result<(u8, Vec<u8>)> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = oneshot::channel();
+        self.pending.lock().unwrap().insert(id, tx);
+        // Forget the request if the caller stops waiting, e.g., on timeout.
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            id,
+        };
+
+        if self.is_closed() || self.outgoing.send((id, method, payload)).is_err() {
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "rpc connection is closed",
+            ));
+        }
+
+        rx.await.map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "rpc connection closed before the response arrived",
+            )
+        })
+    }
+}
+
+impl Drop for RpcClient {
+    fn drop(&mut self) {
+        // The reader would otherwise keep the connection open until the server closes it.
+        self.writer.abort();
+        self.reader.abort();
+    }
+}
+
+struct PendingGuard<'a> {
+    pending: &'a Pending,
+    id: u64,
+}
+
+impl Drop for PendingGuard<'_> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        let mut buf = Vec::new();
+        write_frame(&mut buf, 7, METHOD_APPEND, b"hello").await.unwrap();
+        assert_eq!(buf.len(), 4 + HEADER_LEN + 5);
+
+        let frame = read_frame(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(frame, (7, METHOD_APPEND, b"hello".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_frame_length() {
+        let buf = 3u32.to_be_bytes();
+        let err = read_frame(&mut buf.as_slice()).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_calls() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        // Echo server that answers requests in reverse order.
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+            let mut frames = Vec::new();
+            for _ in 0..3 {
+                frames.push(read_frame(&mut reader).await.unwrap());
+            }
+            for (id, _, payload) in frames.into_iter().rev() {
+                write_frame(&mut writer, id, STATUS_OK, &payload).await.unwrap();
+            }
+        });
+
+        let client = RpcClient::connect(&addr, None).await.unwrap();
+        let calls = (0..3u8).map(|i| client.call(METHOD_VOTE, vec![i]));
+        let results = futures::future::join_all(calls).await;
+        for (i, res) in results.into_iter().enumerate() {
+            assert_eq!(res.unwrap(), (STATUS_OK, vec![i as u8]));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drop_closes_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+
+        let client = RpcClient::connect(&addr, None).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        drop(client);
+
+        let res = tokio::time::timeout(Duration::from_secs(5), read_frame(&mut stream)).await;
+        assert_eq!(
+            res.unwrap().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}
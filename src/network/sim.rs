@@ -0,0 +1,489 @@
+//! Simulated network for the Raft RPCs of nodes running in the same process.
+//!
+//! [`SimNetwork`] hands the RPCs of a node directly to the [`ExampleRaft`] of the target,
+//! registered under its RPC address, instead of sending them over TCP. On the way, messages go
+//! through the faults of the network:
+//!
+//! - partitions: links cut by [`SimNetwork::cut`] or [`SimNetwork::isolate`] lose all messages
+//!   until [`SimNetwork::heal`],
+//! - drops: a request, or its response, is lost with probability [`Faults::drop_rate`],
+//! - delays: a message is delivered after a delay between [`Faults::min_delay`] and
+//!   [`Faults::max_delay`],
+//! - reordering: a message is held back for an extra `max_delay` with probability
+//!   [`Faults::reorder_rate`], so that the messages sent after it overtake it,
+//! - duplicates: a request is delivered a second time, a little later, with probability
+//!   [`Faults::duplicate_rate`]. The response to the copy is discarded.
+//!
+//! The sender of a lost message gets a timeout once the TTL of the RPC expires, like on a real
+//! network. RPCs to a node that is not registered, e.g., because it is shut down, fail right
+//! away with [`Unreachable`].
+//!
+//! Every link draws its faults from its own RNG, seeded from the seed of the network and the
+//! addresses of the link, so with the same seed and faults the n-th message sent on a link
+//! always meets the same fate, however the messages of the other links interleave with it, and
+//! whatever the build. Run on a single thread with a paused clock, e.g., with
+//! `#[tokio::test(flavor = "current_thread", start_paused = true)]`, the delays don't depend on
+//! the load of the machine either.
+//!
+//! Only Raft traffic is simulated. The HTTP API, and the RPCs that are sent outside of Raft,
+//! e.g., to transfer the leadership, still go over the sockets of the nodes.
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use openraft::error::InstallSnapshotError;
+use openraft::error::RPCError;
+use openraft::error::RaftError;
+use openraft::error::RemoteError;
+use openraft::error::Timeout;
+use openraft::error::Unreachable;
+use openraft::network::RPCOption;
+use openraft::network::RPCTypes;
+use openraft::network::RaftNetwork;
+use openraft::network::RaftNetworkFactory;
+use openraft::raft::AppendEntriesRequest;
+use openraft::raft::AppendEntriesResponse;
+use openraft::raft::InstallSnapshotRequest;
+use openraft::raft::InstallSnapshotResponse;
+use openraft::raft::VoteRequest;
+use openraft::raft::VoteResponse;
+use openraft::AnyError;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+use crate::ExampleRaft;
+use crate::Node;
+use crate::NodeId;
+use crate::TypeConfig;
+
+/// Probabilities and delays of the faults of a [`SimNetwork`]. The default is a network without
+/// faults.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Faults {
+    /// Probability that a request is lost, and, independently, that its response is.
+    pub drop_rate: f64,
+    /// Probability that a request is delivered twice.
+    pub duplicate_rate: f64,
+    /// Probability that a message is held back for an extra `max_delay`.
+    pub reorder_rate: f64,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Faults {
+    /// A network that loses, duplicates and reorders a few messages, and delays all of them by
+    /// up to 20 ms.
+    pub fn lossy() -> Self {
+        Self {
+            drop_rate: 0.05,
+            duplicate_rate: 0.05,
+            reorder_rate: 0.05,
+            min_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(20),
+        }
+    }
+
+    fn delay(&self, rng: &mut StdRng) -> Duration {
+        if self.max_delay <= self.min_delay {
+            return self.min_delay;
+        }
+        rng.gen_range(self.min_delay..=self.max_delay)
+    }
+}
+
+/// Counts of the messages sent through a [`SimNetwork`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimStats {
+    /// Requests delivered to their target, duplicates excluded.
+    pub delivered: u64,
+    /// Requests and responses lost to [`Faults::drop_rate`].
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    /// Requests lost to a partition.
+    pub partitioned: u64,
+}
+
+/// The fate of a request sent on a link, drawn from the RNG of the link.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fate {
+    lose_request: bool,
+    lose_response: bool,
+    delay: Duration,
+    /// Delay of the second copy of the request, if it is duplicated.
+    duplicate: Option<Duration>,
+}
+
+/// What happens to a request.
+enum Delivery {
+    /// The target is not registered.
+    Down,
+    /// The request is lost, to a partition or a drop.
+    Lost,
+    Deliver { raft: ExampleRaft, fate: Fate },
+}
+
+struct State {
+    seed: u64,
+    faults: Faults,
+    /// The Raft instances of the running nodes, by RPC address.
+    nodes: HashMap<String, ExampleRaft>,
+    /// Directed links that lose all messages.
+    cut: BTreeSet<(String, String)>,
+    /// Nodes that can't send or receive any message.
+    isolated: BTreeSet<String>,
+    rngs: HashMap<(String, String), StdRng>,
+    stats: SimStats,
+}
+
+impl State {
+    fn send(&mut self, from: &str, to: &str) -> Delivery {
+        let Some(raft) = self.nodes.get(to).cloned() else {
+            return Delivery::Down;
+        };
+        let partitioned = self.isolated.contains(from)
+            || self.isolated.contains(to)
+            || self.cut.contains(&(from.to_string(), to.to_string()));
+        if partitioned {
+            self.stats.partitioned += 1;
+            return Delivery::Lost;
+        }
+
+        let fate = self.draw(from, to);
+        if fate.lose_request {
+            self.stats.dropped += 1;
+            return Delivery::Lost;
+        }
+        self.stats.delivered += 1;
+        if fate.lose_response {
+            self.stats.dropped += 1;
+        }
+        if fate.duplicate.is_some() {
+            self.stats.duplicated += 1;
+        }
+        Delivery::Deliver { raft, fate }
+    }
+
+    fn draw(&mut self, from: &str, to: &str) -> Fate {
+        let seed = self.seed;
+        let faults = &self.faults;
+        let rng = self
+            .rngs
+            .entry((from.to_string(), to.to_string()))
+            .or_insert_with(|| StdRng::seed_from_u64(link_seed(seed, from, to)));
+        // Draw every value for every message, so that the fate of a message doesn't depend on
+        // the fate of the messages before it.
+        let lose_request = rng.gen_bool(faults.drop_rate);
+        let lose_response = rng.gen_bool(faults.drop_rate);
+        let duplicate = rng.gen_bool(faults.duplicate_rate);
+        let reorder = rng.gen_bool(faults.reorder_rate);
+        let mut delay = faults.delay(rng);
+        let duplicate_delay = delay + faults.delay(rng);
+        if reorder {
+            delay += faults.max_delay;
+            self.stats.reordered += 1;
+        }
+        Fate {
+            lose_request,
+            lose_response,
+            delay,
+            duplicate: duplicate.then_some(duplicate_delay),
+        }
+    }
+}
+
+/// A simulated network connecting the Raft instances of a process, see the module
+/// documentation.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<State>>,
+}
+
+impl fmt::Debug for SimNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SimNetwork")
+            .field("seed", &self.seed())
+            .finish()
+    }
+}
+
+impl SimNetwork {
+    /// A network without faults, whose faults will be drawn from `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                seed,
+                faults: Faults::default(),
+                nodes: HashMap::new(),
+                cut: BTreeSet::new(),
+                isolated: BTreeSet::new(),
+                rngs: HashMap::new(),
+                stats: SimStats::default(),
+            })),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.state.lock().unwrap().seed
+    }
+
+    /// Apply `faults` to the messages sent from now on.
+    ///
+    /// # Panics
+    ///
+    /// If a rate is not between 0 and 1.
+    pub fn set_faults(&self, faults: Faults) {
+        for rate in [faults.drop_rate, faults.duplicate_rate, faults.reorder_rate] {
+            assert!((0.0..=1.0).contains(&rate), "invalid fault rate {}", rate);
+        }
+        self.state.lock().unwrap().faults = faults;
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// The network factory of the node `id`, whose RPC address is `addr`.
+    pub fn node(&self, id: NodeId, addr: impl Into<String>) -> SimNode {
+        SimNode {
+            id,
+            addr: addr.into(),
+            network: self.clone(),
+        }
+    }
+
+    /// Deliver the RPCs sent to `addr` to `raft`.
+    pub fn register(&self, addr: impl Into<String>, raft: ExampleRaft) {
+        self.state.lock().unwrap().nodes.insert(addr.into(), raft);
+    }
+
+    /// Fail the RPCs sent to `addr`, as if the node was down.
+    pub fn unregister(&self, addr: &str) {
+        self.state.lock().unwrap().nodes.remove(addr);
+    }
+
+    /// Lose all messages between `a` and `b`, in both directions.
+    pub fn cut(&self, a: &str, b: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.cut.insert((a.to_string(), b.to_string()));
+        state.cut.insert((b.to_string(), a.to_string()));
+    }
+
+    /// Lose all messages sent by or to `addr`.
+    pub fn isolate(&self, addr: &str) {
+        self.state.lock().unwrap().isolated.insert(addr.to_string());
+    }
+
+    /// Remove all partitions.
+    pub fn heal(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.cut.clear();
+        state.isolated.clear();
+    }
+}
+
+/// The [`RaftNetworkFactory`] of a node connected to a [`SimNetwork`].
+#[derive(Clone)]
+pub struct SimNode {
+    id: NodeId,
+    addr: String,
+    network: SimNetwork,
+}
+
+impl RaftNetworkFactory<TypeConfig> for SimNode {
+    type Network = SimConnection;
+
+    async fn new_client(&mut self, target: NodeId, node: &Node) -> Self::Network {
+        SimConnection {
+            id: self.id,
+            addr: self.addr.clone(),
+            target,
+            target_addr: node.rpc_addr.clone(),
+            network: self.network.clone(),
+        }
+    }
+}
+
+pub struct SimConnection {
+    id: NodeId,
+    addr: String,
+    target: NodeId,
+    target_addr: String,
+    network: SimNetwork,
+}
+
+impl SimConnection {
+    /// Send `req` through the network and `call` the Raft instance of the target with it.
+    async fn send<Req, Resp, E, F, Fut>(
+        &self,
+        action: RPCTypes,
+        req: Req,
+        option: RPCOption,
+        call: F,
+    ) -> Result<Resp, RPCError<NodeId, Node, RaftError<NodeId, E>>>
+    where
+        Req: Clone + Send + 'static,
+        E: std::error::Error,
+        F: Fn(ExampleRaft, Req) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = Result<Resp, RaftError<NodeId, E>>> + Send + 'static,
+    {
+        let ttl = option.hard_ttl();
+        let timeout = || {
+            RPCError::Timeout(Timeout {
+                action,
+                id: self.id,
+                target: self.target,
+                timeout: ttl,
+            })
+        };
+
+        let delivery = self.network.state.lock().unwrap().send(&self.addr, &self.target_addr);
+        match delivery {
+            Delivery::Down => Err(RPCError::Unreachable(Unreachable::new(&AnyError::error(
+                format!("{} is down", self.target_addr),
+            )))),
+            Delivery::Lost => {
+                tokio::time::sleep(ttl).await;
+                Err(timeout())
+            }
+            Delivery::Deliver { raft, fate } => {
+                let delay = fate.delay;
+                if let Some(duplicate_delay) = fate.duplicate {
+                    let (call, raft, req) = (call.clone(), raft.clone(), req.clone());
+                    tokio::spawn(async move {
+                        tokio::time::sleep(duplicate_delay).await;
+                        let _ = call(raft, req).await;
+                    });
+                }
+                tokio::time::sleep(delay).await;
+                let res = call(raft, req).await;
+                if fate.lose_response {
+                    tokio::time::sleep(ttl.saturating_sub(delay)).await;
+                    return Err(timeout());
+                }
+                res.map_err(|e| RPCError::RemoteError(RemoteError::new(self.target, e)))
+            }
+        }
+    }
+}
+
+impl RaftNetwork<TypeConfig> for SimConnection {
+    async fn append_entries(
+        &mut self,
+        req: AppendEntriesRequest<TypeConfig>,
+        option: RPCOption,
+    ) -> Result<AppendEntriesResponse<NodeId>, RPCError<NodeId, Node, RaftError<NodeId>>> {
+        self.send(RPCTypes::AppendEntries, req, option, |raft, req| async move {
+            raft.append_entries(req).await
+        })
+        .await
+    }
+
+    async fn install_snapshot(
+        &mut self,
+        req: InstallSnapshotRequest<TypeConfig>,
+        option: RPCOption,
+    ) -> Result<
+        InstallSnapshotResponse<NodeId>,
+        RPCError<NodeId, Node, RaftError<NodeId, InstallSnapshotError>>,
+    > {
+        self.send(RPCTypes::InstallSnapshot, req, option, |raft, req| async move {
+            raft.install_snapshot(req).await
+        })
+        .await
+    }
+
+    async fn vote(
+        &mut self,
+        req: VoteRequest<NodeId>,
+        option: RPCOption,
+    ) -> Result<VoteResponse<NodeId>, RPCError<NodeId, Node, RaftError<NodeId>>> {
+        self.send(RPCTypes::Vote, req, option, |raft, req| async move {
+            raft.vote(req).await
+        })
+        .await
+    }
+}
+
+/// Seed of the RNG of the link from `from` to `to`.
+///
+/// Hashed with FNV-1a, which, unlike `DefaultHasher`, gives the same seeds on every platform and
+/// Rust version.
+fn link_seed(seed: u64, from: &str, to: &str) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const PRIME: u64 = 0x100000001b3;
+    // The separator keeps `("ab", "c")` and `("a", "bc")` apart.
+    let bytes = seed.to_le_bytes().into_iter().chain(from.bytes()).chain([0]).chain(to.bytes());
+    bytes.fold(OFFSET_BASIS, |hash, byte| (hash ^ byte as u64).wrapping_mul(PRIME))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn draws(network: &SimNetwork, from: &str, to: &str, n: usize) -> Vec<Fate> {
+        let mut state = network.state.lock().unwrap();
+        (0..n).map(|_| state.draw(from, to)).collect()
+    }
+
+    #[test]
+    fn test_fates_are_reproducible() {
+        let a = SimNetwork::new(42);
+        a.set_faults(Faults::lossy());
+        let b = SimNetwork::new(42);
+        b.set_faults(Faults::lossy());
+
+        // The fates of a link don't depend on the traffic of the other links.
+        let first = draws(&a, "n1", "n2", 200);
+        draws(&b, "n2", "n1", 50);
+        draws(&b, "n1", "n3", 50);
+        assert_eq!(draws(&b, "n1", "n2", 200), first);
+
+        // Faults happen, at about their rates.
+        let lost = first.iter().filter(|fate| fate.lose_request).count();
+        assert!(lost > 0 && lost < 40, "{} lost", lost);
+        assert!(first.iter().any(|fate| fate.duplicate.is_some()));
+        assert!(first.iter().all(|fate| fate.delay >= Duration::from_millis(1)));
+
+        let other_seed = SimNetwork::new(43);
+        other_seed.set_faults(Faults::lossy());
+        assert_ne!(draws(&other_seed, "n1", "n2", 200), first);
+    }
+
+    #[test]
+    fn test_link_seeds_are_stable() {
+        assert_eq!(link_seed(42, "n1", "n2"), 0x0b6c_d542_790e_fe72);
+        assert_ne!(link_seed(42, "n1", "n2"), link_seed(42, "n2", "n1"));
+        assert_ne!(link_seed(42, "ab", "c"), link_seed(42, "a", "bc"));
+    }
+
+    #[test]
+    fn test_default_network_has_no_faults() {
+        let network = SimNetwork::new(7);
+        for fate in draws(&network, "n1", "n2", 100) {
+            assert_eq!(
+                fate,
+                Fate {
+                    lose_request: false,
+                    lose_response: false,
+                    delay: Duration::ZERO,
+                    duplicate: None,
+                }
+            );
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid fault rate")]
+    fn test_invalid_rate() {
+        SimNetwork::new(7).set_faults(Faults {
+            drop_rate: 1.5,
+            ..Faults::default()
+        });
+    }
+}
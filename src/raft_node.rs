@@ -1,20 +1,38 @@
+use std::collections::BTreeMap;
 use std::collections::BTreeSet;
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
-use openraft::error::NetworkError;
-use openraft::error::RPCError;
-use openraft::error::RemoteError;
-use openraft::error::Unreachable;
+use openraft::LogId;
 use openraft::RaftMetrics;
-use openraft::TryAsRef;
+use reqwest::Certificate;
 use reqwest::Client;
+use reqwest::Identity;
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::backup::Checkpoint;
 use crate::carp::Carp;
+use crate::export::ShardDump;
+use crate::load_balancer::MovedKeys;
+use crate::load_balancer::MovedKeysRequest;
+use crate::load_balancer::ShardLoad;
+use crate::membership::ChangeProgress;
+use crate::membership::MembershipStatus;
+use crate::namespace::Namespace;
+use crate::namespace::Quota;
+use crate::network::api::ScanRequest;
+use crate::network::error::ClientError;
+use crate::network::error::ErrorResponse;
+use crate::telemetry;
+use crate::telemetry::TRACEPARENT_HEADER;
+use crate::tls::TlsConfig;
 use crate::typ;
+use crate::value::Bytes;
 use crate::Node;
 use crate::NodeId;
 use crate::Request;
@@ -22,13 +40,107 @@ use crate::Request;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Empty {}
 
+/// The PEM files an HTTP client was built from.
+#[derive(Default, PartialEq)]
+struct Pem {
+    ca: Vec<u8>,
+    /// The private key followed by the certificate chain.
+    identity: Vec<u8>,
+}
+
+impl Pem {
+    fn read(tls: &TlsConfig) -> std::io::Result<Self> {
+        let ca = std::fs::read(tls.ca_path.as_ref().unwrap_or(&tls.cert_path))?;
+        let mut identity = std::fs::read(&tls.key_path)?;
+        identity.extend(std::fs::read(&tls.cert_path)?);
+        Ok(Self { ca, identity })
+    }
+
+    fn build_client(&self) -> reqwest::Result<Client> {
+        let mut builder = Client::builder()
+            .use_rustls_tls()
+            .tls_built_in_root_certs(false)
+            .identity(Identity::from_pem(&self.identity)?);
+        for cert in Certificate::from_pem_bundle(&self.ca)? {
+            builder = builder.add_root_certificate(cert);
+        }
+        builder.build()
+    }
+}
+
+/// The HTTP client shared by all the [`RaftNode`]s of a transport.
+///
+/// With TLS, the PEM files are checked for changes at most every `reload_interval_ms`, when a
+/// request is sent, and the client is rebuilt if they changed. Like on the nodes, new
+/// connections use the new certificates.
+struct Transport {
+    tls: Option<TlsConfig>,
+    state: Mutex<TransportState>,
+}
+
+struct TransportState {
+    client: Client,
+    pem: Pem,
+    checked_at: Instant,
+}
+
+impl Transport {
+    fn new(tls: Option<TlsConfig>, client: Client, pem: Pem) -> Self {
+        Self {
+            tls,
+            state: Mutex::new(TransportState {
+                client,
+                pem,
+                checked_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// The client to send a request with, rebuilt first if the certificates changed.
+    fn client(&self) -> Client {
+        let mut state = self.state.lock().unwrap();
+        let Some(tls) = &self.tls else {
+            return state.client.clone();
+        };
+        if state.checked_at.elapsed() < Duration::from_millis(tls.reload_interval_ms) {
+            return state.client.clone();
+        }
+        state.checked_at = Instant::now();
+
+        let reloaded = Pem::read(tls).map_err(|e| e.to_string()).and_then(|pem| {
+            if pem == state.pem {
+                return Ok(None);
+            }
+            let client = pem.build_client().map_err(|e| e.to_string())?;
+            Ok(Some((client, pem)))
+        });
+        match reloaded {
+            Ok(Some((client, pem))) => {
+                tracing::info!("reloaded the TLS certificates of the client");
+                state.client = client;
+                state.pem = pem;
+            }
+            Ok(None) => {}
+            Err(e) => tracing::warn!("failed to reload the TLS certificates of the client: {}", e),
+        }
+        state.client.clone()
+    }
+}
+
+#[derive(Clone)]
 pub struct RaftNode {
     /// The leader node to send request to.
     ///
     /// All traffic should be sent to the leader in a cluster.
     pub leader: Arc<Mutex<(NodeId, String)>>,
 
-    pub inner: Client,
+    transport: Arc<Transport>,
+
+    /// `https` if the nodes serve their API over TLS, `http` otherwise.
+    scheme: &'static str,
+
+    /// Bearer token sent with every request, for nodes that require authentication.
+    token: Option<String>,
 }
 
 impl RaftNode {
@@ -36,7 +148,46 @@ impl RaftNode {
     pub fn new(leader_id: NodeId, leader_addr: String) -> Self {
         Self {
             leader: Arc::new(Mutex::new((leader_id, leader_addr))),
-            inner: Client::new(),
+            transport: Arc::new(Transport::new(None, Client::new(), Pem::default())),
+            scheme: "http",
+            token: None,
+        }
+    }
+
+    /// Create a client that talks to nodes serving their API over TLS.
+    ///
+    /// The node certificates are verified against the CA of `tls`. The certificate of `tls` is
+    /// presented as client certificate, for nodes that require mutual TLS. The files are
+    /// reloaded when they change, see [`TlsConfig::reload_interval_ms`].
+    pub fn with_tls(
+        leader_id: NodeId,
+        leader_addr: String,
+        tls: &TlsConfig,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let pem = Pem::read(tls)?;
+        let client = pem.build_client()?;
+
+        Ok(Self {
+            leader: Arc::new(Mutex::new((leader_id, leader_addr))),
+            transport: Arc::new(Transport::new(Some(tls.clone()), client, pem)),
+            scheme: "https",
+            token: None,
+        })
+    }
+
+    /// Authenticate all requests with `token`.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Create a client for another node that uses the same transport as this one.
+    pub fn with_same_transport(&self, leader_id: NodeId, leader_addr: String) -> Self {
+        Self {
+            leader: Arc::new(Mutex::new((leader_id, leader_addr))),
+            transport: self.transport.clone(),
+            scheme: self.scheme,
+            token: self.token.clone(),
         }
     }
 
@@ -51,35 +202,46 @@ impl RaftNode {
     pub async fn write(
         &self,
         req: &Request,
-    ) -> Result<typ::ClientWriteResponse, typ::RPCError<typ::ClientWriteError>> {
+    ) -> Result<typ::ClientWriteResponse, ClientError> {
         self.send_rpc_to_leader("api/write", Some(req)).await
     }
 
     /// Read value by key, in an inconsistent mode.
     ///
     /// This method may return stale value because it does not force to read on a legal leader.
-    pub async fn read(&self, req: &String) -> Result<String, typ::RPCError> {
-        self.do_send_rpc_to_leader("api/read", Some(req)).await
+    /// A key that does not exist returns [`ClientError::NotFound`].
+    pub async fn read(&self, key: impl AsRef<[u8]>) -> Result<Bytes, ClientError> {
+        let req = Bytes::from(key.as_ref());
+        self.do_send_rpc_to_leader("api/read", Some(&req)).await
     }
 
     /// Consistent Read value by key, in an inconsistent mode.
     ///
-    /// This method MUST return consistent value or CheckIsLeaderError.
+    /// This method MUST return consistent value or [`ClientError::NotLeader`]. A key that does
+    /// not exist returns [`ClientError::NotFound`].
     pub async fn consistent_read(
         &self,
-        req: &String,
-    ) -> Result<String, typ::RPCError<typ::CheckIsLeaderError>> {
-        self.do_send_rpc_to_leader("api/consistent_read", Some(req))
+        key: impl AsRef<[u8]>,
+    ) -> Result<Bytes, ClientError> {
+        let req = Bytes::from(key.as_ref());
+        self.do_send_rpc_to_leader("api/consistent_read", Some(&req))
             .await
     }
 
+    /// List the keys of the shard of the node, in order, with their values.
+    ///
+    /// Like [`read`], this method may return stale values.
+    pub async fn scan(&self, req: &ScanRequest) -> Result<Vec<(Bytes, Bytes)>, ClientError> {
+        self.do_send_rpc_to_leader("api/scan", Some(req)).await
+    }
+
     /// Get the current hash ring of the Raft cluster.
     ///
     /// This method retrieves the hash ring, which is used to determine the cluster responsible for a given key.
     /// The hash ring is a data structure that helps in distributing the load evenly across the Raft clusters.
     pub async fn get_hash_ring(
         &self
-    ) -> Result<Carp, typ::RPCError> {
+    ) -> Result<Carp, ClientError> {
         self.do_send_rpc_to_leader("api/get_hash_ring", None::<&()>)
             .await
     }
@@ -90,11 +252,18 @@ impl RaftNode {
     ///
     /// This method updates the hash ring, which is used to determine the cluster responsible for a given key.
     /// The hash ring is a data structure that helps in distributing the load evenly across the Raft clusters.
-    pub async fn update_hash_ring(
-        &self,
-        req: Carp,
-    ) -> Result<typ::ClientWriteResponse, typ::RPCError<typ::ClientWriteError>> {
-        self.send_rpc_to_leader("cluster/update-hash-ring", Some(&req))
+    ///
+    /// The hash ring is not replicated by Raft, so it has to be sent to every node.
+    pub async fn update_hash_ring(&self, req: Carp) -> Result<(), ClientError> {
+        self.do_send_rpc_to_leader("cluster/update-hash-ring", Some(&req))
+            .await
+    }
+
+    /// Like [`Self::update_hash_ring`], for a ring derived from the ring with `config_id`
+    /// `expected`. Fails with [`ClientError::Conflict`] if the node has a newer ring than that.
+    pub async fn update_hash_ring_if(&self, req: Carp, expected: u64) -> Result<(), ClientError> {
+        let uri = format!("cluster/update-hash-ring?expected_config_id={}", expected);
+        self.do_send_rpc_to_leader(&uri, Some(&req))
             .await
     }
 
@@ -104,7 +273,7 @@ impl RaftNode {
     /// With a initialized cluster, new node can be added with [`write`].
     /// Then setup replication with [`add_learner`].
     /// Then make the new node a member with [`change_membership`].
-    pub async fn init(&self) -> Result<(), typ::RPCError<typ::InitializeError>> {
+    pub async fn init(&self) -> Result<(), ClientError> {
         self.do_send_rpc_to_leader("cluster/init", Some(&Empty {}))
             .await
     }
@@ -115,7 +284,7 @@ impl RaftNode {
     pub async fn add_learner(
         &self,
         req: (NodeId, String, String),
-    ) -> Result<typ::ClientWriteResponse, typ::RPCError<typ::ClientWriteError>> {
+    ) -> Result<typ::ClientWriteResponse, ClientError> {
         self.send_rpc_to_leader("cluster/add-learner", Some(&req))
             .await
     }
@@ -127,46 +296,190 @@ impl RaftNode {
     pub async fn change_membership(
         &self,
         req: &BTreeSet<NodeId>,
-    ) -> Result<typ::ClientWriteResponse, typ::RPCError<typ::ClientWriteError>> {
+    ) -> Result<typ::ClientWriteResponse, ClientError> {
         self.send_rpc_to_leader("cluster/change-membership", Some(req))
             .await
     }
 
+    /// Add a node as voter, once it can be reached and caught up with the leader.
+    ///
+    /// Returns [`ChangeProgress::CatchingUp`] while the node is behind. Call it again until it
+    /// returns [`ChangeProgress::Done`].
+    pub async fn add_node(
+        &self,
+        req: (NodeId, String, String),
+    ) -> Result<ChangeProgress, ClientError> {
+        self.send_rpc_to_leader("cluster/add-node", Some(&req))
+            .await
+    }
+
+    /// Replace the member `old` by the node `new`, in the same steps as [`add_node`].
+    pub async fn replace_node(
+        &self,
+        old: NodeId,
+        new: (NodeId, String, String),
+    ) -> Result<ChangeProgress, ClientError> {
+        self.send_rpc_to_leader("cluster/replace-node", Some(&(old, new)))
+            .await
+    }
+
+    /// Remove a member of the Raft cluster.
+    ///
+    /// Fails with [`ClientError::Conflict`] if the remaining voters could not form a quorum.
+    pub async fn remove_node(&self, id: NodeId) -> Result<ChangeProgress, ClientError> {
+        self.send_rpc_to_leader("cluster/remove-node", Some(&id))
+            .await
+    }
+
+    /// Get the voters of the Raft cluster and the replication progress of its learners.
+    pub async fn membership(&self) -> Result<MembershipStatus, ClientError> {
+        self.send_rpc_to_leader("cluster/membership", None::<&()>)
+            .await
+    }
+
+    /// Transfer the leadership of the Raft cluster to the voter `target`.
+    ///
+    /// Returns once `target` is the leader.
+    pub async fn transfer_leader(&self, target: NodeId) -> Result<(), ClientError> {
+        self.send_rpc_to_leader("cluster/transfer-leader", Some(&target))
+            .await
+    }
+
+    /// Drain the node for maintenance: its leadership moves to another voter, it stops serving
+    /// clients and shuts down once its log is applied.
+    ///
+    /// Returns the leader of the Raft cluster.
+    pub async fn drain(&self) -> Result<NodeId, ClientError> {
+        self.do_send_rpc_to_leader("cluster/drain", Some(&Empty {}))
+            .await
+    }
+
+    /// Build a snapshot of the state machine on the node.
+    ///
+    /// Returns the id of the last log entry in the snapshot.
+    pub async fn snapshot(&self) -> Result<Option<LogId<NodeId>>, ClientError> {
+        self.do_send_rpc_to_leader("cluster/snapshot", Some(&Empty {}))
+            .await
+    }
+
+    /// Purge the log of the node up to and including `upto`.
+    ///
+    /// Only entries that are part of a snapshot are purged, see [`snapshot`].
+    pub async fn purge_log(&self, upto: u64) -> Result<(), ClientError> {
+        self.do_send_rpc_to_leader("cluster/purge-log", Some(&upto))
+            .await
+    }
+
+    /// Write a checkpoint of the storage of the node to `dir` on its host, which must not exist
+    /// yet.
+    pub async fn backup(&self, dir: &Path) -> Result<Checkpoint, ClientError> {
+        self.do_send_rpc_to_leader("cluster/backup", Some(&dir.to_path_buf()))
+            .await
+    }
+
+    /// Dump the state machine of the node, which must be the leader of its shard.
+    pub async fn dump(&self) -> Result<ShardDump, ClientError> {
+        self.do_send_rpc_to_leader("cluster/dump", Some(&Empty {}))
+            .await
+    }
+
     /// Get the metrics about the cluster.
     ///
     /// Metrics contains various information about the cluster, such as current leader,
     /// membership config, replication status etc.
     /// See [`RaftMetrics`].
-    pub async fn metrics(&self) -> Result<RaftMetrics<NodeId, Node>, typ::RPCError> {
+    pub async fn metrics(&self) -> Result<RaftMetrics<NodeId, Node>, ClientError> {
         self.do_send_rpc_to_leader("cluster/metrics", None::<&()>)
             .await
     }
 
+    /// Get the load served by the node and the size of its state machine.
+    /// See [`ShardLoad`].
+    pub async fn load(&self) -> Result<ShardLoad, ClientError> {
+        self.do_send_rpc_to_leader("cluster/load", None::<&()>)
+            .await
+    }
+
+    /// Like [`Self::load`], and start a new window for the hot keys of the node. Reserved to
+    /// the [`crate::load_balancer::LoadController`], other callers would shorten its window.
+    pub async fn take_load(&self) -> Result<ShardLoad, ClientError> {
+        self.do_send_rpc_to_leader("cluster/load?new_window=true", None::<&()>)
+            .await
+    }
+
+    /// List the keys the shard of the node serves under `req.from` and another shard serves
+    /// under `req.to`, with their values. See [`MovedKeysRequest`].
+    pub async fn moved_keys(&self, req: &MovedKeysRequest) -> Result<MovedKeys, ClientError> {
+        self.send_rpc_to_leader("cluster/moved-keys", Some(req))
+            .await
+    }
+
+    /// Apply a write to the shard of the node, whether it serves the key or not. Used to move
+    /// keys between shards.
+    pub async fn migrate(&self, req: &Request) -> Result<(), ClientError> {
+        self.send_rpc_to_leader("cluster/migrate", Some(req))
+            .await
+    }
+
+    /// Create a namespace in the Raft cluster.
+    pub async fn create_namespace(
+        &self,
+        name: &str,
+        quota: Quota,
+    ) -> Result<(), ClientError> {
+        self.send_rpc_to_leader("cluster/create-namespace", Some(&(name, quota)))
+            .await
+    }
+
+    /// Delete a namespace and all its keys from the Raft cluster.
+    pub async fn delete_namespace(
+        &self,
+        name: &str,
+    ) -> Result<(), ClientError> {
+        self.send_rpc_to_leader("cluster/delete-namespace", Some(&name))
+            .await
+    }
+
+    /// Replace the quota of a namespace.
+    pub async fn set_namespace_quota(
+        &self,
+        name: &str,
+        quota: Quota,
+    ) -> Result<(), ClientError> {
+        self.send_rpc_to_leader("cluster/set-namespace-quota", Some(&(name, quota)))
+            .await
+    }
+
+    /// Get all namespaces with their quota and usage, as seen by the node.
+    pub async fn namespaces(&self) -> Result<BTreeMap<String, Namespace>, ClientError> {
+        self.do_send_rpc_to_leader("cluster/namespaces", None::<&()>)
+            .await
+    }
+
     // --- Internal methods
 
     /// Send RPC to specified node.
     ///
     /// It sends out a POST request if `req` is Some. Otherwise a GET request.
     /// The remote endpoint must respond with a status code indicating whether the request
-    /// succeeded or not. Based on the status code, the reply will be parsed into `Result<T, E>`.
-    /// An `Err` happened on remote will be wrapped in an [`RPCError::RemoteError`].
-    async fn do_send_rpc_to_leader<Req, Resp, Err>(
+    /// succeeded or not. A failed request is answered with an [`ErrorResponse`], which is
+    /// decoded into a [`ClientError`].
+    async fn do_send_rpc_to_leader<Req, Resp>(
         &self,
         uri: &str,
         req: Option<&Req>,
-    ) -> Result<Resp, RPCError<NodeId, Node, Err>>
+    ) -> Result<Resp, ClientError>
     where
         Req: Serialize + 'static,
         Resp: Serialize + DeserializeOwned,
-        Err: std::error::Error + Serialize + DeserializeOwned,
     {
-        let (leader_id, url) = {
+        let url = {
             let t = self.leader.lock().unwrap();
-            let target_addr = &t.1;
-            (t.0, format!("http://{}/{}", target_addr, uri))
+            format!("{}://{}/{}", self.scheme, t.1, uri)
         };
 
-        let resp = if let Some(r) = req {
+        let client = self.transport.client();
+        let builder = if let Some(r) = req {
             if cfg!(debug_assertions) {
                 println!(
                     ">>> client send request to {}: {}",
@@ -174,46 +487,55 @@ impl RaftNode {
                     serde_json::to_string_pretty(&r).unwrap()
                 );
             }
-            self.inner.post(url.clone()).json(r)
+            client.post(url.clone()).json(r)
         } else {
             if cfg!(debug_assertions) {
                 println!(">>> client send request to {}", url,);
             }
-            self.inner.get(url.clone())
-        }
-        .send()
-        .await
-        .map_err(|e| {
+            client.get(url.clone())
+        };
+        let builder = match &self.token {
+            Some(token) => builder.bearer_auth(token),
+            None => builder,
+        };
+        // Let the node continue the trace of the caller.
+        let builder = match telemetry::current() {
+            Some(traceparent) => builder.header(TRACEPARENT_HEADER, traceparent.to_string()),
+            None => builder,
+        };
+
+        let resp = builder.send().await.map_err(|e| {
             if e.is_connect() {
                 // `Unreachable` informs the caller to backoff for a short while to avoid error log flush.
-                return RPCError::Unreachable(Unreachable::new(&e));
+                return ClientError::Unreachable(e.to_string());
+            }
+            if e.is_timeout() {
+                return ClientError::Timeout(e.to_string());
             }
-            RPCError::Network(NetworkError::new(&e))
+            ClientError::Network(e.to_string())
         })?;
 
         let status = resp.status();
-        let res: Result<Resp, RPCError<NodeId, Node, Err>> = if status.is_success() {
-            let parsed: Resp = resp
-                .json()
+        let res: Result<Resp, ClientError> = if status.is_success() {
+            resp.json()
                 .await
-                .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
-            Ok(parsed)
+                .map_err(|e| ClientError::Network(e.to_string()))
         } else {
-            let remote_err: Err = resp
-                .json()
-                .await
-                .map_err(|e| RPCError::Network(NetworkError::new(&e)))?;
-            Err(RPCError::RemoteError(RemoteError::new(
-                leader_id, remote_err,
-            )))
+            let body: ErrorResponse = resp.json().await.map_err(|e| {
+                ClientError::Network(format!("invalid error response ({}): {}", status, e))
+            })?;
+            Err(body.error.into())
         };
 
         if cfg!(debug_assertions) {
-            println!(
-                "<<< client recv reply from {}: {}",
-                url,
-                serde_json::to_string_pretty(&res).unwrap()
-            );
+            match &res {
+                Ok(r) => println!(
+                    "<<< client recv reply from {}: {}",
+                    url,
+                    serde_json::to_string_pretty(r).unwrap()
+                ),
+                Err(e) => println!("<<< client recv error from {}: {}", url, e),
+            }
         }
 
         res
@@ -221,57 +543,44 @@ impl RaftNode {
 
     /// Try the best to send a request to the leader.
     ///
-    /// If the target node is not a leader, a `ForwardToLeader` error will be
+    /// If the target node is not a leader, a [`ClientError::NotLeader`] error will be
     /// returned and this client will retry at most 3 times to contact the updated leader.
-    async fn send_rpc_to_leader<Req, Resp, Err>(
+    async fn send_rpc_to_leader<Req, Resp>(
         &self,
         uri: &str,
         req: Option<&Req>,
-    ) -> Result<Resp, typ::RPCError<Err>>
+    ) -> Result<Resp, ClientError>
     where
         Req: Serialize + 'static,
         Resp: Serialize + DeserializeOwned,
-        Err: std::error::Error
-            + Serialize
-            + DeserializeOwned
-            + TryAsRef<typ::ForwardToLeader>
-            + Clone,
     {
         // Retry at most 3 times to find a valid leader.
         let mut n_retry = 3;
 
         loop {
-            let res: Result<Resp, typ::RPCError<Err>> = self.do_send_rpc_to_leader(uri, req).await;
-
-            let rpc_err = match res {
+            let err = match self.do_send_rpc_to_leader(uri, req).await {
                 Ok(x) => return Ok(x),
-                Err(rpc_err) => rpc_err,
+                Err(err) => err,
             };
 
-            if let RPCError::RemoteError(remote_err) = &rpc_err {
-                let raft_err: &typ::RaftError<_> = &remote_err.source;
-
-                if let Some(typ::ForwardToLeader {
-                    leader_id: Some(leader_id),
-                    leader_node: Some(leader_node),
-                    ..
-                }) = raft_err.forward_to_leader()
+            if let ClientError::NotLeader {
+                leader_id: Some(leader_id),
+                leader_addr: Some(leader_addr),
+            } = &err
+            {
+                // Update target to the new leader.
                 {
-                    // Update target to the new leader.
-                    {
-                        let mut t = self.leader.lock().unwrap();
-                        let api_addr = leader_node.api_addr.clone();
-                        *t = (*leader_id, api_addr);
-                    }
-
-                    n_retry -= 1;
-                    if n_retry > 0 {
-                        continue;
-                    }
+                    let mut t = self.leader.lock().unwrap();
+                    *t = (*leader_id, leader_addr.clone());
+                }
+
+                n_retry -= 1;
+                if n_retry > 0 {
+                    continue;
                 }
             }
 
-            return Err(rpc_err);
+            return Err(err);
         }
     }
 }
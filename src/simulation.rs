@@ -0,0 +1,245 @@
+//! Harness for fault-injection tests: the nodes of a topology running in this process, with
+//! their Raft traffic going through a [`SimNetwork`].
+//!
+//! [`SimCluster`] starts the nodes with their HTTP API on their addresses, initializes every
+//! shard, publishes the hash ring and writes a client config, so that scenarios drive the cluster
+//! with [`KVClient`]s while they partition, stop and restart nodes and change the faults of the
+//! network. A failure detector keeps the ring pointed at the shard leaders, like the cluster
+//! manager's does.
+//!
+//! The faults are drawn from the seed of the network, which scenarios should print so that a
+//! failure can be replayed with the same seed.
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::cluster_manager::publish_hash_ring;
+use crate::cluster_manager::reconcile_shard;
+use crate::cluster_manager::wait_until_up;
+use crate::failure_detector::FailureDetector;
+use crate::failure_detector::FailureDetectorConfig;
+use crate::kvclient::KVClient;
+use crate::network::sim::SimNetwork;
+use crate::raft_node::RaftNode;
+use crate::start_raft_node;
+use crate::store::read_raft_state;
+use crate::topology::NodeSpec;
+use crate::topology::Topology;
+use crate::NodeConfig;
+use crate::NodeId;
+
+/// Time a shard gets to elect a leader.
+const LEADER_TIMEOUT: Duration = Duration::from_secs(20);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A node started by the harness.
+struct RunningNode {
+    shutdown: watch::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+/// A cluster of in-process nodes connected by a [`SimNetwork`], see the module documentation.
+///
+/// Shards are numbered from 0 in the order of the topology.
+pub struct SimCluster {
+    pub network: SimNetwork,
+    pub topology: Topology,
+    /// Client config of the cluster, for [`KVClient::new`].
+    pub client_config: PathBuf,
+    nodes: HashMap<(usize, NodeId), RunningNode>,
+    transport: RaftNode,
+    detector: RunningNode,
+}
+
+impl SimCluster {
+    /// Start the nodes of `topology` on a network without faults whose faults will be drawn from
+    /// `seed`, and write the client config to `client_config`.
+    pub async fn start(
+        seed: u64,
+        topology: Topology,
+        client_config: PathBuf,
+    ) -> Result<Self, Box<dyn Error>> {
+        topology.validate()?;
+        let network = SimNetwork::new(seed);
+        let transport = RaftNode::new(0, String::new());
+
+        let mut initialized = Vec::new();
+        for shard in &topology.shards {
+            let mut any = false;
+            for node in &shard.nodes {
+                any |= read_raft_state(&node.data_dir)?.is_initialized();
+            }
+            initialized.push(any);
+        }
+
+        let mut nodes = HashMap::new();
+        for (i, shard) in topology.shards.iter().enumerate() {
+            for node in &shard.nodes {
+                fs::create_dir_all(&node.data_dir)?;
+                nodes.insert((i, node.id), spawn_node(node, &network));
+            }
+        }
+        let all_nodes = topology.api_addrs();
+        for addr in all_nodes.iter().flatten() {
+            wait_until_up(&transport, addr).await?;
+        }
+
+        let mut ring = topology.hash_ring(0);
+        for (i, shard) in topology.shards.iter().enumerate() {
+            let leader = reconcile_shard(&transport, &shard.name(i), shard, initialized[i]).await?;
+            let first = &shard.first().api_addr;
+            if *first != leader {
+                ring.set_new_proxy(first, &leader)?;
+            }
+        }
+        publish_hash_ring(&transport, &all_nodes, &ring).await;
+        topology.client_config().save(&client_config)?;
+
+        let detector = FailureDetector::new(
+            FailureDetectorConfig::default(),
+            Arc::new(RwLock::new(ring)),
+            transport.clone(),
+        );
+        let (shutdown, shutdown_rx) = watch::channel(());
+        let detector = RunningNode {
+            shutdown,
+            handle: tokio::spawn(detector.run(shutdown_rx)),
+        };
+
+        Ok(Self {
+            network,
+            topology,
+            client_config,
+            nodes,
+            transport,
+            detector,
+        })
+    }
+
+    /// A client of the cluster.
+    pub async fn client(&self) -> Result<KVClient, Box<dyn Error>> {
+        KVClient::new(self.client_config.to_str().ok_or("invalid client config path")?).await
+    }
+
+    pub fn node_spec(&self, shard: usize, id: NodeId) -> &NodeSpec {
+        self.topology.shards[shard]
+            .nodes
+            .iter()
+            .find(|node| node.id == id)
+            .unwrap_or_else(|| panic!("no node {} in shard {}", id, shard))
+    }
+
+    /// A client of a single node, e.g., to write through it or to read its metrics.
+    pub fn node(&self, shard: usize, id: NodeId) -> RaftNode {
+        let node = self.node_spec(shard, id);
+        self.transport.with_same_transport(id, node.api_addr.clone())
+    }
+
+    pub fn is_running(&self, shard: usize, id: NodeId) -> bool {
+        self.nodes.contains_key(&(shard, id))
+    }
+
+    /// Wait until a running node of `shard`, other than those of `exclude`, is the leader, and
+    /// return its id.
+    ///
+    /// A leader that is partitioned away may still consider itself the leader, exclude it to
+    /// wait for its successor.
+    pub async fn wait_for_leader(
+        &self,
+        shard: usize,
+        exclude: &[NodeId],
+    ) -> Result<NodeId, Box<dyn Error>> {
+        let deadline = Instant::now() + LEADER_TIMEOUT;
+        loop {
+            for node in &self.topology.shards[shard].nodes {
+                if exclude.contains(&node.id) || !self.is_running(shard, node.id) {
+                    continue;
+                }
+                if let Ok(metrics) = self.node(shard, node.id).metrics().await {
+                    if metrics.current_leader == Some(node.id) {
+                        return Ok(node.id);
+                    }
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("shard {} has no leader", shard).into());
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Stop a node. Its storage is kept for [`SimCluster::restart`].
+    pub async fn stop(&mut self, shard: usize, id: NodeId) {
+        if let Some(node) = self.nodes.remove(&(shard, id)) {
+            let _ = node.shutdown.send(());
+            let _ = node.handle.await;
+        }
+    }
+
+    /// Start a stopped node again, on its storage.
+    pub async fn restart(&mut self, shard: usize, id: NodeId) -> Result<(), Box<dyn Error>> {
+        if self.is_running(shard, id) {
+            return Ok(());
+        }
+        let spec = self.node_spec(shard, id).clone();
+        self.nodes.insert((shard, id), spawn_node(&spec, &self.network));
+        wait_until_up(&self.transport, &spec.api_addr).await
+    }
+
+    /// Lose all Raft messages sent by or to a node.
+    pub fn isolate(&self, shard: usize, id: NodeId) {
+        self.network.isolate(&self.node_spec(shard, id).rpc_addr);
+    }
+
+    /// Split `shard` in two: the nodes of `side` can't exchange Raft messages with the others.
+    pub fn partition(&self, shard: usize, side: &[NodeId]) {
+        let nodes = &self.topology.shards[shard].nodes;
+        for a in nodes.iter().filter(|node| side.contains(&node.id)) {
+            for b in nodes.iter().filter(|node| !side.contains(&node.id)) {
+                self.network.cut(&a.rpc_addr, &b.rpc_addr);
+            }
+        }
+    }
+
+    /// Remove all partitions.
+    pub fn heal(&self) {
+        self.network.heal();
+    }
+
+    /// Stop all nodes and the failure detector.
+    pub async fn shutdown(mut self) {
+        let _ = self.detector.shutdown.send(());
+        let _ = self.detector.handle.await;
+        let nodes: Vec<(usize, NodeId)> = self.nodes.keys().copied().collect();
+        for (shard, id) in nodes {
+            self.stop(shard, id).await;
+        }
+    }
+}
+
+fn spawn_node(node: &NodeSpec, network: &SimNetwork) -> RunningNode {
+    let mut config = NodeConfig::new(
+        node.id,
+        node.data_dir.clone(),
+        node.api_addr.clone(),
+        node.rpc_addr.clone(),
+    );
+    config.http_listen_addr = node.listen_api_addr.clone();
+    config.rpc_listen_addr = node.listen_rpc_addr.clone();
+    config.simulated_network = Some(network.clone());
+
+    let (shutdown, shutdown_rx) = watch::channel(());
+    let handle = tokio::spawn(async move {
+        let _ = start_raft_node(config, shutdown_rx).await;
+    });
+    RunningNode { shutdown, handle }
+}
@@ -3,7 +3,10 @@ use std::fmt::Debug;
 use std::io::Cursor;
 use std::ops::RangeBounds;
 use std::path::Path;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Duration;
 
 use byteorder::BigEndian;
 use byteorder::ReadBytesExt;
@@ -27,20 +30,34 @@ use openraft::StorageError;
 use openraft::StorageIOError;
 use openraft::StoredMembership;
 use openraft::Vote;
-use rocksdb::ColumnFamily;
-use rocksdb::ColumnFamilyDescriptor;
-use rocksdb::Direction;
-use rocksdb::Options;
-use rocksdb::DB;
+use serde::de::DeserializeOwned;
 use serde::Deserialize;
 use serde::Serialize;
 use tokio::sync::RwLock;
+use tracing::Instrument;
+use tracing::Span;
 
+use crate::namespace;
+use crate::namespace::Namespace;
+use crate::namespace::NamespaceError;
+use crate::namespace::Quota;
 use crate::typ;
+use crate::value::Bytes;
+use crate::value::Value;
 use crate::Node;
 use crate::NodeId;
 use crate::SnapshotData;
 use crate::TypeConfig;
+use engine::Change;
+use engine::IterMode;
+use engine::MemEngine;
+use engine::RocksEngine;
+use engine::StorageBackend;
+use engine::StorageEngine;
+
+#[cfg(test)]
+mod crash;
+pub mod engine;
 
 /**
  * Here you will set the types of request that will interact with the raft nodes.
@@ -50,7 +67,23 @@ use crate::TypeConfig;
  */
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Request {
-    Set { key: String, value: String },
+    Set { key: Bytes, value: Value },
+    /// Delete a key. Deleting a key that does not exist is not an error.
+    Delete { key: Bytes },
+    /// Set `key` to `value` if its value is `expected`, `None` meaning that it does not exist.
+    ///
+    /// The response holds the value the key had before, so the swap happened if it is `expected`.
+    CompareAndSwap {
+        key: Bytes,
+        expected: Option<Bytes>,
+        value: Value,
+    },
+    /// Create a namespace. Keys of the form `<name>/...` that already exist become part of it.
+    CreateNamespace { name: String, quota: Quota },
+    /// Delete a namespace and all its keys.
+    DeleteNamespace { name: String },
+    /// Replace the quota of a namespace. Keys above the new quota are kept.
+    SetNamespaceQuota { name: String, quota: Quota },
 }
 
 /**
@@ -60,7 +93,33 @@ pub enum Request {
  */
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Response {
-    pub value: Option<String>,
+    /// As stored, possibly compressed. The HTTP API answers with the value as written.
+    pub value: Option<Value>,
+    /// Set if the request was rejected by the state machine, e.g., because of a quota.
+    #[serde(default)]
+    pub error: Option<NamespaceError>,
+}
+
+/// The state machine as stored in a snapshot.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StateMachineSnapshot {
+    #[serde(with = "crate::value::pairs")]
+    pub kvs: BTreeMap<Bytes, Value>,
+    #[serde(default)]
+    pub namespaces: BTreeMap<String, Namespace>,
+}
+
+impl StateMachineSnapshot {
+    /// Decode the data of a snapshot.
+    pub fn decode(data: &[u8]) -> Result<Self, serde_json::Error> {
+        // Snapshots taken before namespaces existed only contain the key-value map.
+        serde_json::from_slice(data).or_else(|_| {
+            serde_json::from_slice(data).map(|kvs| StateMachineSnapshot {
+                kvs,
+                ..Default::default()
+            })
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -82,7 +141,7 @@ pub struct StateMachineStore {
     snapshot_idx: u64,
 
     /// State machine stores snapshot in db.
-    db: Arc<DB>,
+    engine: Arc<dyn StorageEngine>,
 }
 
 #[derive(Debug, Clone)]
@@ -92,7 +151,14 @@ pub struct StateMachineData {
     pub last_membership: StoredMembership<NodeId, Node>,
 
     /// State built from applying the raft logs
-    pub kvs: Arc<RwLock<BTreeMap<String, String>>>,
+    pub kvs: Arc<RwLock<BTreeMap<Bytes, Value>>>,
+
+    /// Namespaces with their quotas and usage. Lock before `kvs` when both are needed.
+    pub namespaces: Arc<RwLock<BTreeMap<String, Namespace>>>,
+
+    /// Total size of the entries of `kvs`, see [`namespace::entry_size`]. Updated together
+    /// with `kvs`.
+    pub byte_size: Arc<AtomicU64>,
 }
 
 impl RaftSnapshotBuilder<TypeConfig> for StateMachineStore {
@@ -101,8 +167,13 @@ impl RaftSnapshotBuilder<TypeConfig> for StateMachineStore {
         let last_membership = self.data.last_membership.clone();
 
         let kv_json = {
+            let namespaces = self.data.namespaces.read().await;
             let kvs = self.data.kvs.read().await;
-            serde_json::to_vec(&*kvs).map_err(|e| StorageIOError::read_state_machine(&e))?
+            let state = StateMachineSnapshot {
+                kvs: kvs.clone(),
+                namespaces: namespaces.clone(),
+            };
+            serde_json::to_vec(&state).map_err(|e| StorageIOError::read_state_machine(&e))?
         };
 
         let snapshot_id = if let Some(last) = last_applied_log {
@@ -132,15 +203,19 @@ impl RaftSnapshotBuilder<TypeConfig> for StateMachineStore {
 }
 
 impl StateMachineStore {
-    async fn new(db: Arc<DB>) -> Result<StateMachineStore, StorageError<NodeId>> {
+    async fn new(
+        engine: Arc<dyn StorageEngine>,
+    ) -> Result<StateMachineStore, StorageError<NodeId>> {
         let mut sm = Self {
             data: StateMachineData {
                 last_applied_log_id: None,
                 last_membership: Default::default(),
                 kvs: Arc::new(Default::default()),
+                namespaces: Arc::new(Default::default()),
+                byte_size: Arc::new(Default::default()),
             },
             snapshot_idx: 0,
-            db,
+            engine,
         };
 
         let snapshot = sm.get_current_snapshot_()?;
@@ -155,21 +230,25 @@ impl StateMachineStore {
         &mut self,
         snapshot: StoredSnapshot,
     ) -> Result<(), StorageError<NodeId>> {
-        let kvs: BTreeMap<String, String> = serde_json::from_slice(&snapshot.data)
+        let state = StateMachineSnapshot::decode(&snapshot.data)
             .map_err(|e| StorageIOError::read_snapshot(Some(snapshot.meta.signature()), &e))?;
 
         self.data.last_applied_log_id = snapshot.meta.last_log_id;
         self.data.last_membership = snapshot.meta.last_membership.clone();
+        let mut namespaces = self.data.namespaces.write().await;
         let mut x = self.data.kvs.write().await;
-        *x = kvs;
+        *namespaces = state.namespaces;
+        *x = state.kvs;
+        let byte_size = x.iter().map(|(k, v)| namespace::entry_size(k, v.size())).sum();
+        self.data.byte_size.store(byte_size, Ordering::Relaxed);
 
         Ok(())
     }
 
     fn get_current_snapshot_(&self) -> StorageResult<Option<StoredSnapshot>> {
         Ok(self
-            .db
-            .get_cf(self.store(), b"snapshot")
+            .engine
+            .get("store", b"snapshot")
             .map_err(|e| StorageError::IO {
                 source: StorageIOError::read(&e),
             })?
@@ -177,35 +256,123 @@ impl StateMachineStore {
     }
 
     fn set_current_snapshot_(&self, snap: StoredSnapshot) -> StorageResult<()> {
-        self.db
-            .put_cf(
-                self.store(),
-                b"snapshot",
-                serde_json::to_vec(&snap).unwrap().as_slice(),
-            )
+        let change = Change::Put {
+            cf: "store",
+            key: b"snapshot".to_vec(),
+            value: serde_json::to_vec(&snap).unwrap(),
+        };
+        self.engine
+            .write(vec![change])
             .map_err(|e| StorageError::IO {
                 source: StorageIOError::write_snapshot(Some(snap.meta.signature()), &e),
             })?;
-        self.flush(
-            ErrorSubject::Snapshot(Some(snap.meta.signature())),
-            ErrorVerb::Write,
-        )?;
         Ok(())
     }
+}
 
-    fn flush(
-        &self,
-        subject: ErrorSubject<NodeId>,
-        verb: ErrorVerb,
-    ) -> Result<(), StorageIOError<NodeId>> {
-        self.db
-            .flush_wal(true)
-            .map_err(|e| StorageIOError::new(subject, verb, AnyError::new(&e)))?;
-        Ok(())
+impl StateMachineData {
+    /// Apply a normal log entry. Returns the written or deleted value, if any.
+    ///
+    /// A rejected request leaves the state machine unchanged.
+    async fn apply_request(&self, req: Request) -> Result<Option<Value>, NamespaceError> {
+        let mut namespaces = self.namespaces.write().await;
+        let mut st = self.kvs.write().await;
+        match req {
+            Request::Set { key, value } => {
+                self.set(&mut namespaces, &mut st, key, value.clone())?;
+                Ok(Some(value))
+            }
+            Request::CompareAndSwap {
+                key,
+                expected,
+                value,
+            } => {
+                let current = st.get(&key).cloned();
+                let matches = match (&current, &expected) {
+                    (None, None) => true,
+                    // A value that can't be decompressed matches nothing.
+                    (Some(current), Some(expected)) => {
+                        current.size() == expected.len() as u64
+                            && current.decode().is_ok_and(|current| current == *expected)
+                    }
+                    _ => false,
+                };
+                if matches {
+                    self.set(&mut namespaces, &mut st, key, value)?;
+                }
+                Ok(current)
+            }
+            Request::Delete { key } => {
+                let Some(old) = st.remove(&key) else {
+                    return Ok(None);
+                };
+                let size = namespace::entry_size(&key, old.size());
+                self.byte_size.fetch_sub(size, Ordering::Relaxed);
+                let name = namespace::namespace_of(&key);
+                if let Some(namespace) = name.and_then(|name| namespaces.get_mut(name)) {
+                    namespace.usage = namespace.usage_after_delete(&key, old.size());
+                }
+                Ok(Some(old))
+            }
+            Request::CreateNamespace { name, quota } => {
+                namespace::validate_name(&name)?;
+                if namespaces.contains_key(&name) {
+                    return Err(NamespaceError::AlreadyExists(name));
+                }
+                let usage = namespace::usage_of(&name, &st);
+                namespaces.insert(name, Namespace { quota, usage });
+                Ok(None)
+            }
+            Request::DeleteNamespace { name } => {
+                if namespaces.remove(&name).is_none() {
+                    return Err(NamespaceError::NotFound(name));
+                }
+                let prefix = namespace::key_prefix(&name);
+                let mut removed = 0;
+                st.retain(|k, v| {
+                    let keep = !k.starts_with(prefix.as_bytes());
+                    if !keep {
+                        removed += namespace::entry_size(k, v.size());
+                    }
+                    keep
+                });
+                self.byte_size.fetch_sub(removed, Ordering::Relaxed);
+                Ok(None)
+            }
+            Request::SetNamespaceQuota { name, quota } => match namespaces.get_mut(&name) {
+                Some(namespace) => {
+                    namespace.quota = quota;
+                    Ok(None)
+                }
+                None => Err(NamespaceError::NotFound(name)),
+            },
+        }
     }
 
-    fn store(&self) -> &ColumnFamily {
-        self.db.cf_handle("store").unwrap()
+    /// Set `key` to `value`, unless it would exceed the quota of the namespace of the key.
+    fn set(
+        &self,
+        namespaces: &mut BTreeMap<String, Namespace>,
+        kvs: &mut BTreeMap<Bytes, Value>,
+        key: Bytes,
+        value: Value,
+    ) -> Result<(), NamespaceError> {
+        let old = kvs.get(&key).map(Value::size);
+        if let Some(name) = namespace::namespace_of(&key) {
+            if let Some(namespace) = namespaces.get_mut(name) {
+                namespace.usage = namespace
+                    .usage_after_set(&key, value.size(), old)
+                    .map_err(|limit| NamespaceError::QuotaExceeded {
+                        namespace: name.to_string(),
+                        limit,
+                    })?;
+            }
+        }
+        let old = old.map_or(0, |size| namespace::entry_size(&key, size));
+        self.byte_size.fetch_add(namespace::entry_size(&key, value.size()), Ordering::Relaxed);
+        self.byte_size.fetch_sub(old, Ordering::Relaxed);
+        kvs.insert(key, value);
+        Ok(())
     }
 }
 
@@ -233,23 +400,34 @@ impl RaftStateMachine<TypeConfig> for StateMachineStore {
             self.data.last_applied_log_id = Some(ent.log_id);
 
             let mut resp_value = None;
+            let mut error = None;
 
             match ent.payload {
                 EntryPayload::Blank => {}
-                EntryPayload::Normal(req) => match req {
-                    Request::Set { key, value } => {
-                        resp_value = Some(value.clone());
-
-                        let mut st = self.data.kvs.write().await;
-                        st.insert(key, value);
+                EntryPayload::Normal(req) => {
+                    // Continue the trace of the client request that proposed the entry.
+                    let span = match req.traceparent {
+                        Some(traceparent) => tracing::info_span!(
+                            "state_machine.apply",
+                            traceparent = %traceparent,
+                            log_index = ent.log_id.index,
+                        ),
+                        None => Span::none(),
+                    };
+                    match self.data.apply_request(req.inner).instrument(span).await {
+                        Ok(value) => resp_value = value,
+                        Err(e) => error = Some(e),
                     }
-                },
+                }
                 EntryPayload::Membership(mem) => {
                     self.data.last_membership = StoredMembership::new(Some(ent.log_id), mem);
                 }
             }
 
-            replies.push(Response { value: resp_value });
+            replies.push(Response {
+                value: resp_value,
+                error,
+            });
         }
         Ok(replies)
     }
@@ -295,7 +473,70 @@ impl RaftStateMachine<TypeConfig> for StateMachineStore {
 
 #[derive(Debug, Clone)]
 pub struct LogStore {
-    db: Arc<DB>,
+    engine: Arc<dyn StorageEngine>,
+}
+
+/// The Raft state a node keeps across restarts, as stored by its [`LogStore`].
+#[derive(Debug, Clone, Default)]
+pub struct RaftState {
+    pub vote: Option<Vote<NodeId>>,
+    pub last_log_id: Option<LogId<NodeId>>,
+    /// The last membership in the log, or in the snapshot if the log holds none.
+    pub membership: Option<StoredMembership<NodeId, Node>>,
+}
+
+impl RaftState {
+    /// Whether the node is part of an initialized cluster: it voted, or it holds a membership.
+    /// Such a node rejoins its cluster on start and must not be initialized again.
+    pub fn is_initialized(&self) -> bool {
+        self.vote.is_some() || self.membership.is_some()
+    }
+}
+
+/// Column families of the storage engine of a node.
+pub const COLUMN_FAMILIES: [&str; 2] = ["store", "logs"];
+
+/// Read-only view of the storage engine of a node, used to export its statistics.
+#[derive(Debug, Clone)]
+pub struct StorageMonitor {
+    engine: Arc<dyn StorageEngine>,
+}
+
+impl StorageMonitor {
+    /// Integer property of the column family `cf`, e.g., `rocksdb.estimate-num-keys`.
+    pub fn property(&self, cf: &str, name: &str) -> Option<u64> {
+        self.engine.property(cf, name)
+    }
+
+    /// The stored snapshot, if any.
+    pub fn snapshot(&self) -> Result<Option<StoredSnapshot>, AnyError> {
+        let Some(data) = self.engine.get("store", b"snapshot")? else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&data).map_err(|e| AnyError::new(&e))
+    }
+
+    /// Size of the stored snapshot in bytes.
+    pub fn snapshot_size(&self) -> u64 {
+        self.engine
+            .get("store", b"snapshot")
+            .ok()
+            .flatten()
+            .map(|data| data.len() as u64)
+            .unwrap_or(0)
+    }
+
+    /// Copy the storage to a new RocksDB database in `dir`, see [`StorageEngine::checkpoint`].
+    pub fn checkpoint(&self, dir: &Path) -> Result<(), AnyError> {
+        self.engine.checkpoint(dir)
+    }
+
+    /// Index of the last committed log entry, as saved by the [`LogStore`].
+    pub fn committed_index(&self) -> Option<u64> {
+        let data = self.engine.get("store", b"committed").ok().flatten()?;
+        let committed: Option<LogId<NodeId>> = serde_json::from_slice(&data).ok()?;
+        committed.map(|id| id.index)
+    }
 }
 type StorageResult<T> = Result<T, StorageError<NodeId>>;
 
@@ -312,89 +553,140 @@ fn bin_to_id(buf: &[u8]) -> u64 {
 }
 
 impl LogStore {
-    fn store(&self) -> &ColumnFamily {
-        self.db.cf_handle("store").unwrap()
+    fn new(engine: Arc<dyn StorageEngine>) -> Self {
+        Self { engine }
+    }
+
+    /// Read the stored vote, the id of the last log entry and the last membership.
+    pub fn raft_state(&self) -> StorageResult<RaftState> {
+        let mut last_log_id = None;
+        let mut membership = None;
+        for res in self.engine.iter("logs", IterMode::End) {
+            let (_, ent) = res.map_err(|e| StorageIOError::read_logs(&e))?;
+            let ent = serde_json::from_slice::<Entry<TypeConfig>>(&ent)
+                .map_err(|e| StorageIOError::read_logs(&e))?;
+            last_log_id.get_or_insert(ent.log_id);
+            if let EntryPayload::Membership(m) = ent.payload {
+                membership = Some(StoredMembership::new(Some(ent.log_id), m));
+                break;
+            }
+        }
+        let last_log_id = last_log_id.or(self.get_last_purged_()?);
+
+        // Entries up to the snapshot may be purged.
+        if membership.is_none() {
+            let snapshot: Option<StoredSnapshot> = self
+                .engine
+                .get("store", b"snapshot")
+                .map_err(|e| StorageIOError::read(&e))?
+                .and_then(|v| serde_json::from_slice(&v).ok());
+            membership = snapshot
+                .map(|snap| snap.meta.last_membership)
+                .filter(|m| m.log_id().is_some());
+        }
+
+        Ok(RaftState {
+            vote: self.get_vote_()?,
+            last_log_id,
+            membership,
+        })
     }
 
-    fn logs(&self) -> &ColumnFamily {
-        self.db.cf_handle("logs").unwrap()
+    pub fn monitor(&self) -> StorageMonitor {
+        StorageMonitor {
+            engine: self.engine.clone(),
+        }
     }
 
-    fn flush(
+    /// Apply `changes` atomically, durable once it returns.
+    fn write(
         &self,
         subject: ErrorSubject<NodeId>,
         verb: ErrorVerb,
+        changes: Vec<Change>,
     ) -> Result<(), StorageIOError<NodeId>> {
-        self.db
-            .flush_wal(true)
-            .map_err(|e| StorageIOError::new(subject, verb, AnyError::new(&e)))?;
-        Ok(())
+        self.engine
+            .write(changes)
+            .map_err(|e| StorageIOError::new(subject, verb, e))
     }
 
-    fn get_last_purged_(&self) -> StorageResult<Option<LogId<u64>>> {
-        Ok(self
-            .db
-            .get_cf(self.store(), b"last_purged_log_id")
-            .map_err(|e| StorageIOError::read(&e))?
-            .and_then(|v| serde_json::from_slice(&v).ok()))
+    /// Read the JSON value of `key` in the `store` column family.
+    ///
+    /// A value that can't be decoded is an error: taking it for a missing one could, e.g., let
+    /// the node vote twice in a term.
+    fn get_json<T: DeserializeOwned>(
+        &self,
+        key: &[u8],
+        subject: ErrorSubject<NodeId>,
+    ) -> Result<Option<T>, StorageIOError<NodeId>> {
+        let read_error = |e: AnyError| StorageIOError::new(subject.clone(), ErrorVerb::Read, e);
+        let Some(value) = self.engine.get("store", key).map_err(read_error)? else {
+            return Ok(None);
+        };
+        serde_json::from_slice(&value)
+            .map(Some)
+            .map_err(|e| read_error(AnyError::new(&e)))
     }
 
-    fn set_last_purged_(&self, log_id: LogId<u64>) -> StorageResult<()> {
-        self.db
-            .put_cf(
-                self.store(),
-                b"last_purged_log_id",
-                serde_json::to_vec(&log_id).unwrap().as_slice(),
-            )
-            .map_err(|e| StorageIOError::write(&e))?;
+    fn put_json<T: Serialize>(key: &[u8], value: &T) -> Change {
+        Change::Put {
+            cf: "store",
+            key: key.to_vec(),
+            value: serde_json::to_vec(value).unwrap(),
+        }
+    }
 
-        self.flush(ErrorSubject::Store, ErrorVerb::Write)?;
-        Ok(())
+    fn get_last_purged_(&self) -> StorageResult<Option<LogId<u64>>> {
+        Ok(self.get_json(b"last_purged_log_id", ErrorSubject::Store)?)
     }
 
     fn set_committed_(
         &self,
         committed: &Option<LogId<NodeId>>,
     ) -> Result<(), StorageIOError<NodeId>> {
-        let json = serde_json::to_vec(committed).unwrap();
-
-        self.db
-            .put_cf(self.store(), b"committed", json)
-            .map_err(|e| StorageIOError::write(&e))?;
-
-        self.flush(ErrorSubject::Store, ErrorVerb::Write)?;
-        Ok(())
+        self.write(
+            ErrorSubject::Store,
+            ErrorVerb::Write,
+            vec![Self::put_json(b"committed", committed)],
+        )
     }
 
     fn get_committed_(&self) -> StorageResult<Option<LogId<NodeId>>> {
-        Ok(self
-            .db
-            .get_cf(self.store(), b"committed")
-            .map_err(|e| StorageError::IO {
-                source: StorageIOError::read(&e),
-            })?
-            .and_then(|v| serde_json::from_slice(&v).ok()))
+        let committed: Option<Option<LogId<NodeId>>> =
+            self.get_json(b"committed", ErrorSubject::Store)?;
+        Ok(committed.flatten())
     }
 
     fn set_vote_(&self, vote: &Vote<NodeId>) -> StorageResult<()> {
-        self.db
-            .put_cf(self.store(), b"vote", serde_json::to_vec(vote).unwrap())
-            .map_err(|e| StorageError::IO {
-                source: StorageIOError::write_vote(&e),
-            })?;
-
-        self.flush(ErrorSubject::Vote, ErrorVerb::Write)?;
+        self.write(
+            ErrorSubject::Vote,
+            ErrorVerb::Write,
+            vec![Self::put_json(b"vote", vote)],
+        )?;
         Ok(())
     }
 
     fn get_vote_(&self) -> StorageResult<Option<Vote<NodeId>>> {
-        Ok(self
-            .db
-            .get_cf(self.store(), b"vote")
-            .map_err(|e| StorageError::IO {
-                source: StorageIOError::write_vote(&e),
-            })?
-            .and_then(|v| serde_json::from_slice(&v).ok()))
+        Ok(self.get_json(b"vote", ErrorSubject::Vote)?)
+    }
+
+    /// Write `entries` to disk, all or none of them.
+    fn append_<I>(&self, entries: I) -> StorageResult<()>
+    where
+        I: IntoIterator<Item = Entry<TypeConfig>>,
+    {
+        let mut changes = Vec::new();
+        for entry in entries {
+            let id = id_to_bin(entry.log_id.index);
+            assert_eq!(bin_to_id(&id), entry.log_id.index);
+            changes.push(Change::Put {
+                cf: "logs",
+                key: id,
+                value: serde_json::to_vec(&entry).map_err(|e| StorageIOError::write_logs(&e))?,
+            });
+        }
+        self.write(ErrorSubject::Logs, ErrorVerb::Write, changes)?;
+        Ok(())
     }
 }
 
@@ -408,25 +700,24 @@ impl RaftLogReader<TypeConfig> for LogStore {
             std::ops::Bound::Excluded(x) => id_to_bin(*x + 1),
             std::ops::Bound::Unbounded => id_to_bin(0),
         };
-        self.db
-            .iterator_cf(
-                self.logs(),
-                rocksdb::IteratorMode::From(&start, Direction::Forward),
-            )
-            .map(|res| {
-                let (id, val) = res.unwrap();
-                let entry: StorageResult<Entry<_>> =
-                    serde_json::from_slice(&val).map_err(|e| StorageError::IO {
-                        source: StorageIOError::read_logs(&e),
-                    });
-                let id = bin_to_id(&id);
-
-                assert_eq!(Ok(id), entry.as_ref().map(|e| e.log_id.index));
-                (id, entry)
-            })
-            .take_while(|(id, _)| range.contains(id))
-            .map(|x| x.1)
-            .collect()
+        let mut entries = Vec::new();
+        for res in self.engine.iter("logs", IterMode::From(&start)) {
+            let (id, val) = res.map_err(|e| StorageIOError::read_logs(&e))?;
+            let id = bin_to_id(&id);
+            if !range.contains(&id) {
+                break;
+            }
+            let entry: Entry<TypeConfig> =
+                serde_json::from_slice(&val).map_err(|e| StorageIOError::read_logs(&e))?;
+            if entry.log_id.index != id {
+                let e = AnyError::error(format!("entry {} holds log id {}", id, entry.log_id));
+                return Err(
+                    StorageIOError::new(ErrorSubject::LogIndex(id), ErrorVerb::Read, e).into(),
+                );
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
     }
 }
 
@@ -434,18 +725,16 @@ impl RaftLogStorage<TypeConfig> for LogStore {
     type LogReader = Self;
 
     async fn get_log_state(&mut self) -> StorageResult<LogState<TypeConfig>> {
-        let last = self
-            .db
-            .iterator_cf(self.logs(), rocksdb::IteratorMode::End)
-            .next()
-            .and_then(|res| {
-                let (_, ent) = res.unwrap();
-                Some(
-                    serde_json::from_slice::<Entry<TypeConfig>>(&ent)
-                        .ok()?
-                        .log_id,
-                )
-            });
+        // An undecodable last entry is an error: skipping it would hide the entries before it.
+        let last = match self.engine.iter("logs", IterMode::End).next() {
+            Some(res) => {
+                let (_, ent) = res.map_err(|e| StorageIOError::read_logs(&e))?;
+                let ent = serde_json::from_slice::<Entry<TypeConfig>>(&ent)
+                    .map_err(|e| StorageIOError::read_logs(&e))?;
+                Some(ent.log_id)
+            }
+            None => None,
+        };
 
         let last_purged_log_id = self.get_last_purged_()?;
 
@@ -487,18 +776,8 @@ impl RaftLogStorage<TypeConfig> for LogStore {
         I: IntoIterator<Item = Entry<TypeConfig>> + Send,
         I::IntoIter: Send,
     {
-        for entry in entries {
-            let id = id_to_bin(entry.log_id.index);
-            assert_eq!(bin_to_id(&id), entry.log_id.index);
-            self.db
-                .put_cf(
-                    self.logs(),
-                    id,
-                    serde_json::to_vec(&entry).map_err(|e| StorageIOError::write_logs(&e))?,
-                )
-                .map_err(|e| StorageIOError::write_logs(&e))?;
-        }
-
+        // The entries must be on disk before openraft counts them as persisted.
+        self.append_(entries)?;
         callback.log_io_completed(Ok(()));
 
         Ok(())
@@ -508,23 +787,30 @@ impl RaftLogStorage<TypeConfig> for LogStore {
     async fn truncate(&mut self, log_id: LogId<NodeId>) -> StorageResult<()> {
         tracing::debug!("delete_log: [{:?}, +oo)", log_id);
 
-        let from = id_to_bin(log_id.index);
-        let to = id_to_bin(0xff_ff_ff_ff_ff_ff_ff_ff);
-        self.db
-            .delete_range_cf(self.logs(), &from, &to)
-            .map_err(|e| StorageIOError::write_logs(&e).into())
+        let change = Change::DeleteRange {
+            cf: "logs",
+            from: id_to_bin(log_id.index),
+            to: id_to_bin(0xff_ff_ff_ff_ff_ff_ff_ff),
+        };
+        self.write(ErrorSubject::Logs, ErrorVerb::Delete, vec![change])?;
+        Ok(())
     }
 
     #[tracing::instrument(level = "debug", skip(self))]
     async fn purge(&mut self, log_id: LogId<NodeId>) -> Result<(), StorageError<NodeId>> {
         tracing::debug!("delete_log: [0, {:?}]", log_id);
 
-        self.set_last_purged_(log_id)?;
-        let from = id_to_bin(0);
-        let to = id_to_bin(log_id.index + 1);
-        self.db
-            .delete_range_cf(self.logs(), &from, &to)
-            .map_err(|e| StorageIOError::write_logs(&e).into())
+        // The marker and the deletion go together, so that the log never has a gap after it.
+        let changes = vec![
+            Self::put_json(b"last_purged_log_id", &log_id),
+            Change::DeleteRange {
+                cf: "logs",
+                from: id_to_bin(0),
+                to: id_to_bin(log_id.index + 1),
+            },
+        ];
+        self.write(ErrorSubject::Logs, ErrorVerb::Delete, changes)?;
+        Ok(())
     }
 
     async fn get_log_reader(&mut self) -> Self::LogReader {
@@ -532,19 +818,36 @@ impl RaftLogStorage<TypeConfig> for LogStore {
     }
 }
 
-pub(crate) async fn new_storage<P: AsRef<Path>>(db_path: P) -> (LogStore, StateMachineStore) {
-    let mut db_opts = Options::default();
-    db_opts.create_missing_column_families(true);
-    db_opts.create_if_missing(true);
-
-    let store = ColumnFamilyDescriptor::new("store", Options::default());
-    let logs = ColumnFamilyDescriptor::new("logs", Options::default());
+/// Read the Raft state stored in the data directory `db_path`, without opening it for writing.
+///
+/// A directory without a database yields an empty state. The node may be running.
+pub fn read_raft_state<P: AsRef<Path>>(db_path: P) -> StorageResult<RaftState> {
+    let db_path = db_path.as_ref();
+    if !db_path.join("CURRENT").exists() {
+        return Ok(RaftState::default());
+    }
+    let engine = RocksEngine::open_read_only(db_path).map_err(|e| StorageIOError::read(&e))?;
+    LogStore::new(Arc::new(engine)).raft_state()
+}
 
-    let db = DB::open_cf_descriptors(&db_opts, db_path, vec![store, logs]).unwrap();
-    let db = Arc::new(db);
+/// Open the storage of a node on `backend`, in `dir` if it keeps its data on disk.
+pub(crate) async fn open_storage(
+    backend: StorageBackend,
+    dir: &Path,
+) -> (LogStore, StateMachineStore) {
+    let engine: Arc<dyn StorageEngine> = match backend {
+        StorageBackend::RocksDb => match RocksEngine::open(dir).await {
+            Ok(engine) => Arc::new(engine),
+            Err(e) => panic!("failed to open {}: {}", dir.display(), e),
+        },
+        StorageBackend::Memory => Arc::new(MemEngine::default()),
+    };
+    new_storage(engine).await
+}
 
-    let log_store = LogStore { db: db.clone() };
-    let sm_store = StateMachineStore::new(db).await.unwrap();
+pub(crate) async fn new_storage(engine: Arc<dyn StorageEngine>) -> (LogStore, StateMachineStore) {
+    let log_store = LogStore::new(engine.clone());
+    let sm_store = StateMachineStore::new(engine).await.unwrap();
 
     (log_store, sm_store)
 }
@@ -555,7 +858,7 @@ mod tests {
     use openraft::testing::StoreBuilder;
     use tempfile::TempDir;
 
-    /// Struct to test the memory store.
+    /// Struct to test the RocksDB store.
     struct RocksBuilder {}
 
     impl StoreBuilder<TypeConfig, LogStore, StateMachineStore, TempDir> for RocksBuilder {
@@ -563,14 +866,85 @@ mod tests {
             &self,
         ) -> Result<(TempDir, LogStore, StateMachineStore), StorageError<NodeId>> {
             let td = TempDir::new().expect("couldn't create temp dir");
-            let (log_store, sm) = new_storage(td.path()).await;
+            let (log_store, sm) = open_storage(StorageBackend::RocksDb, td.path()).await;
             Ok((td, log_store, sm))
         }
     }
 
+    /// Struct to test the memory store.
+    struct MemBuilder {}
+
+    impl StoreBuilder<TypeConfig, LogStore, StateMachineStore, ()> for MemBuilder {
+        async fn build(&self) -> Result<((), LogStore, StateMachineStore), StorageError<NodeId>> {
+            let (log_store, sm) = new_storage(Arc::new(MemEngine::default())).await;
+            Ok(((), log_store, sm))
+        }
+    }
+
     #[test]
-    pub fn test_mem_store() -> Result<(), StorageError<NodeId>> {
+    pub fn test_rocks_store() -> Result<(), StorageError<NodeId>> {
         openraft::testing::Suite::test_all(RocksBuilder {})?;
         Ok(())
     }
+
+    #[test]
+    pub fn test_mem_store() -> Result<(), StorageError<NodeId>> {
+        openraft::testing::Suite::test_all(MemBuilder {})?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_compare_and_swap() -> Result<(), NamespaceError> {
+        let (_log_store, sm) = new_storage(Arc::new(MemEngine::default())).await;
+        let cas = |expected: Option<&str>, value: Value| Request::CompareAndSwap {
+            key: "key".into(),
+            expected: expected.map(Bytes::from),
+            value,
+        };
+
+        // The key must not exist yet.
+        assert_eq!(sm.data.apply_request(cas(None, "a".into())).await?, None);
+        assert_eq!(sm.data.apply_request(cas(None, "b".into())).await?, Some("a".into()));
+        assert_eq!(sm.data.apply_request(cas(Some("b"), "c".into())).await?, Some("a".into()));
+        assert_eq!(sm.data.apply_request(cas(Some("a"), "c".into())).await?, Some("a".into()));
+        assert_eq!(sm.data.kvs.read().await.get(b"key".as_slice()), Some(&"c".into()));
+
+        // Compressed values are compared as written.
+        let large = "d".repeat(4096);
+        let compressed = Value::compress(large.clone().into(), Some(0));
+        assert!(matches!(compressed, Value::Zstd { .. }));
+        sm.data.apply_request(cas(Some("c"), compressed.clone())).await?;
+        assert_eq!(sm.data.apply_request(cas(Some(&large), "e".into())).await?, Some(compressed));
+        assert_eq!(sm.data.kvs.read().await.get(b"key".as_slice()), Some(&"e".into()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_raft_state() -> Result<(), StorageError<NodeId>> {
+        let td = TempDir::new().expect("couldn't create temp dir");
+        assert!(!read_raft_state(td.path())?.is_initialized());
+
+        let (log_store, _sm) = open_storage(StorageBackend::RocksDb, td.path()).await;
+        assert!(!log_store.raft_state()?.is_initialized());
+
+        let membership = openraft::Membership::new(
+            vec![std::collections::BTreeSet::from([1])],
+            BTreeMap::from([(1, Node::default())]),
+        );
+        let log_id = LogId::new(openraft::CommittedLeaderId::new(1, 1), 0);
+        let entry = Entry::<TypeConfig> {
+            log_id,
+            payload: EntryPayload::Membership(membership),
+        };
+        log_store.append_([entry])?;
+        log_store.set_vote_(&Vote::new(1, 1))?;
+
+        // Readable while the node holds the database.
+        let state = read_raft_state(td.path())?;
+        assert!(state.is_initialized());
+        assert_eq!(state.vote, Some(Vote::new(1, 1)));
+        assert_eq!(state.last_log_id, Some(log_id));
+        assert_eq!(state.membership.and_then(|m| *m.log_id()), Some(log_id));
+        Ok(())
+    }
 }
@@ -0,0 +1,270 @@
+//! Crash-consistency tests of the [`LogStore`].
+//!
+//! A [`CrashEngine`] wraps the [`RocksEngine`] of a log store and crashes it at its n-th write:
+//! the write is lost, or reaches the disk torn, and every later write is lost. The tests run a
+//! workload of Raft storage calls, crash it at every write, reopen the database and check that
+//! the store holds exactly what was acknowledged before the crash, or reports a torn value as
+//! an error rather than taking it for a missing one.
+use std::ops::RangeInclusive;
+use std::sync::Mutex;
+
+use openraft::CommittedLeaderId;
+use tempfile::TempDir;
+
+use super::engine::KeyValues;
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Crash {
+    /// The write does not reach the disk.
+    Lost,
+    /// The write reaches the disk with the value of its last put cut in half, as if the last
+    /// sector was not written.
+    Torn,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    writes: usize,
+    armed: Option<(usize, Crash)>,
+    crashed: bool,
+}
+
+/// Counts the writes to an engine, and crashes it at the armed one.
+#[derive(Debug)]
+struct CrashEngine {
+    inner: RocksEngine,
+    state: Mutex<State>,
+}
+
+impl CrashEngine {
+    /// Crash at the `n`-th write since the engine was opened, counting from 0.
+    fn arm(&self, n: usize, crash: Crash) {
+        self.state.lock().unwrap().armed = Some((n, crash));
+    }
+
+    fn writes(&self) -> usize {
+        self.state.lock().unwrap().writes
+    }
+
+    /// The changes of a write that reach the disk, and the error of the write if it crashed.
+    fn intercept(&self, mut changes: Vec<Change>) -> (Vec<Change>, Option<AnyError>) {
+        let mut state = self.state.lock().unwrap();
+        let n = state.writes;
+        state.writes += 1;
+        if state.crashed {
+            return (Vec::new(), Some(AnyError::error("the store crashed")));
+        }
+        let Some((at, crash)) = state.armed else {
+            return (changes, None);
+        };
+        if at != n {
+            return (changes, None);
+        }
+        state.crashed = true;
+        match crash {
+            Crash::Lost => changes.clear(),
+            Crash::Torn => {
+                let last_put = changes.iter_mut().rev().find_map(|change| match change {
+                    Change::Put { value, .. } => Some(value),
+                    Change::DeleteRange { .. } => None,
+                });
+                if let Some(value) = last_put {
+                    value.truncate(value.len() / 2);
+                }
+            }
+        }
+        (changes, Some(AnyError::error(format!("crash at write {}", n))))
+    }
+}
+
+impl StorageEngine for CrashEngine {
+    fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, AnyError> {
+        self.inner.get(cf, key)
+    }
+
+    fn write(&self, changes: Vec<Change>) -> Result<(), AnyError> {
+        let (changes, crash) = self.intercept(changes);
+        self.inner.write(changes)?;
+        crash.map_or(Ok(()), Err)
+    }
+
+    fn iter(&self, cf: &str, mode: IterMode<'_>) -> KeyValues<'_> {
+        self.inner.iter(cf, mode)
+    }
+
+    fn checkpoint(&self, dir: &Path) -> Result<(), AnyError> {
+        self.inner.checkpoint(dir)
+    }
+}
+
+/// A call to the log store, each of which makes one write.
+#[derive(Debug, Clone)]
+enum Op {
+    Vote(Vote<NodeId>),
+    Append(Vec<Entry<TypeConfig>>),
+    Commit(LogId<NodeId>),
+    Truncate(LogId<NodeId>),
+    Purge(LogId<NodeId>),
+}
+
+fn log_id(term: u64, index: u64) -> LogId<NodeId> {
+    LogId::new(CommittedLeaderId::new(term, 1), index)
+}
+
+fn append(term: u64, indexes: RangeInclusive<u64>) -> Op {
+    let entries = indexes
+        .map(|index| Entry {
+            log_id: log_id(term, index),
+            payload: EntryPayload::Blank,
+        })
+        .collect();
+    Op::Append(entries)
+}
+
+/// The calls openraft makes on a node that appends, commits, loses an election, has its
+/// uncommitted entries replaced by the new leader, and purges its log after snapshots.
+fn workload() -> Vec<Op> {
+    vec![
+        Op::Vote(Vote::new(1, 1)),
+        append(1, 0..=4),
+        Op::Commit(log_id(1, 2)),
+        append(1, 5..=7),
+        Op::Vote(Vote::new(2, 2)),
+        Op::Truncate(log_id(1, 6)),
+        append(2, 6..=8),
+        Op::Commit(log_id(2, 7)),
+        Op::Purge(log_id(1, 3)),
+        append(2, 9..=10),
+        Op::Commit(log_id(2, 9)),
+        Op::Purge(log_id(2, 8)),
+    ]
+}
+
+async fn run(store: &mut LogStore, op: &Op) -> StorageResult<()> {
+    match op {
+        Op::Vote(vote) => store.save_vote(vote).await,
+        Op::Append(entries) => store.append_(entries.clone()),
+        Op::Commit(log_id) => store.save_committed(Some(*log_id)).await,
+        Op::Truncate(log_id) => store.truncate(*log_id).await,
+        Op::Purge(log_id) => store.purge(*log_id).await,
+    }
+}
+
+/// What a log store holds, as openraft reads it when the node starts.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Stored {
+    vote: Option<Vote<NodeId>>,
+    committed: Option<LogId<NodeId>>,
+    last_purged: Option<LogId<NodeId>>,
+    last_log_id: Option<LogId<NodeId>>,
+    logs: Vec<LogId<NodeId>>,
+}
+
+impl Stored {
+    async fn read(store: &mut LogStore) -> StorageResult<Self> {
+        let state = store.get_log_state().await?;
+        let logs = store.try_get_log_entries(..).await?;
+        Ok(Self {
+            vote: store.read_vote().await?,
+            committed: store.read_committed().await?,
+            last_purged: state.last_purged_log_id,
+            last_log_id: state.last_log_id,
+            logs: logs.iter().map(|entry| entry.log_id).collect(),
+        })
+    }
+
+    /// The state after `op` succeeded.
+    fn apply(&mut self, op: &Op) {
+        match op {
+            Op::Vote(vote) => self.vote = Some(*vote),
+            Op::Append(entries) => self.logs.extend(entries.iter().map(|entry| entry.log_id)),
+            Op::Commit(log_id) => self.committed = Some(*log_id),
+            Op::Truncate(log_id) => self.logs.retain(|id| id.index < log_id.index),
+            Op::Purge(log_id) => {
+                self.last_purged = Some(*log_id);
+                self.logs.retain(|id| id.index > log_id.index);
+            }
+        }
+        self.last_log_id = self.logs.last().copied().or(self.last_purged);
+    }
+
+    /// Check the invariants openraft relies on when it starts on the store.
+    fn check_invariants(&self) {
+        let first = self.last_purged.map_or(0, |id| id.index + 1);
+        for (i, id) in self.logs.iter().enumerate() {
+            assert_eq!(id.index, first + i as u64, "gap in the log: {:?}", self);
+        }
+        assert!(
+            self.logs.windows(2).all(|ids| ids[0].leader_id <= ids[1].leader_id),
+            "terms go back: {:?}",
+            self
+        );
+        assert_eq!(self.last_log_id, self.logs.last().copied().or(self.last_purged));
+        assert!(self.committed <= self.last_log_id, "committed beyond the log: {:?}", self);
+    }
+}
+
+/// Open the log store in `dir`, with its engine to crash it.
+async fn open(dir: &Path) -> (LogStore, Arc<CrashEngine>) {
+    let engine = Arc::new(CrashEngine {
+        inner: RocksEngine::open(dir).await.expect("couldn't open the database"),
+        state: Mutex::default(),
+    });
+    (new_storage(engine.clone()).await.0, engine)
+}
+
+#[tokio::test]
+async fn test_crash_at_every_write() -> Result<(), StorageError<NodeId>> {
+    let ops = workload();
+
+    // The state acknowledged after every prefix of the workload.
+    let dir = TempDir::new().expect("couldn't create temp dir");
+    let (mut store, engine) = open(dir.path()).await;
+    let mut expected = vec![Stored::default()];
+    for op in &ops {
+        run(&mut store, op).await?;
+        let mut next = expected.last().unwrap().clone();
+        next.apply(op);
+        next.check_invariants();
+        expected.push(next);
+    }
+    assert_eq!(engine.writes(), ops.len());
+    assert_eq!(&Stored::read(&mut store).await?, expected.last().unwrap());
+    drop((store, engine));
+
+    for n in 0..ops.len() {
+        for crash in [Crash::Lost, Crash::Torn] {
+            let dir = TempDir::new().expect("couldn't create temp dir");
+            let (mut store, engine) = open(dir.path()).await;
+            engine.arm(n, crash);
+            for (i, op) in ops.iter().enumerate() {
+                let res = run(&mut store, op).await;
+                assert_eq!(res.is_err(), i >= n, "{:?} crash at write {}, op {}", crash, n, i);
+            }
+            drop((store, engine));
+
+            let (mut store, _) = open(dir.path()).await;
+            match (crash, Stored::read(&mut store).await) {
+                (Crash::Lost, Ok(stored)) => {
+                    stored.check_invariants();
+                    assert_eq!(stored, expected[n], "lost write {}", n);
+                    // The node picks up where it crashed.
+                    for op in &ops[n..] {
+                        run(&mut store, op).await?;
+                    }
+                    assert_eq!(&Stored::read(&mut store).await?, expected.last().unwrap());
+                }
+                (Crash::Lost, Err(e)) => panic!("lost write {}: {}", n, e),
+                // Only a write without values, such as a truncation, can be torn unnoticed.
+                (Crash::Torn, Ok(stored)) => {
+                    stored.check_invariants();
+                    assert!(matches!(ops[n], Op::Truncate(_)), "torn write {}: {:?}", n, stored);
+                    assert_eq!(stored, expected[n + 1], "torn write {}", n);
+                }
+                (Crash::Torn, Err(_)) => {}
+            }
+        }
+    }
+    Ok(())
+}
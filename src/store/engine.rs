@@ -0,0 +1,286 @@
+//! Storage engines underneath the [`LogStore`] and the [`StateMachineStore`].
+//!
+//! A [`StorageEngine`] is an ordered key-value store with the column families of
+//! [`COLUMN_FAMILIES`]: `store` holds the vote, the committed and purged log ids and the
+//! snapshot, `logs` holds the log entries by big-endian index. Writes are batches of
+//! [`Change`]s, applied atomically and durable once [`StorageEngine::write`] returns.
+//!
+//! [`RocksEngine`] keeps the data in RocksDB, in the data directory of the node. [`MemEngine`]
+//! keeps it in memory, for tests and benchmarks: a node on it loses its state, including its
+//! vote, when it stops, so it must not rejoin its cluster afterwards.
+//!
+//! Every engine can write a checkpoint of its data as a RocksDB database, which is how backups
+//! are taken, see [`crate::backup`].
+//!
+//! [`LogStore`]: super::LogStore
+//! [`StateMachineStore`]: super::StateMachineStore
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::ops::Bound;
+use std::path::Path;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use openraft::AnyError;
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::ColumnFamily;
+use rocksdb::ColumnFamilyDescriptor;
+use rocksdb::Direction;
+use rocksdb::IteratorMode;
+use rocksdb::Options;
+use rocksdb::WriteBatch;
+use rocksdb::WriteOptions;
+use rocksdb::DB;
+use serde::Deserialize;
+use serde::Serialize;
+
+use super::COLUMN_FAMILIES;
+
+/// Storage engine of a node, see the module documentation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageBackend {
+    /// RocksDB, in the data directory of the node.
+    #[default]
+    RocksDb,
+    /// In memory. The node loses its state when it stops.
+    Memory,
+}
+
+/// A change to the data of a [`StorageEngine`].
+#[derive(Debug, Clone)]
+pub enum Change {
+    Put {
+        cf: &'static str,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    /// Delete the keys in `[from, to)`.
+    DeleteRange {
+        cf: &'static str,
+        from: Vec<u8>,
+        to: Vec<u8>,
+    },
+}
+
+/// Where [`StorageEngine::iter`] starts.
+#[derive(Debug, Clone, Copy)]
+pub enum IterMode<'a> {
+    /// From the first key that is not smaller than this one, forwards.
+    From(&'a [u8]),
+    /// From the last key, backwards.
+    End,
+}
+
+pub type KeyValues<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), AnyError>> + 'a>;
+
+/// An ordered key-value store with column families, see the module documentation.
+///
+/// Column families are those of [`COLUMN_FAMILIES`], engines may panic on others.
+pub trait StorageEngine: Debug + Send + Sync {
+    fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, AnyError>;
+
+    /// Apply `changes` atomically. They are durable once this returns.
+    fn write(&self, changes: Vec<Change>) -> Result<(), AnyError>;
+
+    /// The keys of `cf` with their values, in order from `mode`.
+    fn iter(&self, cf: &str, mode: IterMode<'_>) -> KeyValues<'_>;
+
+    /// Copy the data, as of a single point in time, to a new RocksDB database in `dir`, which
+    /// must not exist yet. The engine keeps serving reads and writes meanwhile.
+    fn checkpoint(&self, dir: &Path) -> Result<(), AnyError>;
+
+    /// Integer property of the column family `cf`, e.g., `rocksdb.estimate-num-keys`, if the
+    /// engine has it.
+    fn property(&self, _cf: &str, _name: &str) -> Option<u64> {
+        None
+    }
+}
+
+/// A [`StorageEngine`] on RocksDB.
+#[derive(Debug)]
+pub struct RocksEngine {
+    db: DB,
+}
+
+impl RocksEngine {
+    /// Open the database in `path`, creating it if it does not exist.
+    pub async fn open(path: &Path) -> Result<Self, rocksdb::Error> {
+        // A previous instance of the node in this process may still be releasing the database.
+        let mut retries = 50;
+        loop {
+            match Self::open_now(path) {
+                Ok(engine) => return Ok(engine),
+                Err(e) if retries > 0 && e.to_string().contains("lock") => {
+                    retries -= 1;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn open_now(path: &Path) -> Result<Self, rocksdb::Error> {
+        let mut db_opts = Options::default();
+        db_opts.create_missing_column_families(true);
+        db_opts.create_if_missing(true);
+        let cfs = COLUMN_FAMILIES
+            .iter()
+            .map(|name| ColumnFamilyDescriptor::new(*name, Options::default()));
+        let db = DB::open_cf_descriptors(&db_opts, path, cfs)?;
+        Ok(Self { db })
+    }
+
+    /// Open the database in `path` for reading only. The node may be running.
+    pub fn open_read_only(path: &Path) -> Result<Self, rocksdb::Error> {
+        let db = DB::open_cf_for_read_only(&Options::default(), path, COLUMN_FAMILIES, false)?;
+        Ok(Self { db })
+    }
+
+    fn cf(&self, name: &str) -> &ColumnFamily {
+        self.db
+            .cf_handle(name)
+            .unwrap_or_else(|| panic!("no column family {}", name))
+    }
+}
+
+impl StorageEngine for RocksEngine {
+    fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, AnyError> {
+        self.db.get_cf(self.cf(cf), key).map_err(|e| AnyError::new(&e))
+    }
+
+    fn write(&self, changes: Vec<Change>) -> Result<(), AnyError> {
+        let mut batch = WriteBatch::default();
+        for change in changes {
+            match change {
+                Change::Put { cf, key, value } => batch.put_cf(self.cf(cf), key, value),
+                Change::DeleteRange { cf, from, to } => {
+                    batch.delete_range_cf(self.cf(cf), from, to)
+                }
+            }
+        }
+        let mut options = WriteOptions::default();
+        options.set_sync(true);
+        self.db.write_opt(batch, &options).map_err(|e| AnyError::new(&e))
+    }
+
+    fn iter(&self, cf: &str, mode: IterMode<'_>) -> KeyValues<'_> {
+        let mode = match mode {
+            IterMode::From(start) => IteratorMode::From(start, Direction::Forward),
+            IterMode::End => IteratorMode::End,
+        };
+        Box::new(self.db.iterator_cf(self.cf(cf), mode).map(|res| {
+            res.map(|(key, value)| (key.into_vec(), value.into_vec()))
+                .map_err(|e| AnyError::new(&e))
+        }))
+    }
+
+    fn checkpoint(&self, dir: &Path) -> Result<(), AnyError> {
+        // Hard links to the files of the database, after flushing its memtables.
+        Checkpoint::new(&self.db)
+            .and_then(|checkpoint| checkpoint.create_checkpoint(dir))
+            .map_err(|e| AnyError::new(&e))
+    }
+
+    fn property(&self, cf: &str, name: &str) -> Option<u64> {
+        let cf = self.db.cf_handle(cf)?;
+        self.db.property_int_value_cf(cf, name).ok().flatten()
+    }
+}
+
+/// A [`StorageEngine`] in memory, see the module documentation.
+#[derive(Debug)]
+pub struct MemEngine {
+    cfs: RwLock<HashMap<&'static str, BTreeMap<Vec<u8>, Vec<u8>>>>,
+}
+
+impl Default for MemEngine {
+    fn default() -> Self {
+        let cfs = COLUMN_FAMILIES.iter().map(|name| (*name, BTreeMap::new()));
+        Self {
+            cfs: RwLock::new(cfs.collect()),
+        }
+    }
+}
+
+impl MemEngine {
+    /// An engine holding a copy of the data of `engine`.
+    pub fn copy_of(engine: &dyn StorageEngine) -> Result<Self, AnyError> {
+        let mut cfs = HashMap::new();
+        for name in COLUMN_FAMILIES {
+            let keys = engine.iter(name, IterMode::From(&[])).collect::<Result<_, _>>()?;
+            cfs.insert(name, keys);
+        }
+        Ok(Self {
+            cfs: RwLock::new(cfs),
+        })
+    }
+}
+
+impl StorageEngine for MemEngine {
+    fn get(&self, cf: &str, key: &[u8]) -> Result<Option<Vec<u8>>, AnyError> {
+        let cfs = self.cfs.read().unwrap();
+        Ok(cfs[cf].get(key).cloned())
+    }
+
+    fn write(&self, changes: Vec<Change>) -> Result<(), AnyError> {
+        let mut cfs = self.cfs.write().unwrap();
+        for change in changes {
+            match change {
+                Change::Put { cf, key, value } => {
+                    cfs.get_mut(cf).unwrap().insert(key, value);
+                }
+                Change::DeleteRange { cf, from, to } => {
+                    let keys = cfs.get_mut(cf).unwrap();
+                    let mut deleted = keys.split_off(&from);
+                    keys.append(&mut deleted.split_off(&to));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn iter(&self, cf: &str, mode: IterMode<'_>) -> KeyValues<'_> {
+        // The name of the column family, for as long as the iterator lives.
+        let name = self.cfs.read().unwrap().get_key_value(cf).map(|(name, _)| *name);
+        let cf = name.unwrap_or_else(|| panic!("no column family {}", cf));
+        let forward = matches!(mode, IterMode::From(_));
+        let mut next = match mode {
+            IterMode::From(start) => Bound::Included(start.to_vec()),
+            IterMode::End => Bound::Unbounded,
+        };
+        // Every step looks the next key up again, so that the iterator does not hold the lock.
+        Box::new(std::iter::from_fn(move || {
+            let cfs = self.cfs.read().unwrap();
+            let bound = next.as_ref().map(Vec::as_slice);
+            let (key, value) = if forward {
+                cfs[cf].range::<[u8], _>((bound, Bound::Unbounded)).next()?
+            } else {
+                cfs[cf].range::<[u8], _>((Bound::Unbounded, bound)).next_back()?
+            };
+            next = Bound::Excluded(key.clone());
+            Some(Ok((key.clone(), value.clone())))
+        }))
+    }
+
+    fn checkpoint(&self, dir: &Path) -> Result<(), AnyError> {
+        if dir.exists() {
+            return Err(AnyError::error(format!("{} already exists", dir.display())));
+        }
+        let changes: Vec<Change> = {
+            let cfs = self.cfs.read().unwrap();
+            cfs.iter()
+                .flat_map(|(&cf, keys)| {
+                    keys.iter().map(move |(key, value)| Change::Put {
+                        cf,
+                        key: key.clone(),
+                        value: value.clone(),
+                    })
+                })
+                .collect()
+        };
+        let copy = RocksEngine::open_now(dir).map_err(|e| AnyError::new(&e))?;
+        copy.write(changes)
+    }
+}
@@ -0,0 +1,570 @@
+//! Runs the nodes of a managed cluster as separate `raft-kv` processes.
+//!
+//! Every node of the topology gets its own process, started with the addresses and data
+//! directory of the topology, and a task that supervises it. A process that exits while it
+//! should be running is restarted after [`ProcessConfig::restart_delay_ms`], unless it keeps
+//! exiting right after starting. The output of a node is appended to
+//! `<log_dir>/<shard>-node-<id>.log`, along with lines of the supervisor recording when the
+//! process was started, signaled and restarted.
+//!
+//! Nodes can be killed with `SIGKILL`, to simulate a crash the supervisor recovers from, stopped
+//! and started again, or suspended with `SIGSTOP`, to simulate a node that hangs without closing
+//! its connections.
+use std::fmt;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::ExitStatus;
+use std::process::Stdio;
+use std::time::Duration;
+
+use nix::sys::signal;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::process::Child;
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::sync::oneshot;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+use crate::auth::AuthConfig;
+use crate::topology::NodeSpec;
+use crate::topology::Topology;
+use crate::value::ValueConfig;
+use crate::NodeId;
+
+/// A process that ran for this long before exiting was not crash looping.
+const MIN_UPTIME: Duration = Duration::from_secs(10);
+
+/// Time a stopped process gets to shut down before it is killed.
+const STOP_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Error, Debug)]
+pub enum SupervisorError {
+    #[error("node executable {0} not found")]
+    MissingBinary(PathBuf),
+    #[error("no node {id} in shard {shard}")]
+    UnknownNode { shard: String, id: NodeId },
+    #[error("node {id} of shard {shard} is {status}")]
+    InvalidState {
+        shard: String,
+        id: NodeId,
+        status: ProcessStatus,
+    },
+    #[error("failed to signal the node process: {0}")]
+    Signal(#[from] nix::Error),
+    #[error("the supervisor of node {id} of shard {shard} is gone")]
+    Gone { shard: String, id: NodeId },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// How the node processes are started and supervised.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct ProcessConfig {
+    /// The `raft-kv` executable. Defaults to the one next to the current executable.
+    pub binary: Option<PathBuf>,
+    /// Directory the logs of the nodes are written to.
+    pub log_dir: PathBuf,
+    /// `RUST_LOG` of the nodes. Inherited from the manager if not set.
+    pub log_level: Option<String>,
+    /// Whether processes that exit without being stopped are restarted.
+    pub restart: bool,
+    /// Time before a process that exited is restarted, in milliseconds.
+    pub restart_delay_ms: u64,
+    /// Number of restarts in a row of a process that keeps exiting within 10 s of starting,
+    /// after which it is not restarted anymore.
+    pub max_restarts: u32,
+}
+
+impl Default for ProcessConfig {
+    fn default() -> Self {
+        Self {
+            binary: None,
+            log_dir: PathBuf::from("logs"),
+            log_level: None,
+            restart: true,
+            restart_delay_ms: 1000,
+            max_restarts: 5,
+        }
+    }
+}
+
+/// The state of the process of a node.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum ProcessStatus {
+    Running { pid: u32 },
+    /// Stopped with `SIGSTOP`, until it is resumed.
+    Suspended { pid: u32 },
+    /// Exited, it is restarted after the restart delay.
+    Restarting,
+    /// Stopped on request, until it is started again.
+    Stopped,
+    /// Exited and not restarted, because restarts are disabled or it kept exiting.
+    Exited,
+}
+
+impl fmt::Display for ProcessStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProcessStatus::Running { pid } => write!(f, "running (pid {})", pid),
+            ProcessStatus::Suspended { pid } => write!(f, "suspended (pid {})", pid),
+            ProcessStatus::Restarting => write!(f, "restarting"),
+            ProcessStatus::Stopped => write!(f, "stopped"),
+            ProcessStatus::Exited => write!(f, "exited"),
+        }
+    }
+}
+
+/// A node and the state of its process.
+#[derive(Debug, Clone, Serialize)]
+pub struct NodeProcess {
+    pub shard: String,
+    pub id: NodeId,
+    pub api_addr: String,
+    pub status: ProcessStatus,
+    /// Number of times the process was restarted after exiting.
+    pub restarts: u32,
+    pub log_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Kill,
+    Stop,
+    Start,
+    Suspend,
+    Resume,
+}
+
+struct Control {
+    op: Op,
+    reply: oneshot::Sender<Result<(), SupervisorError>>,
+}
+
+/// A node and the task supervising its process.
+struct Supervised {
+    control: mpsc::UnboundedSender<Control>,
+    state: watch::Receiver<NodeProcess>,
+    handle: JoinHandle<()>,
+}
+
+/// Runs every node of a topology as a process, see the module documentation.
+pub struct Supervisor {
+    nodes: Vec<Supervised>,
+}
+
+impl Supervisor {
+    /// Start the processes of all nodes of `topology`. The nodes accept the tokens of `auth` and
+    /// values as configured by `values`.
+    pub fn new(
+        config: &ProcessConfig,
+        topology: &Topology,
+        auth: &AuthConfig,
+        values: ValueConfig,
+    ) -> Result<Self, SupervisorError> {
+        let binary = match &config.binary {
+            Some(binary) => binary.clone(),
+            None => std::env::current_exe()?.with_file_name("raft-kv"),
+        };
+        if !binary.is_file() {
+            return Err(SupervisorError::MissingBinary(binary));
+        }
+        fs::create_dir_all(&config.log_dir)?;
+        let auth_config = if auth.tokens.is_empty() {
+            None
+        } else {
+            Some(write_auth_config(&config.log_dir, auth)?)
+        };
+
+        let mut nodes = Vec::new();
+        for (i, shard) in topology.shards.iter().enumerate() {
+            let name = shard.name(i);
+            for node in &shard.nodes {
+                fs::create_dir_all(&node.data_dir)?;
+                let launch = Launch {
+                    binary: binary.clone(),
+                    node: node.clone(),
+                    auth_config: auth_config.clone(),
+                    values,
+                    log_level: config.log_level.clone(),
+                    log_path: config.log_dir.join(format!("{}-node-{}.log", name, node.id)),
+                };
+                let (state_tx, state_rx) = watch::channel(NodeProcess {
+                    shard: name.clone(),
+                    id: node.id,
+                    api_addr: node.api_addr.clone(),
+                    status: ProcessStatus::Stopped,
+                    restarts: 0,
+                    log_path: launch.log_path.clone(),
+                });
+                let (control_tx, control_rx) = mpsc::unbounded_channel();
+                let handle = tokio::spawn(supervise(launch, config.clone(), control_rx, state_tx));
+                nodes.push(Supervised {
+                    control: control_tx,
+                    state: state_rx,
+                    handle,
+                });
+            }
+        }
+        Ok(Self { nodes })
+    }
+
+    /// The nodes and the state of their processes, in the order of the topology.
+    pub fn nodes(&self) -> Vec<NodeProcess> {
+        self.nodes.iter().map(|node| node.state.borrow().clone()).collect()
+    }
+
+    pub fn node(&self, shard: &str, id: NodeId) -> Result<NodeProcess, SupervisorError> {
+        Ok(self.find(shard, id)?.state.borrow().clone())
+    }
+
+    /// Kill the process of a node with `SIGKILL`. It is restarted like a crashed process.
+    pub async fn kill(&self, shard: &str, id: NodeId) -> Result<(), SupervisorError> {
+        self.control(shard, id, Op::Kill).await
+    }
+
+    /// Stop the process of a node with `SIGTERM`, and keep it down until it is started again.
+    pub async fn stop(&self, shard: &str, id: NodeId) -> Result<(), SupervisorError> {
+        self.control(shard, id, Op::Stop).await
+    }
+
+    /// Start the process of a node that was stopped, exited or is waiting to be restarted.
+    pub async fn start(&self, shard: &str, id: NodeId) -> Result<(), SupervisorError> {
+        self.control(shard, id, Op::Start).await
+    }
+
+    /// Suspend the process of a node with `SIGSTOP`. Its connections stay open, but it doesn't
+    /// answer anymore.
+    pub async fn suspend(&self, shard: &str, id: NodeId) -> Result<(), SupervisorError> {
+        self.control(shard, id, Op::Suspend).await
+    }
+
+    /// Resume a suspended process with `SIGCONT`.
+    pub async fn resume(&self, shard: &str, id: NodeId) -> Result<(), SupervisorError> {
+        self.control(shard, id, Op::Resume).await
+    }
+
+    /// Stop all processes and wait for them to exit.
+    pub async fn shutdown(self) {
+        let handles = self.nodes.into_iter().map(|node| {
+            // Closing the control channel stops the process and its supervisor.
+            drop(node.control);
+            node.handle
+        });
+        let _ = futures::future::join_all(handles).await;
+    }
+
+    fn find(&self, shard: &str, id: NodeId) -> Result<&Supervised, SupervisorError> {
+        self.nodes
+            .iter()
+            .find(|node| {
+                let state = node.state.borrow();
+                state.shard == shard && state.id == id
+            })
+            .ok_or_else(|| SupervisorError::UnknownNode {
+                shard: shard.to_string(),
+                id,
+            })
+    }
+
+    async fn control(&self, shard: &str, id: NodeId, op: Op) -> Result<(), SupervisorError> {
+        let gone = || SupervisorError::Gone {
+            shard: shard.to_string(),
+            id,
+        };
+        let (reply, result) = oneshot::channel();
+        self.find(shard, id)?
+            .control
+            .send(Control { op, reply })
+            .map_err(|_| gone())?;
+        result.await.map_err(|_| gone())?
+    }
+}
+
+/// Write the tokens accepted by the nodes to `<log_dir>/auth.toml`, readable by the owner only.
+fn write_auth_config(log_dir: &Path, auth: &AuthConfig) -> std::io::Result<PathBuf> {
+    let path = log_dir.join("auth.toml");
+    let contents = toml::to_string(auth)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)?
+        .write_all(contents.as_bytes())?;
+    Ok(path)
+}
+
+/// How to start the process of a node.
+struct Launch {
+    binary: PathBuf,
+    node: NodeSpec,
+    auth_config: Option<PathBuf>,
+    values: ValueConfig,
+    log_level: Option<String>,
+    log_path: PathBuf,
+}
+
+impl Launch {
+    fn spawn(&self) -> std::io::Result<Child> {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log_path)?;
+        let node = &self.node;
+        let mut command = Command::new(&self.binary);
+        command
+            .arg("--id")
+            .arg(node.id.to_string())
+            .arg("--http-addr")
+            .arg(&node.api_addr)
+            .arg("--rpc-addr")
+            .arg(&node.rpc_addr)
+            .arg("--data-dir")
+            .arg(&node.data_dir)
+            .arg("--max-value-size")
+            .arg(self.values.max_size.to_string())
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .kill_on_drop(true);
+        if let Some(addr) = &node.listen_api_addr {
+            command.arg("--listen-http-addr").arg(addr);
+        }
+        if let Some(addr) = &node.listen_rpc_addr {
+            command.arg("--listen-rpc-addr").arg(addr);
+        }
+        if let Some(path) = &self.auth_config {
+            command.arg("--auth-config").arg(path);
+        }
+        match self.values.compression_threshold {
+            Some(threshold) => command.arg("--compression-threshold").arg(threshold.to_string()),
+            None => command.arg("--no-compression"),
+        };
+        if let Some(level) = &self.log_level {
+            command.env("RUST_LOG", level);
+        }
+        command.spawn()
+    }
+
+    /// Append a line of the supervisor to the log of the node.
+    fn log(&self, message: &str) {
+        let line = format!("=== supervisor: node {} {}\n", self.node.id, message);
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log_path)
+            .and_then(|mut file| file.write_all(line.as_bytes()));
+        if let Err(e) = written {
+            tracing::warn!("failed to write to {}: {}", self.log_path.display(), e);
+        }
+    }
+}
+
+/// Start the process of a node and keep it in the state requested through `control`, until the
+/// control channel is closed.
+async fn supervise(
+    launch: Launch,
+    config: ProcessConfig,
+    mut control: mpsc::UnboundedReceiver<Control>,
+    state: watch::Sender<NodeProcess>,
+) {
+    let restart_delay = Duration::from_millis(config.restart_delay_ms);
+    let mut child = spawn(&launch, &state);
+    let mut started = Instant::now();
+    let mut restart_at: Option<Instant> = None;
+    // Restarts in a row of a process that exited right after starting.
+    let mut crashes = 0;
+
+    loop {
+        tokio::select! {
+            exit = wait(&mut child) => {
+                child = None;
+                launch.log(&format!("exited with {}", describe(&exit)));
+                if started.elapsed() >= MIN_UPTIME {
+                    crashes = 0;
+                }
+                if config.restart && crashes < config.max_restarts {
+                    crashes += 1;
+                    restart_at = Some(Instant::now() + restart_delay);
+                    state.send_modify(|node| node.status = ProcessStatus::Restarting);
+                } else {
+                    launch.log("is not restarted");
+                    state.send_modify(|node| node.status = ProcessStatus::Exited);
+                }
+            }
+            _ = tokio::time::sleep_until(restart_at.unwrap_or_else(Instant::now)),
+                if restart_at.is_some() =>
+            {
+                restart_at = None;
+                child = spawn(&launch, &state);
+                started = Instant::now();
+                state.send_modify(|node| node.restarts += 1);
+            }
+            request = control.recv() => {
+                let Some(Control { op, reply }) = request else {
+                    stop(&launch, &mut child).await;
+                    state.send_modify(|node| node.status = ProcessStatus::Stopped);
+                    return;
+                };
+                let status = state.borrow().status;
+                let invalid = || -> Result<(), SupervisorError> {
+                    let node = state.borrow();
+                    Err(SupervisorError::InvalidState {
+                        shard: node.shard.clone(),
+                        id: node.id,
+                        status,
+                    })
+                };
+                let result = match (op, status) {
+                    (
+                        Op::Kill,
+                        ProcessStatus::Running { pid } | ProcessStatus::Suspended { pid },
+                    ) => {
+                        launch.log("killed");
+                        send_signal(pid, Signal::SIGKILL)
+                    }
+                    (Op::Kill, _) => invalid(),
+                    (Op::Stop, _) => {
+                        restart_at = None;
+                        stop(&launch, &mut child).await;
+                        state.send_modify(|node| node.status = ProcessStatus::Stopped);
+                        Ok(())
+                    }
+                    (
+                        Op::Start,
+                        ProcessStatus::Running { .. } | ProcessStatus::Suspended { .. },
+                    ) => Ok(()),
+                    (Op::Start, _) => {
+                        restart_at = None;
+                        crashes = 0;
+                        child = spawn(&launch, &state);
+                        started = Instant::now();
+                        Ok(())
+                    }
+                    (Op::Suspend, ProcessStatus::Running { pid }) => {
+                        launch.log("suspended");
+                        let result = send_signal(pid, Signal::SIGSTOP);
+                        if result.is_ok() {
+                            state.send_modify(|node| {
+                                node.status = ProcessStatus::Suspended { pid }
+                            });
+                        }
+                        result
+                    }
+                    (Op::Suspend, ProcessStatus::Suspended { .. }) => Ok(()),
+                    (Op::Suspend, _) => invalid(),
+                    (Op::Resume, ProcessStatus::Suspended { pid }) => {
+                        launch.log("resumed");
+                        let result = send_signal(pid, Signal::SIGCONT);
+                        if result.is_ok() {
+                            state.send_modify(|node| node.status = ProcessStatus::Running { pid });
+                        }
+                        result
+                    }
+                    (Op::Resume, ProcessStatus::Running { .. }) => Ok(()),
+                    (Op::Resume, _) => invalid(),
+                };
+                let _ = reply.send(result);
+            }
+        }
+    }
+}
+
+/// Start the process of a node. A node that can't be started is left `Exited`.
+fn spawn(launch: &Launch, state: &watch::Sender<NodeProcess>) -> Option<Child> {
+    match launch.spawn() {
+        Ok(child) => {
+            let pid = child.id().unwrap_or_default();
+            launch.log(&format!("started with pid {}", pid));
+            state.send_modify(|node| node.status = ProcessStatus::Running { pid });
+            Some(child)
+        }
+        Err(e) => {
+            launch.log(&format!("failed to start: {}", e));
+            tracing::error!("failed to start node {}: {}", launch.node.id, e);
+            state.send_modify(|node| node.status = ProcessStatus::Exited);
+            None
+        }
+    }
+}
+
+fn send_signal(pid: u32, signal: Signal) -> Result<(), SupervisorError> {
+    Ok(signal::kill(Pid::from_raw(pid as i32), signal)?)
+}
+
+/// Wait for the process to exit. Never returns if there is no process.
+async fn wait(child: &mut Option<Child>) -> std::io::Result<ExitStatus> {
+    match child {
+        Some(child) => child.wait().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Ask the process to shut down with `SIGTERM`, and kill it if it is still running after
+/// [`STOP_TIMEOUT`].
+async fn stop(launch: &Launch, child: &mut Option<Child>) {
+    let Some(mut process) = child.take() else {
+        return;
+    };
+    if let Some(pid) = process.id() {
+        let pid = Pid::from_raw(pid as i32);
+        let _ = signal::kill(pid, Signal::SIGTERM);
+        // A suspended process has to run to handle the signal.
+        let _ = signal::kill(pid, Signal::SIGCONT);
+    }
+    let exit = match tokio::time::timeout(STOP_TIMEOUT, process.wait()).await {
+        Ok(exit) => exit,
+        Err(_) => {
+            launch.log("did not stop in time, killing it");
+            let _ = process.start_kill();
+            process.wait().await
+        }
+    };
+    launch.log(&format!("stopped with {}", describe(&exit)));
+}
+
+fn describe(exit: &std::io::Result<ExitStatus>) -> String {
+    match exit {
+        Ok(status) => status.to_string(),
+        Err(e) => format!("unknown status ({})", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_process_status_json() {
+        let running = serde_json::to_value(ProcessStatus::Running { pid: 42 }).unwrap();
+        assert_eq!(running, serde_json::json!({"state": "running", "pid": 42}));
+        let stopped = serde_json::to_value(ProcessStatus::Stopped).unwrap();
+        assert_eq!(stopped, serde_json::json!({"state": "stopped"}));
+        assert_eq!(
+            ProcessStatus::Suspended { pid: 7 }.to_string(),
+            "suspended (pid 7)"
+        );
+    }
+
+    #[test]
+    fn test_process_config_defaults() {
+        let config: ProcessConfig = toml::from_str("log_dir = \"out\"").unwrap();
+        assert_eq!(config.log_dir, PathBuf::from("out"));
+        assert!(config.restart);
+        assert_eq!(config.restart_delay_ms, 1000);
+        assert!(config.binary.is_none());
+    }
+}
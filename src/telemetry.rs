@@ -0,0 +1,524 @@
+//! Distributed tracing across clients and nodes.
+//!
+//! A trace context in the [W3C `traceparent`] format follows a request from the
+//! [`KVClient`](crate::kvclient::KVClient), through the HTTP API and the Raft log, to the
+//! state machine of every node:
+//!
+//! - [`RaftNode`](crate::raft_node::RaftNode) sends the context of the current span in the
+//!   `traceparent` header, and the [`http`] middleware continues the trace on the node.
+//! - Writes are proposed as [`Traced`] requests, so every log entry carries the context of the
+//!   request that proposed it. Followers and the state machine continue the trace from there.
+//! - `AppendEntries` RPCs are [`Traced`] as well, so the follower's handling of the RPC is a
+//!   child of the leader's call.
+//!
+//! Spans are ordinary `tracing` spans. The [`TraceLayer`] assigns them trace and span ids and
+//! may export them as OpenTelemetry (OTLP) JSON, one `ExportTraceServiceRequest` per line, the
+//! format of the OpenTelemetry collector's file exporter. Only spans that are enabled for the
+//! layer take part, so filter it with e.g. [`filter()`].
+//!
+//! A span continues a remote trace if it is created with a `traceparent` field, otherwise it
+//! continues the trace of its parent span, or starts a new one.
+//!
+//! [W3C `traceparent`]: https://www.w3.org/TR/trace-context/#traceparent-header
+use std::fmt;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::LineWriter;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use axum::extract::MatchedPath;
+use axum::extract::Request;
+use axum::extract::State;
+use axum::middleware::Next;
+use axum::response::Response;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::json;
+use serde_json::Value;
+use thiserror::Error;
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::span::Attributes;
+use tracing::span::Id;
+use tracing::span::Record;
+use tracing::Instrument;
+use tracing::Level;
+use tracing::Span;
+use tracing::Subscriber;
+use tracing_subscriber::filter::Targets;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+use tracing_subscriber::Registry;
+
+use crate::AppState;
+
+/// Header that carries the trace context of HTTP requests.
+pub const TRACEPARENT_HEADER: &str = "traceparent";
+
+/// Name of the span field that continues a remote trace.
+const TRACEPARENT_FIELD: &str = "traceparent";
+
+/// Name of the span field that sets the OpenTelemetry span kind, e.g., `server`.
+const KIND_FIELD: &str = "otel.kind";
+
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+#[error("invalid traceparent {0:?}")]
+pub struct InvalidTraceParent(String);
+
+/// A trace context: the trace and the span within it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct TraceParent {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+impl TraceParent {
+    fn random(trace_id: Option<u128>) -> Self {
+        Self {
+            trace_id: trace_id.unwrap_or_else(|| rand::random::<u128>().max(1)),
+            span_id: rand::random::<u64>().max(1),
+        }
+    }
+}
+
+impl fmt::Display for TraceParent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // All spans are sampled.
+        write!(f, "00-{:032x}-{:016x}-01", self.trace_id, self.span_id)
+    }
+}
+
+impl FromStr for TraceParent {
+    type Err = InvalidTraceParent;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidTraceParent(s.to_string());
+        let parts: Vec<&str> = s.trim().split('-').collect();
+        let [version, trace_id, span_id, flags] = parts[..] else {
+            return Err(invalid());
+        };
+        if version.len() != 2
+            || version == "ff"
+            || trace_id.len() != 32
+            || span_id.len() != 16
+            || flags.len() != 2
+        {
+            return Err(invalid());
+        }
+        let trace_id = u128::from_str_radix(trace_id, 16).map_err(|_| invalid())?;
+        let span_id = u64::from_str_radix(span_id, 16).map_err(|_| invalid())?;
+        u8::from_str_radix(flags, 16).map_err(|_| invalid())?;
+        if trace_id == 0 || span_id == 0 {
+            return Err(invalid());
+        }
+        Ok(Self { trace_id, span_id })
+    }
+}
+
+impl From<TraceParent> for String {
+    fn from(value: TraceParent) -> Self {
+        value.to_string()
+    }
+}
+
+impl TryFrom<String> for TraceParent {
+    type Error = InvalidTraceParent;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// A message with the trace context of the span that sent it.
+///
+/// Without a context it is encoded exactly like the message itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Traced<T> {
+    #[serde(flatten)]
+    pub inner: T,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traceparent: Option<TraceParent>,
+}
+
+impl<T> Traced<T> {
+    /// Attach the context of the current span to `inner`.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            traceparent: current(),
+        }
+    }
+
+    /// Attach the context of `span` to `inner`.
+    pub fn in_span(inner: T, span: &Span) -> Self {
+        Self {
+            inner,
+            traceparent: context(span),
+        }
+    }
+}
+
+/// The trace context of the current span, if it is traced by a [`TraceLayer`].
+pub fn current() -> Option<TraceParent> {
+    context(&Span::current())
+}
+
+/// The trace context of `span`, if it is traced by a [`TraceLayer`].
+pub fn context(span: &Span) -> Option<TraceParent> {
+    span.with_subscriber(|(id, dispatch)| {
+        let registry = dispatch.downcast_ref::<Registry>()?;
+        let span = registry.span(id)?;
+        let extensions = span.extensions();
+        extensions.get::<SpanData>().map(|data| data.context)
+    })
+    .flatten()
+}
+
+/// The default filter of a [`TraceLayer`]: the spans of this crate, at `INFO` or above.
+pub fn filter() -> Targets {
+    Targets::new().with_target(env!("CARGO_CRATE_NAME"), Level::INFO)
+}
+
+/// What the [`TraceLayer`] keeps per span.
+struct SpanData {
+    context: TraceParent,
+    parent_span_id: Option<u64>,
+    kind: i32,
+    start: SystemTime,
+    attributes: Vec<(String, Value)>,
+}
+
+/// Collects the fields of a span.
+#[derive(Default)]
+struct FieldVisitor {
+    remote_parent: Option<TraceParent>,
+    kind: Option<i32>,
+    attributes: Vec<(String, Value)>,
+}
+
+impl FieldVisitor {
+    fn record(&mut self, field: &Field, value: Value) {
+        match field.name() {
+            TRACEPARENT_FIELD => {
+                self.remote_parent = value.as_str().and_then(|s| s.parse().ok());
+            }
+            KIND_FIELD => self.kind = value.as_str().map(span_kind),
+            name => self.attributes.push((name.to_string(), value)),
+        }
+    }
+}
+
+impl Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, Value::from(format!("{:?}", value)));
+    }
+}
+
+/// OpenTelemetry span kind of an `otel.kind` field.
+fn span_kind(kind: &str) -> i32 {
+    match kind {
+        "internal" => 1,
+        "server" => 2,
+        "client" => 3,
+        "producer" => 4,
+        "consumer" => 5,
+        _ => 0,
+    }
+}
+
+/// A `tracing` layer that assigns trace contexts to spans and optionally exports them.
+pub struct TraceLayer {
+    service: String,
+    exporter: Option<Mutex<LineWriter<File>>>,
+}
+
+impl TraceLayer {
+    /// Propagate trace contexts without exporting spans.
+    pub fn new(service: impl Into<String>) -> Self {
+        Self {
+            service: service.into(),
+            exporter: None,
+        }
+    }
+
+    /// Append finished spans to the file at `path` as OTLP JSON lines.
+    pub fn with_file(service: impl Into<String>, path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            service: service.into(),
+            exporter: Some(Mutex::new(LineWriter::new(file))),
+        })
+    }
+
+    fn export(&self, name: &str, target: &str, data: SpanData, end: SystemTime) {
+        let Some(exporter) = &self.exporter else {
+            return;
+        };
+        let line = otlp_json(&self.service, name, target, &data, end);
+        let mut exporter = exporter.lock().unwrap();
+        if let Err(e) = writeln!(exporter, "{}", line) {
+            eprintln!("failed to export span: {}", e);
+        }
+    }
+}
+
+impl<S> Layer<S> for TraceLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::default();
+        attrs.record(&mut visitor);
+
+        let parent = visitor.remote_parent.or_else(|| {
+            let parent = span.parent()?;
+            let extensions = parent.extensions();
+            extensions.get::<SpanData>().map(|data| data.context)
+        });
+        let data = SpanData {
+            context: TraceParent::random(parent.map(|p| p.trace_id)),
+            parent_span_id: parent.map(|p| p.span_id),
+            kind: visitor.kind.unwrap_or(1),
+            start: SystemTime::now(),
+            attributes: visitor.attributes,
+        };
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut visitor = FieldVisitor::default();
+        values.record(&mut visitor);
+        if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+            data.attributes.extend(visitor.attributes);
+            if let Some(kind) = visitor.kind {
+                data.kind = kind;
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let data = span.extensions_mut().remove::<SpanData>();
+        if let Some(data) = data {
+            let metadata = span.metadata();
+            self.export(metadata.name(), metadata.target(), data, SystemTime::now());
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(b) => json!({ "boolValue": b }),
+        // 64 bit integers are strings in OTLP JSON.
+        Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+        Value::Number(n) => json!({ "doubleValue": n }),
+        Value::String(s) => json!({ "stringValue": s }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+/// A single span as an OTLP `ExportTraceServiceRequest`.
+fn otlp_json(service: &str, name: &str, target: &str, data: &SpanData, end: SystemTime) -> Value {
+    let mut attributes = vec![attribute("code.namespace", &Value::from(target))];
+    attributes.extend(data.attributes.iter().map(|(k, v)| attribute(k, v)));
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", &Value::from(service))],
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_PKG_NAME") },
+                "spans": [{
+                    "traceId": format!("{:032x}", data.context.trace_id),
+                    "spanId": format!("{:016x}", data.context.span_id),
+                    "parentSpanId": data
+                        .parent_span_id
+                        .map(|id| format!("{:016x}", id))
+                        .unwrap_or_default(),
+                    "name": name,
+                    "kind": data.kind,
+                    "startTimeUnixNano": unix_nanos(data.start),
+                    "endTimeUnixNano": unix_nanos(end),
+                    "attributes": attributes,
+                }],
+            }],
+        }],
+    })
+}
+
+/// Middleware that continues the trace of the caller, or starts a new one.
+///
+/// Requests are handled in a `http.server` span.
+pub async fn http(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let traceparent = req
+        .headers()
+        .get(TRACEPARENT_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_default();
+    let span = tracing::info_span!(
+        "http.server",
+        otel.kind = "server",
+        traceparent = %traceparent,
+        node_id = state.id,
+        http.method = %req.method(),
+        http.route = %route,
+        http.status_code = tracing::field::Empty,
+    );
+    let response = next.run(req).instrument(span.clone()).await;
+    span.record("http.status_code", response.status().as_u16());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn test_traceparent_roundtrip() {
+        let tp: TraceParent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+            .parse()
+            .unwrap();
+        assert_eq!(tp.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(tp.span_id, 0x00f067aa0ba902b7);
+        assert_eq!(
+            tp.to_string(),
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+    }
+
+    #[test]
+    fn test_invalid_traceparent() {
+        for s in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+            "00-4bf92f3577b34da6a3ce929d0e0e473x-00f067aa0ba902b7-01",
+        ] {
+            assert!(s.parse::<TraceParent>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_traced_encoding() {
+        let untraced = Traced {
+            inner: crate::store::Request::Set {
+                key: "k".into(),
+                value: "v".into(),
+            },
+            traceparent: None,
+        };
+        let json = serde_json::to_string(&untraced).unwrap();
+        assert_eq!(json, r#"{"Set":{"key":"k","value":"v"}}"#);
+
+        // Entries written before tracing was added still decode.
+        let decoded: Traced<crate::store::Request> = serde_json::from_str(&json).unwrap();
+        assert!(decoded.traceparent.is_none());
+
+        let traced = Traced {
+            traceparent: Some(TraceParent::random(None)),
+            ..untraced
+        };
+        let json = serde_json::to_vec(&traced).unwrap();
+        let decoded: Traced<crate::store::Request> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(decoded.traceparent, traced.traceparent);
+    }
+
+    #[test]
+    fn test_spans_share_trace() {
+        let subscriber = tracing_subscriber::registry().with(TraceLayer::new("test"));
+        tracing::subscriber::with_default(subscriber, || {
+            let remote = TraceParent::random(None);
+            let root = tracing::info_span!("root", traceparent = %remote);
+            let root_context = context(&root).unwrap();
+            assert_eq!(root_context.trace_id, remote.trace_id);
+            assert_ne!(root_context.span_id, remote.span_id);
+
+            let child = root.in_scope(|| {
+                let child = tracing::info_span!("child");
+                let _guard = child.enter();
+                current().unwrap()
+            });
+            assert_eq!(child.trace_id, remote.trace_id);
+            assert_ne!(child.span_id, root_context.span_id);
+
+            let other = tracing::info_span!("other");
+            assert_ne!(context(&other).unwrap().trace_id, remote.trace_id);
+        });
+    }
+
+    #[test]
+    fn test_otlp_json() {
+        let data = SpanData {
+            context: TraceParent {
+                trace_id: 1,
+                span_id: 2,
+            },
+            parent_span_id: Some(3),
+            kind: 2,
+            start: UNIX_EPOCH,
+            attributes: vec![("node_id".to_string(), Value::from(1u64))],
+        };
+        let end = UNIX_EPOCH + std::time::Duration::from_nanos(5);
+        let json = otlp_json("node-1", "http.server", "target", &data, end);
+        let span = &json["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(span["traceId"], "00000000000000000000000000000001");
+        assert_eq!(span["spanId"], "0000000000000002");
+        assert_eq!(span["parentSpanId"], "0000000000000003");
+        assert_eq!(span["kind"], 2);
+        assert_eq!(span["endTimeUnixNano"], "5");
+        assert_eq!(span["attributes"][1]["value"]["intValue"], "1");
+    }
+}
@@ -0,0 +1,298 @@
+//! TLS for the Raft RPC and the HTTP API.
+//!
+//! Certificates and keys are read from PEM files. The same certificate is used by a node as its
+//! server certificate and, with mutual TLS, as its client certificate towards other nodes.
+//! [`TlsContext::watch`] reloads the files periodically, so certificates can be rotated without
+//! restarting the node. New connections use the new certificates, open ones are not affected.
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::pki_types::ServerName;
+use rustls::server::WebPkiClientVerifier;
+use rustls::ClientConfig;
+use rustls::RootCertStore;
+use rustls::ServerConfig;
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::TlsConnector;
+
+/// TLS settings of a node.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TlsConfig {
+    /// PEM file with the certificate chain of the node.
+    pub cert_path: PathBuf,
+    /// PEM file with the private key of the node.
+    pub key_path: PathBuf,
+    /// PEM file with the certificates trusted to sign the certificates of other nodes and
+    /// clients. Defaults to `cert_path`, which works for a self-signed certificate shared by
+    /// all nodes.
+    #[serde(default)]
+    pub ca_path: Option<PathBuf>,
+    /// Whether clients have to present a certificate signed by the CA (mutual TLS).
+    #[serde(default)]
+    pub require_client_auth: bool,
+    /// How often the files are checked for changes, in milliseconds.
+    #[serde(default = "default_reload_interval_ms")]
+    pub reload_interval_ms: u64,
+}
+
+fn default_reload_interval_ms() -> u64 {
+    10_000
+}
+
+impl TlsConfig {
+    pub fn new(cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        Self {
+            cert_path: cert_path.into(),
+            key_path: key_path.into(),
+            ca_path: None,
+            require_client_auth: false,
+            reload_interval_ms: default_reload_interval_ms(),
+        }
+    }
+
+    fn ca_path(&self) -> &Path {
+        self.ca_path.as_deref().unwrap_or(&self.cert_path)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TlsError {
+    #[error("failed to read {0}: {1}")]
+    Io(PathBuf, #[source] io::Error),
+    #[error("no private key found in {0}")]
+    NoPrivateKey(PathBuf),
+    #[error("no certificate found in {0}")]
+    NoCertificate(PathBuf),
+    #[error("{0}")]
+    Rustls(#[from] rustls::Error),
+    #[error("{0}")]
+    Verifier(#[from] rustls::server::VerifierBuilderError),
+}
+
+/// The PEM files a [`TlsContext`] was built from.
+#[derive(Debug, Clone, PartialEq)]
+struct PemFiles {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    ca: Vec<u8>,
+}
+
+impl PemFiles {
+    fn read(config: &TlsConfig) -> Result<Self, TlsError> {
+        let read = |path: &Path| fs::read(path).map_err(|e| TlsError::Io(path.to_path_buf(), e));
+        Ok(Self {
+            cert: read(&config.cert_path)?,
+            key: read(&config.key_path)?,
+            ca: read(config.ca_path())?,
+        })
+    }
+}
+
+/// Server and client TLS configurations of a node, reloaded when the PEM files change.
+pub struct TlsContext {
+    config: TlsConfig,
+    files: Mutex<PemFiles>,
+    server: RwLock<Arc<ServerConfig>>,
+    client: RwLock<Arc<ClientConfig>>,
+}
+
+impl TlsContext {
+    pub fn load(config: TlsConfig) -> Result<Arc<Self>, TlsError> {
+        let files = PemFiles::read(&config)?;
+        let (server, client) = build_configs(&config, &files)?;
+        Ok(Arc::new(Self {
+            config,
+            files: Mutex::new(files),
+            server: RwLock::new(server),
+            client: RwLock::new(client),
+        }))
+    }
+
+    pub fn config(&self) -> &TlsConfig {
+        &self.config
+    }
+
+    pub fn server_config(&self) -> Arc<ServerConfig> {
+        self.server.read().unwrap().clone()
+    }
+
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        self.client.read().unwrap().clone()
+    }
+
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.server_config())
+    }
+
+    /// Open a TLS connection to `addr`. The host part of `addr` must match the certificate of
+    /// the peer.
+    pub async fn connect(
+        &self,
+        addr: &str,
+    ) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+        let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        TlsConnector::from(self.client_config())
+            .connect(server_name, stream)
+            .await
+    }
+
+    /// Rebuild the TLS configurations if any of the PEM files changed.
+    ///
+    /// Returns `true` if the configurations were replaced. On error the current configurations
+    /// are kept.
+    pub fn reload_if_changed(&self) -> Result<bool, TlsError> {
+        let files = PemFiles::read(&self.config)?;
+        let mut current = self.files.lock().unwrap();
+        if *current == files {
+            return Ok(false);
+        }
+        let (server, client) = build_configs(&self.config, &files)?;
+        *self.server.write().unwrap() = server;
+        *self.client.write().unwrap() = client;
+        *current = files;
+        Ok(true)
+    }
+
+    /// Reload the certificates periodically until `shutdown_signal` fires.
+    ///
+    /// `http` is updated with the new server configuration on every reload.
+    pub async fn watch(
+        self: Arc<Self>,
+        http: Option<RustlsConfig>,
+        mut shutdown_signal: watch::Receiver<()>,
+    ) {
+        let mut interval =
+            tokio::time::interval(Duration::from_millis(self.config.reload_interval_ms));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = shutdown_signal.changed() => return,
+            }
+            match self.reload_if_changed() {
+                Ok(true) => {
+                    tracing::info!("reloaded TLS certificates");
+                    if let Some(http) = &http {
+                        http.reload_from_config(self.server_config());
+                    }
+                }
+                Ok(false) => {}
+                Err(e) => tracing::warn!("failed to reload TLS certificates: {}", e),
+            }
+        }
+    }
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::aws_lc_rs::default_provider())
+}
+
+fn build_configs(
+    config: &TlsConfig,
+    files: &PemFiles,
+) -> Result<(Arc<ServerConfig>, Arc<ClientConfig>), TlsError> {
+    let certs = parse_certs(&files.cert, &config.cert_path)?;
+    let key = parse_key(&files.key, &config.key_path)?;
+
+    let mut roots = RootCertStore::empty();
+    for ca in parse_certs(&files.ca, config.ca_path())? {
+        roots.add(ca)?;
+    }
+    let roots = Arc::new(roots);
+
+    let server = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?;
+    let server = if config.require_client_auth {
+        let verifier = WebPkiClientVerifier::builder_with_provider(roots.clone(), provider())
+            .build()?;
+        server.with_client_cert_verifier(verifier)
+    } else {
+        server.with_no_client_auth()
+    };
+    let mut server = server.with_single_cert(certs.clone(), key.clone_key())?;
+    server.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    let client = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_client_auth_cert(certs, key)?;
+
+    Ok((Arc::new(server), Arc::new(client)))
+}
+
+fn parse_certs(pem: &[u8], path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let certs = rustls_pemfile::certs(&mut &pem[..])
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+fn parse_key(pem: &[u8], path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    rustls_pemfile::private_key(&mut &pem[..])
+        .map_err(|e| TlsError::Io(path.to_path_buf(), e))?
+        .ok_or_else(|| TlsError::NoPrivateKey(path.to_path_buf()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn write_self_signed(dir: &Path) -> TlsConfig {
+        let cert = rcgen::generate_simple_self_signed(vec!["127.0.0.1".to_string()]).unwrap();
+        fs::write(dir.join("cert.pem"), cert.cert.pem()).unwrap();
+        fs::write(dir.join("key.pem"), cert.key_pair.serialize_pem()).unwrap();
+        TlsConfig::new(dir.join("cert.pem"), dir.join("key.pem"))
+    }
+
+    #[test]
+    fn test_reload_on_change() {
+        let dir = TempDir::new().unwrap();
+        let config = write_self_signed(dir.path());
+        let ctx = TlsContext::load(config).unwrap();
+        let before = ctx.server_config();
+
+        assert!(!ctx.reload_if_changed().unwrap());
+        assert!(Arc::ptr_eq(&before, &ctx.server_config()));
+
+        write_self_signed(dir.path());
+        assert!(ctx.reload_if_changed().unwrap());
+        assert!(!Arc::ptr_eq(&before, &ctx.server_config()));
+    }
+
+    #[test]
+    fn test_invalid_files_keep_current_config() {
+        let dir = TempDir::new().unwrap();
+        let config = write_self_signed(dir.path());
+        let ctx = TlsContext::load(config.clone()).unwrap();
+        let before = ctx.server_config();
+
+        fs::write(&config.key_path, "not a key").unwrap();
+        assert!(matches!(
+            ctx.reload_if_changed(),
+            Err(TlsError::NoPrivateKey(_))
+        ));
+        assert!(Arc::ptr_eq(&before, &ctx.server_config()));
+    }
+}
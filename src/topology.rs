@@ -0,0 +1,435 @@
+//! Declarative description of a cluster: its shards, their nodes and the weights of the shards
+//! in the hash ring.
+//!
+//! A topology is part of the cluster config read by [`crate::cluster_manager::ClusterManager`]:
+//!
+//! ```toml
+//! client_config = "cluster.json"
+//!
+//! [[shards]]
+//! name = "a"
+//! weight = 1.0
+//!
+//! [[shards.nodes]]
+//! id = 1
+//! api_addr = "10.0.0.1:31101"
+//! rpc_addr = "10.0.0.1:32101"
+//! data_dir = "data/a/1"
+//! # Bind to all interfaces instead of the advertised addresses.
+//! listen_api_addr = "0.0.0.0:31101"
+//! listen_rpc_addr = "0.0.0.0:32101"
+//! ```
+//!
+//! A shard is identified in the hash ring by the API address of its first node. Node ids only
+//! have to be unique within their shard, each shard being its own Raft cluster.
+//!
+//! Clients don't need the whole topology. [`ClientConfig`] lists the API addresses of the
+//! nodes of every shard, which is all they need to fetch the hash ring.
+use std::collections::BTreeSet;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::carp::Carp;
+use crate::NodeId;
+
+/// Errors of an invalid topology.
+#[derive(Error, Debug, PartialEq)]
+pub enum TopologyError {
+    #[error("the topology has no shards")]
+    NoShards,
+    #[error("shard {0} has no nodes")]
+    EmptyShard(String),
+    #[error("shard name {0} is used twice")]
+    DuplicateShard(String),
+    #[error("node id {id} is used twice in shard {shard}")]
+    DuplicateNodeId { shard: String, id: NodeId },
+    #[error("address {0} is used twice")]
+    DuplicateAddr(String),
+    #[error("data directory {0} is used twice")]
+    DuplicateDataDir(PathBuf),
+    #[error("address {0:?} is not of the form host:port")]
+    InvalidAddr(String),
+    #[error("shard {shard} has weight {weight}, weights have to be positive")]
+    InvalidWeight { shard: String, weight: f32 },
+}
+
+/// Shards of a cluster and their nodes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Topology {
+    pub shards: Vec<ShardSpec>,
+}
+
+/// A shard, i.e., a Raft cluster that owns a part of the hash ring.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ShardSpec {
+    /// Name of the shard in logs and in the client config. Defaults to `shard-<n>`, numbered
+    /// from 1 in the order of the topology.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Weight of the shard in the hash ring, relative to the other shards.
+    #[serde(default = "default_weight")]
+    pub weight: f32,
+    /// The first node is the initial leader.
+    pub nodes: Vec<NodeSpec>,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+/// A node of a shard.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodeSpec {
+    pub id: NodeId,
+    /// Address of the HTTP API, as reached by clients and other nodes.
+    pub api_addr: String,
+    /// Address of the Raft RPC, as reached by other nodes.
+    pub rpc_addr: String,
+    /// Directory of the node's storage. Kept across restarts.
+    pub data_dir: PathBuf,
+    /// Address the HTTP API binds to. Defaults to `api_addr`.
+    #[serde(default)]
+    pub listen_api_addr: Option<String>,
+    /// Address the Raft RPC binds to. Defaults to `rpc_addr`.
+    #[serde(default)]
+    pub listen_rpc_addr: Option<String>,
+}
+
+impl Topology {
+    /// A topology of `shards` shards of `nodes_per_shard` nodes on the loopback interface.
+    ///
+    /// Shard `s` (from 1) gets the API ports `base_port + s * 100 + n` and the RPC ports
+    /// `base_port + 1000 + s * 100 + n` for its nodes `n` (from 1), so up to 99 nodes per shard
+    /// and 9 shards fit. Data directories are `<data_root>/shard-<s>/node-<n>`.
+    pub fn local(shards: u64, nodes_per_shard: u64, base_port: u16, data_root: &Path) -> Self {
+        let shards = (1..=shards)
+            .map(|shard| ShardSpec {
+                name: None,
+                weight: default_weight(),
+                nodes: (1..=nodes_per_shard)
+                    .map(|id| {
+                        let port = base_port as u64 + shard * 100 + id;
+                        NodeSpec {
+                            id,
+                            api_addr: format!("127.0.0.1:{}", port),
+                            rpc_addr: format!("127.0.0.1:{}", port + 1000),
+                            data_dir: data_root
+                                .join(format!("shard-{}", shard))
+                                .join(format!("node-{}", id)),
+                            listen_api_addr: None,
+                            listen_rpc_addr: None,
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+        Topology { shards }
+    }
+
+    /// Check that the topology can be started: every shard has nodes, and no id, address or
+    /// data directory is used twice.
+    pub fn validate(&self) -> Result<(), TopologyError> {
+        if self.shards.is_empty() {
+            return Err(TopologyError::NoShards);
+        }
+        let mut names = HashSet::new();
+        let mut addrs = HashSet::new();
+        let mut dirs = HashSet::new();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let name = shard.name(i);
+            if !names.insert(name.clone()) {
+                return Err(TopologyError::DuplicateShard(name));
+            }
+            if !(shard.weight.is_finite() && shard.weight > 0.0) {
+                return Err(TopologyError::InvalidWeight {
+                    shard: name,
+                    weight: shard.weight,
+                });
+            }
+            if shard.nodes.is_empty() {
+                return Err(TopologyError::EmptyShard(name));
+            }
+
+            let mut ids = BTreeSet::new();
+            for node in &shard.nodes {
+                if !ids.insert(node.id) {
+                    return Err(TopologyError::DuplicateNodeId {
+                        shard: name,
+                        id: node.id,
+                    });
+                }
+                for addr in [&node.api_addr, &node.rpc_addr] {
+                    check_addr(addr)?;
+                    if !addrs.insert(addr.clone()) {
+                        return Err(TopologyError::DuplicateAddr(addr.clone()));
+                    }
+                }
+                // Listen addresses may repeat, e.g., `0.0.0.0:<port>` on different hosts.
+                for addr in [&node.listen_api_addr, &node.listen_rpc_addr].into_iter().flatten() {
+                    check_addr(addr)?;
+                }
+                if !dirs.insert(node.data_dir.clone()) {
+                    return Err(TopologyError::DuplicateDataDir(node.data_dir.clone()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The API addresses of the nodes of every shard.
+    pub fn api_addrs(&self) -> Vec<Vec<String>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.nodes.iter().map(|node| node.api_addr.clone()).collect())
+            .collect()
+    }
+
+    /// The hash ring of the topology, with the first node of every shard as its leader.
+    pub fn hash_ring(&self, config_id: u32) -> Carp {
+        let total: f32 = self.shards.iter().map(|shard| shard.weight).sum();
+        Carp::with_followers(
+            self.shards
+                .iter()
+                .map(|shard| {
+                    let addrs: Vec<String> =
+                        shard.nodes.iter().map(|node| node.api_addr.clone()).collect();
+                    (addrs[0].clone(), shard.weight / total, addrs[1..].to_vec())
+                })
+                .collect(),
+            config_id,
+        )
+    }
+
+    /// The config clients need to reach the cluster.
+    pub fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            shards: self
+                .shards
+                .iter()
+                .enumerate()
+                .map(|(i, shard)| ClientShard {
+                    name: shard.name(i),
+                    nodes: shard.nodes.iter().map(|node| node.api_addr.clone()).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl ShardSpec {
+    /// The name of the shard at position `index` of the topology.
+    pub fn name(&self, index: usize) -> String {
+        self.name
+            .clone()
+            .unwrap_or_else(|| format!("shard-{}", index + 1))
+    }
+
+    /// The node the shard is initialized on.
+    pub fn first(&self) -> &NodeSpec {
+        &self.nodes[0]
+    }
+}
+
+fn check_addr(addr: &str) -> Result<(), TopologyError> {
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(TopologyError::InvalidAddr(addr.to_string())),
+    }
+}
+
+/// What clients need to know about a cluster, written by the cluster manager.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientConfig {
+    pub shards: Vec<ClientShard>,
+}
+
+/// The nodes of a shard, as listed in a [`ClientConfig`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientShard {
+    pub name: String,
+    /// API addresses of the nodes.
+    pub nodes: Vec<String>,
+}
+
+/// The formats of a client config file.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ClientConfigFile {
+    Config(ClientConfig),
+    /// A list of API addresses per shard, as written by earlier versions.
+    Addrs(Vec<Vec<String>>),
+}
+
+impl ClientConfig {
+    /// Read a client config, or a JSON list of API addresses per shard.
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).map_err(|e| {
+            std::io::Error::new(e.kind(), format!("failed to read {}: {}", path.display(), e))
+        })?;
+        let file: ClientConfigFile = serde_json::from_str(&contents).map_err(|e| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid client config {}: {}", path.display(), e),
+            )
+        })?;
+        Ok(match file {
+            ClientConfigFile::Config(config) => config,
+            ClientConfigFile::Addrs(shards) => ClientConfig {
+                shards: shards
+                    .into_iter()
+                    .enumerate()
+                    .map(|(i, nodes)| ClientShard {
+                        name: format!("shard-{}", i + 1),
+                        nodes,
+                    })
+                    .collect(),
+            },
+        })
+    }
+
+    /// Write the config as JSON.
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let contents = serde_json::to_string_pretty(self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        std::fs::write(path, contents)
+    }
+
+    /// The API addresses of the nodes of every shard.
+    pub fn api_addrs(&self) -> Vec<Vec<String>> {
+        self.shards.iter().map(|shard| shard.nodes.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology(toml: &str) -> Topology {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_validate() {
+        let topology = topology(
+            r#"
+            [[shards]]
+            name = "a"
+            weight = 2.0
+            [[shards.nodes]]
+            id = 1
+            api_addr = "10.0.0.1:31101"
+            rpc_addr = "10.0.0.1:32101"
+            data_dir = "data/a/1"
+            listen_api_addr = "0.0.0.0:31101"
+            [[shards.nodes]]
+            id = 2
+            api_addr = "10.0.0.2:31101"
+            rpc_addr = "10.0.0.2:32101"
+            data_dir = "data/a/2"
+
+            [[shards]]
+            [[shards.nodes]]
+            id = 1
+            api_addr = "10.0.0.3:31101"
+            rpc_addr = "10.0.0.3:32101"
+            data_dir = "data/b/1"
+            "#,
+        );
+        assert_eq!(topology.validate(), Ok(()));
+        assert_eq!(topology.shards[1].weight, 1.0);
+        assert_eq!(topology.shards[1].name(1), "shard-2");
+        assert_eq!(
+            topology.shards[0].nodes[0].listen_api_addr.as_deref(),
+            Some("0.0.0.0:31101")
+        );
+
+        let ring = topology.hash_ring(3);
+        assert_eq!(ring.config_id, 3);
+        assert_eq!(ring.nodes[0].addr, "10.0.0.1:31101");
+        assert!((ring.nodes[0].relative_load - 2.0 / 3.0).abs() < 1e-6);
+        assert_eq!(
+            ring.get_followers("10.0.0.1:31101"),
+            Some(&vec!["10.0.0.2:31101".to_string()])
+        );
+
+        let client = topology.client_config();
+        assert_eq!(client.shards[0].name, "a");
+        assert_eq!(client.api_addrs(), topology.api_addrs());
+    }
+
+    #[test]
+    fn test_validate_errors() {
+        let valid = Topology::local(2, 3, 31000, Path::new("data"));
+        assert_eq!(valid.validate(), Ok(()));
+
+        let mut t = valid.clone();
+        t.shards.clear();
+        assert_eq!(t.validate(), Err(TopologyError::NoShards));
+
+        let mut t = valid.clone();
+        t.shards[1].nodes.clear();
+        assert_eq!(
+            t.validate(),
+            Err(TopologyError::EmptyShard("shard-2".to_string()))
+        );
+
+        let mut t = valid.clone();
+        t.shards[0].nodes[1].id = 1;
+        assert!(matches!(
+            t.validate(),
+            Err(TopologyError::DuplicateNodeId { id: 1, .. })
+        ));
+
+        let mut t = valid.clone();
+        t.shards[1].nodes[0].rpc_addr = t.shards[0].nodes[0].api_addr.clone();
+        assert!(matches!(t.validate(), Err(TopologyError::DuplicateAddr(_))));
+
+        let mut t = valid.clone();
+        t.shards[1].nodes[0].data_dir = t.shards[0].nodes[0].data_dir.clone();
+        assert!(matches!(t.validate(), Err(TopologyError::DuplicateDataDir(_))));
+
+        let mut t = valid.clone();
+        t.shards[0].nodes[0].api_addr = "localhost".to_string();
+        assert!(matches!(t.validate(), Err(TopologyError::InvalidAddr(_))));
+
+        let mut t = valid.clone();
+        t.shards[0].weight = 0.0;
+        assert!(matches!(t.validate(), Err(TopologyError::InvalidWeight { .. })));
+
+        let mut t = valid;
+        t.shards[0].name = Some("shard-2".to_string());
+        assert!(matches!(t.validate(), Err(TopologyError::DuplicateShard(_))));
+    }
+
+    #[test]
+    fn test_local_ports() {
+        // More than 9 nodes per shard don't collide with the next shard.
+        let topology = Topology::local(3, 12, 31000, Path::new("data"));
+        assert_eq!(topology.validate(), Ok(()));
+        assert_eq!(topology.shards[0].nodes[11].api_addr, "127.0.0.1:31112");
+        assert_eq!(topology.shards[1].nodes[0].api_addr, "127.0.0.1:31201");
+        assert_eq!(topology.shards[1].nodes[0].rpc_addr, "127.0.0.1:32201");
+    }
+
+    #[test]
+    fn test_load_client_config() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("cluster.json");
+
+        let config = Topology::local(2, 2, 31000, Path::new("data")).client_config();
+        config.save(&path).unwrap();
+        assert_eq!(ClientConfig::load(&path).unwrap(), config);
+
+        // The plain list of addresses is still accepted.
+        std::fs::write(&path, r#"[["a:1", "b:1"], ["c:1"]]"#).unwrap();
+        let config = ClientConfig::load(&path).unwrap();
+        assert_eq!(config.shards[1].name, "shard-2");
+        assert_eq!(config.api_addrs(), vec![vec!["a:1", "b:1"], vec!["c:1"]]);
+    }
+}
@@ -0,0 +1,520 @@
+//! Keys and values as byte strings, and the compression of large values.
+//!
+//! Keys and values are arbitrary bytes. In JSON, i.e., in the HTTP API, the log and the
+//! snapshots, [`Bytes`] that are valid UTF-8 are written as a string and other bytes as an
+//! object `{"base64": "..."}` with the bytes in standard base64. MessagePack, as used by the Raft
+//! RPC, holds them as is. Strings written before keys and values were bytes read back as their
+//! UTF-8 encoding.
+//!
+//! The node that receives a write compresses values of at least
+//! [`ValueConfig::compression_threshold`] bytes with zstd before proposing them, so the log, the
+//! replication and the snapshots carry the compressed [`Value`]. Reads decompress it again:
+//! clients only ever see the original bytes.
+use std::borrow::Borrow;
+use std::fmt;
+use std::ops::Deref;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde::de;
+use serde::de::MapAccess;
+use serde::de::SeqAccess;
+use serde::de::Visitor;
+use serde::ser::SerializeMap;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+use thiserror::Error;
+
+/// Default of [`ValueConfig::max_size`].
+pub const DEFAULT_MAX_VALUE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default of [`ValueConfig::compression_threshold`].
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// zstd level of compressed values, low enough to keep writes fast.
+const COMPRESSION_LEVEL: i32 = 3;
+
+/// Room left in the body of a write for its key and the JSON around the value.
+const BODY_OVERHEAD: usize = 64 * 1024;
+
+/// Errors of values.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ValueError {
+    #[error("the value has {size} bytes, more than the maximum of {max} bytes")]
+    TooLarge { size: usize, max: usize },
+    #[error("values must be written uncompressed, the node compresses them")]
+    Compressed,
+    #[error("the stored value can't be decompressed: {0}")]
+    Corrupt(String),
+}
+
+/// A key or a value.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Bytes(Vec<u8>);
+
+impl Bytes {
+    pub fn new(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+
+    /// The bytes as a string, if they are valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0).ok()
+    }
+}
+
+impl Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Borrow<[u8]> for Bytes {
+    fn borrow(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Self(bytes)
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(bytes: &[u8]) -> Self {
+        Self(bytes.to_vec())
+    }
+}
+
+impl From<String> for Bytes {
+    fn from(s: String) -> Self {
+        Self(s.into_bytes())
+    }
+}
+
+impl From<&str> for Bytes {
+    fn from(s: &str) -> Self {
+        Self(s.as_bytes().to_vec())
+    }
+}
+
+impl PartialEq<[u8]> for Bytes {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<str> for Bytes {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for Bytes {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<String> for Bytes {
+    fn eq(&self, other: &String) -> bool {
+        self.0 == other.as_bytes()
+    }
+}
+
+impl PartialEq<Bytes> for &str {
+    fn eq(&self, other: &Bytes) -> bool {
+        other == self
+    }
+}
+
+impl PartialEq<Bytes> for String {
+    fn eq(&self, other: &Bytes) -> bool {
+        other == self
+    }
+}
+
+/// Text as a string, other bytes as a byte string literal.
+impl fmt::Debug for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.as_str() {
+            Some(s) => fmt::Debug::fmt(s, f),
+            None => write!(f, "b\"{}\"", self.0.escape_ascii()),
+        }
+    }
+}
+
+/// The bytes as UTF-8, with invalid sequences replaced.
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(&self.0);
+        }
+        match self.as_str() {
+            Some(s) => serializer.serialize_str(s),
+            None => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("base64", &BASE64.encode(&self.0))?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(BytesVisitor)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a string, bytes or {\"base64\": string}")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Bytes, E> {
+        Ok(v.into())
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Bytes, E> {
+        Ok(v.into())
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+        Ok(v.into())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+        Ok(v.into())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes.into())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Bytes, A::Error> {
+        let field = map.next_key::<String>()?;
+        if field.as_deref() != Some("base64") {
+            let field = field.unwrap_or_default();
+            return Err(de::Error::unknown_field(&field, &["base64"]));
+        }
+        let encoded: String = map.next_value()?;
+        if let Some(field) = map.next_key::<String>()? {
+            return Err(de::Error::unknown_field(&field, &["base64"]));
+        }
+        BASE64.decode(encoded).map(Bytes).map_err(de::Error::custom)
+    }
+}
+
+/// A value as the state machine stores it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    /// The bytes as written.
+    Raw(Bytes),
+    /// The bytes compressed with zstd. `size` is the size of the bytes as written.
+    Zstd { zstd: Bytes, size: u64 },
+}
+
+impl Value {
+    /// `bytes`, compressed if they have at least `threshold` bytes and compression makes them
+    /// smaller.
+    pub fn compress(bytes: Bytes, threshold: Option<usize>) -> Self {
+        match threshold {
+            Some(threshold) if bytes.len() >= threshold => {}
+            _ => return Value::Raw(bytes),
+        }
+        match zstd::bulk::compress(&bytes, COMPRESSION_LEVEL) {
+            Ok(compressed) if compressed.len() < bytes.len() => Value::Zstd {
+                zstd: compressed.into(),
+                size: bytes.len() as u64,
+            },
+            _ => Value::Raw(bytes),
+        }
+    }
+
+    /// Size of the bytes as written, which quotas and limits apply to.
+    pub fn size(&self) -> u64 {
+        match self {
+            Value::Raw(bytes) => bytes.len() as u64,
+            Value::Zstd { size, .. } => *size,
+        }
+    }
+
+    /// The bytes as written.
+    pub fn decode(&self) -> Result<Bytes, ValueError> {
+        match self {
+            Value::Raw(bytes) => Ok(bytes.clone()),
+            Value::Zstd { zstd, size } => {
+                let bytes = zstd::bulk::decompress(zstd, *size as usize)
+                    .map_err(|e| ValueError::Corrupt(e.to_string()))?;
+                if bytes.len() as u64 != *size {
+                    let msg = format!("{} bytes instead of {}", bytes.len(), size);
+                    return Err(ValueError::Corrupt(msg));
+                }
+                Ok(bytes.into())
+            }
+        }
+    }
+}
+
+impl Default for Value {
+    fn default() -> Self {
+        Value::Raw(Bytes::default())
+    }
+}
+
+impl From<Bytes> for Value {
+    fn from(bytes: Bytes) -> Self {
+        Value::Raw(bytes)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(bytes: Vec<u8>) -> Self {
+        Value::Raw(bytes.into())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Raw(s.into())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Value::Raw(s.into())
+    }
+}
+
+/// Limits and compression of the values a node accepts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ValueConfig {
+    /// Writes of larger values are rejected, in bytes.
+    pub max_size: usize,
+    /// Values of at least this many bytes are compressed. `None` disables compression, as does a
+    /// threshold above `max_size` in `Config.toml`.
+    pub compression_threshold: Option<usize>,
+}
+
+impl Default for ValueConfig {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_VALUE_SIZE,
+            compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+        }
+    }
+}
+
+impl ValueConfig {
+    /// The value to propose for a write of `value`: checked against [`ValueConfig::max_size`]
+    /// and compressed if it is large enough.
+    pub fn prepare(&self, value: Value) -> Result<Value, ValueError> {
+        let Value::Raw(bytes) = value else {
+            return Err(ValueError::Compressed);
+        };
+        if bytes.len() > self.max_size {
+            return Err(ValueError::TooLarge {
+                size: bytes.len(),
+                max: self.max_size,
+            });
+        }
+        Ok(Value::compress(bytes, self.compression_threshold))
+    }
+
+    /// Size limit of the body of an HTTP request, large enough for a value of
+    /// [`ValueConfig::max_size`] bytes in JSON, where a byte may take up to 6 characters.
+    pub fn body_limit(&self) -> usize {
+        self.max_size.saturating_mul(6).saturating_add(BODY_OVERHEAD)
+    }
+}
+
+/// (De)serializes a map with [`Bytes`] keys as a sequence of pairs, as JSON objects only have
+/// string keys. Maps are accepted as well, as written before keys were bytes.
+pub(crate) mod pairs {
+    use std::collections::BTreeMap;
+    use std::fmt;
+    use std::marker::PhantomData;
+
+    use serde::de::MapAccess;
+    use serde::de::SeqAccess;
+    use serde::de::Visitor;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serialize;
+    use serde::Serializer;
+
+    pub fn serialize<K, V, S>(map: &BTreeMap<K, V>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        K: Serialize,
+        V: Serialize,
+        S: Serializer,
+    {
+        serializer.collect_seq(map)
+    }
+
+    pub fn deserialize<'de, K, V, D>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+    where
+        K: Deserialize<'de> + Ord,
+        V: Deserialize<'de>,
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(PairsVisitor(PhantomData))
+    }
+
+    struct PairsVisitor<K, V>(PhantomData<(K, V)>);
+
+    impl<'de, K, V> Visitor<'de> for PairsVisitor<K, V>
+    where
+        K: Deserialize<'de> + Ord,
+        V: Deserialize<'de>,
+    {
+        type Value = BTreeMap<K, V>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("a sequence of key-value pairs or a map")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let mut map = BTreeMap::new();
+            while let Some((key, value)) = seq.next_element()? {
+                map.insert(key, value);
+            }
+            Ok(map)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+            let mut map = BTreeMap::new();
+            while let Some((key, value)) = access.next_entry()? {
+                map.insert(key, value);
+            }
+            Ok(map)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn test_bytes_json() {
+        let text = Bytes::from("café");
+        assert_eq!(serde_json::to_string(&text).unwrap(), "\"café\"");
+        let binary = Bytes::from(vec![0xff, 0x00, 0x01]);
+        assert_eq!(serde_json::to_string(&binary).unwrap(), "{\"base64\":\"/wAB\"}");
+
+        for bytes in [text, binary] {
+            let json = serde_json::to_vec(&bytes).unwrap();
+            assert_eq!(serde_json::from_slice::<Bytes>(&json).unwrap(), bytes);
+        }
+        assert!(serde_json::from_str::<Bytes>("{\"hex\":\"ff\"}").is_err());
+        assert!(serde_json::from_str::<Bytes>("{\"base64\":\"not base64!\"}").is_err());
+    }
+
+    #[test]
+    fn test_bytes_msgpack() {
+        let bytes = Bytes::from(vec![0xff, 0x00, 0x01]);
+        let packed = rmp_serde::to_vec_named(&bytes).unwrap();
+        // A bin of 3 bytes.
+        assert_eq!(packed, [0xc4, 3, 0xff, 0x00, 0x01]);
+        assert_eq!(rmp_serde::from_slice::<Bytes>(&packed).unwrap(), bytes);
+
+        // As written before keys and values were bytes.
+        let packed = rmp_serde::to_vec_named("text").unwrap();
+        assert_eq!(rmp_serde::from_slice::<Bytes>(&packed).unwrap(), "text");
+    }
+
+    #[test]
+    fn test_compression() {
+        let large = Bytes::from("a".repeat(4096));
+        let value = Value::compress(large.clone(), Some(1024));
+        assert!(matches!(value, Value::Zstd { .. }), "{:?}", value);
+        assert_eq!(value.size(), 4096);
+        assert_eq!(value.decode().unwrap(), large);
+
+        let json = serde_json::to_vec(&value).unwrap();
+        assert!(json.len() < 1024);
+        assert_eq!(serde_json::from_slice::<Value>(&json).unwrap(), value);
+        let packed = rmp_serde::to_vec_named(&value).unwrap();
+        assert_eq!(rmp_serde::from_slice::<Value>(&packed).unwrap(), value);
+
+        // Below the threshold, or disabled.
+        assert_eq!(Value::compress(large.clone(), Some(8192)), Value::Raw(large.clone()));
+        assert_eq!(Value::compress(large.clone(), None), Value::Raw(large));
+        // Incompressible.
+        let random: Vec<u8> = (0..4096).map(|_| rand::random()).collect();
+        let random = Bytes::from(random);
+        assert_eq!(Value::compress(random.clone(), Some(1024)), Value::Raw(random));
+    }
+
+    #[test]
+    fn test_prepare() {
+        let config = ValueConfig {
+            max_size: 8,
+            compression_threshold: None,
+        };
+        assert_eq!(config.prepare("12345678".into()), Ok("12345678".into()));
+        assert_eq!(
+            config.prepare("123456789".into()),
+            Err(ValueError::TooLarge { size: 9, max: 8 })
+        );
+        let compressed = Value::compress("a".repeat(64).into(), Some(0));
+        assert_eq!(config.prepare(compressed), Err(ValueError::Compressed));
+    }
+
+    #[test]
+    fn test_pairs() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct State {
+            #[serde(with = "pairs")]
+            kvs: BTreeMap<Bytes, Value>,
+        }
+
+        let state = State {
+            kvs: BTreeMap::from([
+                (Bytes::from("text"), Value::from("value")),
+                (Bytes::from(vec![0xff]), Value::from(vec![0xfe])),
+            ]),
+        };
+        let json = serde_json::to_vec(&state).unwrap();
+        assert_eq!(serde_json::from_slice::<State>(&json).unwrap(), state);
+
+        let legacy: State = serde_json::from_str("{\"kvs\":{\"a\":\"1\",\"b\":\"2\"}}").unwrap();
+        assert_eq!(legacy.kvs.get(b"a".as_slice()), Some(&Value::from("1")));
+        assert_eq!(legacy.kvs.len(), 2);
+    }
+}
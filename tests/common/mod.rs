@@ -0,0 +1,101 @@
+//! Helpers shared by the tests that run their nodes in threads of the test process.
+//!
+//! A test owns a range of ports starting at `base`: node `id` serves its HTTP API on
+//! `base + id` and its Raft RPC on `base + 1000 + id`.
+
+// Every test uses a part of the helpers only.
+#![allow(dead_code)]
+
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::start_example_raft_node;
+use distrib_kv_store::store::Request;
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+
+pub fn get_addr(base: u64, node_id: u64) -> String {
+    format!("127.0.0.1:{}", base + node_id)
+}
+
+pub fn get_rpc_addr(base: u64, node_id: u64) -> String {
+    format!("127.0.0.1:{}", base + 1000 + node_id)
+}
+
+pub fn set(key: &str, value: &str) -> Request {
+    Request::Set {
+        key: key.into(),
+        value: value.into(),
+    }
+}
+
+/// Run a node in a thread until `shutdown_rx` fires. The thread ends when the node shuts down.
+pub fn spawn_node(
+    node_id: u64,
+    dir: PathBuf,
+    addr: String,
+    rpc_addr: String,
+    shutdown_rx: watch::Receiver<()>,
+) -> JoinHandle<()> {
+    let handle = Handle::current();
+    thread::spawn(move || {
+        let x = handle.block_on(start_example_raft_node(
+            node_id,
+            dir,
+            addr,
+            rpc_addr,
+            shutdown_rx,
+        ));
+        println!("x: {:?}", x);
+    })
+}
+
+/// Start the nodes `ids` with their data in `dir/node-<id>`, and wait for them to start up.
+pub async fn start_nodes(
+    base: u64,
+    ids: impl IntoIterator<Item = u64>,
+    dir: &Path,
+    shutdown_tx: &watch::Sender<()>,
+) -> Vec<JoinHandle<()>> {
+    let nodes = ids
+        .into_iter()
+        .map(|node_id| {
+            spawn_node(
+                node_id,
+                dir.join(format!("node-{}", node_id)),
+                get_addr(base, node_id),
+                get_rpc_addr(base, node_id),
+                shutdown_tx.subscribe(),
+            )
+        })
+        .collect();
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+    nodes
+}
+
+/// Form a shard of the voters `ids`: the first one initializes it and adds the others.
+///
+/// Returns a client of the first node, which is the leader.
+pub async fn init_shard(base: u64, ids: &[u64]) -> Result<RaftNode, ClientError> {
+    let leader = RaftNode::new(ids[0], get_addr(base, ids[0]));
+    leader.init().await?;
+    for &node_id in &ids[1..] {
+        leader
+            .add_learner((
+                node_id,
+                get_addr(base, node_id),
+                get_rpc_addr(base, node_id),
+            ))
+            .await?;
+    }
+    leader
+        .change_membership(&ids.iter().copied().collect::<BTreeSet<_>>())
+        .await?;
+    Ok(leader)
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use std::time::Duration;
+
+use distrib_kv_store::admin::Admin;
+use distrib_kv_store::admin::AdminError;
+use distrib_kv_store::admin::Role;
+use distrib_kv_store::carp::Carp;
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::store::Request;
+use tokio::sync::watch;
+
+use common::get_addr;
+use common::init_shard;
+use common::start_nodes;
+
+const PORT: u64 = 31600;
+
+/// Operate a shard of 3 nodes through [`Admin`]: inspect it, move its leadership, compact its
+/// logs and shrink it.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_admin_operations() -> Result<(), Box<dyn std::error::Error>> {
+    let (shutdown_tx, _) = watch::channel(());
+    let dir = tempfile::TempDir::new()?;
+    start_nodes(PORT, 1..=3, dir.path(), &shutdown_tx).await;
+
+    let leader = init_shard(PORT, &[1, 2, 3]).await?;
+    let followers = vec![get_addr(PORT, 2), get_addr(PORT, 3)];
+    let ring = Carp::with_followers(vec![(get_addr(PORT, 1), 1.0, followers)], 0);
+    for node_id in 1..=3 {
+        RaftNode::new(node_id, get_addr(PORT, node_id))
+            .update_hash_ring(ring.clone())
+            .await?;
+    }
+    for i in 0..10 {
+        leader
+            .write(&Request::Set {
+                key: format!("key-{}", i).into(),
+                value: "value".into(),
+            })
+            .await?;
+    }
+
+    let admin = Admin::new(RaftNode::new(0, String::new()), vec![get_addr(PORT, 2)]);
+    let shard = get_addr(PORT, 1);
+
+    // --- Status
+
+    let status = admin.shard_status(&admin.ring().await?, &shard).await;
+    assert_eq!(status.leader, Some(1));
+    assert_eq!(status.nodes.len(), 3);
+    let roles: Vec<Role> = status.nodes.iter().map(|node| node.role).collect();
+    assert_eq!(roles, vec![Role::Leader, Role::Follower, Role::Follower]);
+
+    // --- Leadership transfer
+
+    admin.transfer_leader(&shard, 2).await?;
+    let metrics = RaftNode::new(1, get_addr(PORT, 1)).metrics().await?;
+    assert_eq!(metrics.current_leader, Some(2));
+    let ring = admin.ring().await?;
+    assert_eq!(ring.get_proxy(&shard), get_addr(PORT, 2));
+
+    // A ring derived from the one before the transfer would revert it, and is refused.
+    let node = RaftNode::new(1, get_addr(PORT, 1));
+    assert!(matches!(
+        node.update_hash_ring_if(ring.clone(), ring.config_id - 1)
+            .await,
+        Err(ClientError::Conflict(_))
+    ));
+    node.update_hash_ring_if(ring.clone(), ring.config_id)
+        .await?;
+
+    // Writes keep working under the new leader.
+    RaftNode::new(2, get_addr(PORT, 2))
+        .write(&Request::Set {
+            key: "after-transfer".into(),
+            value: "value".into(),
+        })
+        .await?;
+
+    // --- Compaction
+
+    let snapshots = admin.snapshot(&shard, true).await?;
+    assert_eq!(snapshots.len(), 3);
+    for snapshot in &snapshots {
+        assert!(snapshot.error.is_none(), "{:?}", snapshot);
+        assert!(snapshot.purged.is_some(), "{:?}", snapshot);
+    }
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let metrics = RaftNode::new(2, get_addr(PORT, 2)).metrics().await?;
+    assert!(metrics.purged.is_some());
+
+    // --- Membership
+
+    assert!(matches!(
+        admin.remove_node(&shard, 2).await,
+        Err(AdminError::IsLeader(2))
+    ));
+    admin.remove_node(&shard, 3).await?;
+    let status = admin.shard_status(&admin.ring().await?, &shard).await;
+    assert_eq!(status.nodes.len(), 2);
+    assert!(admin.ring().await?.shard_of(&get_addr(PORT, 3)).is_none());
+
+    let _ = shutdown_tx.send(());
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use std::thread;
+use std::time::Duration;
+
+use distrib_kv_store::auth::Acl;
+use distrib_kv_store::auth::AuthConfig;
+use distrib_kv_store::auth::Role;
+use distrib_kv_store::auth::TokenConfig;
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::start_raft_node;
+use distrib_kv_store::store::Request;
+use distrib_kv_store::NodeConfig;
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+
+const ADDR: &str = "127.0.0.1:31201";
+const RPC_ADDR: &str = "127.0.0.1:32201";
+
+fn set(key: &str, value: &str) -> Request {
+    Request::Set {
+        key: key.into(),
+        value: value.into(),
+    }
+}
+
+/// Start a single node that requires tokens and check roles and prefix ACLs.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_tokens_and_acls() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let mut config = NodeConfig::new(1, dir.path(), ADDR.to_string(), RPC_ADDR.to_string());
+    config.auth = AuthConfig {
+        tokens: vec![
+            TokenConfig {
+                token: "admin-secret".to_string(),
+                role: Role::Admin,
+                acls: Vec::new(),
+            },
+            TokenConfig {
+                token: "tenant-a".to_string(),
+                role: Role::Client,
+                acls: vec![Acl {
+                    prefix: "a/".to_string(),
+                    read: true,
+                    write: true,
+                }],
+            },
+        ],
+    };
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let handle = Handle::current();
+    thread::spawn(move || {
+        let x = handle.block_on(start_raft_node(config, shutdown_rx));
+        println!("x: {:?}", x);
+    });
+
+    // Wait for server to start up.
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+
+    // --- Management requires an admin token.
+    let anonymous = RaftNode::new(1, ADDR.to_string());
+    assert!(matches!(
+        anonymous.init().await,
+        Err(ClientError::Unauthorized(_))
+    ));
+    let tenant = RaftNode::new(1, ADDR.to_string()).with_token("tenant-a");
+    assert!(matches!(tenant.init().await, Err(ClientError::Forbidden(_))));
+    assert!(tenant.metrics().await.is_err());
+
+    let admin = RaftNode::new(1, ADDR.to_string()).with_token("admin-secret");
+    admin.init().await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // --- Clients are restricted to their prefixes.
+    tenant.write(&set("a/foo", "bar")).await?;
+    assert_eq!("bar", tenant.read(&"a/foo".to_string()).await?);
+    assert!(matches!(
+        tenant.write(&set("b/foo", "bar")).await,
+        Err(ClientError::Forbidden(_))
+    ));
+    assert!(tenant.read(&"b/foo".to_string()).await.is_err());
+
+    // --- Admins may access every key, anonymous callers none.
+    admin.write(&set("b/foo", "baz")).await?;
+    assert_eq!("baz", admin.read(&"b/foo".to_string()).await?);
+    assert!(anonymous.read(&"a/foo".to_string()).await.is_err());
+
+    let _ = shutdown_tx.send(());
+    Ok(())
+}
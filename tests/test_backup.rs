@@ -0,0 +1,95 @@
+use std::collections::BTreeMap;
+
+use distrib_kv_store::admin::AdminError;
+use distrib_kv_store::backup::BackupError;
+use distrib_kv_store::backup::Manifest;
+use distrib_kv_store::cluster_manager::ClusterConfig;
+use distrib_kv_store::cluster_manager::ClusterManager;
+use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::store::read_raft_state;
+use distrib_kv_store::topology::Topology;
+
+/// Back up a running cluster whose shards hold part of their state in snapshots and part in
+/// their logs, restore it on new data directories up to the last committed entry of one shard
+/// and up to an earlier entry of the other, and check what the restored cluster serves.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_backup_and_restore() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let topology = Topology::local(2, 3, 47000, &dir.path().join("data"));
+    let mut config = ClusterConfig::new(topology.clone());
+    config.client_config = dir.path().join("cluster.json");
+    let backup_dir = dir.path().join("backup");
+
+    let mut cluster = ClusterManager::start(config.clone()).await?;
+    let client = KVClient::new(config.client_config.to_str().unwrap()).await?;
+    for i in 0..20 {
+        client.write(&format!("key-{}", i), &i.to_string()).await?;
+    }
+    for node in topology.shards.iter().flat_map(|shard| &shard.nodes) {
+        RaftNode::new(node.id, node.api_addr.clone()).snapshot().await?;
+    }
+    for i in 20..40 {
+        client.write(&format!("key-{}", i), &i.to_string()).await?;
+    }
+    // The last entries of the shard of the counter, one per write.
+    for i in 1..=5 {
+        client.write("counter", &i.to_string()).await?;
+    }
+    let counter_shard = cluster.hash_ring.read().await.get_original("counter").to_string();
+
+    let manifest = cluster.backup(&backup_dir).await?;
+    assert_eq!(manifest.shards.len(), 2);
+    for shard in &manifest.shards {
+        assert!(shard.checkpoint.snapshot.is_some(), "{:?}", shard);
+        assert!(shard.checkpoint.committed > shard.checkpoint.snapshot, "{:?}", shard);
+    }
+    assert_eq!(Manifest::load(&backup_dir)?.shards.len(), 2);
+    assert!(matches!(
+        cluster.backup(&backup_dir).await,
+        Err(AdminError::Backup(BackupError::NotEmpty(_)))
+    ));
+
+    // Not part of the backup.
+    client.write("key-0", "changed").await?;
+    client.write("after-backup", "1").await?;
+    cluster.shutdown().await?;
+
+    let restored_topology = Topology::local(2, 3, 47000, &dir.path().join("restored"));
+    let mut restored_config = ClusterConfig::new(restored_topology.clone());
+    restored_config.client_config = dir.path().join("restored.json");
+
+    // Up to the write of 3 to the counter.
+    let committed = manifest.shards.iter().find(|shard| shard.shard == counter_shard);
+    let committed = committed.unwrap().checkpoint.committed.unwrap().index;
+    let invalid = BTreeMap::from([(counter_shard.clone(), committed + 1)]);
+    assert!(matches!(
+        ClusterManager::restore(&restored_config, &backup_dir, &invalid).await,
+        Err(BackupError::InvalidIndex { .. })
+    ));
+    let until = BTreeMap::from([(counter_shard.clone(), committed - 2)]);
+    let restored = ClusterManager::restore(&restored_config, &backup_dir, &until).await?;
+    assert_eq!(restored.len(), 2);
+    for node in restored_topology.shards.iter().flat_map(|shard| &shard.nodes) {
+        assert!(read_raft_state(&node.data_dir)?.is_initialized(), "node {}", node.id);
+    }
+    assert!(matches!(
+        ClusterManager::restore(&restored_config, &backup_dir, &until).await,
+        Err(BackupError::NotEmpty(_))
+    ));
+
+    let mut cluster = ClusterManager::start(restored_config.clone()).await?;
+    let client = KVClient::new(restored_config.client_config.to_str().unwrap()).await?;
+    for i in 0..40 {
+        assert_eq!(client.consistent_read(&format!("key-{}", i)).await?, i.to_string());
+    }
+    assert_eq!(client.consistent_read("counter").await?, "3");
+    let missing = client.consistent_read("after-backup").await;
+    assert!(matches!(missing, Err(ClientError::NotFound(_))), "{:?}", missing);
+    client.write("after-restore", "1").await?;
+    assert_eq!(client.consistent_read("after-restore").await?, "1");
+
+    cluster.shutdown().await?;
+    Ok(())
+}
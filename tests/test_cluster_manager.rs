@@ -0,0 +1,56 @@
+use distrib_kv_store::cluster_manager::ClusterConfig;
+use distrib_kv_store::cluster_manager::ClusterManager;
+use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::topology::ClientConfig;
+use distrib_kv_store::topology::Topology;
+
+/// Start two shards of two nodes from a topology, and use them through the client config
+/// written by the manager.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_start_topology() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+
+    // Invalid topologies are refused before any node starts.
+    let mut invalid = Topology::local(2, 2, 33000, &dir.path().join("data"));
+    invalid.shards[1].nodes[0].api_addr = invalid.shards[0].nodes[0].api_addr.clone();
+    assert!(ClusterManager::start(ClusterConfig::new(invalid))
+        .await
+        .is_err());
+
+    let mut topology = Topology::local(2, 2, 33000, &dir.path().join("data"));
+    topology.shards[1].weight = 3.0;
+    let mut config = ClusterConfig::new(topology.clone());
+    config.client_config = dir.path().join("cluster.json");
+    let mut cluster = ClusterManager::start(config).await?;
+
+    // Every shard is a Raft cluster of its nodes, with the data directories of the topology.
+    for shard in &topology.shards {
+        let first = shard.first();
+        let metrics = RaftNode::new(first.id, first.api_addr.clone()).metrics().await?;
+        let voters: Vec<u64> = metrics.membership_config.membership().voter_ids().collect();
+        assert_eq!(voters, vec![1, 2]);
+        for node in &shard.nodes {
+            assert!(node.data_dir.exists(), "{:?}", node.data_dir);
+        }
+    }
+
+    // The ring follows the weights of the topology.
+    let ring = cluster.hash_ring.read().await.clone();
+    assert_eq!(ring.nodes.len(), 2);
+    assert!((ring.nodes[1].relative_load - 0.75).abs() < 1e-6);
+
+    let client_config = ClientConfig::load(dir.path().join("cluster.json"))?;
+    assert_eq!(client_config, topology.client_config());
+
+    let client = KVClient::new(dir.path().join("cluster.json").to_str().unwrap()).await?;
+    for i in 0..20 {
+        client.write(&format!("key-{}", i), &i.to_string()).await?;
+    }
+    for i in 0..20 {
+        assert_eq!(client.consistent_read(&format!("key-{}", i)).await?, i.to_string());
+    }
+
+    cluster.shutdown().await?;
+    Ok(())
+}
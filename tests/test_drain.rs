@@ -0,0 +1,62 @@
+mod common;
+
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::raft_node::RaftNode;
+use tokio::sync::watch;
+
+use common::get_addr;
+use common::init_shard;
+use common::set;
+use common::start_nodes;
+
+const PORT: u64 = 31800;
+
+/// Move the leadership of a shard of 3 nodes, then drain the leader: the shard keeps accepting
+/// writes and the drained node shuts down.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_transfer_leader_and_drain() -> Result<(), Box<dyn std::error::Error>> {
+    let (shutdown_tx, _) = watch::channel(());
+    let dir = tempfile::TempDir::new()?;
+    let mut nodes = start_nodes(PORT, 1..=3, dir.path(), &shutdown_tx).await;
+
+    let leader = init_shard(PORT, &[1, 2, 3]).await?;
+    leader.write(&set("before", "1")).await?;
+
+    // --- Leadership transfer
+
+    // Followers forward the request to the leader.
+    RaftNode::new(3, get_addr(PORT, 3)).transfer_leader(2).await?;
+    for node_id in 1..=3 {
+        let metrics = RaftNode::new(node_id, get_addr(PORT, node_id)).metrics().await?;
+        assert_eq!(metrics.current_leader, Some(2), "node {}", node_id);
+    }
+    let node2 = RaftNode::new(2, get_addr(PORT, 2));
+    node2.write(&set("after-transfer", "2")).await?;
+
+    // Only voters can become leader.
+    assert!(matches!(
+        node2.transfer_leader(4).await,
+        Err(ClientError::BadRequest(_))
+    ));
+
+    // --- Drain the leader
+
+    let new_leader = node2.drain().await?;
+    assert!(new_leader == 1 || new_leader == 3, "{}", new_leader);
+
+    // The drained node shuts down, the others keep serving.
+    let drained = nodes.remove(1);
+    tokio::task::spawn_blocking(move || drained.join()).await?.unwrap();
+    assert!(matches!(
+        node2.read(&"before".to_string()).await,
+        Err(ClientError::Unreachable(_))
+    ));
+
+    let leader = RaftNode::new(new_leader, get_addr(PORT, new_leader));
+    leader.write(&set("after-drain", "3")).await?;
+    assert_eq!(leader.consistent_read(&"after-transfer".to_string()).await?, "2");
+    assert_eq!(leader.consistent_read(&"after-drain".to_string()).await?, "3");
+
+    let _ = shutdown_tx.send(());
+    Ok(())
+}
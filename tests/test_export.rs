@@ -0,0 +1,69 @@
+use distrib_kv_store::admin::AdminError;
+use distrib_kv_store::cluster_manager::ClusterConfig;
+use distrib_kv_store::cluster_manager::ClusterManager;
+use distrib_kv_store::export::ExportError;
+use distrib_kv_store::export::ExportManifest;
+use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::namespace::Quota;
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::topology::Topology;
+
+/// Export a cluster of 2 shards while it serves writes, import the export into a cluster of 3
+/// shards, and check that the keys and namespaces end up where the new ring places them.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_export_and_import() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let export_dir = dir.path().join("export");
+
+    let mut config = ClusterConfig::new(Topology::local(2, 3, 49000, &dir.path().join("source")));
+    config.client_config = dir.path().join("source.json");
+    let mut cluster = ClusterManager::start(config.clone()).await?;
+    let client = KVClient::new(config.client_config.to_str().unwrap()).await?;
+    let quota = Quota {
+        max_keys: Some(100),
+        max_bytes: None,
+        max_value_size: Some(16),
+    };
+    client.create_namespace("team", quota).await?;
+    for i in 0..30 {
+        client.write(&format!("key-{}", i), &i.to_string()).await?;
+        client.write(&format!("team/key-{}", i), &i.to_string()).await?;
+    }
+
+    let manifest = cluster.export(&export_dir).await?;
+    assert_eq!(manifest.shards.len(), 2);
+    assert_eq!(manifest.ring.nodes.len(), 2);
+    assert_eq!(manifest.shards.iter().map(|shard| shard.keys).sum::<usize>(), 60);
+    assert_eq!(ExportManifest::load(&export_dir)?.shards.len(), 2);
+    assert!(matches!(
+        cluster.export(&export_dir).await,
+        Err(AdminError::Export(ExportError::NotEmpty(_)))
+    ));
+
+    // Not part of the export.
+    client.write("key-0", "changed").await?;
+    client.write("after-export", "1").await?;
+    cluster.shutdown().await?;
+
+    let mut config = ClusterConfig::new(Topology::local(3, 3, 51000, &dir.path().join("target")));
+    config.client_config = dir.path().join("target.json");
+    let mut cluster = ClusterManager::start(config.clone()).await?;
+    let summary = cluster.import(&export_dir, 8).await?;
+    assert_eq!(summary.keys, 60);
+    assert_eq!(summary.namespaces, 1);
+    assert_eq!(summary.skipped, 0);
+
+    let client = KVClient::new(config.client_config.to_str().unwrap()).await?;
+    for i in 0..30 {
+        assert_eq!(client.consistent_read(&format!("key-{}", i)).await?, i.to_string());
+        assert_eq!(client.consistent_read(&format!("team/key-{}", i)).await?, i.to_string());
+    }
+    let missing = client.consistent_read("after-export").await;
+    assert!(matches!(missing, Err(ClientError::NotFound(_))), "{:?}", missing);
+    assert_eq!(client.namespace_usage("team").await?.key_count, 30);
+    // The quota of the namespace was imported too.
+    assert!(client.write("team/large", "a value longer than 16 bytes").await.is_err());
+
+    cluster.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use std::path::Path;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::linearizability::History;
+use distrib_kv_store::linearizability::Operation;
+use distrib_kv_store::linearizability::Outcome;
+use distrib_kv_store::linearizability::Recorder;
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::network::sim::Faults;
+use distrib_kv_store::simulation::SimCluster;
+use distrib_kv_store::topology::Topology;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+/// Registers shared by the clients, spread over the shards by the ring.
+const KEYS: [&str; 6] = ["reg-0", "reg-1", "reg-2", "reg-3", "reg-4", "reg-5"];
+
+const CLIENTS: usize = 5;
+
+/// Time after which a client gives up on a request, whose outcome becomes unknown.
+const OP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Seed of the simulated network and of the choices of the clients and the nemesis,
+/// `SIM_SEED` to replay a failure.
+fn seed() -> u64 {
+    let seed = std::env::var("SIM_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or(2024);
+    println!("SIM_SEED={}", seed);
+    seed
+}
+
+async fn send(client: &KVClient, key: &str, op: &Operation) -> Result<Outcome, ClientError> {
+    match op {
+        Operation::Read => {
+            match client.consistent_read(key).await {
+                Ok(value) => Ok(Outcome::Value(Some(value.to_string()))),
+                Err(ClientError::NotFound(_)) => Ok(Outcome::Value(None)),
+                Err(e) => Err(e),
+            }
+        }
+        Operation::Write { value } => {
+            client.write(key, value).await?;
+            Ok(Outcome::Ok)
+        }
+        Operation::Cas { expected, value } => {
+            let swapped = client
+                .compare_and_swap(key, expected.as_deref().map(str::as_bytes), value)
+                .await?;
+            Ok(if swapped { Outcome::Ok } else { Outcome::Mismatch })
+        }
+    }
+}
+
+/// Send random reads, writes and compare-and-swaps until `stop`, one at a time. Every written
+/// value is unique. Returns the number of operations that completed.
+async fn run_client(
+    process: usize,
+    client: KVClient,
+    recorder: Recorder,
+    seed: u64,
+    stop: Arc<AtomicBool>,
+) -> usize {
+    let mut rng = StdRng::seed_from_u64(seed.wrapping_add(process as u64));
+    // The last value read of every key, to swap from it.
+    let mut seen: Vec<Option<String>> = vec![None; KEYS.len()];
+    let mut completed = 0;
+    let mut n = 0;
+    while !stop.load(Ordering::Relaxed) {
+        let k = rng.gen_range(0..KEYS.len());
+        n += 1;
+        let value = format!("{}-{}", process, n);
+        let op = match rng.gen_range(0..4) {
+            0 | 1 => Operation::Read,
+            2 => Operation::Write { value },
+            _ => Operation::Cas {
+                expected: seen[k].clone(),
+                value,
+            },
+        };
+
+        let invocation = recorder.invoke(process, KEYS[k], op.clone());
+        let outcome = match tokio::time::timeout(OP_TIMEOUT, send(&client, KEYS[k], &op)).await {
+            Ok(Ok(outcome)) => outcome,
+            // The request may still take effect.
+            Ok(Err(_)) | Err(_) => Outcome::Unknown,
+        };
+        if let Outcome::Value(value) = &outcome {
+            seen[k] = value.clone();
+        }
+        if outcome != Outcome::Unknown {
+            completed += 1;
+        }
+        invocation.complete(outcome);
+    }
+    completed
+}
+
+/// Check `history`, and write it to disk if it is not linearizable.
+fn check(history: &History, name: &str, seed: u64) {
+    if let Err(violation) = history.check() {
+        let path = Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join("linearizability")
+            .join(format!("{}-{}.json", name, seed));
+        history.save(&path).unwrap();
+        let longest: Vec<_> = violation
+            .longest
+            .iter()
+            .map(|&i| &violation.events[i])
+            .collect();
+        panic!(
+            "{}, history written to {}\nlongest linearizable order: {:#?}",
+            violation,
+            path.display(),
+            longest
+        );
+    }
+}
+
+/// Run concurrent clients against two shards on a lossy network, while the nemesis stops and
+/// restarts leaders and isolates them, and check that the history of every key is linearizable.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_linearizable_registers() -> Result<(), Box<dyn std::error::Error>> {
+    let seed = seed();
+    let dir = tempfile::TempDir::new()?;
+    let topology = Topology::local(2, 3, 45000, &dir.path().join("data"));
+    let mut cluster = SimCluster::start(seed, topology, dir.path().join("cluster.json")).await?;
+    cluster.network.set_faults(Faults::lossy());
+
+    let recorder = Recorder::new();
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut clients = Vec::new();
+    for process in 0..CLIENTS {
+        let client = cluster.client().await?;
+        clients.push(tokio::spawn(run_client(
+            process,
+            client,
+            recorder.clone(),
+            seed,
+            stop.clone(),
+        )));
+    }
+
+    let mut rng = StdRng::seed_from_u64(seed);
+    for _ in 0..6 {
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        let shard = rng.gen_range(0..2);
+        let leader = cluster.wait_for_leader(shard, &[]).await?;
+        if rng.gen_bool(0.5) {
+            println!("nemesis: stop node {} of shard {}", leader, shard);
+            cluster.stop(shard, leader).await;
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            cluster.restart(shard, leader).await?;
+        } else {
+            println!("nemesis: isolate node {} of shard {}", leader, shard);
+            cluster.isolate(shard, leader);
+            tokio::time::sleep(Duration::from_secs(3)).await;
+            cluster.heal();
+        }
+    }
+    // Let the clients complete some operations on the healed cluster.
+    cluster.network.set_faults(Faults::default());
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    stop.store(true, Ordering::Relaxed);
+
+    let mut completed = 0;
+    for client in clients {
+        completed += client.await?;
+    }
+    let history = recorder.history();
+    println!("{} operations, {} completed", history.events.len(), completed);
+    assert!(completed > 100, "only {} operations completed", completed);
+    check(&history, "registers", seed);
+
+    cluster.shutdown().await;
+    Ok(())
+}
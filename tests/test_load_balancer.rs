@@ -0,0 +1,127 @@
+mod common;
+
+use std::sync::Arc;
+
+use distrib_kv_store::carp::Carp;
+use distrib_kv_store::load_balancer::LoadBalancerConfig;
+use distrib_kv_store::load_balancer::LoadController;
+use distrib_kv_store::namespace::Quota;
+use distrib_kv_store::raft_node::RaftNode;
+use tokio::sync::watch;
+use tokio::sync::RwLock;
+
+use common::get_addr;
+use common::init_shard;
+use common::set;
+use common::start_nodes;
+
+const PORT: u64 = 57600;
+
+/// Reweight two shards while one of them serves all requests: the keys that change shard are
+/// moved, unless more than `max_moved_keys` would, and the namespace quotas follow the weights.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_reweighting_moves_keys() -> Result<(), Box<dyn std::error::Error>> {
+    let (shutdown_tx, _) = watch::channel(());
+    let dir = tempfile::TempDir::new()?;
+    start_nodes(PORT, 1..=2, dir.path(), &shutdown_tx).await;
+
+    init_shard(PORT, &[1]).await?;
+    init_shard(PORT, &[2]).await?;
+    let ring = Carp::with_followers(
+        vec![
+            (get_addr(PORT, 1), 0.5, vec![]),
+            (get_addr(PORT, 2), 0.5, vec![]),
+        ],
+        0,
+    );
+    let node = |addr: &str| RaftNode::new(0, addr.to_string());
+    for node_id in 1..=2 {
+        node(&get_addr(PORT, node_id))
+            .update_hash_ring(ring.clone())
+            .await?;
+    }
+    let quota = Quota {
+        max_keys: Some(1000),
+        ..Default::default()
+    };
+    let weights =
+        |ring: &Carp| -> Vec<f32> { ring.nodes.iter().map(|n| n.relative_load).collect() };
+    for (index, shard) in ring.nodes.iter().enumerate() {
+        node(&shard.addr)
+            .create_namespace("team", quota.share(&weights(&ring), index))
+            .await?;
+    }
+    let keys: Vec<String> = (0..100).map(|i| format!("team/key-{}", i)).collect();
+    for key in &keys {
+        node(ring.get_original(key)).write(&set(key, key)).await?;
+    }
+    let busy = get_addr(PORT, 1);
+
+    // Send requests to the keys of the first shard only, between two steps of `controller`.
+    let reweight = |mut controller: LoadController| {
+        let (ring, keys, busy) = (ring.clone(), keys.clone(), busy.clone());
+        async move {
+            controller.step().await;
+            for key in keys.iter().filter(|key| ring.get_original(key) == busy) {
+                for _ in 0..5 {
+                    node(&busy).read(key).await.unwrap();
+                }
+            }
+            controller.step().await;
+        }
+    };
+
+    // Too many keys would move.
+    let config = LoadBalancerConfig {
+        enabled: true,
+        max_moved_keys: 0,
+        ..Default::default()
+    };
+    let hash_ring = Arc::new(RwLock::new(ring.clone()));
+    let transport = RaftNode::new(0, String::new());
+    reweight(LoadController::new(config, hash_ring, transport.clone())).await;
+    assert_eq!(node(&busy).get_hash_ring().await?.config_id, ring.config_id);
+
+    let hash_ring = Arc::new(RwLock::new(ring.clone()));
+    let controller = LoadController::new(LoadBalancerConfig::default(), hash_ring, transport);
+    reweight(controller).await;
+    let reweighted = node(&busy).get_hash_ring().await?;
+    assert!(reweighted.config_id > ring.config_id);
+    let other = node(&get_addr(PORT, 2)).get_hash_ring().await?;
+    assert_eq!(other.config_id, reweighted.config_id);
+    let busy_weight = |ring: &Carp| {
+        ring.nodes
+            .iter()
+            .find(|n| n.addr == busy)
+            .unwrap()
+            .relative_load
+    };
+    assert!(busy_weight(&reweighted) < busy_weight(&ring));
+
+    // Every key is served by its new shard, and only held there.
+    let moved = keys
+        .iter()
+        .filter(|key| ring.get_original(key) != reweighted.get_original(key))
+        .count();
+    assert!(moved > 0);
+    for key in &keys {
+        let value = node(reweighted.get_original(key)).read(key).await?;
+        assert_eq!(value, key.as_str());
+    }
+    let mut held = 0;
+    for node_id in 1..=2 {
+        held += node(&get_addr(PORT, node_id)).load().await?.key_count;
+    }
+    assert_eq!(held, keys.len() as u64);
+
+    // The quota is split by the new weights.
+    let mut usage = 0;
+    for (index, shard) in reweighted.nodes.iter().enumerate() {
+        let namespace = node(&shard.addr).namespaces().await?["team"].clone();
+        assert_eq!(namespace.quota, quota.share(&weights(&reweighted), index));
+        usage += namespace.usage.key_count;
+    }
+    assert_eq!(usage, keys.len() as u64);
+
+    Ok(())
+}
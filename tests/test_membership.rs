@@ -0,0 +1,110 @@
+mod common;
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use distrib_kv_store::membership::ChangeProgress;
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::raft_node::RaftNode;
+use tokio::sync::watch;
+
+use common::get_addr;
+use common::get_rpc_addr;
+use common::set;
+use common::start_nodes;
+
+const PORT: u64 = 31900;
+
+fn node(node_id: u64) -> (u64, String, String) {
+    (node_id, get_addr(PORT, node_id), get_rpc_addr(PORT, node_id))
+}
+
+async fn voters(node: &RaftNode) -> Result<BTreeSet<u64>, ClientError> {
+    Ok(node.membership().await?.voters)
+}
+
+/// Grow, reshape and shrink a shard with the safe membership operations, and check that
+/// unsafe changes are refused.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_safe_membership_changes() -> Result<(), Box<dyn std::error::Error>> {
+    let (shutdown_tx, _) = watch::channel(());
+    let dir = tempfile::TempDir::new()?;
+    start_nodes(PORT, 1..=4, dir.path(), &shutdown_tx).await;
+
+    let leader = RaftNode::new(1, get_addr(PORT, 1));
+    leader.init().await?;
+    leader.write(&set("before", "1")).await?;
+
+    // --- Add nodes, waiting for them to catch up
+
+    for node_id in [2, 3] {
+        while let ChangeProgress::CatchingUp { learners } = leader.add_node(node(node_id)).await? {
+            assert_eq!(learners[0].id, node_id);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+    assert_eq!(voters(&leader).await?, BTreeSet::from([1, 2, 3]));
+    // Adding a voter again is a no-op.
+    assert_eq!(leader.add_node(node(2)).await?, ChangeProgress::Done);
+
+    // Nodes that can't be reached are not added.
+    assert!(matches!(
+        leader.add_node(node(5)).await,
+        Err(ClientError::BadRequest(_))
+    ));
+    let status = leader.membership().await?;
+    assert!(status.learners.is_empty());
+    assert!(!status.joint);
+
+    // --- Replace a node
+
+    while let ChangeProgress::CatchingUp { .. } = leader.replace_node(3, node(4)).await? {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(voters(&leader).await?, BTreeSet::from([1, 2, 4]));
+    // Repeating a completed change is a no-op.
+    assert_eq!(leader.replace_node(3, node(4)).await?, ChangeProgress::Done);
+
+    // --- Refuse to lose the quorum
+
+    // Node 4 goes down. It is still up to date, but no longer answers the heartbeats.
+    RaftNode::new(4, get_addr(PORT, 4)).drain().await?;
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+    assert!(matches!(
+        leader.remove_node(2).await,
+        Err(ClientError::Conflict(_))
+    ));
+
+    // Node 4 falls behind.
+    for i in 0..150 {
+        leader.write(&set(&format!("key-{}", i), "value")).await?;
+    }
+
+    // Without node 2, nodes 1 and 4 could not commit anything.
+    assert!(matches!(
+        leader.remove_node(2).await,
+        Err(ClientError::Conflict(_))
+    ));
+    assert_eq!(leader.remove_node(4).await?, ChangeProgress::Done);
+    assert_eq!(voters(&leader).await?, BTreeSet::from([1, 2]));
+
+    // --- Remove the leader
+
+    // The leadership moves to node 2 first, which removes node 1.
+    assert_eq!(
+        RaftNode::new(2, get_addr(PORT, 2)).remove_node(1).await?,
+        ChangeProgress::Done
+    );
+    let node2 = RaftNode::new(2, get_addr(PORT, 2));
+    assert_eq!(voters(&node2).await?, BTreeSet::from([2]));
+    assert_eq!(node2.consistent_read(&"key-149".to_string()).await?, "value");
+
+    // The last voter stays.
+    assert!(matches!(
+        node2.remove_node(2).await,
+        Err(ClientError::BadRequest(_))
+    ));
+
+    let _ = shutdown_tx.send(());
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use std::thread;
+use std::time::Duration;
+
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::start_raft_node;
+use distrib_kv_store::store::Request;
+use distrib_kv_store::NodeConfig;
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+
+const ADDR: &str = "127.0.0.1:31401";
+const RPC_ADDR: &str = "127.0.0.1:32401";
+
+/// Start a single node, write to it and check the Prometheus metrics it exports.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_prometheus_metrics() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let config = NodeConfig::new(1, dir.path(), ADDR.to_string(), RPC_ADDR.to_string());
+
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let handle = Handle::current();
+    thread::spawn(move || {
+        let x = handle.block_on(start_raft_node(config, shutdown_rx));
+        println!("x: {:?}", x);
+    });
+
+    // Wait for server to start up.
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+
+    let node = RaftNode::new(1, ADDR.to_string());
+    node.init().await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    for i in 0..3 {
+        node.write(&Request::Set {
+            key: format!("key{}", i).into(),
+            value: "value".into(),
+        })
+        .await?;
+    }
+    assert_eq!("value", node.read(&"key0".to_string()).await?);
+
+    let res = reqwest::get(format!("http://{}/metrics", ADDR)).await?;
+    assert!(res.status().is_success());
+    let text = res.text().await?;
+    for expected in [
+        r#"kv_http_requests_total{method="POST",route="/api/write",status="200"} 3"#,
+        r#"kv_http_request_duration_seconds_bucket{route="/api/read""#,
+        "kv_raft_current_term 1",
+        "kv_raft_current_leader 1",
+        "kv_raft_is_leader 1",
+        "kv_keys 3",
+        "kv_hash_ring_config_id 0",
+        r#"kv_rocksdb_property{cf="logs",property="rocksdb.estimate-num-keys"}"#,
+    ] {
+        assert!(text.contains(expected), "missing {} in:\n{}", expected, text);
+    }
+
+    // Init, the blank leader entry and three writes.
+    let applied = value_of(&text, "kv_raft_last_applied_index");
+    assert!(applied >= 4, "{}", applied);
+    assert!(value_of(&text, "kv_raft_committed_index") >= applied);
+
+    let _ = shutdown_tx.send(());
+    Ok(())
+}
+
+fn value_of(text: &str, metric: &str) -> u64 {
+    text.lines()
+        .find_map(|line| line.strip_prefix(metric)?.strip_prefix(' '))
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| panic!("missing metric {} in:\n{}", metric, text))
+}
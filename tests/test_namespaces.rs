@@ -0,0 +1,170 @@
+use std::thread;
+use std::time::Duration;
+
+use distrib_kv_store::cluster_manager::ClusterConfig;
+use distrib_kv_store::cluster_manager::ClusterManager;
+use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::namespace::Quota;
+use distrib_kv_store::namespace::Usage;
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::network::error::ErrorCode;
+use distrib_kv_store::network::error::ErrorResponse;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::start_example_raft_node;
+use distrib_kv_store::store::Request;
+use distrib_kv_store::topology::Topology;
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+
+const ADDR: &str = "127.0.0.1:31301";
+const RPC_ADDR: &str = "127.0.0.1:32301";
+
+fn set(key: &str, value: &str) -> Request {
+    Request::Set {
+        key: key.into(),
+        value: value.into(),
+    }
+}
+
+/// Start a single node and check that namespace quotas are enforced.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_namespace_quotas() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let (shutdown_tx, shutdown_rx) = watch::channel(());
+    let handle = Handle::current();
+    thread::spawn(move || {
+        let x = handle.block_on(start_example_raft_node(
+            1,
+            dir.path(),
+            ADDR.to_string(),
+            RPC_ADDR.to_string(),
+            shutdown_rx,
+        ));
+        println!("x: {:?}", x);
+    });
+
+    // Wait for server to start up.
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+
+    let node = RaftNode::new(1, ADDR.to_string());
+    node.init().await?;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // --- Keys written before the namespace exists count towards its usage.
+    node.write(&set("team/a", "1")).await?;
+    let quota = Quota {
+        max_keys: Some(2),
+        max_bytes: None,
+        max_value_size: Some(4),
+    };
+    node.create_namespace("team", quota).await?;
+    assert!(matches!(
+        node.create_namespace("team", quota).await,
+        Err(ClientError::Conflict(_))
+    ));
+    assert!(matches!(
+        node.create_namespace("a/b", quota).await,
+        Err(ClientError::BadRequest(_))
+    ));
+
+    node.write(&set("team/b", "2")).await?;
+    assert!(matches!(
+        node.write(&set("team/c", "3")).await,
+        Err(ClientError::QuotaExceeded(_))
+    ));
+    assert!(matches!(
+        node.write(&set("team/a", "too long")).await,
+        Err(ClientError::QuotaExceeded(_))
+    ));
+    // Overwriting a key doesn't add one, and other keys are not limited.
+    node.write(&set("team/a", "11")).await?;
+    node.write(&set("other", "value")).await?;
+
+    let namespaces = node.namespaces().await?;
+    assert_eq!(
+        Usage {
+            key_count: 2,
+            byte_size: 6 + 2 + 6 + 1,
+        },
+        namespaces["team"].usage
+    );
+
+    // --- Raising the quota allows more keys.
+    node.set_namespace_quota(
+        "team",
+        Quota {
+            max_keys: Some(3),
+            ..quota
+        },
+    )
+    .await?;
+    node.write(&set("team/c", "3")).await?;
+
+    // --- Deleting the namespace deletes its keys.
+    node.delete_namespace("team").await?;
+    assert!(matches!(
+        node.delete_namespace("team").await,
+        Err(ClientError::NotFound(_))
+    ));
+    assert!(matches!(
+        node.read(&"team/a".to_string()).await,
+        Err(ClientError::NotFound(_))
+    ));
+    assert_eq!("value", node.read(&"other".to_string()).await?);
+    assert!(node.namespaces().await?.is_empty());
+
+    // --- Malformed requests are answered with the error envelope.
+    let resp = reqwest::Client::new()
+        .post(format!("http://{}/api/read", ADDR))
+        .header("content-type", "application/json")
+        .body("{not json")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), reqwest::StatusCode::BAD_REQUEST);
+    let body: ErrorResponse = resp.json().await?;
+    assert_eq!(body.error.code, ErrorCode::BadRequest);
+
+    let _ = shutdown_tx.send(());
+    Ok(())
+}
+
+/// Check that the quota of a namespace is a limit for the whole cluster, not for every shard.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_namespace_quotas_across_shards() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let mut config = ClusterConfig::new(Topology::local(3, 1, 55000, &dir.path().join("data")));
+    config.client_config = dir.path().join("cluster.json");
+    let mut cluster = ClusterManager::start(config.clone()).await?;
+    let client = KVClient::new(config.client_config.to_str().unwrap()).await?;
+
+    // Every shard accepts its share of the keys, 2 out of 6.
+    let quota = Quota {
+        max_keys: Some(6),
+        ..Default::default()
+    };
+    client.create_namespace("team", quota).await?;
+    let mut written = 0;
+    for i in 0..60 {
+        match client.write(format!("team/key-{}", i), "value").await {
+            Ok(()) => written += 1,
+            Err(ClientError::QuotaExceeded(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    assert_eq!(written, 6);
+    assert_eq!(client.namespace_usage("team").await?.key_count, 6);
+
+    // A new quota is split the same way.
+    let quota = Quota {
+        max_keys: Some(9),
+        ..Default::default()
+    };
+    client.set_namespace_quota("team", quota).await?;
+    for i in 0..60 {
+        let _ = client.write(format!("team/more-{}", i), "value").await;
+    }
+    assert_eq!(client.namespace_usage("team").await?.key_count, 9);
+
+    cluster.shutdown().await?;
+    Ok(())
+}
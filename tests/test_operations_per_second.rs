@@ -1,5 +1,6 @@
 // use distrib_kv_store::cluster_manager::ClusterManager;
 use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::network::error::ClientError;
 use std::error::Error;
 use rand::{rngs::StdRng, SeedableRng, Rng, distributions::Alphanumeric};
 use std::time::{Duration, Instant};
@@ -23,7 +24,7 @@ async fn test_write_operations() -> Result<(), Box<dyn Error>> {
     // tokio::time::sleep(Duration::from_secs(1)).await;
 
     let mut rng = StdRng::from_entropy(); // StdRng is Send
-    let client = KVClient::new("all_nodes.json").await?;
+    let client = KVClient::new("cluster.json").await?;
 
     let mut ops_data: Vec<(f64, usize)> = Vec::new();
 
@@ -58,7 +59,7 @@ async fn test_read_operations() -> Result<(), Box<dyn Error>> {
     // tokio::time::sleep(Duration::from_secs(1)).await;
 
     let mut rng = StdRng::from_entropy(); // StdRng is Send
-    let client = KVClient::new("all_nodes.json").await?;
+    let client = KVClient::new("cluster.json").await?;
 
     let mut ops_data: Vec<(f64, usize)> = Vec::new();
 
@@ -67,7 +68,10 @@ async fn test_read_operations() -> Result<(), Box<dyn Error>> {
     let mut i = 0;
     while Instant::now() < end_time {
         let key: String = (0..10).map(|_| rng.sample(Alphanumeric) as char).collect();
-        client.read(&key).await?;
+        match client.read(&key).await {
+            Ok(_) | Err(ClientError::NotFound(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
 
         if i % 100 == 0 {
             let elapsed = start_time.elapsed().as_secs_f64();
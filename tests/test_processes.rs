@@ -0,0 +1,130 @@
+use std::future::Future;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use distrib_kv_store::cluster_manager::ClusterConfig;
+use distrib_kv_store::cluster_manager::ClusterManager;
+use distrib_kv_store::cluster_manager::DeployMode;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::store::Request;
+use distrib_kv_store::supervisor::ProcessStatus;
+use distrib_kv_store::topology::Topology;
+
+fn get_addr(node_id: u64) -> String {
+    format!("127.0.0.1:{}", 37100 + node_id)
+}
+
+fn set(key: &str, value: &str) -> Request {
+    Request::Set {
+        key: key.into(),
+        value: value.into(),
+    }
+}
+
+/// Poll `check` until it returns true, for up to 20 s.
+async fn wait_until<F, Fut>(what: &str, mut check: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while !check().await {
+        assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Run a shard of 3 nodes as processes, and crash, suspend and stop them one at a time.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_node_processes() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let topology = Topology::local(1, 3, 37000, &dir.path().join("data"));
+    let mut config = ClusterConfig::new(topology);
+    config.client_config = dir.path().join("cluster.json");
+    config.mode = DeployMode::MultiProcess;
+    config.processes.binary = Some(PathBuf::from(env!("CARGO_BIN_EXE_raft-kv")));
+    config.processes.log_dir = dir.path().join("logs");
+    config.processes.restart_delay_ms = 200;
+
+    let mut cluster = ClusterManager::start(config).await?;
+    let supervisor = cluster.supervisor.as_ref().unwrap();
+    let nodes = supervisor.nodes();
+    assert_eq!(nodes.len(), 3);
+    for node in &nodes {
+        assert!(matches!(node.status, ProcessStatus::Running { .. }), "{:?}", node);
+    }
+    RaftNode::new(1, get_addr(1)).write(&set("before", "1")).await?;
+
+    // --- A suspended leader is replaced
+
+    supervisor.suspend("shard-1", 1).await?;
+    assert!(matches!(
+        supervisor.node("shard-1", 1)?.status,
+        ProcessStatus::Suspended { .. }
+    ));
+    wait_until("nodes 2 and 3 elect a leader", || async move {
+        let metrics = RaftNode::new(2, get_addr(2)).metrics().await;
+        matches!(metrics.map(|m| m.current_leader), Ok(Some(2 | 3)))
+    })
+    .await;
+    let leader_id = RaftNode::new(2, get_addr(2)).metrics().await?.current_leader.unwrap();
+    let leader = RaftNode::new(leader_id, get_addr(leader_id));
+    leader.write(&set("suspended", "1")).await?;
+    supervisor.resume("shard-1", 1).await?;
+
+    // --- A killed follower is restarted and catches up
+
+    let follower = if leader_id == 2 { 3 } else { 2 };
+    supervisor.kill("shard-1", follower).await?;
+    wait_until("the follower is restarted", || async move {
+        let node = supervisor.node("shard-1", follower).unwrap();
+        node.restarts == 1 && matches!(node.status, ProcessStatus::Running { .. })
+    })
+    .await;
+    leader.write(&set("killed", "1")).await?;
+    let applied = leader.metrics().await?.last_applied;
+    wait_until("the follower catches up", || async move {
+        match RaftNode::new(follower, get_addr(follower)).metrics().await {
+            Ok(metrics) => metrics.last_applied >= applied,
+            Err(_) => false,
+        }
+    })
+    .await;
+
+    // --- A stopped node stays down until it is started
+
+    supervisor.stop("shard-1", 1).await?;
+    assert_eq!(supervisor.node("shard-1", 1)?.status, ProcessStatus::Stopped);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(supervisor.node("shard-1", 1)?.status, ProcessStatus::Stopped);
+    assert!(RaftNode::new(1, get_addr(1)).metrics().await.is_err());
+    // The other two nodes still form a quorum.
+    leader.write(&set("stopped", "1")).await?;
+
+    supervisor.start("shard-1", 1).await?;
+    wait_until("node 1 is back", || async move {
+        RaftNode::new(1, get_addr(1)).metrics().await.is_ok()
+    })
+    .await;
+    assert_eq!(supervisor.node("shard-1", 1)?.restarts, 0);
+    for key in ["before", "suspended", "killed", "stopped"] {
+        assert_eq!(leader.consistent_read(&key.to_string()).await?, "1");
+    }
+
+    // Unknown nodes are refused, resuming a running node does nothing.
+    assert!(supervisor.kill("shard-2", 1).await.is_err());
+    supervisor.resume("shard-1", 1).await?;
+
+    // The supervisor's own events are in the logs of the nodes.
+    let log = std::fs::read_to_string(supervisor.node("shard-1", follower)?.log_path)?;
+    assert!(log.contains("killed"));
+    assert_eq!(log.matches("started with pid").count(), 2);
+
+    cluster.shutdown().await?;
+    assert!(cluster.supervisor.is_none());
+    for node_id in 1..=3 {
+        assert!(RaftNode::new(node_id, get_addr(node_id)).metrics().await.is_err());
+    }
+    Ok(())
+}
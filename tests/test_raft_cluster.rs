@@ -4,6 +4,7 @@ use std::panic::PanicInfo;
 use std::thread;
 use std::time::Duration;
 
+use distrib_kv_store::network::error::ClientError;
 use distrib_kv_store::raft_node::RaftNode;
 use distrib_kv_store::start_example_raft_node;
 use distrib_kv_store::store::Request;
@@ -209,8 +210,8 @@ async fn test_cluster() -> Result<(), Box<dyn std::error::Error>> {
     println!("=== write `foo=bar`");
     let _x = leader
         .write(&Request::Set {
-            key: "foo".to_string(),
-            value: "bar".to_string(),
+            key: "foo".into(),
+            value: "bar".into(),
         })
         .await?;
 
@@ -239,8 +240,8 @@ async fn test_cluster() -> Result<(), Box<dyn std::error::Error>> {
     println!("=== write `foo` on node 2 (not leader, should be forwarded)");
     let _x = client2
         .write(&Request::Set {
-            key: "foo".to_string(),
-            value: "wow".to_string(),
+            key: "foo".into(),
+            value: "wow".into(),
         })
         .await?;
 
@@ -266,16 +267,18 @@ async fn test_cluster() -> Result<(), Box<dyn std::error::Error>> {
     let x = leader.consistent_read(&("foo".to_string())).await?;
     assert_eq!("wow", x);
 
-    println!("=== consistent_read `foo` on node 2 MUST return CheckIsLeaderError");
+    println!("=== consistent_read `foo` on node 2 MUST return NotLeader");
     let x = client2.consistent_read(&("foo".to_string())).await;
     match x {
         Err(e) => {
-            let s = e.to_string();
-            let expect_err:String = "error occur on remote peer 2: has to forward request to: Some(1), Some(Node { rpc_addr: \"127.0.0.1:32001\", api_addr: \"127.0.0.1:31001\" })".to_string();
+            let expect_err = ClientError::NotLeader {
+                leader_id: Some(1),
+                leader_addr: Some("127.0.0.1:31001".to_string()),
+            };
 
-            assert_eq!(s, expect_err);
+            assert_eq!(e, expect_err);
         }
-        Ok(_) => panic!("MUST return CheckIsLeaderError"),
+        Ok(_) => panic!("MUST return NotLeader"),
     }
 
     Ok(())
@@ -0,0 +1,92 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use distrib_kv_store::cluster_manager::ClusterConfig;
+use distrib_kv_store::cluster_manager::ClusterManager;
+use distrib_kv_store::cluster_manager::DeployMode;
+use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::store::read_raft_state;
+use distrib_kv_store::supervisor::ProcessStatus;
+use distrib_kv_store::topology::Topology;
+
+/// Kill a shard of 3 node processes with SIGKILL, with part of its state in a snapshot and part
+/// in the log, restart it on the same data directories and check that it rejoins without losing
+/// data.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_restart_shard() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let topology = Topology::local(1, 3, 35000, &dir.path().join("data"));
+    let mut config = ClusterConfig::new(topology.clone());
+    config.client_config = dir.path().join("cluster.json");
+    config.mode = DeployMode::MultiProcess;
+    config.processes.binary = Some(PathBuf::from(env!("CARGO_BIN_EXE_raft-kv")));
+    config.processes.log_dir = dir.path().join("logs");
+    // Killed nodes stay down until the shard is started again.
+    config.processes.restart = false;
+    let shard = &topology.shards[0];
+    let leader = RaftNode::new(1, shard.first().api_addr.clone());
+    let client_config = dir.path().join("cluster.json");
+
+    let mut cluster = ClusterManager::start(config.clone()).await?;
+    let client = KVClient::new(client_config.to_str().unwrap()).await?;
+    for i in 0..30 {
+        client.write(&format!("key-{}", i), &i.to_string()).await?;
+    }
+    // Part of the state is only recoverable from the snapshots.
+    for node in &shard.nodes {
+        RaftNode::new(node.id, node.api_addr.clone()).snapshot().await?;
+    }
+    for i in 30..60 {
+        client.write(&format!("key-{}", i), &i.to_string()).await?;
+    }
+    let before = leader.metrics().await?;
+    // Let the followers append the whole log, so that every node is checked below.
+    for node in &shard.nodes {
+        let raft_node = RaftNode::new(node.id, node.api_addr.clone());
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while raft_node.metrics().await?.last_log_index < before.last_log_index {
+            assert!(Instant::now() < deadline, "node {} does not catch up", node.id);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    // Crash every node at once, without giving it a chance to flush anything.
+    let supervisor = cluster.supervisor.as_ref().unwrap();
+    for node in &shard.nodes {
+        supervisor.kill("shard-1", node.id).await?;
+    }
+    let deadline = Instant::now() + Duration::from_secs(20);
+    while supervisor.nodes().iter().any(|node| node.status != ProcessStatus::Exited) {
+        assert!(Instant::now() < deadline, "nodes still running: {:?}", supervisor.nodes());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    cluster.shutdown().await?;
+
+    // Every node stored its vote and the membership of the shard.
+    for node in &shard.nodes {
+        let state = read_raft_state(&node.data_dir)?;
+        assert!(state.is_initialized(), "node {}", node.id);
+        assert!(state.last_log_id >= before.last_applied, "node {}", node.id);
+    }
+
+    // The shard rejoins instead of being initialized again.
+    let mut cluster = ClusterManager::start(config).await?;
+    let metrics = leader.metrics().await?;
+    let voters: BTreeSet<u64> = metrics.membership_config.membership().voter_ids().collect();
+    assert_eq!(voters, BTreeSet::from([1, 2, 3]));
+    assert!(metrics.current_term > before.current_term);
+    assert!(metrics.last_log_index > before.last_log_index);
+
+    let client = KVClient::new(client_config.to_str().unwrap()).await?;
+    for i in 0..60 {
+        assert_eq!(client.consistent_read(&format!("key-{}", i)).await?, i.to_string());
+    }
+    client.write("after-restart", "1").await?;
+    assert_eq!(client.consistent_read("after-restart").await?, "1");
+
+    cluster.shutdown().await?;
+    Ok(())
+}
@@ -0,0 +1,114 @@
+mod common;
+
+use std::time::Duration;
+
+use distrib_kv_store::bulk;
+use distrib_kv_store::bulk::Format;
+use distrib_kv_store::bulk::Record;
+use distrib_kv_store::carp::Carp;
+use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::value::Bytes;
+use tokio::sync::watch;
+
+use common::get_addr;
+use common::get_rpc_addr;
+use common::spawn_node;
+
+const PORT: u64 = 31700;
+
+/// Import keys into two single-node shards, scan and export them across both shards, and
+/// delete some of them.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_scan_and_bulk() -> Result<(), Box<dyn std::error::Error>> {
+    let (shutdown_tx, _) = watch::channel(());
+    let dir = tempfile::TempDir::new()?;
+    // Every shard is a single node 1.
+    for shard in 1..=2 {
+        spawn_node(
+            1,
+            dir.path().join(format!("shard-{}", shard)),
+            get_addr(PORT, shard),
+            get_rpc_addr(PORT, shard),
+            shutdown_tx.subscribe(),
+        );
+    }
+
+    // Wait for servers to start up.
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+
+    let ring = Carp::new(vec![(get_addr(PORT, 1), 0.5), (get_addr(PORT, 2), 0.5)], 1);
+    for shard in 1..=2 {
+        let node = RaftNode::new(1, get_addr(PORT, shard));
+        node.init().await?;
+        node.update_hash_ring(ring.clone()).await?;
+    }
+    let nodes_path = dir.path().join("all_nodes.json");
+    std::fs::write(
+        &nodes_path,
+        serde_json::to_string(&vec![vec![get_addr(PORT, 1)], vec![get_addr(PORT, 2)]])?,
+    )?;
+    let client = KVClient::new(nodes_path.to_str().unwrap()).await?;
+
+    let records: Vec<Record> = (0..50)
+        .map(|i| Record {
+            key: format!("user/{:02}", i).into(),
+            value: format!("name, \"{}\"", i).into(),
+        })
+        .collect();
+    assert_eq!(bulk::import(&client, records.clone(), 8).await?, 50);
+    client.write("other", "value").await?;
+
+    // Both shards hold some of the keys.
+    for shard in 1..=2 {
+        let node = RaftNode::new(1, get_addr(PORT, shard));
+        assert!(node.load().await?.key_count > 0);
+    }
+
+    // --- Scan in pages
+
+    let mut scanned = Vec::new();
+    let mut last: Option<Bytes> = None;
+    loop {
+        let page = client.scan("user/", last.as_deref(), 7).await?;
+        if page.is_empty() {
+            break;
+        }
+        assert!(page.len() <= 7);
+        last = page.last().map(|(key, _)| key.clone());
+        scanned.extend(page);
+    }
+    let expected: Vec<(Bytes, Bytes)> = records
+        .iter()
+        .map(|r| (r.key.clone(), r.value.clone()))
+        .collect();
+    assert_eq!(scanned, expected);
+
+    // --- Export
+
+    for format in [Format::JsonLines, Format::Csv] {
+        let mut out = Vec::new();
+        assert_eq!(bulk::export(&client, "user/", format, &mut out).await?, 50);
+        assert_eq!(bulk::parse(std::str::from_utf8(&out)?, format)?, records);
+    }
+
+    // --- Delete
+
+    client.delete("user/00").await?;
+    client.delete("user/49").await?;
+    client.delete("missing").await?;
+    assert!(matches!(client.read("user/00").await, Err(ClientError::NotFound(_))));
+    let keys: Vec<Bytes> = client
+        .scan("user/", None, 100)
+        .await?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    assert_eq!(keys.len(), 48);
+    assert_eq!(keys.first().and_then(Bytes::as_str), Some("user/01"));
+    assert_eq!(keys.last().and_then(Bytes::as_str), Some("user/48"));
+
+    let _ = shutdown_tx.send(());
+    Ok(())
+}
@@ -0,0 +1,176 @@
+use std::future::Future;
+use std::time::Duration;
+
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::network::sim::Faults;
+use distrib_kv_store::simulation::SimCluster;
+use distrib_kv_store::store::Request;
+use distrib_kv_store::topology::Topology;
+use tokio::time::Instant;
+
+/// Seed of the simulated network, `SIM_SEED` to replay a failure.
+fn seed() -> u64 {
+    let seed = std::env::var("SIM_SEED")
+        .ok()
+        .and_then(|seed| seed.parse().ok())
+        .unwrap_or(2024);
+    println!("SIM_SEED={}", seed);
+    seed
+}
+
+fn set(key: &str, value: &str) -> Request {
+    Request::Set {
+        key: key.into(),
+        value: value.into(),
+    }
+}
+
+/// Retry `op` for up to 20 s, e.g., while a shard elects a new leader.
+async fn retry<T, F, Fut>(mut op: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ClientError>>,
+{
+    let deadline = Instant::now() + Duration::from_secs(20);
+    loop {
+        match op().await {
+            Ok(res) => return res,
+            Err(e) => {
+                assert!(Instant::now() < deadline, "gave up: {}", e);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+            }
+        }
+    }
+}
+
+/// Wait until every running node of `shard` applied the log of its leader, and check that they
+/// all hold the same values for the keys of the shard among `keys`.
+async fn wait_for_convergence(cluster: &SimCluster, shard: usize, keys: &[String]) {
+    let leader = cluster.wait_for_leader(shard, &[]).await.unwrap();
+    let leader = cluster.node(shard, leader);
+    let applied = leader.metrics().await.unwrap().last_applied;
+    let deadline = Instant::now() + Duration::from_secs(20);
+    for node in &cluster.topology.shards[shard].nodes {
+        if !cluster.is_running(shard, node.id) {
+            continue;
+        }
+        let client = cluster.node(shard, node.id);
+        while client.metrics().await.unwrap().last_applied < applied {
+            assert!(Instant::now() < deadline, "node {} did not catch up", node.id);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        for key in keys {
+            // Keys of the other shards are refused.
+            let Ok(expected) = leader.read(key).await else {
+                continue;
+            };
+            assert_eq!(
+                client.read(key).await.unwrap(),
+                expected,
+                "node {} of shard {}, key {}",
+                node.id,
+                shard,
+                key
+            );
+        }
+    }
+}
+
+/// Write to two shards over a network that drops, delays, reorders and duplicates Raft messages.
+#[tokio::test(flavor = "current_thread", start_paused = true)]
+async fn test_lossy_network() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let topology = Topology::local(2, 3, 39000, &dir.path().join("data"));
+    let cluster = SimCluster::start(seed(), topology, dir.path().join("cluster.json")).await?;
+    let client = cluster.client().await?;
+
+    cluster.network.set_faults(Faults::lossy());
+    let keys: Vec<String> = (0..40).map(|i| format!("key-{}", i)).collect();
+    for (i, key) in keys.iter().enumerate() {
+        let value = i.to_string();
+        retry(|| client.write(key, &value)).await;
+    }
+    let stats = cluster.network.stats();
+    assert!(stats.dropped > 0, "{:?}", stats);
+    assert!(stats.duplicated > 0, "{:?}", stats);
+    assert!(stats.reordered > 0, "{:?}", stats);
+
+    cluster.network.set_faults(Faults::default());
+    for (i, key) in keys.iter().enumerate() {
+        assert_eq!(retry(|| client.consistent_read(key)).await, i.to_string());
+    }
+    // Duplicated and reordered messages left every replica with the same state.
+    for shard in 0..2 {
+        wait_for_convergence(&cluster, shard, &keys).await;
+    }
+
+    cluster.shutdown().await;
+    Ok(())
+}
+
+/// Isolate the leader of a shard: the others elect a new leader and keep accepting writes, and
+/// the old leader catches up once the partition heals.
+#[tokio::test(flavor = "current_thread", start_paused = true)]
+async fn test_partitioned_leader() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let topology = Topology::local(1, 3, 41000, &dir.path().join("data"));
+    let cluster = SimCluster::start(seed(), topology, dir.path().join("cluster.json")).await?;
+    let client = cluster.client().await?;
+    client.write("key", "before").await?;
+
+    let old = cluster.wait_for_leader(0, &[]).await?;
+    cluster.isolate(0, old);
+    let new = cluster.wait_for_leader(0, &[old]).await?;
+    assert_ne!(old, new);
+    cluster.node(0, new).write(&set("key", "during")).await?;
+    // The isolated leader did not see the write.
+    assert_eq!(cluster.node(0, old).read(&"key".to_string()).await?, "before");
+
+    cluster.heal();
+    assert_eq!(retry(|| client.consistent_read("key")).await, "during");
+    wait_for_convergence(&cluster, 0, &["key".to_string()]).await;
+    assert!(cluster.network.stats().partitioned > 0);
+
+    cluster.shutdown().await;
+    Ok(())
+}
+
+/// Stop the leader of every shard on a lossy network, keep writing, and restart them.
+#[tokio::test(flavor = "current_thread", start_paused = true)]
+async fn test_stopped_leaders() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let topology = Topology::local(2, 3, 43000, &dir.path().join("data"));
+    let mut cluster = SimCluster::start(seed(), topology, dir.path().join("cluster.json")).await?;
+    let client = cluster.client().await?;
+    cluster.network.set_faults(Faults::lossy());
+
+    let keys: Vec<String> = (0..40).map(|i| format!("key-{}", i)).collect();
+    for key in &keys[..20] {
+        retry(|| client.write(key, key)).await;
+    }
+
+    let mut stopped = Vec::new();
+    for shard in 0..2 {
+        let leader = cluster.wait_for_leader(shard, &[]).await?;
+        cluster.stop(shard, leader).await;
+        stopped.push((shard, leader));
+    }
+    // The client fails over to the remaining nodes of every shard.
+    for key in &keys[20..] {
+        retry(|| client.write(key, key)).await;
+    }
+
+    for (shard, id) in stopped {
+        cluster.restart(shard, id).await?;
+    }
+    cluster.network.set_faults(Faults::default());
+    for key in &keys {
+        assert_eq!(retry(|| client.consistent_read(key)).await, *key);
+    }
+    for shard in 0..2 {
+        wait_for_convergence(&cluster, shard, &keys).await;
+    }
+
+    cluster.shutdown().await;
+    Ok(())
+}
@@ -0,0 +1,137 @@
+use std::fs;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::start_raft_node;
+use distrib_kv_store::store::Request;
+use distrib_kv_store::tls::TlsConfig;
+use distrib_kv_store::NodeConfig;
+use maplit::btreeset;
+use rcgen::BasicConstraints;
+use rcgen::CertificateParams;
+use rcgen::IsCa;
+use rcgen::KeyPair;
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+
+fn get_addr(node_id: u64) -> String {
+    format!("127.0.0.1:{}", 31100 + node_id)
+}
+
+fn get_rpc_addr(node_id: u64) -> String {
+    format!("127.0.0.1:{}", 32100 + node_id)
+}
+
+/// Write a CA and a certificate for `127.0.0.1` signed by it to `dir`.
+fn write_certs(dir: &Path) -> Result<TlsConfig, Box<dyn std::error::Error>> {
+    let mut ca_params = CertificateParams::new(Vec::<String>::new())?;
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca_key = KeyPair::generate()?;
+    let ca = ca_params.self_signed(&ca_key)?;
+
+    let node_key = KeyPair::generate()?;
+    let node = CertificateParams::new(vec!["127.0.0.1".to_string()])?.signed_by(
+        &node_key,
+        &ca,
+        &ca_key,
+    )?;
+
+    fs::write(dir.join("ca.pem"), ca.pem())?;
+    fs::write(dir.join("node.pem"), node.pem())?;
+    fs::write(dir.join("node.key"), node_key.serialize_pem())?;
+
+    let mut config = TlsConfig::new(dir.join("node.pem"), dir.join("node.key"));
+    config.ca_path = Some(dir.join("ca.pem"));
+    config.require_client_auth = true;
+    Ok(config)
+}
+
+/// Setup a cluster of 3 nodes that require mutual TLS.
+/// Write to it and read from it, and check that clients without a certificate are rejected and
+/// that rotated certificates are picked up.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_mutual_tls_cluster() -> Result<(), Box<dyn std::error::Error>> {
+    let certs = tempfile::TempDir::new()?;
+    let mut tls = write_certs(certs.path())?;
+    tls.reload_interval_ms = 200;
+
+    let mut shutdown_txs = Vec::new();
+    let mut dirs = Vec::new();
+    for id in 1..=3 {
+        let dir = tempfile::TempDir::new()?;
+        let mut config = NodeConfig::new(id, dir.path(), get_addr(id), get_rpc_addr(id));
+        config.tls = Some(tls.clone());
+        dirs.push(dir);
+
+        let (shutdown_tx, shutdown_rx) = watch::channel(());
+        shutdown_txs.push(shutdown_tx);
+
+        let handle = Handle::current();
+        thread::spawn(move || {
+            let x = handle.block_on(start_raft_node(config, shutdown_rx));
+            println!("x: {:?}", x);
+        });
+    }
+
+    // Wait for server to start up.
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+
+    let leader = RaftNode::with_tls(1, get_addr(1), &tls)?;
+    leader.init().await?;
+    leader.add_learner((2, get_addr(2), get_rpc_addr(2))).await?;
+    leader.add_learner((3, get_addr(3), get_rpc_addr(3))).await?;
+    leader.change_membership(&btreeset! {1, 2, 3}).await?;
+
+    let x = leader.metrics().await?;
+    assert_eq!(
+        &vec![btreeset![1, 2, 3]],
+        x.membership_config.membership().get_joint_config()
+    );
+
+    leader
+        .write(&Request::Set {
+            key: "foo".into(),
+            value: "bar".into(),
+        })
+        .await?;
+
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    // --- Replication to the followers went through the TLS RPC.
+    for id in 2..=3 {
+        let client = RaftNode::with_tls(id, get_addr(id), &tls)?;
+        assert_eq!("bar", client.read(&"foo".to_string()).await?);
+    }
+
+    // --- Plain HTTP is not served.
+    let plain = RaftNode::new(1, get_addr(1));
+    assert!(plain.read(&"foo".to_string()).await.is_err());
+
+    // --- A client that trusts the CA but has no certificate fails the handshake.
+    let ca = fs::read(tls.ca_path.as_ref().unwrap())?;
+    let anonymous = reqwest::Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(reqwest::Certificate::from_pem(&ca)?)
+        .build()?;
+    let res = anonymous
+        .post(format!("https://{}/api/read", get_addr(1)))
+        .json(&"foo")
+        .send()
+        .await;
+    assert!(res.is_err());
+
+    // --- Rotated certificates are reloaded by the nodes and by the clients.
+    write_certs(certs.path())?;
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+    // The first connection of the leader's client to node 2 needs the new certificates on both
+    // sides.
+    let follower = leader.with_same_transport(2, get_addr(2));
+    assert_eq!("bar", follower.read(&"foo".to_string()).await?);
+
+    for tx in shutdown_txs {
+        let _ = tx.send(());
+    }
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeSet;
+use std::thread;
+use std::time::Duration;
+
+use distrib_kv_store::carp::Carp;
+use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::start_raft_node;
+use distrib_kv_store::telemetry;
+use distrib_kv_store::telemetry::TraceLayer;
+use distrib_kv_store::NodeConfig;
+use serde_json::Value;
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Layer;
+
+fn get_addr(node_id: u64) -> String {
+    format!("127.0.0.1:{}", 31500 + node_id)
+}
+
+fn get_rpc_addr(node_id: u64) -> String {
+    format!("127.0.0.1:{}", 32500 + node_id)
+}
+
+/// The exported spans, as the `span` objects of the OTLP JSON lines.
+fn read_spans(path: &std::path::Path) -> Vec<Value> {
+    let contents = std::fs::read_to_string(path).unwrap();
+    contents
+        .lines()
+        .map(|line| {
+            let request: Value = serde_json::from_str(line).unwrap();
+            request["resourceSpans"][0]["scopeSpans"][0]["spans"][0].clone()
+        })
+        .collect()
+}
+
+fn attribute<'a>(span: &'a Value, key: &str) -> Option<&'a Value> {
+    span["attributes"]
+        .as_array()?
+        .iter()
+        .find(|a| a["key"] == key)
+        .map(|a| &a["value"])
+}
+
+/// Write through `KVClient` to a cluster of 3 nodes and check that the write is a single trace
+/// spanning the client, the leader, the followers and every state machine.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_write_is_traced_end_to_end() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let trace_file = dir.path().join("spans.json");
+    let tracer = TraceLayer::with_file("test", &trace_file)?;
+    tracing::subscriber::set_global_default(
+        tracing_subscriber::registry().with(tracer.with_filter(telemetry::filter())),
+    )?;
+
+    let (shutdown_tx, _) = watch::channel(());
+    let handle = Handle::current();
+    for node_id in 1..=3 {
+        let config = NodeConfig::new(
+            node_id,
+            dir.path().join(format!("node-{}", node_id)),
+            get_addr(node_id),
+            get_rpc_addr(node_id),
+        );
+        let shutdown_rx = shutdown_tx.subscribe();
+        let handle = handle.clone();
+        thread::spawn(move || {
+            let x = handle.block_on(start_raft_node(config, shutdown_rx));
+            println!("x: {:?}", x);
+        });
+    }
+
+    // Wait for servers to start up.
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+
+    let leader = RaftNode::new(1, get_addr(1));
+    leader.init().await?;
+    leader.add_learner((2, get_addr(2), get_rpc_addr(2))).await?;
+    leader.add_learner((3, get_addr(3), get_rpc_addr(3))).await?;
+    leader.change_membership(&BTreeSet::from([1, 2, 3])).await?;
+
+    let ring = Carp::with_followers(vec![(get_addr(1), 1.0, vec![get_addr(2), get_addr(3)])], 0);
+    for node_id in 1..=3 {
+        RaftNode::new(node_id, get_addr(node_id))
+            .update_hash_ring(ring.clone())
+            .await?;
+    }
+    let nodes_path = dir.path().join("all_nodes.json");
+    std::fs::write(
+        &nodes_path,
+        serde_json::to_string(&vec![vec![get_addr(1), get_addr(2), get_addr(3)]])?,
+    )?;
+
+    let client = KVClient::new(nodes_path.to_str().unwrap()).await?;
+    client.write("traced", "value").await?;
+
+    // Wait for the followers to apply the write.
+    tokio::time::sleep(Duration::from_millis(1_000)).await;
+
+    let spans = read_spans(&trace_file);
+    let root = spans
+        .iter()
+        .find(|span| span["name"] == "kv_client.write")
+        .expect("client span was exported");
+    assert_eq!(root["parentSpanId"], "");
+    let trace: Vec<&Value> = spans
+        .iter()
+        .filter(|span| span["traceId"] == root["traceId"])
+        .collect();
+    let count = |name: &str| trace.iter().filter(|span| span["name"] == name).count();
+
+    assert_eq!(count("http.server"), 1);
+    assert!(count("raft.append_entries") >= 2);
+    // Every follower handled the entry, and every node applied it.
+    let followers: BTreeSet<String> = trace
+        .iter()
+        .filter(|span| span["name"] == "raft.handle_append_entries")
+        .filter_map(|span| attribute(span, "node_id"))
+        .map(|value| value["intValue"].to_string())
+        .collect();
+    assert_eq!(followers.len(), 2, "{:?}", followers);
+    assert_eq!(count("state_machine.apply"), 3);
+
+    // All spans but the root have a parent within the trace.
+    let span_ids: BTreeSet<&Value> = trace.iter().map(|span| &span["spanId"]).collect();
+    for span in &trace {
+        if span["name"] != "kv_client.write" {
+            assert!(span_ids.contains(&span["parentSpanId"]), "{}", span);
+        }
+    }
+
+    let _ = shutdown_tx.send(());
+    Ok(())
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use distrib_kv_store::cluster_manager::ClusterConfig;
+use distrib_kv_store::cluster_manager::ClusterManager;
+use distrib_kv_store::kvclient::KVClient;
+use distrib_kv_store::namespace::Quota;
+use distrib_kv_store::network::error::ClientError;
+use distrib_kv_store::raft_node::RaftNode;
+use distrib_kv_store::topology::Topology;
+use distrib_kv_store::value::Bytes;
+use distrib_kv_store::value::Value;
+use distrib_kv_store::value::ValueConfig;
+
+/// Store binary keys and values, and large values that the nodes compress, in a shard of 3
+/// nodes, and check that values over the maximum size are rejected.
+#[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+async fn test_binary_and_large_values() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = tempfile::TempDir::new()?;
+    let topology = Topology::local(1, 3, 53000, &dir.path().join("data"));
+    let mut config = ClusterConfig::new(topology.clone());
+    config.client_config = dir.path().join("cluster.json");
+    config.values = ValueConfig {
+        max_size: 64 * 1024,
+        compression_threshold: Some(1024),
+    };
+    let mut cluster = ClusterManager::start(config.clone()).await?;
+    let client = KVClient::new(config.client_config.to_str().unwrap()).await?;
+
+    // --- Binary keys and values
+
+    let key = b"bin/\xff\x00key";
+    let value = [0u8, 1, 2, 0xfe, 0xff];
+    client.write(key, value).await?;
+    assert_eq!(client.consistent_read(key).await?, value[..]);
+    assert!(client.compare_and_swap(key, Some(&value[..]), b"\x80").await?);
+    assert!(!client.compare_and_swap(key, Some(&value[..]), b"\x81").await?);
+    let entries = client.scan(b"bin/", None, 10).await?;
+    assert_eq!(entries, vec![(Bytes::from(&key[..]), Bytes::from(&b"\x80"[..]))]);
+
+    // --- Large values are compressed, but read back as written
+
+    client.create_namespace("team", Quota::default()).await?;
+    let large = "a compressible value ".repeat(3000);
+    client.write("team/large", &large).await?;
+    assert_eq!(client.consistent_read("team/large").await?, large);
+    // Quotas count the size as written.
+    let usage = client.namespace_usage("team").await?;
+    assert_eq!(usage.byte_size, ("team/large".len() + large.len()) as u64);
+
+    let ring = cluster.hash_ring.read().await.clone();
+    let leader = RaftNode::new(1, ring.get_proxy(&ring.nodes[0].addr).to_string());
+    let dump = leader.dump().await?;
+    let stored = dump.state.kvs.get(b"team/large".as_slice()).unwrap();
+    assert!(matches!(stored, Value::Zstd { .. }), "{:?}", stored);
+    assert_eq!(stored.size(), large.len() as u64);
+
+    // Followers decode the compressed entries replicated to them.
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    for node in &topology.shards[0].nodes {
+        let node = RaftNode::new(node.id, node.api_addr.clone());
+        assert_eq!(node.read("team/large").await?, large);
+    }
+
+    // --- Values over the maximum size are rejected
+
+    let too_large = vec![b'x'; 64 * 1024 + 1];
+    let err = client.write("too-large", &too_large).await.unwrap_err();
+    assert!(matches!(err, ClientError::TooLarge(_)), "{:?}", err);
+    let missing = client.consistent_read("too-large").await;
+    assert!(matches!(missing, Err(ClientError::NotFound(_))), "{:?}", missing);
+    client.write("max", &too_large[1..]).await?;
+    assert_eq!(client.consistent_read("max").await?.len(), 64 * 1024);
+
+    cluster.shutdown().await?;
+    Ok(())
+}